@@ -0,0 +1,118 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Access log
+//! Listener of reads and writes for audit trails
+//!
+
+use pref::PRef;
+use error::Error;
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+
+/// listener of db accesses
+pub trait AccessLog: Send + Sync {
+    /// data was looked up with key, pref is invalid if not found
+    fn on_read(&self, key: &[u8], pref: PRef, found: bool);
+
+    /// data was stored with key at pref
+    fn on_write(&self, key: &[u8], pref: PRef);
+
+    /// key was forgotten
+    fn on_forget(&self, key: &[u8]);
+
+    /// batch ended
+    fn on_batch(&self);
+}
+
+/// access log appending a JSON object per line to a file, keys are hex encoded
+pub struct FileAccessLog {
+    file: Mutex<File>
+}
+
+impl FileAccessLog {
+    /// open or create the log file at path
+    pub fn new(path: &str) -> Result<FileAccessLog, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileAccessLog { file: Mutex::new(file) })
+    }
+
+    fn write_line(&self, line: String) {
+        // an audit trail must not stop the db
+        if let Ok(mut file) = self.file.lock() {
+            writeln!(file, "{}", line).ok();
+        }
+    }
+}
+
+fn hex(key: &[u8]) -> String {
+    key.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl AccessLog for FileAccessLog {
+    fn on_read(&self, key: &[u8], pref: PRef, found: bool) {
+        self.write_line(format!(r#"{{"event":"read","key":"{}","pref":{},"found":{}}}"#, hex(key), pref.as_u64(), found));
+    }
+
+    fn on_write(&self, key: &[u8], pref: PRef) {
+        self.write_line(format!(r#"{{"event":"write","key":"{}","pref":{}}}"#, hex(key), pref.as_u64()));
+    }
+
+    fn on_forget(&self, key: &[u8]) {
+        self.write_line(format!(r#"{{"event":"forget","key":"{}"}}"#, hex(key)));
+    }
+
+    fn on_batch(&self) {
+        self.write_line(r#"{"event":"batch"}"#.to_string());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use api::transient;
+
+    use super::*;
+    use std::{env, fs, process};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_file_access_log() {
+        let path = env::temp_dir().join(format!("hammersbald_test_access_log_{}", process::id())).to_string_lossy().to_string();
+        fs::remove_file(&path).ok();
+
+        let mut db = transient(1).unwrap();
+        db.set_access_log(Arc::new(FileAccessLog::new(&path).unwrap()));
+        let pref = db.put_keyed(&[0xab, 0x01], b"data").unwrap();
+        db.get_keyed(&[0xab, 0x01]).unwrap();
+        db.get_keyed(b"missing").unwrap();
+        db.forget(&[0xab, 0x01]).unwrap();
+        db.batch().unwrap();
+        db.shutdown();
+
+        let log = fs::read_to_string(&path).unwrap();
+        let lines = log.lines().collect::<Vec<_>>();
+        assert_eq!(lines, vec!(
+            format!(r#"{{"event":"write","key":"ab01","pref":{}}}"#, pref.as_u64()),
+            format!(r#"{{"event":"read","key":"ab01","pref":{},"found":true}}"#, pref.as_u64()),
+            format!(r#"{{"event":"read","key":"{}","pref":{},"found":false}}"#, hex(b"missing"), PRef::invalid().as_u64()),
+            r#"{"event":"forget","key":"ab01"}"#.to_string(),
+            r#"{"event":"batch"}"#.to_string()
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -18,29 +18,120 @@
 //!
 use logfile::LogFile;
 use tablefile::TableFile;
-use datafile::{DataFile, EnvelopeIterator};
-use memtable::MemTable;
+use datafile::{DataFile, EnvelopeIterator, DagIterator, is_padding, close_over_refs};
+use memtable::{MemTable, BatchPhase};
 use format::{Payload,Envelope};
-use persistent::Persistent;
+use persistent::{Persistent, RepairReport, Config};
 use transient::Transient;
+use transaction::Transaction;
 use pref::PRef;
 use error::Error;
+use stats::{Stats, StatsDiff, BackupStats, CompactionReport, LinkCompactionStats, MemTableStats, RecoveryStats, DBStats};
+use accesslog::AccessLog;
+#[cfg(feature = "tokio_support")]
+use pagedfile::BlockingFuture;
 
 use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
 
 use std::{
+    collections::{HashMap, HashSet},
+    fs,
     io,
-    io::{Cursor, Read, Write}
+    io::{Cursor, Read, Write},
+    sync::{Arc, Mutex, mpsc},
+    thread
+};
+#[cfg(feature = "tokio_support")]
+use std::{
+    future::Future,
+    sync::MutexGuard
 };
 
 /// Hammersbald
 pub struct Hammersbald {
-    mem: MemTable
+    mem: MemTable,
+    snapshot: Option<String>,
+    lock_file: Option<fs::File>,
+    access_log: Option<Arc<dyn AccessLog>>,
+    watches: Mutex<HashMap<Vec<u8>, Watch>>,
+    last_recovery_stats: Option<RecoveryStats>
+}
+
+// watchers of a key and its data when they were last notified
+struct Watch {
+    last: Option<Vec<u8>>,
+    senders: Vec<mpsc::Sender<Option<Vec<u8>>>>
 }
 
-/// create or open a persistent db
+/// create or open a persistent db, with the chunk sizes it was created with
 pub fn persistent(name: &str, cached_data_pages: usize, bucket_fill_target: usize) -> Result<Box<dyn HammersbaldAPI>, Error> {
-    Persistent::new_db(name, cached_data_pages,bucket_fill_target)
+    Persistent::open(Config::from_db(name).with_cached_pages(cached_data_pages).with_bucket_fill_target(bucket_fill_target))
+}
+
+/// create or open a persistent db with all parameters of config
+pub fn persistent_with_config(config: Config) -> Result<Box<dyn HammersbaldAPI>, Error> {
+    Persistent::open(config)
+}
+
+/// create or open a persistent db written on the blocking thread pool of tokio, see Persistent::new_async_db
+#[cfg(feature = "tokio_support")]
+pub fn persistent_async(config: Config) -> Result<AsyncHammersbald, Error> {
+    Persistent::new_async_db(config)
+}
+
+/// a db shared by the tasks of a tokio runtime, clones refer to the same db
+#[cfg(feature = "tokio_support")]
+#[derive(Clone)]
+pub struct AsyncHammersbald {
+    db: Arc<Mutex<Box<dyn HammersbaldAPI>>>,
+    handle: tokio::runtime::Handle
+}
+
+#[cfg(feature = "tokio_support")]
+impl AsyncHammersbald {
+    /// share a db, must be called within a tokio runtime
+    pub fn new(db: Box<dyn HammersbaldAPI>) -> Result<AsyncHammersbald, Error> {
+        let handle = tokio::runtime::Handle::try_current().map_err(|e| Error::IO(io::Error::new(io::ErrorKind::Other, e.to_string())))?;
+        Ok(AsyncHammersbald { db: Arc::new(Mutex::new(db)), handle })
+    }
+
+    /// lock the db for the calling task
+    pub fn lock(&self) -> Result<MutexGuard<'_, Box<dyn HammersbaldAPI>>, Error> {
+        Ok(self.db.lock()?)
+    }
+
+    /// write pending data and make it durable as batch does, without blocking the async executor
+    pub fn flush_async(&self) -> impl Future<Output=Result<(), Error>> {
+        let db = self.db.clone();
+        BlockingFuture::new(self.handle.spawn_blocking(move || db.lock()?.batch()))
+    }
+
+    /// flush then shut the db down without blocking the async executor
+    pub fn shutdown_async(&self) -> impl Future<Output=Result<(), Error>> {
+        let db = self.db.clone();
+        BlockingFuture::new(self.handle.spawn_blocking(move || {
+            let mut db = db.lock()?;
+            db.batch()?;
+            db.shutdown();
+            Ok(())
+        }))
+    }
+}
+
+/// open a persistent db for reading only, see Persistent::open_read_only
+pub fn open_read_only(name: &str, cached_data_pages: usize) -> Result<Box<dyn HammersbaldAPI>, Error> {
+    Persistent::open_read_only(name, cached_data_pages)
+}
+
+/// repair a persistent db that can not be opened after a crash, see Persistent::open_repair
+pub fn repair(name: &str, dry_run: bool) -> Result<RepairReport, Error> {
+    Persistent::open_repair(name, dry_run)
+}
+
+/// index all keyed data of a persistent db anew, if its table is lost or corrupted beyond repair
+/// see Persistent::open_rebuild_index, returns the number of records indexed
+pub fn rebuild_index(name: &str) -> Result<usize, Error> {
+    Persistent::open_rebuild_index(name)
 }
 
 /// create a transient db
@@ -48,11 +139,31 @@ pub fn transient(bucket_fill_target: usize) -> Result<Box<dyn HammersbaldAPI>, E
     Transient::new_db("",0,bucket_fill_target)
 }
 
+/// changes between statistics taken before and after with stats_snapshot of the same open db
+pub fn stats_diff(before: &Stats, after: &Stats) -> StatsDiff {
+    let data_bytes = |stats: &Stats| stats.type_bytes.indexed + stats.type_bytes.referred;
+    StatsDiff {
+        inserted: after.inserted.saturating_sub(before.inserted),
+        forgotten: after.forgotten.saturating_sub(before.forgotten),
+        data_bytes_added: data_bytes(after).saturating_sub(data_bytes(before)),
+        data_bytes_freed: after.freed_bytes.saturating_sub(before.freed_bytes),
+        buckets_added: after.buckets.saturating_sub(before.buckets),
+        batches_elapsed: after.batches.saturating_sub(before.batches)
+    }
+}
+
 /// public API to Hammersbald
 pub trait HammersbaldAPI : Send + Sync {
     /// end current batch and start a new batch
     fn batch(&mut self)  -> Result<(), Error>;
 
+    /// end current batch and start a new batch, calling progress at the start of each phase
+    fn batch_with_progress(&mut self, progress: &dyn Fn(BatchPhase))  -> Result<(), Error>;
+
+    /// write what is stored so far to disk, a crash recovers to the last checkpoint or batch
+    /// unlike batch this does not notify watches, the access log or count a batch
+    fn checkpoint(&mut self) -> Result<(), Error>;
+
     /// stop background writer
     fn shutdown(&mut self);
 
@@ -60,28 +171,263 @@ pub trait HammersbaldAPI : Send + Sync {
     /// returns a persistent reference to stored data
     fn put_keyed(&mut self, key: &[u8], data: &[u8]) -> Result<PRef, Error>;
 
+    /// store data accessible with keys
+    /// nothing is stored if any key or data is too long
+    /// returns persistent references to stored data in the order of items
+    fn put_keyed_batch(&mut self, items: &[(&[u8], &[u8])]) -> Result<Vec<PRef>, Error>;
+
+    /// store data accessible with a key that was never stored before
+    /// faster than put_keyed as earlier data of the key is not searched, debug builds panic if there is
+    /// returns a persistent reference to stored data
+    fn put_keyed_unique(&mut self, key: &[u8], data: &[u8]) -> Result<PRef, Error>;
+
+    /// store data accessible with a key only if the key is not yet stored
+    /// returns a persistent reference to stored data or None if the key was already stored
+    fn put_keyed_if_absent(&mut self, key: &[u8], data: &[u8]) -> Result<Option<PRef>, Error>;
+
     /// retrieve data with key
     /// returns Some(persistent reference, data) or None
     fn get_keyed(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>)>, Error>;
 
+    /// retrieve data with key into buf, reusing its allocation
+    /// buf is cleared and holds the data if found, returns Some(persistent reference) or None
+    fn get_keyed_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<PRef>, Error>;
+
+    /// store data accessible with key together with persistent references to other data
+    /// the links are stored as the referred data of the key, compaction keeps and moves what they refer to
+    /// returns a persistent reference to stored data
+    fn put_keyed_with_links(&mut self, key: &[u8], data: &[u8], links: &[PRef]) -> Result<PRef, Error>;
+
+    /// retrieve data stored with put_keyed_with_links
+    /// returns Some(persistent reference, data, links) or None
+    fn get_keyed_with_links(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>, Vec<PRef>)>, Error>;
+
+    /// store a new version of data accessible with key, earlier versions are kept
+    /// returns a persistent reference to stored data and its version starting with 1
+    fn put_keyed_versioned(&mut self, key: &[u8], data: &[u8]) -> Result<(PRef, u32), Error>;
+
+    /// retrieve a version of data stored with put_keyed_versioned
+    /// returns Some(persistent reference, data) or None
+    fn get_keyed_version(&self, key: &[u8], version: u32) -> Result<Option<(PRef, Vec<u8>)>, Error>;
+
+    /// retrieve the persistent reference of data stored with key
+    /// returns Some(persistent reference) or None, data is not copied
+    fn get_pref_for_key(&self, key: &[u8]) -> Result<Option<PRef>, Error>;
+
     /// store data
     /// returns a persistent reference
     fn put(&mut self, data: &[u8]) -> Result<PRef, Error>;
 
+    /// store data with persistent references to other data, as a node of put_referred_dag
+    /// returns a persistent reference, dag follows the references from it and compaction moves them along
+    fn put_with_refs(&mut self, data: &[u8], refs: &[PRef]) -> Result<PRef, Error>;
+
+    /// store a DAG of data, each node given as data and indices of its children in nodes
+    /// a node is stored with put_with_refs, referring to its children
+    /// returns persistent references of the nodes in the order of nodes
+    fn put_referred_dag(&mut self, nodes: &[(&[u8], Vec<usize>)]) -> Result<Vec<PRef>, Error>;
+
     /// retrieve data using a persistent reference
     /// returns (key, data)
     fn get(&self, pref: PRef) -> Result<(Vec<u8>, Vec<u8>), Error>;
 
+    /// retrieve data and the persistent references stored with it using a persistent reference
+    /// as stored with put_with_refs, or with put_keyed_with_links for data with a key
+    /// returns (data, references)
+    fn get_with_refs(&self, pref: PRef) -> Result<(Vec<u8>, Vec<PRef>), Error>;
+
+    /// store a list of persistent references as their number (u32) followed by the references
+    /// returns a persistent reference to the list
+    fn put_pref_list(&mut self, prefs: &[PRef]) -> Result<PRef, Error>;
+
+    /// retrieve a list of persistent references stored with put_pref_list
+    fn get_pref_list(&self, pref: PRef) -> Result<Vec<PRef>, Error>;
+
     /// a quick (in-memory) check if the db may have the key
     /// this might return false positive, but if it is false key is definitely not used.
     fn may_have_key(&self, key: &[u8]) -> Result<bool, Error>;
 
+    /// may_have_key for many keys at once, faster than may_have_key for each
+    fn may_have_key_bulk(&self, keys: &[&[u8]]) -> Result<Vec<bool>, Error>;
+
+    /// check if the db has the key, without reading its data
+    fn contains_key(&self, key: &[u8]) -> Result<bool, Error>;
+
+    /// a quick (in-memory) lookup of the key without reading data
+    /// a ProbablyPresent pref might point to data of an other key, verify with get if needed
+    fn get_keyed_approximate(&self, key: &[u8]) -> Result<ApproximateResult, Error>;
+
+    /// the hash table bucket the key is stored in, to debug hash collisions
+    fn inspect_bucket(&self, key: &[u8]) -> Result<BucketInspection, Error>;
+
+    /// call log on put_keyed, get_keyed, forget and batch
+    fn set_access_log(&mut self, log: Arc<dyn AccessLog>);
+
+    /// receive data of the key whenever a batch ends with data different from before
+    /// None is received if the key was forgotten
+    fn watch(&self, key: &[u8]) -> Result<mpsc::Receiver<Option<Vec<u8>>>, Error>;
+
     /// forget a key (if known)
     /// This is not a real delete as data will be still accessible through its PRef, but contains hash table growth
     fn forget(&mut self, key: &[u8]) -> Result<(), Error>;
 
+    /// forget many keys (if known), faster than forget for each
+    fn forget_batch(&mut self, keys: &[&[u8]]) -> Result<(), Error>;
+
     /// iterator of data
     fn iter(&self) -> HammersbaldIterator;
+
+    /// iterator of data in insertion order
+    /// data is yielded in ascending PRef order, that is the order it was stored with put, put_keyed etc.
+    fn iter_by_insertion_order(&self) -> HammersbaldIterator {
+        self.iter()
+    }
+
+    /// iterator of data accessible with keys
+    fn iter_indexed(&self) -> IndexedIterator;
+
+    /// iterator of data accessible only with persistent references
+    fn iter_referred(&self) -> ReferredIterator;
+
+    /// breadth first iterator of the DAG stored with put_referred_dag at root
+    /// yields depth, persistent reference and envelope of each node once
+    fn dag(&self, root: PRef) -> DagIterator;
+
+    /// iterator of data resuming at a token saved with HammersbaldIterator::save_token
+    ///
+    /// ```
+    /// # use hammersbald::transient;
+    /// let mut db = transient(1).unwrap();
+    /// db.put_keyed(b"a", b"1").unwrap();
+    /// db.batch().unwrap();
+    ///
+    /// // process everything stored so far, then remember where we stopped
+    /// let mut iter = db.iter();
+    /// for (_pref, _key, _data) in iter.by_ref() {}
+    /// let token = iter.save_token();
+    ///
+    /// db.put_keyed(b"b", b"2").unwrap();
+    /// db.batch().unwrap();
+    ///
+    /// // process only what was stored since
+    /// let mut iter = db.iter_from_token(token);
+    /// let keys = iter.by_ref().map(|(_, key, _)| key).filter(|key| !key.is_empty()).collect::<Vec<_>>();
+    /// assert_eq!(keys, vec!(b"b".to_vec()));
+    /// let token = iter.save_token();
+    /// assert_eq!(db.iter_from_token(token).count(), 0);
+    /// ```
+    fn iter_from_token(&self, token: PRef) -> HammersbaldIterator {
+        self.iter_from(token)
+    }
+
+    /// iterator of data starting at start, e.g. the length of the data file at a checkpoint
+    fn iter_from(&self, start: PRef) -> HammersbaldIterator;
+
+    /// iterator of data stored after the data at pref, exclusive of that data
+    /// use the PRef of the last processed data to continue without processing it again
+    /// an invalid pref iterates from the start
+    fn iter_since_pref(&self, pref: PRef) -> HammersbaldIterator;
+
+    /// iterator of data stored at or after from and before to
+    /// use current_data_pos before and after a batch to iterate the data of that batch
+    fn iter_range(&self, from: PRef, to: PRef) -> HammersbaldIterator;
+
+    /// position the next data will be stored at
+    fn current_data_pos(&self) -> PRef;
+
+    /// iterator of data whose key starts with prefix, in the order stored
+    /// this reads all stored data, so it is O(N) in the number of records
+    /// data overwritten or forgotten is returned too, as with iter
+    fn prefix_scan<'a>(&'a self, prefix: &'a [u8]) -> PrefixIterator<'a>;
+
+    /// prefix_scan reading segments of the data file in parallel
+    /// returns (persistent reference, key, data) in the order stored
+    #[cfg(feature = "parallel_reads")]
+    fn prefix_scan_parallel(&self, prefix: &[u8]) -> Result<Vec<(PRef, Vec<u8>, Vec<u8>)>, Error>;
+
+    /// set the keys of the hash of keys, ending the current batch
+    /// only possible before any data is stored with a key
+    fn set_sip_keys(&mut self, sip0: u64, sip1: u64) -> Result<(), Error>;
+
+    /// the keys of the hash of keys
+    fn get_sip_keys(&self) -> (u64, u64);
+
+    /// statistics of the db, compare two with stats_diff
+    /// this scans all stored data
+    fn stats_snapshot(&self) -> Result<Stats, Error>;
+
+    /// statistics of the hash table
+    /// this reads all links
+    fn stats(&self) -> Result<MemTableStats, Error>;
+
+    /// number of buckets by the number of keys in them, index i is the number of buckets with i keys
+    /// this reads all links
+    fn bucket_fill_histogram(&self) -> Result<Vec<usize>, Error>;
+
+    /// sizes of the files and the hash table, and the number of garbage entries in the data file
+    /// this reads all links and all data
+    fn get_stats(&self) -> Result<DBStats, Error>;
+
+    /// number of keys stored, without reading the hash table
+    fn count_live_keys(&self) -> Result<usize, Error>;
+
+    /// what the recovery at open did, None if opened without recovery
+    fn last_recovery_stats(&self) -> Option<RecoveryStats>;
+
+    /// end current batch and copy the db files to files named dest_name
+    /// the copy can be opened as a persistent db of that name
+    fn backup(&mut self, dest_name: &str) -> Result<BackupStats, Error>;
+
+    /// grow the hash table to at least target_bucket_count buckets, then end current batch
+    /// this avoids incremental rehashing while storing a known number of keys, the table does not shrink
+    fn rehash_to_target(&mut self, target_bucket_count: usize) -> Result<(), Error>;
+
+    /// end current batch and replace the link file with one without links the hash table no longer uses
+    /// the new files are swapped in once the log records them, so a crash recovers the db before or after compacting
+    fn compact_link_file(&mut self) -> Result<LinkCompactionStats, Error>;
+
+    /// end current batch and replace the data file with one of only data accessible with keys and referred data they list
+    /// persistent references to data change, data no longer accessible with keys is removed
+    /// referred data is kept if linked with put_keyed_with_links, links are moved along
+    /// refuses with InvalidArgument if there is referred data no keyed data links, as stored with put alone
+    /// the new files are swapped in once the log records them, so a crash recovers the db before or after compacting
+    /// returns the bytes reclaimed
+    fn compact_data_file(&mut self) -> Result<u64, Error>;
+
+    /// end current batch and compact the data file as compact_data_file does
+    /// reports the new pref of each record kept, including referred data
+    fn compact(&mut self) -> Result<CompactionReport, Error>;
+
+    /// copy data accessible with keys into a new transient db
+    /// referred data is not copied as its persistent references would differ in the copy
+    fn clone_to_transient(&self) -> Result<Box<dyn HammersbaldAPI>, Error>;
+
+    /// an independent transient copy of the db with the same files, ending the current batch
+    /// unlike clone_to_transient forgotten data and persistent references are kept
+    fn try_clone(&mut self) -> Result<Box<dyn HammersbaldAPI>, Error>;
+
+    /// start buffering put_keyed and forget, that are applied and batched at once on commit
+    fn transaction(&mut self) -> Transaction;
+}
+
+/// result of HammersbaldAPI::get_keyed_approximate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApproximateResult {
+    /// the key is not in the db
+    DefinitelyAbsent,
+    /// the key's hash is in the db, data at the pref might belong to an other key
+    ProbablyPresent(PRef)
+}
+
+/// result of HammersbaldAPI::inspect_bucket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BucketInspection {
+    /// number of the bucket the key is stored in
+    pub bucket_number: usize,
+    /// hash and persistent reference of all keys in the bucket
+    pub all_slots: Vec<(u32, PRef)>,
+    /// the slot of the key, if it is in the db
+    pub matching_slot: Option<(u32, PRef)>
 }
 
 /// A helper to build Hammersbald data elements
@@ -148,20 +494,106 @@ impl Hammersbald {
     /// create a new db with key and data file
     pub fn new(log: LogFile, table: TableFile, data: DataFile, link: DataFile, bucket_fill_target :usize) -> Result<Hammersbald, Error> {
         let mem = MemTable::new(log, table, data, link, bucket_fill_target);
-        let mut db = Hammersbald { mem };
+        let mut db = Hammersbald { mem, snapshot: None, lock_file: None, access_log: None, watches: Mutex::new(HashMap::new()), last_recovery_stats: None };
         db.recover()?;
         db.load()?;
         db.batch()?;
         Ok(db)
     }
 
+    /// a transient db with a copy of the files, to be called after batch
+    pub fn copy_to_transient(&self) -> Result<Hammersbald, Error> {
+        let (log, table, data, link) = self.mem.copy_to_transient()?;
+        Hammersbald::new(log, table, data, link, self.mem.bucket_fill_target())
+    }
+
+    /// open a db without recovery or writing any of its files
+    pub fn new_read_only(log: LogFile, table: TableFile, data: DataFile, link: DataFile) -> Result<Hammersbald, Error> {
+        let mem = MemTable::new(log, table, data, link, 1);
+        let mut db = Hammersbald { mem, snapshot: None, lock_file: None, access_log: None, watches: Mutex::new(HashMap::new()), last_recovery_stats: None };
+        db.load()?;
+        Ok(db)
+    }
+
+    /// create a new db that loads the memtable from a snapshot file if it matches the files
+    /// and writes the snapshot file at shutdown
+    pub fn new_with_snapshot(log: LogFile, table: TableFile, data: DataFile, link: DataFile, bucket_fill_target :usize, snapshot: &str) -> Result<Hammersbald, Error> {
+        let mem = MemTable::new(log, table, data, link, bucket_fill_target);
+        let mut db = Hammersbald { mem, snapshot: Some(snapshot.to_string()), lock_file: None, access_log: None, watches: Mutex::new(HashMap::new()), last_recovery_stats: None };
+        db.recover()?;
+        let mut restored = false;
+        if let Ok(mut file) = fs::File::open(snapshot) {
+            restored = match db.mem.restore(&mut io::BufReader::new(&mut file)) {
+                Ok(restored) => restored,
+                Err(_) => false
+            };
+            // a snapshot is only valid until the next change
+            fs::remove_file(snapshot)?;
+        }
+        if !restored {
+            db.load()?;
+        }
+        db.batch()?;
+        Ok(db)
+    }
+
+    /// keep the file locking the db until shutdown
+    pub fn set_lock_file(&mut self, lock: fs::File) {
+        self.lock_file = Some(lock);
+    }
+
+    /// chunk sizes of the data and link, table and log files the db was created with, zeros if not stored
+    pub fn chunk_sizes(&self) -> [u64; 3] {
+        self.mem.chunk_sizes()
+    }
+
+    /// store the chunk sizes of the files and end the current batch
+    pub fn set_chunk_sizes(&mut self, chunk_sizes: [u64; 3]) -> Result<(), Error> {
+        self.mem.set_chunk_sizes(chunk_sizes);
+        self.batch()
+    }
+
+    /// number of keys whose data can not be read
+    pub fn verify_integrity(&self) -> Result<usize, Error> {
+        let mut unreadable = 0;
+        for slots in self.mem.slots() {
+            for (_, pref) in slots {
+                let readable = match self.mem.get_envelope(pref) {
+                    Ok(envelope) => match Payload::deserialize(envelope.payload()) {
+                        Ok(Payload::Indexed(_)) => true,
+                        _ => false
+                    },
+                    Err(_) => false
+                };
+                if !readable {
+                    unreadable += 1;
+                }
+            }
+        }
+        Ok(unreadable)
+    }
+
+    /// discard the hash table and index all keyed data of the data file, then end the batch
+    /// returns the number of records indexed
+    pub fn rebuild_index(&mut self) -> Result<usize, Error> {
+        self.mem.rebuild_from_data_file()
+    }
+
+    fn save_snapshot(&self, snapshot: &str) -> Result<(), Error> {
+        let mut file = io::BufWriter::new(fs::File::create(snapshot)?);
+        self.mem.serialize(&mut file)?;
+        file.flush()?;
+        Ok(())
+    }
+
     /// load memtable
     fn load(&mut self) -> Result<(), Error> {
         self.mem.load()
     }
 
     fn recover(&mut self) -> Result<(), Error> {
-        self.mem.recover()
+        self.last_recovery_stats = Some(self.mem.recover_with_stats()?);
+        Ok(())
     }
 
     /// get hash table bucket iterator
@@ -188,129 +620,2224 @@ impl Hammersbald {
     pub fn params(&self) -> (usize, u32, usize, u64, u64, u64, u64, u64) {
         self.mem.params()
     }
+
+    // send changed data of watched keys, watches without receivers are dropped
+    fn notify_watches(&self) -> Result<(), Error> {
+        let mut watches = self.watches.lock()?;
+        let mut unwatched = Vec::new();
+        for (key, watch) in watches.iter_mut() {
+            let current = self.mem.get(key)?.map(|(_, data, _)| data);
+            if current != watch.last {
+                watch.senders.retain(|sender| sender.send(current.clone()).is_ok());
+                watch.last = current;
+            }
+            if watch.senders.is_empty() {
+                unwatched.push(key.clone());
+            }
+        }
+        for key in unwatched {
+            watches.remove(&key);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Hammersbald {
+    // write what the background writers hold, also if shutdown was not called
+    // no snapshot is saved, as a db failing to open is dropped too
+    // nothing is written while unwinding, a panic in drop would abort
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            self.mem.shutdown();
+        }
+    }
 }
 
 impl HammersbaldAPI for Hammersbald {
 
     fn batch(&mut self)  -> Result<(), Error> {
-        self.mem.batch()
+        self.batch_with_progress(&|_| {})
+    }
+
+    fn batch_with_progress(&mut self, progress: &dyn Fn(BatchPhase))  -> Result<(), Error> {
+        self.mem.batch_with_progress(progress)?;
+        if let Some(ref log) = self.access_log {
+            log.on_batch();
+        }
+        self.notify_watches()
+    }
+
+    fn checkpoint(&mut self) -> Result<(), Error> {
+        self.mem.checkpoint()
     }
 
     fn shutdown(&mut self) {
-        self.mem.shutdown()
+        if let Some(ref snapshot) = self.snapshot {
+            if self.mem.is_clean() {
+                if self.save_snapshot(snapshot).is_err() {
+                    fs::remove_file(snapshot).ok();
+                }
+            }
+        }
+        self.mem.shutdown();
+        // closing releases the lock
+        self.lock_file = None;
     }
 
     fn put_keyed(&mut self, key: &[u8], data: &[u8]) -> Result<PRef, Error> {
+        self.put_keyed_with_links(key, data, &[])
+    }
+
+    fn put_keyed_batch(&mut self, items: &[(&[u8], &[u8])]) -> Result<Vec<PRef>, Error> {
+        if items.iter().any(|(key, data)| key.len() > u16::max_value() as usize || data.len() >= 1 << 23) {
+            return Err(Error::KeyTooLong);
+        }
+        let mut stored = Vec::with_capacity(items.len());
+        for (key, data) in items {
+            stored.push((*key, self.mem.append_data(key, data, &[])?));
+        }
+        self.mem.put_all(&stored)?;
+        if let Some(ref log) = self.access_log {
+            for (key, data_offset) in &stored {
+                log.on_write(key, *data_offset);
+            }
+        }
+        Ok(stored.into_iter().map(|(_, data_offset)| data_offset).collect())
+    }
+
+    fn put_keyed_unique(&mut self, key: &[u8], data: &[u8]) -> Result<PRef, Error> {
         #[cfg(debug_assertions)]
         {
-            if key.len() > 255 || data.len() >= 1 << 23 {
+            if key.len() > u16::max_value() as usize || data.len() >= 1 << 23 {
                 return Err(Error::KeyTooLong);
             }
         }
-        let data_offset = self.mem.append_data(key, data)?;
-        self.mem.put(key, data_offset)?;
+        let data_offset = self.mem.append_data(key, data, &[])?;
+        self.mem.put_no_dedup(key, data_offset)?;
+        if let Some(ref log) = self.access_log {
+            log.on_write(key, data_offset);
+        }
         Ok(data_offset)
     }
 
+    fn put_keyed_if_absent(&mut self, key: &[u8], data: &[u8]) -> Result<Option<PRef>, Error> {
+        // no other thread can modify the table between the check and the insert while we borrow it mutable
+        if self.mem.get_pref(key)?.is_some() {
+            return Ok(None);
+        }
+        self.put_keyed_unique(key, data).map(Some)
+    }
+
     fn get_keyed(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>)>, Error> {
-        self.mem.get(key)
+        let result = self.mem.get(key)?.map(|(pref, data, _)| (pref, data));
+        if let Some(ref log) = self.access_log {
+            match result {
+                Some((pref, _)) => log.on_read(key, pref, true),
+                None => log.on_read(key, PRef::invalid(), false)
+            }
+        }
+        Ok(result)
     }
 
-    fn put(&mut self, data: &[u8]) -> Result<PRef, Error> {
-        let data_offset = self.mem.append_referred(data)?;
+    fn get_keyed_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<PRef>, Error> {
+        let result = self.mem.get_into(key, buf)?;
+        if let Some(ref log) = self.access_log {
+            match result {
+                Some(pref) => log.on_read(key, pref, true),
+                None => log.on_read(key, PRef::invalid(), false)
+            }
+        }
+        Ok(result)
+    }
+
+    fn put_keyed_with_links(&mut self, key: &[u8], data: &[u8], links: &[PRef]) -> Result<PRef, Error> {
+        #[cfg(debug_assertions)]
+        {
+            if key.len() > u16::max_value() as usize || data.len() >= 1 << 23 {
+                return Err(Error::KeyTooLong);
+            }
+        }
+        let data_offset = self.mem.append_data(key, data, links)?;
+        self.mem.put(key, data_offset)?;
+        if let Some(ref log) = self.access_log {
+            log.on_write(key, data_offset);
+        }
         Ok(data_offset)
     }
 
-    fn get(&self, pref: PRef) -> Result<(Vec<u8>, Vec<u8>), Error> {
-        let envelope = self.mem.get_envelope(pref)?;
-        match Payload::deserialize(envelope.payload())? {
-            Payload::Referred(referred) => return Ok((vec!(), referred.data.to_vec())),
-            Payload::Indexed(indexed) => return Ok((indexed.key.to_vec(), indexed.data.data.to_vec())),
-            _ => Err(Error::Corrupted("referred should point to data".to_string()))
+    fn get_keyed_with_links(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>, Vec<PRef>)>, Error> {
+        let result = self.mem.get(key)?;
+        if let Some(ref log) = self.access_log {
+            match result {
+                Some((pref, _, _)) => log.on_read(key, pref, true),
+                None => log.on_read(key, PRef::invalid(), false)
+            }
         }
+        Ok(result)
     }
 
-    fn may_have_key(&self, key: &[u8]) -> Result<bool, Error> {
-        self.mem.may_have_key(key)
+    fn put_keyed_versioned(&mut self, key: &[u8], data: &[u8]) -> Result<(PRef, u32), Error> {
+        let version_key = version_counter_key(key);
+        let version = match self.mem.get(&version_key)? {
+            Some((_, v, _)) => Cursor::new(v).read_u32::<BigEndian>()? + 1,
+            None => 1
+        };
+        let pref = self.put_keyed(&versioned_key(key, version), data)?;
+        let mut v = vec!();
+        v.write_u32::<BigEndian>(version)?;
+        self.put_keyed(&version_key, &v)?;
+        Ok((pref, version))
     }
 
-    fn forget(&mut self, key: &[u8]) -> Result<(), Error> {
-        self.mem.forget(key)
+    fn get_keyed_version(&self, key: &[u8], version: u32) -> Result<Option<(PRef, Vec<u8>)>, Error> {
+        Ok(self.mem.get(&versioned_key(key, version))?.map(|(pref, data, _)| (pref, data)))
     }
 
-    fn iter(&self) -> HammersbaldIterator {
-        HammersbaldIterator{ ei: self.mem.data_envelopes()}
+    fn get_pref_for_key(&self, key: &[u8]) -> Result<Option<PRef>, Error> {
+        self.mem.get_pref(key)
     }
-}
 
-/// iterate data content
-pub struct HammersbaldIterator<'a> {
-    ei: EnvelopeIterator<'a>
-}
+    fn put(&mut self, data: &[u8]) -> Result<PRef, Error> {
+        let data_offset = self.mem.append_referred(data)?;
+        Ok(data_offset)
+    }
 
-impl<'a> Iterator for HammersbaldIterator<'a> {
-    type Item = (PRef, Vec<u8>, Vec<u8>);
+    fn put_with_refs(&mut self, data: &[u8], refs: &[PRef]) -> Result<PRef, Error> {
+        self.mem.append_referred_with_refs(data, refs)
+    }
 
-    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
-        if let Some((pref, envelope)) = self.ei.next() {
-            match Payload::deserialize(envelope.payload()).unwrap() {
-                Payload::Indexed(indexed) => {
-                    return Some((pref, indexed.key.to_vec(), indexed.data.data.to_vec()))
-                },
-                Payload::Referred(referred) => {
-                    return Some((pref, vec!(), referred.data.to_vec()))
-                },
-                _ => return None
+    fn put_referred_dag(&mut self, nodes: &[(&[u8], Vec<usize>)]) -> Result<Vec<PRef>, Error> {
+        // children are stored before their parents
+        let mut parents = vec!(Vec::new(); nodes.len());
+        let mut waiting_for = vec!(0usize; nodes.len());
+        let mut ready = Vec::new();
+        for (n, (_, children)) in nodes.iter().enumerate() {
+            if children.len() > 0xffff {
+                return Err(Error::InvalidArgument(format!("dag node {} has too many children", n)));
+            }
+            for child in children {
+                if *child >= nodes.len() {
+                    return Err(Error::InvalidArgument(format!("dag node {} refers to unknown node {}", n, child)));
+                }
+                parents[*child].push(n);
+            }
+            waiting_for[n] = children.len();
+            if children.is_empty() {
+                ready.push(n);
             }
         }
-        None
+        let mut order = Vec::with_capacity(nodes.len());
+        while let Some(n) = ready.pop() {
+            order.push(n);
+            for parent in &parents[n] {
+                waiting_for[*parent] -= 1;
+                if waiting_for[*parent] == 0 {
+                    ready.push(*parent);
+                }
+            }
+        }
+        if order.len() < nodes.len() {
+            return Err(Error::InvalidArgument("dag has a cycle".to_string()));
+        }
+
+        let mut prefs = vec!(PRef::invalid(); nodes.len());
+        for n in order {
+            let (data, children) = &nodes[n];
+            let refs = children.iter().map(|child| prefs[*child]).collect::<Vec<_>>();
+            prefs[n] = self.put_with_refs(data, refs.as_slice())?;
+        }
+        Ok(prefs)
     }
-}
 
-#[cfg(test)]
-mod test {
-    extern crate rand;
-    extern crate hex;
+    fn get(&self, pref: PRef) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        let payload = self.mem.read_envelope_raw(pref)?;
+        match Payload::deserialize(&payload)? {
+            Payload::Referred(referred) => Ok((vec!(), referred.data.to_vec())),
+            Payload::ReferredWithRefs(node) => Ok((vec!(), node.data.data.to_vec())),
+            Payload::Indexed(indexed) => Ok((indexed.key.to_vec(), indexed.data.data.to_vec())),
+            _ => Err(Error::corrupted("referred should point to data"))
+        }
+    }
 
-    use transient::Transient;
+    fn get_with_refs(&self, pref: PRef) -> Result<(Vec<u8>, Vec<PRef>), Error> {
+        let payload = self.mem.read_envelope_raw(pref)?;
+        match Payload::deserialize(&payload)? {
+            Payload::Referred(referred) => Ok((referred.data.to_vec(), vec!())),
+            Payload::ReferredWithRefs(node) => Ok((node.data.data.to_vec(), node.refs)),
+            Payload::Indexed(indexed) => Ok((indexed.data.data.to_vec(), indexed.referred)),
+            _ => Err(Error::corrupted("referred should point to data"))
+        }
+    }
 
-    use self::rand::thread_rng;
-    use std::collections::HashMap;
-    use api::test::rand::RngCore;
+    fn put_pref_list(&mut self, prefs: &[PRef]) -> Result<PRef, Error> {
+        let mut writer = HammersbaldDataWriter::new();
+        writer.write_u32::<BigEndian>(prefs.len() as u32)?;
+        for pref in prefs {
+            writer.write_ref(*pref);
+        }
+        self.put(writer.as_slice())
+    }
 
-    #[test]
-    fn test_two_batches() {
-        let mut db = Transient::new_db("first", 1, 1).unwrap();
+    fn get_pref_list(&self, pref: PRef) -> Result<Vec<PRef>, Error> {
+        let (_, data) = self.get(pref)?;
+        let mut reader = HammersbaldDataReader::new(data.as_slice());
+        let n = reader.read_u32::<BigEndian>()? as usize;
+        if data.len() != 4 + n * 6 {
+            return Err(Error::corrupted(format!("not a list of references at {}", pref)));
+        }
+        let mut prefs = Vec::with_capacity(n);
+        for _ in 0 .. n {
+            prefs.push(reader.read_ref()?);
+        }
+        Ok(prefs)
+    }
 
-        let mut rng = thread_rng();
+    fn may_have_key(&self, key: &[u8]) -> Result<bool, Error> {
+        self.mem.may_have_key(key)
+    }
 
-        let mut check = HashMap::new();
-        let mut key = [0x0u8;32];
-        let mut data = [0x0u8;40];
+    fn may_have_key_bulk(&self, keys: &[&[u8]]) -> Result<Vec<bool>, Error> {
+        self.mem.may_have_key_bulk(keys)
+    }
 
-        for _ in 0 .. 10000 {
-            rng.fill_bytes(&mut key);
-            rng.fill_bytes(&mut data);
-            let pref = db.put_keyed(&key, &data).unwrap();
-            check.insert(key, (pref, data));
-        }
-        db.batch().unwrap();
+    fn contains_key(&self, key: &[u8]) -> Result<bool, Error> {
+        self.mem.has_key(key)
+    }
 
-        for (k, (o, v)) in check.iter() {
-            assert_eq!(db.get_keyed(&k[..]).unwrap(), Some((*o, v.to_vec())));
+    fn get_keyed_approximate(&self, key: &[u8]) -> Result<ApproximateResult, Error> {
+        match self.mem.get_pref_approximate(key)? {
+            Some(pref) => Ok(ApproximateResult::ProbablyPresent(pref)),
+            None => Ok(ApproximateResult::DefinitelyAbsent)
         }
+    }
 
-        for _ in 0 .. 10000 {
-            rng.fill_bytes(&mut key);
-            rng.fill_bytes(&mut data);
-            let pref = db.put_keyed(&key, &data).unwrap();
-            check.insert(key, (pref, data));
+    fn inspect_bucket(&self, key: &[u8]) -> Result<BucketInspection, Error> {
+        let bucket_number = self.mem.bucket_for_key(key);
+        let all_slots = self.mem.get_bucket_pref_chain(key)?;
+        let matching_slot = match self.mem.get_pref(key)? {
+            Some(pref) => all_slots.iter().find(|(_, p)| *p == pref).cloned(),
+            None => None
+        };
+        Ok(BucketInspection { bucket_number, all_slots, matching_slot })
+    }
+
+    fn watch(&self, key: &[u8]) -> Result<mpsc::Receiver<Option<Vec<u8>>>, Error> {
+        let (sender, receiver) = mpsc::channel();
+        let mut watches = self.watches.lock()?;
+        if let Some(watch) = watches.get_mut(key) {
+            watch.senders.push(sender);
+            return Ok(receiver);
         }
-        db.batch().unwrap();
+        let last = self.mem.get(key)?.map(|(_, data, _)| data);
+        watches.insert(key.to_vec(), Watch { last, senders: vec!(sender) });
+        Ok(receiver)
+    }
 
-        for (k, (o, v)) in check.iter() {
-            assert_eq!(db.get_keyed(&k[..]).unwrap(), Some((*o, v.to_vec())));
+    fn set_access_log(&mut self, log: Arc<dyn AccessLog>) {
+        self.access_log = Some(log);
+    }
+
+    fn forget(&mut self, key: &[u8]) -> Result<(), Error> {
+        self.mem.forget(key)?;
+        if let Some(ref log) = self.access_log {
+            log.on_forget(key);
         }
+        Ok(())
+    }
+
+    fn forget_batch(&mut self, keys: &[&[u8]]) -> Result<(), Error> {
+        self.mem.forget_all(keys)?;
+        if let Some(ref log) = self.access_log {
+            for key in keys {
+                log.on_forget(key);
+            }
+        }
+        Ok(())
+    }
+
+    fn iter(&self) -> HammersbaldIterator {
+        HammersbaldIterator{ ei: self.mem.data_envelopes()}
+    }
+
+    fn dag(&self, root: PRef) -> DagIterator {
+        self.mem.dag(root)
+    }
+
+    fn iter_from(&self, start: PRef) -> HammersbaldIterator {
+        HammersbaldIterator::resume_from(&self.mem, start)
+    }
+
+    fn iter_since_pref(&self, pref: PRef) -> HammersbaldIterator {
+        if !pref.is_valid() {
+            return self.iter();
+        }
+        let mut ei = self.mem.data_envelopes_from(pref);
+        // skip the data at pref
+        ei.next();
+        HammersbaldIterator{ ei }
+    }
+
+    fn iter_range(&self, from: PRef, to: PRef) -> HammersbaldIterator {
+        HammersbaldIterator{ ei: self.mem.data_envelopes_range(from, to) }
+    }
+
+    fn prefix_scan<'a>(&'a self, prefix: &'a [u8]) -> PrefixIterator<'a> {
+        PrefixIterator{ ei: self.mem.data_envelopes(), prefix }
+    }
+
+    #[cfg(feature = "parallel_reads")]
+    fn prefix_scan_parallel(&self, prefix: &[u8]) -> Result<Vec<(PRef, Vec<u8>, Vec<u8>)>, Error> {
+        use rayon::prelude::*;
+
+        let starts = self.mem.data_segment_starts(rayon::current_num_threads())?;
+        let segments = starts.par_iter().enumerate().map(|(i, start)| {
+            let fence = starts.get(i + 1).cloned().unwrap_or_else(PRef::invalid);
+            PrefixIterator{ ei: self.mem.data_envelopes_range(*start, fence), prefix }.collect::<Vec<_>>()
+        }).collect::<Vec<_>>();
+        Ok(segments.into_iter().flatten().collect())
+    }
+
+    fn current_data_pos(&self) -> PRef {
+        self.mem.data_position()
+    }
+
+    fn iter_indexed(&self) -> IndexedIterator {
+        IndexedIterator{ ei: self.mem.data_envelopes()}
+    }
+
+    fn iter_referred(&self) -> ReferredIterator {
+        ReferredIterator{ ei: self.mem.data_envelopes()}
+    }
+
+    fn set_sip_keys(&mut self, sip0: u64, sip1: u64) -> Result<(), Error> {
+        self.mem.set_sip_keys(sip0, sip1)
+    }
+
+    fn get_sip_keys(&self) -> (u64, u64) {
+        self.mem.sip_keys()
+    }
+
+    fn count_live_keys(&self) -> Result<usize, Error> {
+        Ok(self.mem.entry_count())
+    }
+
+    fn get_stats(&self) -> Result<DBStats, Error> {
+        let (_, _, bucket_count, table_file_bytes, data_file_bytes, link_file_bytes, _, _) = self.mem.params();
+        let live = self.mem.slots().flat_map(|slots| slots.into_iter().map(|(_, pref)| pref)).collect::<HashSet<_>>();
+        let mut referred = HashSet::new();
+        let mut referred_data = Vec::new();
+        let mut nodes = HashMap::new();
+        let mut garbage_indexed = 0;
+        for (pref, envelope) in self.data_envelopes() {
+            match Payload::deserialize(envelope.payload())? {
+                Payload::Indexed(indexed) => {
+                    if live.contains(&pref) {
+                        referred.extend(indexed.referred);
+                    } else {
+                        garbage_indexed += 1;
+                    }
+                },
+                Payload::Referred(_) if !is_padding(envelope.payload()) => referred_data.push(pref),
+                Payload::ReferredWithRefs(node) => {
+                    nodes.insert(pref, node.refs);
+                    referred_data.push(pref);
+                },
+                _ => {}
+            }
+        }
+        close_over_refs(&mut referred, &nodes);
+        Ok(DBStats {
+            bucket_count,
+            entry_count: live.len(),
+            load_factor: if bucket_count > 0 { live.len() as f64 / bucket_count as f64 } else { 0.0 },
+            data_file_bytes,
+            link_file_bytes,
+            table_file_bytes,
+            log_file_bytes: self.mem.log_file_len()?,
+            garbage_indexed,
+            garbage_referred: referred_data.iter().filter(|pref| !referred.contains(pref)).count()
+        })
+    }
+
+    fn last_recovery_stats(&self) -> Option<RecoveryStats> {
+        self.last_recovery_stats.clone()
+    }
+
+    fn stats(&self) -> Result<MemTableStats, Error> {
+        self.mem.stats()
+    }
+
+    fn bucket_fill_histogram(&self) -> Result<Vec<usize>, Error> {
+        self.mem.bucket_fill_histogram()
+    }
+
+    fn stats_snapshot(&self) -> Result<Stats, Error> {
+        let counters = self.mem.counters();
+        Ok(Stats {
+            memtable_bytes: self.mem.estimated_memory_usage(),
+            type_counts: self.mem.count_by_type(),
+            type_bytes: self.mem.bytes_by_type(),
+            buckets: self.mem.params().2,
+            inserted: counters.inserted,
+            forgotten: counters.forgotten,
+            freed_bytes: counters.freed_bytes,
+            batches: counters.batches
+        })
+    }
+
+    fn backup(&mut self, dest_name: &str) -> Result<BackupStats, Error> {
+        self.batch()?;
+        self.mem.backup(dest_name)
+    }
+
+    fn rehash_to_target(&mut self, target_bucket_count: usize) -> Result<(), Error> {
+        self.mem.rehash_to_target(target_bucket_count)
+    }
+
+    fn compact_link_file(&mut self) -> Result<LinkCompactionStats, Error> {
+        self.mem.compact_link_file()
+    }
+
+    fn compact_data_file(&mut self) -> Result<u64, Error> {
+        self.mem.compact_data_file()
+    }
+
+    fn compact(&mut self) -> Result<CompactionReport, Error> {
+        self.mem.compact()
+    }
+
+    fn clone_to_transient(&self) -> Result<Box<dyn HammersbaldAPI>, Error> {
+        let mut copy = transient(self.mem.bucket_fill_target())?;
+        for (pref, envelope) in self.data_envelopes() {
+            if let Payload::Indexed(indexed) = Payload::deserialize(envelope.payload())? {
+                if self.mem.get_pref(indexed.key)? == Some(pref) {
+                    copy.put_keyed(indexed.key, indexed.data.data)?;
+                }
+            }
+        }
+        copy.batch()?;
+        Ok(copy)
+    }
+
+    fn try_clone(&mut self) -> Result<Box<dyn HammersbaldAPI>, Error> {
+        self.batch()?;
+        Ok(Box::new(self.copy_to_transient()?))
+    }
+
+    fn transaction(&mut self) -> Transaction {
+        Transaction::new(self)
+    }
+}
+
+fn version_counter_key(key: &[u8]) -> Vec<u8> {
+    let mut k = key.to_vec();
+    k.extend_from_slice(b"_ver");
+    k
+}
+
+fn versioned_key(key: &[u8], version: u32) -> Vec<u8> {
+    let mut k = key.to_vec();
+    k.extend_from_slice(b"_v");
+    k.write_u32::<BigEndian>(version).unwrap();
+    k
+}
+
+/// iterate data content
+pub struct HammersbaldIterator<'a> {
+    ei: EnvelopeIterator<'a>
+}
+
+impl<'a> HammersbaldIterator<'a> {
+    /// iterate data starting at start
+    pub fn resume_from(mem: &'a MemTable, start: PRef) -> HammersbaldIterator<'a> {
+        HammersbaldIterator{ ei: mem.data_envelopes_from(start) }
+    }
+
+    /// token to resume iteration after the last returned data with HammersbaldAPI::iter_from_token
+    pub fn save_token(&self) -> PRef {
+        self.ei.pos()
+    }
+
+    /// iterate only data whose key satisfies the predicate, data is only copied for those
+    /// referred data is checked with an empty key
+    pub fn filter_keyed<P: Fn(&[u8]) -> bool>(self, predicate: P) -> FilteredHammersbaldIterator<'a, P> {
+        FilteredHammersbaldIterator{ ei: self.ei, predicate }
+    }
+}
+
+impl<'a> Iterator for HammersbaldIterator<'a> {
+    type Item = (PRef, Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        if let Some((pref, envelope)) = self.ei.next() {
+            match Payload::deserialize(envelope.payload()).unwrap() {
+                Payload::Indexed(indexed) => {
+                    return Some((pref, indexed.key.to_vec(), indexed.data.data.to_vec()))
+                },
+                Payload::Referred(referred) => {
+                    return Some((pref, vec!(), referred.data.to_vec()))
+                },
+                Payload::ReferredWithRefs(node) => {
+                    return Some((pref, vec!(), node.data.data.to_vec()))
+                },
+                _ => return None
+            }
+        }
+        None
+    }
+}
+
+/// iterate data accessible with keys
+pub struct IndexedIterator<'a> {
+    ei: EnvelopeIterator<'a>
+}
+
+impl<'a> Iterator for IndexedIterator<'a> {
+    type Item = (PRef, Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        while let Some((pref, envelope)) = self.ei.next() {
+            match Payload::deserialize(envelope.payload()).unwrap() {
+                Payload::Indexed(indexed) => {
+                    return Some((pref, indexed.key.to_vec(), indexed.data.data.to_vec()))
+                },
+                Payload::Referred(_) | Payload::ReferredWithRefs(_) => {},
+                _ => return None
+            }
+        }
+        None
+    }
+}
+
+/// iterate data accessible only with persistent references
+pub struct ReferredIterator<'a> {
+    ei: EnvelopeIterator<'a>
+}
+
+impl<'a> Iterator for ReferredIterator<'a> {
+    type Item = (PRef, Vec<u8>);
+
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        while let Some((pref, envelope)) = self.ei.next() {
+            match Payload::deserialize(envelope.payload()).unwrap() {
+                Payload::Indexed(_) => {},
+                // alignment padding is not data
+                Payload::Referred(_) if is_padding(envelope.payload()) => {},
+                Payload::Referred(referred) => {
+                    return Some((pref, referred.data.to_vec()))
+                },
+                Payload::ReferredWithRefs(node) => {
+                    return Some((pref, node.data.data.to_vec()))
+                },
+                _ => return None
+            }
+        }
+        None
+    }
+}
+
+/// iterate data with keys starting with a prefix
+pub struct PrefixIterator<'a> {
+    ei: EnvelopeIterator<'a>,
+    prefix: &'a [u8]
+}
+
+impl<'a> Iterator for PrefixIterator<'a> {
+    type Item = (PRef, Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        while let Some((pref, envelope)) = self.ei.next() {
+            match Payload::deserialize(envelope.payload()).unwrap() {
+                Payload::Indexed(indexed) => {
+                    if indexed.key.starts_with(self.prefix) {
+                        return Some((pref, indexed.key.to_vec(), indexed.data.data.to_vec()))
+                    }
+                },
+                Payload::Referred(_) | Payload::ReferredWithRefs(_) => {},
+                _ => return None
+            }
+        }
+        None
+    }
+}
+
+/// iterate data content with keys satisfying a predicate
+pub struct FilteredHammersbaldIterator<'a, P> {
+    ei: EnvelopeIterator<'a>,
+    predicate: P
+}
+
+impl<'a, P: Fn(&[u8]) -> bool> Iterator for FilteredHammersbaldIterator<'a, P> {
+    type Item = (PRef, Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        while let Some((pref, envelope)) = self.ei.next() {
+            match Payload::deserialize(envelope.payload()).unwrap() {
+                Payload::Indexed(indexed) => {
+                    if (self.predicate)(indexed.key) {
+                        return Some((pref, indexed.key.to_vec(), indexed.data.data.to_vec()))
+                    }
+                },
+                Payload::Referred(referred) => {
+                    if (self.predicate)(&[]) {
+                        return Some((pref, vec!(), referred.data.to_vec()))
+                    }
+                },
+                Payload::ReferredWithRefs(node) => {
+                    if (self.predicate)(&[]) {
+                        return Some((pref, vec!(), node.data.data.to_vec()))
+                    }
+                },
+                _ => return None
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate rand;
+    extern crate hex;
+
+    use transient::Transient;
+    use api::{HammersbaldAPI, HammersbaldIterator, ApproximateResult, persistent, persistent_with_config, open_read_only, repair, rebuild_index, stats_diff};
+    use persistent::Config;
+    use pagedfile::SyncMode;
+    use page::PAGE_SIZE;
+    use datafile::is_padding;
+    use stats::{TypeCounts, TypeBytes, StatsDiff};
+    use error::Error;
+    use pref::PRef;
+    use memtable::BatchPhase;
+    use tablefile::FORMAT_VERSION;
+
+    use self::rand::thread_rng;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::{env, fs, process, thread};
+    use std::time::Duration;
+    use std::io::Read;
+    use api::test::rand::RngCore;
+    use byteorder::{ByteOrder, BigEndian};
+
+    #[test]
+    fn test_two_batches() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        let mut rng = thread_rng();
+
+        let mut check = HashMap::new();
+        let mut key = [0x0u8;32];
+        let mut data = [0x0u8;40];
+
+        for _ in 0 .. 10000 {
+            rng.fill_bytes(&mut key);
+            rng.fill_bytes(&mut data);
+            let pref = db.put_keyed(&key, &data).unwrap();
+            check.insert(key, (pref, data));
+        }
+        db.batch().unwrap();
+
+        for (k, (o, v)) in check.iter() {
+            assert_eq!(db.get_keyed(&k[..]).unwrap(), Some((*o, v.to_vec())));
+        }
+
+        for _ in 0 .. 10000 {
+            rng.fill_bytes(&mut key);
+            rng.fill_bytes(&mut data);
+            let pref = db.put_keyed(&key, &data).unwrap();
+            check.insert(key, (pref, data));
+        }
+        db.batch().unwrap();
+
+        for (k, (o, v)) in check.iter() {
+            assert_eq!(db.get_keyed(&k[..]).unwrap(), Some((*o, v.to_vec())));
+        }
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_read_before_batch() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        let small = db.put_keyed(b"small", b"small data").unwrap();
+        let large_data = vec!(0x42u8; 3 * 4096);
+        let large = db.put_keyed(b"large", large_data.as_slice()).unwrap();
+
+        assert_eq!(db.get_keyed(b"small").unwrap(), Some((small, b"small data".to_vec())));
+        assert_eq!(db.get_keyed(b"large").unwrap(), Some((large, large_data.clone())));
+        db.batch().unwrap();
+        assert_eq!(db.get_keyed(b"small").unwrap(), Some((small, b"small data".to_vec())));
+        assert_eq!(db.get_keyed(b"large").unwrap(), Some((large, large_data)));
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_put_referred_dag() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        // 0 -> (1, 2), 1 -> (3, 4), 2 -> (5, 6)
+        let data = (0 .. 7).map(|n| vec!(n as u8; 10 + n)).collect::<Vec<_>>();
+        let children = vec!(vec!(1, 2), vec!(3, 4), vec!(5, 6), vec!(), vec!(), vec!(), vec!());
+        let nodes = data.iter().zip(children.iter())
+            .map(|(d, c)| (d.as_slice(), c.clone())).collect::<Vec<_>>();
+        let prefs = db.put_referred_dag(nodes.as_slice()).unwrap();
+        db.batch().unwrap();
+
+        for n in 0 .. 7 {
+            assert_eq!(db.get(prefs[n]).unwrap(), (vec!(), data[n].clone()));
+            let (stored, refs) = db.get_with_refs(prefs[n]).unwrap();
+            assert_eq!(stored, data[n]);
+            assert_eq!(refs, children[n].iter().map(|child| prefs[*child]).collect::<Vec<_>>());
+        }
+
+        match db.put_referred_dag(&[(b"a", vec!(1)), (b"b", vec!(0))]) {
+            Err(Error::InvalidArgument(_)) => {},
+            _ => panic!("a cycle should be rejected")
+        }
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_filter_keyed() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        for i in 0 .. 1000 {
+            let key = if i % 2 == 0 { format!("type_A_{}", i) } else { format!("type_B_{}", i) };
+            db.put_keyed(key.as_bytes(), &[i as u8; 20]).unwrap();
+        }
+        db.put(b"referred").unwrap();
+        db.batch().unwrap();
+
+        let found = db.iter().filter_keyed(|k| k.starts_with(b"type_A")).collect::<Vec<_>>();
+        assert_eq!(found.len(), 500);
+        assert!(found.iter().all(|(_, k, _)| k.starts_with(b"type_A")));
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_clone_to_transient() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        let mut rng = thread_rng();
+        let mut check = HashMap::new();
+        let mut key = [0x0u8;32];
+        let mut data = [0x0u8;40];
+
+        for _ in 0 .. 500 {
+            rng.fill_bytes(&mut key);
+            rng.fill_bytes(&mut data);
+            db.put_keyed(&key, &data).unwrap();
+            check.insert(key, data);
+        }
+        db.batch().unwrap();
+
+        let mut copy = db.clone_to_transient().unwrap();
+        for (k, v) in check.iter() {
+            assert_eq!(copy.get_keyed(&k[..]).unwrap().unwrap().1, v.to_vec());
+        }
+        let mut added = Vec::new();
+        for _ in 0 .. 100 {
+            rng.fill_bytes(&mut key);
+            copy.put_keyed(&key, &data).unwrap();
+            added.push(key);
+        }
+        copy.batch().unwrap();
+        for k in &added {
+            assert!(copy.get_keyed(&k[..]).unwrap().is_some());
+            assert!(db.get_keyed(&k[..]).unwrap().is_none());
+        }
+        assert_eq!(db.iter().filter_keyed(|k| !k.is_empty()).count(), 500);
+        copy.shutdown();
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_get_pref_for_key() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        let mut rng = thread_rng();
+
+        let mut check = HashMap::new();
+        let mut key = [0x0u8;32];
+        let mut data = [0x0u8;40];
+
+        for _ in 0 .. 1000 {
+            rng.fill_bytes(&mut key);
+            rng.fill_bytes(&mut data);
+            let pref = db.put_keyed(&key, &data).unwrap();
+            check.insert(key, (pref, data));
+        }
+        db.batch().unwrap();
+
+        for (k, (o, v)) in check.iter() {
+            let pref = db.get_pref_for_key(&k[..]).unwrap().unwrap();
+            assert!(pref.is_valid());
+            assert_eq!(pref, *o);
+            assert_eq!(db.get(pref).unwrap(), (k.to_vec(), v.to_vec()));
+        }
+        rng.fill_bytes(&mut key);
+        assert_eq!(db.get_pref_for_key(&key[..]).unwrap(), None);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_put_keyed_versioned() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        for i in 1u32 .. 6 {
+            let data = format!("content {}", i);
+            let (pref, version) = db.put_keyed_versioned(b"key", data.as_bytes()).unwrap();
+            assert_eq!(version, i);
+            assert_eq!(db.get_keyed_version(b"key", i).unwrap(), Some((pref, data.into_bytes())));
+        }
+        db.batch().unwrap();
+
+        assert_eq!(db.get_keyed_version(b"key", 1).unwrap().unwrap().1, b"content 1".to_vec());
+        assert_eq!(db.get_keyed_version(b"key", 5).unwrap().unwrap().1, b"content 5".to_vec());
+        assert_eq!(db.get_keyed_version(b"key", 6).unwrap(), None);
+        assert_eq!(db.get_keyed_version(b"other", 1).unwrap(), None);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_sip_keys() {
+        let dir = env::temp_dir().join(format!("hammersbald_test_sip_keys_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let name = dir.join("db").to_string_lossy().to_string();
+
+        let mut db = persistent(&name, 100, 1).unwrap();
+        db.set_sip_keys(42, 99).unwrap();
+        assert_eq!(db.get_sip_keys(), (42, 99));
+        let mut check = HashMap::new();
+        for i in 0u32 .. 100 {
+            let key = format!("key {}", i);
+            check.insert(key.clone(), db.put_keyed(key.as_bytes(), &[i as u8]).unwrap());
+        }
+        match db.set_sip_keys(1, 2) {
+            Err(Error::InvalidArgument(_)) => {},
+            _ => panic!("sip keys should not change once keys are stored")
+        }
+        db.batch().unwrap();
+        db.shutdown();
+
+        let mut db = persistent(&name, 100, 1).unwrap();
+        assert_eq!(db.get_sip_keys(), (42, 99));
+        for (key, pref) in &check {
+            assert_eq!(db.get_pref_for_key(key.as_bytes()).unwrap(), Some(*pref));
+        }
+        db.shutdown();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_persistent_snapshot() {
+        let dir = env::temp_dir().join(format!("hammersbald_test_persistent_snapshot_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let name = dir.join("db").to_string_lossy().to_string();
+        let snapshot = name.clone() + ".mt";
+        let stale = name.clone() + ".stale";
+
+        let mut db = persistent(&name, 100, 1).unwrap();
+        db.set_sip_keys(42, 99).unwrap();
+        for i in 0u32 .. 100 {
+            db.put_keyed(&i.to_be_bytes(), &i.to_be_bytes()).unwrap();
+        }
+        db.batch().unwrap();
+        db.shutdown();
+        drop(db);
+        fs::copy(&snapshot, &stale).unwrap();
+
+        // first page fields are those of the snapshot
+        let mut db = persistent(&name, 100, 1).unwrap();
+        assert!(fs::metadata(&snapshot).is_err());
+        assert_eq!(db.get_sip_keys(), (42, 99));
+        assert_eq!(db.stats().unwrap().entry_count, 100);
+        for i in 0u32 .. 100 {
+            assert_eq!(db.get_keyed(&i.to_be_bytes()).unwrap().unwrap().1, i.to_be_bytes().to_vec());
+        }
+        for i in 100u32 .. 150 {
+            db.put_keyed(&i.to_be_bytes(), &i.to_be_bytes()).unwrap();
+        }
+        db.batch().unwrap();
+        db.shutdown();
+        drop(db);
+        let mut table = Vec::new();
+        fs::File::open(name.clone() + ".0.tb").unwrap().read_to_end(&mut table).unwrap();
+        assert_eq!(BigEndian::read_u16(&table[28 .. 30]), FORMAT_VERSION);
+
+        // a snapshot of the files before the last change is not used
+        fs::rename(&stale, &snapshot).unwrap();
+        let mut db = persistent(&name, 100, 1).unwrap();
+        assert!(fs::metadata(&snapshot).is_err());
+        assert_eq!(db.get_sip_keys(), (42, 99));
+        assert_eq!(db.stats().unwrap().entry_count, 150);
+        for i in 0u32 .. 150 {
+            assert_eq!(db.get_keyed(&i.to_be_bytes()).unwrap().unwrap().1, i.to_be_bytes().to_vec());
+        }
+        db.shutdown();
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_backup() {
+        let dir = env::temp_dir().join(format!("hammersbald_test_backup_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let name = dir.join("db").to_string_lossy().to_string();
+        let copy = dir.join("copy").to_string_lossy().to_string();
+
+        let mut db = persistent(&name, 100, 1).unwrap();
+        let mut rng = thread_rng();
+        let mut key = [0x0u8;32];
+        let mut check = HashMap::new();
+        for _ in 0 .. 1000 {
+            rng.fill_bytes(&mut key);
+            let o = db.put_keyed(&key, &key).unwrap();
+            check.insert(key, o);
+        }
+        let stats = db.backup(&copy).unwrap();
+        assert_eq!(stats.files_copied, 4);
+        assert!(stats.bytes_copied > 0);
+        db.shutdown();
+
+        let mut backup = persistent(&copy, 100, 1).unwrap();
+        for (k, o) in &check {
+            assert_eq!(backup.get_keyed(&k[..]).unwrap(), Some((*o, k.to_vec())));
+        }
+        backup.shutdown();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_count_live_keys() {
+        let dir = env::temp_dir().join(format!("hammersbald_test_count_live_keys_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let name = dir.join("db").to_string_lossy().to_string();
+
+        let mut db = persistent(&name, 100, 1).unwrap();
+        for i in 0u32 .. 1000 {
+            db.put_keyed(&i.to_be_bytes(), b"first").unwrap();
+        }
+        db.batch().unwrap();
+        assert_eq!(db.count_live_keys().unwrap(), 1000);
+        for i in 1000u32 .. 1500 {
+            db.put_keyed(&i.to_be_bytes(), b"second").unwrap();
+        }
+        // overwriting does not add a key
+        db.put_keyed(&0u32.to_be_bytes(), b"again").unwrap();
+        for i in 0u32 .. 200 {
+            db.forget(&i.to_be_bytes()).unwrap();
+        }
+        assert_eq!(db.count_live_keys().unwrap(), 1300);
+        {
+            let mut transaction = db.transaction();
+            transaction.put_keyed(b"new", b"key").unwrap();
+            transaction.forget(&1000u32.to_be_bytes()).unwrap();
+            transaction.forget(&0u32.to_be_bytes()).unwrap();
+            transaction.put_keyed(&2000u32.to_be_bytes(), b"another").unwrap();
+            assert_eq!(transaction.count_live_keys().unwrap(), 1301);
+        }
+        db.batch().unwrap();
+        db.shutdown();
+
+        let mut db = persistent(&name, 100, 1).unwrap();
+        assert_eq!(db.count_live_keys().unwrap(), 1300);
+        db.shutdown();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rebuild_index() {
+        let dir = env::temp_dir().join(format!("hammersbald_test_rebuild_index_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let name = dir.join("db").to_string_lossy().to_string();
+
+        let mut db = persistent(&name, 100, 1).unwrap();
+        for i in 0u32 .. 1000 {
+            db.put_keyed(&i.to_be_bytes(), b"first").unwrap();
+        }
+        db.put(b"not indexed").unwrap();
+        db.batch().unwrap();
+        for i in 0u32 .. 100 {
+            db.put_keyed(&i.to_be_bytes(), b"second").unwrap();
+        }
+        db.batch().unwrap();
+        db.shutdown();
+
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().map_or(false, |e| e == "tb") {
+                let len = fs::metadata(&path).unwrap().len() as usize;
+                fs::write(&path, vec![0u8; len]).unwrap();
+            }
+        }
+        assert!(persistent(&name, 100, 1).is_err());
+
+        assert_eq!(rebuild_index(&name).unwrap(), 1100);
+
+        let mut db = persistent(&name, 100, 1).unwrap();
+        assert_eq!(db.count_live_keys().unwrap(), 1000);
+        for i in 0u32 .. 1000 {
+            let expected: &[u8] = if i < 100 { b"second" } else { b"first" };
+            assert_eq!(db.get_keyed(&i.to_be_bytes()).unwrap().unwrap().1, expected.to_vec());
+        }
+        db.shutdown();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_drop_without_shutdown() {
+        let dir = env::temp_dir().join(format!("hammersbald_test_drop_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let name = dir.join("db").to_string_lossy().to_string();
+
+        {
+            let mut db = persistent(&name, 100, 1).unwrap();
+            for i in 0u32 .. 100 {
+                db.put_keyed(&i.to_be_bytes(), &i.to_be_bytes()).unwrap();
+            }
+            db.batch().unwrap();
+            db.put_keyed(b"unbatched", b"data").unwrap();
+        }
+
+        // the lock was released by the drop
+        let mut db = persistent(&name, 100, 1).unwrap();
+        for i in 0u32 .. 100 {
+            assert_eq!(db.get_keyed(&i.to_be_bytes()).unwrap().unwrap().1, i.to_be_bytes().to_vec());
+        }
+        assert!(db.get_keyed(b"unbatched").unwrap().is_none());
+        db.shutdown();
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_drop_releases_files() {
+        let dir = env::temp_dir().join(format!("hammersbald_test_drop_releases_files_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let name = dir.join("db").to_string_lossy().to_string();
+        // the background writers hold their files until they stopped
+        let open_files = || fs::read_dir("/proc/self/fd").unwrap()
+            .filter_map(|fd| fs::read_link(fd.unwrap().path()).ok())
+            .filter(|path| path.starts_with(&dir)).count();
+
+        for round in 0u32 .. 5 {
+            let mut db = persistent(&name, 100, 1).unwrap();
+            db.put_keyed(&round.to_be_bytes(), b"data").unwrap();
+            db.batch().unwrap();
+            assert!(open_files() > 0);
+            if round % 2 == 0 {
+                db.shutdown();
+            }
+            drop(db);
+            assert_eq!(open_files(), 0);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_drop_while_panicking() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        let dir = env::temp_dir().join(format!("hammersbald_test_drop_while_panicking_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let name = dir.join("db").to_string_lossy().to_string();
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut db = persistent(&name, 100, 1).unwrap();
+            db.put_keyed(b"batched", b"1").unwrap();
+            db.batch().unwrap();
+            db.put_keyed(b"pending", b"2").unwrap();
+            panic!("drop the db while unwinding");
+        }));
+        assert!(result.is_err());
+
+        let mut db = persistent(&name, 100, 1).unwrap();
+        assert_eq!(db.get_keyed(b"batched").unwrap().unwrap().1, b"1".to_vec());
+        assert!(db.get_keyed(b"pending").unwrap().is_none());
+        db.shutdown();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "tokio_support")]
+    #[test]
+    fn test_persistent_async() {
+        use std::future;
+        use std::task::Poll;
+        use api::persistent_async;
+
+        let dir = env::temp_dir().join(format!("hammersbald_test_persistent_async_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let name = dir.join("db").to_string_lossy().to_string();
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let _guard = runtime.enter();
+        let db = persistent_async(Config::new(&name)).unwrap();
+        let tasks = (0 .. 4u32).map(|t| {
+            let db = db.clone();
+            // a task of the runtime, not a thread of its blocking pool
+            runtime.spawn(future::poll_fn(move |_| {
+                for i in 0 .. 250u32 {
+                    let key = (t * 250 + i).to_be_bytes();
+                    db.lock().unwrap().put_keyed(&key, &key).unwrap();
+                }
+                for i in 0 .. 250u32 {
+                    let key = (t * 250 + i).to_be_bytes();
+                    assert_eq!(db.lock().unwrap().get_keyed(&key).unwrap().unwrap().1, key.to_vec());
+                }
+                Poll::Ready(())
+            }))
+        }).collect::<Vec<_>>();
+        for task in tasks {
+            runtime.block_on(task).unwrap();
+        }
+        runtime.block_on(db.flush_async()).unwrap();
+        for i in 0 .. 1000u32 {
+            assert_eq!(db.lock().unwrap().get_keyed(&i.to_be_bytes()).unwrap().unwrap().1, i.to_be_bytes().to_vec());
+        }
+        runtime.block_on(db.shutdown_async()).unwrap();
+        drop(db);
+
+        let mut db = persistent(&name, 100, 1).unwrap();
+        assert_eq!(db.count_live_keys().unwrap(), 1000);
+        for i in 0 .. 1000u32 {
+            assert_eq!(db.get_keyed(&i.to_be_bytes()).unwrap().unwrap().1, i.to_be_bytes().to_vec());
+        }
+        db.shutdown();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pref_list() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        let mut prefs = Vec::new();
+        for i in 0u8 .. 10 {
+            prefs.push(db.put(&[i]).unwrap());
+        }
+        let list = db.put_pref_list(&prefs).unwrap();
+        let empty = db.put_pref_list(&[]).unwrap();
+        db.batch().unwrap();
+
+        assert_eq!(db.get_pref_list(list).unwrap(), prefs);
+        assert_eq!(db.get_pref_list(empty).unwrap(), Vec::new());
+        assert!(db.get_pref_list(prefs[0]).is_err());
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_put_keyed_unique() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        let mut check = HashMap::new();
+        for i in 0u32 .. 10000 {
+            let key = format!("key {}", i);
+            check.insert(key.clone(), db.put_keyed_unique(key.as_bytes(), &i.to_be_bytes()).unwrap());
+        }
+        db.batch().unwrap();
+        for (i, (key, pref)) in check.iter().enumerate() {
+            let (p, data) = db.get_keyed(key.as_bytes()).unwrap().unwrap();
+            assert_eq!(p, *pref);
+            assert_eq!(data.len(), 4);
+            if i == 0 {
+                assert_eq!(db.get(p).unwrap().0, key.as_bytes().to_vec());
+            }
+        }
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.entry_count, 0);
+        assert_eq!(stats.load_factor, 0.0);
+        assert_eq!(stats.collision_rate, 0.0);
+
+        let mut rng = thread_rng();
+        let mut key = [0x0u8;32];
+        for _ in 0 .. 10000 {
+            rng.fill_bytes(&mut key);
+            db.put_keyed(&key, &key).unwrap();
+        }
+        db.batch().unwrap();
+
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.entry_count, 10000);
+        assert_eq!(stats.load_factor, 10000.0 / stats.bucket_count as f64);
+        assert!(stats.collision_rate < 0.01);
+        assert_eq!(stats.bucket_count, db.stats_snapshot().unwrap().buckets);
+        assert!(stats.rehash_step < 1 << (stats.log_mod + 1));
+        assert!(stats.data_file_bytes > 10000 * 64 && stats.link_file_bytes > 0);
+        assert_eq!(stats.data_cache.capacity, 1);
+
+        db.get_keyed(&key).unwrap().unwrap();
+        db.get_keyed(&key).unwrap().unwrap();
+        let after = db.stats().unwrap();
+        assert!(after.data_cache.hits + after.data_cache.misses > stats.data_cache.hits + stats.data_cache.misses);
+        assert!(after.data_cache.hits > stats.data_cache.hits);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_get_stats() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+        let mut rng = thread_rng();
+        let mut keys = Vec::new();
+        for _ in 0 .. 1000 {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            db.put_keyed(&key, &key).unwrap();
+            keys.push(key);
+        }
+        db.batch().unwrap();
+        let stats = db.get_stats().unwrap();
+        assert_eq!(stats.entry_count, 1000);
+        assert_eq!(stats.garbage_indexed, 0);
+        // padding of the batch is not garbage
+        assert_eq!(stats.garbage_referred, 0);
+        assert_eq!(stats.load_factor, 1000.0 / stats.bucket_count as f64);
+
+        let linked = db.put(b"linked").unwrap();
+        db.put_keyed_with_links(b"links", b"data", &[linked]).unwrap();
+        db.put(b"unlinked").unwrap();
+        db.batch().unwrap();
+        assert_eq!(db.get_stats().unwrap().garbage_referred, 1);
+        db.forget(b"links").unwrap();
+
+        for key in &keys[.. 500] {
+            db.forget(key).unwrap();
+        }
+        db.batch().unwrap();
+        let stats = db.get_stats().unwrap();
+        assert_eq!(stats.entry_count, 500);
+        assert!(stats.garbage_indexed >= 501);
+        assert_eq!(stats.garbage_referred, 2);
+        assert!(stats.data_file_bytes > 1000 * 64 && stats.table_file_bytes > 0 && stats.log_file_bytes > 0);
+        assert!(stats.to_string().contains("keys: 500"));
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+        let mut rng = thread_rng();
+        let mut keys = Vec::new();
+        for _ in 0 .. 1000 {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            db.put_keyed(&key, &key).unwrap();
+            keys.push(key);
+        }
+        db.batch().unwrap();
+        for key in &keys[.. 500] {
+            db.forget(key).unwrap();
+        }
+        db.batch().unwrap();
+        let before = keys.iter().map(|k| db.get_keyed(k).unwrap()).collect::<Vec<_>>();
+
+        let report = db.compact().unwrap();
+        assert!(report.bytes_after < report.bytes_before);
+        assert_eq!(report.remapping.len(), 500);
+        assert!(report.records_moved > 0 && report.records_moved <= 500);
+        for (key, before) in keys.iter().zip(before) {
+            let after = db.get_keyed(key).unwrap();
+            match before {
+                Some((pref, data)) => {
+                    let (moved, after) = after.unwrap();
+                    assert_eq!(after, data);
+                    assert_eq!(report.remapping[&pref], moved);
+                },
+                None => assert!(after.is_none())
+            }
+        }
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_compact_persistent() {
+        let dir = env::temp_dir().join(format!("hammersbald_test_compact_persistent_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let name = dir.join("db").to_string_lossy().to_string();
+
+        let mut db = persistent(&name, 100, 1).unwrap();
+        for i in 0u32 .. 100 {
+            db.put_keyed(&i.to_be_bytes(), &[i as u8; 100]).unwrap();
+        }
+        db.batch().unwrap();
+        for i in 0u32 .. 50 {
+            db.forget(&i.to_be_bytes()).unwrap();
+        }
+        let report = db.compact().unwrap();
+        assert!(report.bytes_after < report.bytes_before);
+        assert_eq!(report.remapping.len(), 50);
+        db.shutdown();
+
+        let mut db = persistent(&name, 100, 1).unwrap();
+        for i in 50u32 .. 100 {
+            let (pref, data) = db.get_keyed(&i.to_be_bytes()).unwrap().unwrap();
+            assert_eq!(data, vec!(i as u8; 100));
+            assert!(report.remapping.values().any(|moved| *moved == pref));
+        }
+        for i in 0u32 .. 50 {
+            assert!(db.get_keyed(&i.to_be_bytes()).unwrap().is_none());
+        }
+
+        // referred data linked from a key is kept and its link moved along
+        let referred = db.put(b"referred").unwrap();
+        db.put_keyed_with_links(b"links", b"data", &[referred]).unwrap();
+        let forgotten = db.put(b"forgotten").unwrap();
+        db.put_keyed_with_links(b"forgotten links", b"data", &[forgotten]).unwrap();
+        db.forget(b"forgotten links").unwrap();
+        let report = db.compact().unwrap();
+        assert!(report.remapping.contains_key(&referred));
+        assert!(!report.remapping.contains_key(&forgotten));
+        let (_, data, links) = db.get_keyed_with_links(b"links").unwrap().unwrap();
+        assert_eq!(data, b"data".to_vec());
+        assert_eq!(links, vec!(report.remapping[&referred]));
+        assert_eq!(db.get(links[0]).unwrap().1, b"referred".to_vec());
+
+        // data stored without key and not linked is only accessible by its pref, compaction refuses to remove it
+        let unlinked = db.put(b"unlinked").unwrap();
+        match db.compact() {
+            Err(Error::InvalidArgument(_)) => {},
+            _ => panic!("compaction should refuse to remove data stored without key")
+        }
+        db.shutdown();
+
+        let mut db = persistent(&name, 100, 1).unwrap();
+        assert_eq!(db.get(unlinked).unwrap().1, b"unlinked".to_vec());
+        let (_, _, links) = db.get_keyed_with_links(b"links").unwrap().unwrap();
+        assert_eq!(db.get(links[0]).unwrap().1, b"referred".to_vec());
+        assert_eq!(db.get_keyed(&99u32.to_be_bytes()).unwrap().unwrap().1, vec!(99; 100));
+        db.shutdown();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_put_keyed_batch() {
+        let mut single = Transient::new_db("first", 1, 1).unwrap();
+        let mut bulk = Transient::new_db("second", 1, 1).unwrap();
+
+        let items = (0u32 .. 10000).map(|i| (format!("key {}", i % 9000).into_bytes(), i.to_be_bytes().to_vec())).collect::<Vec<_>>();
+        let items = items.iter().map(|(k, d)| (k.as_slice(), d.as_slice())).collect::<Vec<_>>();
+
+        let single_prefs = items.iter().map(|(k, d)| single.put_keyed(k, d).unwrap()).collect::<Vec<_>>();
+        single.batch().unwrap();
+
+        let bulk_prefs = bulk.put_keyed_batch(&items).unwrap();
+        bulk.batch().unwrap();
+
+        assert_eq!(bulk_prefs.len(), items.len());
+        for (n, (key, _)) in items.iter().enumerate() {
+            // later data of a key overwrites earlier
+            let (pref, data) = bulk.get_keyed(key).unwrap().unwrap();
+            assert_eq!(single.get_keyed(key).unwrap().unwrap().1, data);
+            if n >= 1000 {
+                assert_eq!(pref, bulk_prefs[n]);
+                assert_eq!(data, items[n].1.to_vec());
+            }
+            assert_eq!(bulk.get(bulk_prefs[n]).unwrap(), single.get(single_prefs[n]).unwrap());
+        }
+
+        let indexed = bulk.stats_snapshot().unwrap().type_counts.indexed;
+        let long = [0u8; 65536];
+        assert!(bulk.put_keyed_batch(&[(b"fine", b"data"), (&long, b"data")]).is_err());
+        bulk.batch().unwrap();
+        assert!(bulk.get_keyed(b"fine").unwrap().is_none());
+        assert_eq!(bulk.stats_snapshot().unwrap().type_counts.indexed, indexed);
+
+        single.shutdown();
+        bulk.shutdown();
+    }
+
+    #[test]
+    fn test_get_keyed_lookups() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+        let mut rng = thread_rng();
+        let mut keys = Vec::new();
+        for _ in 0 .. 50000 {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            db.put_keyed(&key, &key[.. 16]).unwrap();
+            keys.push(key);
+        }
+        // the last keys are looked up in the page not yet written
+        db.batch().unwrap();
+        for key in &keys[49000 ..] {
+            db.put_keyed(key, &key[16 ..]).unwrap();
+        }
+
+        let lookups = (0 .. 50000).map(|_| keys[rng.next_u32() as usize % keys.len()]).collect::<Vec<_>>();
+        let found = lookups.iter().map(|key| db.get_keyed(key).unwrap().unwrap().1).collect::<Vec<_>>();
+
+        for (key, data) in lookups.iter().zip(found) {
+            let overwritten = keys[49000 ..].contains(key);
+            assert_eq!(data, if overwritten { key[16 ..].to_vec() } else { key[.. 16].to_vec() });
+        }
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_get_keyed_into() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+        for i in 0u32 .. 100 {
+            db.put_keyed(&i.to_be_bytes(), &[i as u8; 100]).unwrap();
+        }
+        db.batch().unwrap();
+
+        let mut buf = Vec::with_capacity(1024);
+        let (ptr, capacity) = (buf.as_ptr(), buf.capacity());
+        for i in 0u32 .. 1000 {
+            let key = (i % 100).to_be_bytes();
+            let pref = db.get_keyed_into(&key, &mut buf).unwrap();
+            assert_eq!(pref, db.get_pref_for_key(&key).unwrap());
+            assert_eq!(buf, vec![(i % 100) as u8; 100]);
+        }
+        // the buffer was never reallocated
+        assert_eq!((buf.as_ptr(), buf.capacity()), (ptr, capacity));
+
+        for i in 0u32 .. 1000 {
+            assert_eq!(db.get_keyed(&(i % 100).to_be_bytes()).unwrap().unwrap().1, vec![(i % 100) as u8; 100]);
+        }
+
+        assert_eq!(db.get_keyed_into(b"missing", &mut buf).unwrap(), None);
+        assert!(buf.is_empty());
+        {
+            let mut transaction = db.transaction();
+            transaction.put_keyed(b"pending", b"data").unwrap();
+            assert_eq!(transaction.get_keyed_into(b"pending", &mut buf).unwrap(), Some(PRef::invalid()));
+            assert_eq!(buf, b"data".to_vec());
+        }
+        db.shutdown();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn test_put_keyed_unique_duplicate() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+        db.put_keyed_unique(b"key", b"1").unwrap();
+        db.put_keyed_unique(b"key", b"2").unwrap();
+    }
+
+    #[test]
+    fn test_put_keyed_if_absent() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+        let first = db.put_keyed_if_absent(b"key", b"1").unwrap().unwrap();
+        assert_eq!(db.put_keyed_if_absent(b"key", b"2").unwrap(), None);
+        db.batch().unwrap();
+        assert_eq!(db.put_keyed_if_absent(b"key", b"3").unwrap(), None);
+        assert_eq!(db.get_keyed(b"key").unwrap().unwrap(), (first, b"1".to_vec()));
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_try_clone() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        for i in 0u32 .. 500 {
+            db.put_keyed(&i.to_be_bytes(), &i.to_be_bytes()).unwrap();
+        }
+        let mut copy = db.try_clone().unwrap();
+        assert_eq!(copy.get_sip_keys(), db.get_sip_keys());
+
+        for i in 0u32 .. 250 {
+            db.forget(&i.to_be_bytes()).unwrap();
+        }
+        db.put_keyed(b"original", b"only").unwrap();
+        db.batch().unwrap();
+
+        for i in 0u32 .. 500 {
+            assert_eq!(copy.get_keyed(&i.to_be_bytes()).unwrap().unwrap().1, i.to_be_bytes().to_vec());
+        }
+        assert!(copy.get_keyed(b"original").unwrap().is_none());
+        copy.put_keyed(b"copy", b"only").unwrap();
+        copy.batch().unwrap();
+        assert!(db.get_keyed(b"copy").unwrap().is_none());
+        copy.shutdown();
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_forget_batch() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        let keys = (0u32 .. 1000).map(|i| i.to_be_bytes()).collect::<Vec<_>>();
+        for key in &keys {
+            db.put_keyed(key, key).unwrap();
+        }
+        db.batch().unwrap();
+        db.forget_batch(&keys.iter().map(|k| &k[..]).collect::<Vec<_>>()).unwrap();
+        db.batch().unwrap();
+        for key in &keys {
+            assert!(!db.may_have_key(key).unwrap());
+            assert!(db.get_keyed(key).unwrap().is_none());
+        }
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_dag() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        // a binary tree of 3 levels
+        let nodes: Vec<(&[u8], Vec<usize>)> = (0usize .. 7).map(|n|
+            (&b"0123456"[n .. n + 1], if n < 3 { vec!(2 * n + 1, 2 * n + 2) } else { vec!() })).collect();
+        let prefs = db.put_referred_dag(&nodes).unwrap();
+        db.batch().unwrap();
+
+        let visited = db.dag(prefs[0]).map(|(depth, pref, _)| (depth, pref)).collect::<Vec<_>>();
+        assert_eq!(visited.len(), 7);
+        for (n, pref) in prefs.iter().enumerate() {
+            let depth = if n == 0 { 0 } else if n < 3 { 1 } else { 2 };
+            assert!(visited.contains(&(depth, *pref)));
+        }
+        assert!(visited.windows(2).all(|w| w[0].0 <= w[1].0));
+
+        // a node with two parents is visited once
+        let nodes: Vec<(&[u8], Vec<usize>)> = vec!((b"root", vec!(1, 2)), (b"a", vec!(3)), (b"b", vec!(3)), (b"shared", vec!()));
+        let prefs = db.put_referred_dag(&nodes).unwrap();
+        assert_eq!(db.dag(prefs[0]).count(), 4);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_put_with_refs() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        // a chain of 5 records each referring to the previous
+        let mut chain = vec!(db.put_with_refs(b"0", &[]).unwrap());
+        for n in 1u8 .. 5 {
+            let prev = *chain.last().unwrap();
+            chain.push(db.put_with_refs(&[b'0' + n], &[prev]).unwrap());
+        }
+        db.batch().unwrap();
+
+        let visited = db.dag(chain[4]).map(|(depth, pref, _)| (depth, pref)).collect::<Vec<_>>();
+        assert_eq!(visited, chain.iter().rev().enumerate().map(|(depth, pref)| (depth, *pref)).collect::<Vec<_>>());
+
+        assert_eq!(db.get_with_refs(chain[2]).unwrap(), (b"2".to_vec(), vec!(chain[1])));
+        assert_eq!(db.get(chain[2]).unwrap(), (vec!(), b"2".to_vec()));
+
+        // a key linking the head keeps the chain, compaction moves the refs of the nodes along
+        db.put_keyed_with_links(b"head", b"", &[chain[4]]).unwrap();
+        let forgotten = db.put_with_refs(b"forgotten", &[chain[0]]).unwrap();
+        db.put_keyed_with_links(b"forgotten", b"", &[forgotten]).unwrap();
+        db.forget(b"forgotten").unwrap();
+        db.batch().unwrap();
+        assert_eq!(db.get_stats().unwrap().garbage_referred, 1);
+        let report = db.compact().unwrap();
+        assert!(!report.remapping.contains_key(&forgotten));
+        let (_, _, head) = db.get_keyed_with_links(b"head").unwrap().unwrap();
+        let moved = db.dag(head[0]).map(|(_, pref, _)| pref).collect::<Vec<_>>();
+        assert_eq!(moved, chain.iter().rev().map(|pref| report.remapping[pref]).collect::<Vec<_>>());
+        for (n, pref) in moved.iter().rev().enumerate() {
+            let refs = if n == 0 { vec!() } else { vec!(moved[5 - n]) };
+            assert_eq!(db.get_with_refs(*pref).unwrap(), (vec!(b'0' + n as u8), refs));
+        }
+        assert_eq!(db.get_stats().unwrap().garbage_referred, 0);
+
+        // data stored with put is a leaf, its bytes are not read as refs
+        let leaf = db.put(&[0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!(db.dag(leaf).count(), 1);
+        assert_eq!(db.get_with_refs(leaf).unwrap().1, vec!());
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_put_keyed_with_links() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        let mut links = Vec::new();
+        for i in 0u8 .. 5 {
+            links.push(db.put(&[i]).unwrap());
+        }
+        let pref = db.put_keyed_with_links(b"block", b"header", &links).unwrap();
+        let none = db.put_keyed_with_links(b"empty", b"", &[]).unwrap();
+        db.batch().unwrap();
+
+        assert_eq!(db.get_keyed_with_links(b"block").unwrap(), Some((pref, b"header".to_vec(), links.clone())));
+        assert_eq!(db.get_keyed_with_links(b"empty").unwrap(), Some((none, vec!(), vec!())));
+        assert_eq!(db.get_keyed_with_links(b"missing").unwrap(), None);
+        // links are not part of the data
+        assert_eq!(db.get_keyed(b"block").unwrap(), Some((pref, b"header".to_vec())));
+        for (i, link) in links.iter().enumerate() {
+            assert_eq!(db.get(*link).unwrap(), (vec!(), vec!(i as u8)));
+        }
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_watch() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        let receiver = db.watch(b"watched").unwrap();
+        db.put_keyed(b"other", b"1").unwrap();
+        db.batch().unwrap();
+        assert!(receiver.try_recv().is_err());
+
+        db.put_keyed(b"watched", b"1").unwrap();
+        db.batch().unwrap();
+        assert_eq!(receiver.recv_timeout(Duration::from_millis(100)).unwrap(), Some(b"1".to_vec()));
+
+        // not changed
+        db.put_keyed(b"watched", b"1").unwrap();
+        db.batch().unwrap();
+        assert!(receiver.try_recv().is_err());
+
+        db.forget(b"watched").unwrap();
+        db.batch().unwrap();
+        assert_eq!(receiver.recv_timeout(Duration::from_millis(100)).unwrap(), None);
+
+        drop(receiver);
+        db.put_keyed(b"watched", b"2").unwrap();
+        db.batch().unwrap();
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_may_have_key_bulk() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        let mut rng = thread_rng();
+        let mut keys = Vec::new();
+        for _ in 0 .. 500 {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            db.put_keyed(&key, &key).unwrap();
+            keys.push(key);
+        }
+        db.batch().unwrap();
+        for _ in 0 .. 500 {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            keys.push(key);
+        }
+        let refs = keys.iter().map(|k| &k[..]).collect::<Vec<_>>();
+        let result = db.may_have_key_bulk(&refs).unwrap();
+        assert_eq!(result.len(), 1000);
+        assert!(result[.. 500].iter().all(|b| *b));
+        let false_positives = result[500 ..].iter().filter(|b| **b).count();
+        assert!(false_positives < 25);
+        for (key, maybe) in refs.iter().zip(result.iter()) {
+            assert_eq!(db.may_have_key(key).unwrap(), *maybe);
+        }
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_contains_key() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        let mut rng = thread_rng();
+        let mut present = Vec::new();
+        for _ in 0 .. 1000 {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            db.put_keyed(&key, &[0u8; 100]).unwrap();
+            present.push(key);
+        }
+        db.batch().unwrap();
+
+        for key in &present {
+            assert!(db.contains_key(key).unwrap());
+        }
+        let mut absent = [0x0u8;32];
+        for _ in 0 .. 1000 {
+            rng.fill_bytes(&mut absent);
+            assert!(!db.contains_key(&absent).unwrap());
+        }
+        let pref = db.put(b"referred").unwrap();
+        assert!(!db.contains_key(b"referred").unwrap());
+        assert_eq!(db.get(pref).unwrap().1, b"referred".to_vec());
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_get_keyed_approximate() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        let mut present = Vec::new();
+        for i in 0u32 .. 1000 {
+            let key = format!("present {}", i);
+            present.push((db.put_keyed(key.as_bytes(), &[1]).unwrap(), key));
+        }
+        db.batch().unwrap();
+
+        for (pref, key) in &present {
+            assert_eq!(db.get_keyed_approximate(key.as_bytes()).unwrap(), ApproximateResult::ProbablyPresent(*pref));
+        }
+        let absent = (0u32 .. 10000).filter(|i|
+            db.get_keyed_approximate(format!("absent {}", i).as_bytes()).unwrap() == ApproximateResult::DefinitelyAbsent).count();
+        assert!(absent >= 9900);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_stats_diff() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        for i in 0u32 .. 200 {
+            db.put_keyed(&i.to_be_bytes(), &[0u8; 10]).unwrap();
+        }
+        db.batch().unwrap();
+        let before = db.stats_snapshot().unwrap();
+        for i in 200u32 .. 500 {
+            db.put_keyed(&i.to_be_bytes(), &[0u8; 10]).unwrap();
+        }
+        for i in 0u32 .. 100 {
+            db.forget(&i.to_be_bytes()).unwrap();
+        }
+        db.batch().unwrap();
+        let after = db.stats_snapshot().unwrap();
+
+        let diff = stats_diff(&before, &after);
+        assert_eq!(diff.inserted, 300);
+        assert_eq!(diff.forgotten, 100);
+        assert!(diff.data_bytes_added >= 300 * (3 + 1 + 2 + 4 + 3 + 10));
+        assert_eq!(diff.data_bytes_freed, 100 * (3 + 1 + 2 + 4 + 3 + 10));
+        assert!(diff.buckets_added > 0);
+        assert_eq!(diff.batches_elapsed, 1);
+        assert_eq!(stats_diff(&after, &after), StatsDiff::default());
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_count_by_type() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        for i in 0u32 .. 100 {
+            db.put_keyed(&i.to_be_bytes(), &[0u8; 10]).unwrap();
+        }
+        for _ in 0 .. 200 {
+            db.put(&[0u8; 20]).unwrap();
+        }
+        let stats = db.stats_snapshot().unwrap();
+        assert_eq!(stats.type_counts, TypeCounts { indexed: 100, referred: 200, links: 0 });
+        assert_eq!(stats.type_bytes, TypeBytes { indexed: 100 * (3 + 1 + 2 + 4 + 3 + 10), referred: 200 * (3 + 1 + 3 + 20), links: 0 });
+
+        // padding to page boundary is not counted
+        db.batch().unwrap();
+        let stats = db.stats_snapshot().unwrap();
+        assert_eq!(stats.type_counts.indexed, 100);
+        assert_eq!(stats.type_counts.referred, 200);
+        assert_eq!(stats.type_bytes.referred, 200 * (3 + 1 + 3 + 20));
+        assert!(stats.type_counts.links > 0);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_lock() {
+        let dir = env::temp_dir().join(format!("hammersbald_test_lock_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let name = dir.join("db").to_string_lossy().to_string();
+
+        let mut db = persistent(&name, 100, 1).unwrap();
+        let other = name.clone();
+        match thread::spawn(move || persistent(&other, 100, 1).map(|_| ())).join().unwrap() {
+            Err(Error::AlreadyOpen) => {},
+            _ => panic!("second open should fail")
+        }
+        db.shutdown();
+
+        let other = name.clone();
+        let mut reopened = thread::spawn(move || persistent(&other, 100, 1)).join().unwrap().unwrap();
+        reopened.shutdown();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_persistent_with_config() {
+        let dir = env::temp_dir().join(format!("hammersbald_test_config_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let name = dir.join("db").to_string_lossy().to_string();
+
+        let config = Config::new(&name).with_cached_pages(10).with_data_chunk_bytes(2 * PAGE_SIZE as u64)
+            .with_sync_mode(SyncMode::Full);
+        let mut db = persistent_with_config(config.clone()).unwrap();
+        for i in 0u32 .. 100 {
+            db.put_keyed(&i.to_be_bytes(), &[0u8; 100]).unwrap();
+        }
+        db.batch().unwrap();
+        db.shutdown();
+        drop(db);
+        assert!(fs::metadata(name.clone() + ".0.bc").is_ok());
+        assert!(fs::metadata(name.clone() + ".1.bc").is_ok());
+        assert_eq!(fs::metadata(name.clone() + ".0.bc").unwrap().len(), 2 * PAGE_SIZE as u64);
+
+        let mut db = persistent_with_config(config).unwrap();
+        for i in 0u32 .. 100 {
+            assert_eq!(db.get_keyed(&i.to_be_bytes()).unwrap().unwrap().1, vec!(0u8; 100));
+        }
+        db.shutdown();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stored_chunk_sizes() {
+        let dir = env::temp_dir().join(format!("hammersbald_test_stored_chunk_sizes_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let name = dir.join("db").to_string_lossy().to_string();
+
+        let config = Config::new(&name).with_data_chunk_bytes(2 * PAGE_SIZE as u64);
+        let mut db = persistent_with_config(config).unwrap();
+        for i in 0u32 .. 200 {
+            db.put_keyed(&i.to_be_bytes(), &[i as u8; 100]).unwrap();
+        }
+        db.batch().unwrap();
+        db.shutdown();
+        drop(db);
+        assert!(fs::metadata(name.clone() + ".2.bc").is_ok());
+        assert_eq!(Config::from_db(&name).data_chunk_bytes, 2 * PAGE_SIZE as u64);
+
+        let check = |db: &dyn HammersbaldAPI| {
+            for i in 0u32 .. 200 {
+                assert_eq!(db.get_keyed(&i.to_be_bytes()).unwrap().unwrap().1, vec!(i as u8; 100));
+            }
+        };
+        // chunk sizes other than those stored are rejected
+        match persistent_with_config(Config::new(&name)) {
+            Err(Error::InvalidArgument(_)) => {},
+            _ => panic!("other chunk sizes should be rejected")
+        }
+        let mut reader = open_read_only(&name, 100).unwrap();
+        check(reader.as_ref());
+        reader.shutdown();
+        drop(reader);
+
+        let report = repair(&name, false).unwrap();
+        assert_eq!((report.partial_page_bytes, report.unlogged_bytes, report.unreadable_keys), (0, 0, 0));
+        let mut db = persistent(&name, 100, 1).unwrap();
+        check(db.as_ref());
+        db.shutdown();
+        drop(db);
+
+        assert_eq!(rebuild_index(&name).unwrap(), 200);
+        let mut db = persistent(&name, 100, 1).unwrap();
+        check(db.as_ref());
+        db.shutdown();
+        drop(db);
+
+        // a lost table leaves the chunks of the data file to rebuild from
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().unwrap() == "tb" {
+                fs::remove_file(path).unwrap();
+            }
+        }
+        assert_eq!(rebuild_index(&name).unwrap(), 200);
+        let mut db = persistent(&name, 100, 1).unwrap();
+        check(db.as_ref());
+        db.shutdown();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_persistent_with_compression() {
+        let dir = env::temp_dir().join(format!("hammersbald_test_compression_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let plain_name = dir.join("plain").to_string_lossy().to_string();
+        let name = dir.join("db").to_string_lossy().to_string();
+
+        let mut plain = persistent_with_config(Config::new(&plain_name)).unwrap();
+        let mut db = persistent_with_config(Config::new(&name).with_compression(0.1)).unwrap();
+        let data = b"script".iter().cycle().take(900).cloned().collect::<Vec<_>>();
+        for i in 0u32 .. 1000 {
+            plain.put_keyed(&i.to_be_bytes(), &data).unwrap();
+            db.put_keyed(&i.to_be_bytes(), &data).unwrap();
+        }
+        plain.batch().unwrap();
+        db.batch().unwrap();
+        plain.shutdown();
+        db.shutdown();
+        drop((plain, db));
+        assert!(fs::metadata(name.clone() + ".0.bc").unwrap().len() * 2 < fs::metadata(plain_name.clone() + ".0.bc").unwrap().len());
+
+        // compressed data is read without the option
+        let mut db = persistent_with_config(Config::new(&name)).unwrap();
+        for i in 0u32 .. 1000 {
+            assert_eq!(db.get_keyed(&i.to_be_bytes()).unwrap().unwrap().1, data);
+        }
+        db.shutdown();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let dir = env::temp_dir().join(format!("hammersbald_test_read_only_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let name = dir.join("db").to_string_lossy().to_string();
+
+        let mut db = persistent(&name, 100, 1).unwrap();
+        let mut check = HashMap::new();
+        for i in 0u32 .. 1000 {
+            let key = format!("key {}", i);
+            check.insert(key.clone(), db.put_keyed(key.as_bytes(), &i.to_be_bytes()).unwrap());
+        }
+        let referred = db.put(b"referred").unwrap();
+        db.batch().unwrap();
+
+        // readable while the writer is open
+        let mut reader = open_read_only(&name, 100).unwrap();
+        for (key, pref) in &check {
+            assert_eq!(reader.get_keyed(key.as_bytes()).unwrap().unwrap().0, *pref);
+            assert!(reader.may_have_key(key.as_bytes()).unwrap());
+        }
+        assert_eq!(reader.get(referred).unwrap().1, b"referred".to_vec());
+        assert_eq!(reader.iter().filter_keyed(|k| !k.is_empty()).count(), 1000);
+
+        let is_read_only = |e: Error| match e { Error::ReadOnly => true, _ => false };
+        assert!(is_read_only(reader.put_keyed(b"new", b"data").unwrap_err()));
+        assert!(is_read_only(reader.put(b"data").unwrap_err()));
+        assert!(is_read_only(reader.forget(b"key 1").unwrap_err()));
+        assert!(is_read_only(reader.batch().unwrap_err()));
+        reader.shutdown();
+
+        db.shutdown();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_iter_indexed_and_referred() {
+        let mut db = Transient::new_hammersbald(1, 1).unwrap();
+
+        for i in 0u32 .. 100 {
+            db.put_keyed(&i.to_be_bytes(), &[1u8]).unwrap();
+            db.put(&[2u8]).unwrap();
+        }
+        // the batch pads the data file to a page boundary
+        db.batch().unwrap();
+        assert_eq!(db.iter_indexed().count(), 100);
+        assert!(db.iter_indexed().all(|(_, k, d)| k.len() == 4 && d == vec!(1u8)));
+        assert_eq!(db.iter_referred().count(), 100);
+        assert!(db.iter_referred().all(|(_, d)| d == vec!(2u8)));
+        let padding = db.data_envelopes().filter(|(_, envelope)| is_padding(envelope.payload())).count();
+        assert!(padding > 0);
+        assert_eq!(db.iter_indexed().count() + db.iter_referred().count() + padding, db.data_envelopes().count());
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_iter_from_token() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        let mut rng = thread_rng();
+        let mut key = [0x0u8;32];
+        for _ in 0 .. 500 {
+            rng.fill_bytes(&mut key);
+            db.put_keyed(&key, &key).unwrap();
+        }
+        db.batch().unwrap();
+
+        let mut iter = db.iter();
+        assert_eq!(iter.by_ref().take(200).filter(|(_, k, _)| !k.is_empty()).count(), 200);
+        let token = iter.save_token();
+
+        for _ in 0 .. 100 {
+            rng.fill_bytes(&mut key);
+            db.put_keyed(&key, &key).unwrap();
+        }
+        db.batch().unwrap();
+
+        assert_eq!(db.iter_from_token(token).filter_keyed(|k| !k.is_empty()).count(), 400);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_iter_by_insertion_order() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        let a = db.put_keyed(b"A", b"1").unwrap();
+        let b = db.put_keyed(b"B", b"2").unwrap();
+        let c = db.put_keyed(b"C", b"3").unwrap();
+        db.batch().unwrap();
+        assert!(a < b && b < c);
+
+        let found = db.iter_by_insertion_order().filter(|(_, k, _)| !k.is_empty()).collect::<Vec<_>>();
+        assert_eq!(found.iter().map(|(_, k, _)| k.clone()).collect::<Vec<_>>(), vec!(b"A".to_vec(), b"B".to_vec(), b"C".to_vec()));
+        assert_eq!(found.iter().map(|(p, _, _)| *p).collect::<Vec<_>>(), vec!(a, b, c));
+        assert!(found.windows(2).all(|w| w[0].0 < w[1].0));
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_iter_since_pref() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        let a = db.put_keyed(b"A", b"1").unwrap();
+        let b = db.put_keyed(b"B", b"2").unwrap();
+        let c = db.put_keyed(b"C", b"3").unwrap();
+        db.batch().unwrap();
+
+        let keys = |iter: HammersbaldIterator| iter.filter(|(_, k, _)| !k.is_empty()).map(|(_, k, _)| k).collect::<Vec<_>>();
+        assert_eq!(keys(db.iter_since_pref(a)), vec!(b"B".to_vec(), b"C".to_vec()));
+        assert_eq!(keys(db.iter_since_pref(b)), vec!(b"C".to_vec()));
+        assert!(keys(db.iter_since_pref(c)).is_empty());
+        assert_eq!(keys(db.iter_since_pref(PRef::invalid())), vec!(b"A".to_vec(), b"B".to_vec(), b"C".to_vec()));
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_iter_range() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        let start = db.current_data_pos();
+        let first = (0u32 .. 100).map(|i| i.to_be_bytes().to_vec()).collect::<Vec<_>>();
+        for key in &first {
+            db.put_keyed(key, b"first").unwrap();
+        }
+        db.batch().unwrap();
+        let middle = db.current_data_pos();
+        let second = (100u32 .. 150).map(|i| i.to_be_bytes().to_vec()).collect::<Vec<_>>();
+        for key in &second {
+            db.put_keyed(key, b"second").unwrap();
+        }
+        db.batch().unwrap();
+        let end = db.current_data_pos();
+
+        let keys = |iter: HammersbaldIterator| iter.filter(|(_, k, _)| !k.is_empty()).map(|(_, k, _)| k).collect::<Vec<_>>();
+        assert_eq!(keys(db.iter_range(start, middle)), first);
+        assert_eq!(keys(db.iter_range(middle, end)), second);
+        assert!(db.iter_range(middle, end).filter(|(_, k, _)| !k.is_empty()).all(|(_, _, d)| d == b"second".to_vec()));
+        assert!(keys(db.iter_range(end, end)).is_empty());
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_prefix_scan() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        for i in 0u32 .. 1000 {
+            let mut key = vec!((i % 4) as u8);
+            key.extend_from_slice(&i.to_be_bytes());
+            db.put_keyed(&key, &i.to_be_bytes()).unwrap();
+            if i % 10 == 0 {
+                db.put(&[(i % 4) as u8; 10]).unwrap();
+            }
+        }
+        db.batch().unwrap();
+
+        for category in 0 .. 4u8 {
+            let found = db.prefix_scan(&[category]).collect::<Vec<_>>();
+            assert_eq!(found.len(), 250);
+            for (pref, key, data) in &found {
+                assert_eq!(key[0], category);
+                assert_eq!(&key[1 ..], data.as_slice());
+                assert_eq!(db.get_keyed(key).unwrap().unwrap().0, *pref);
+            }
+            #[cfg(feature = "parallel_reads")]
+            assert_eq!(db.prefix_scan_parallel(&[category]).unwrap(), found);
+        }
+        assert_eq!(db.prefix_scan(&[4]).count(), 0);
+        assert_eq!(db.prefix_scan(&[]).count(), 1000);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_inspect_bucket() {
+        let mut db = Transient::new_db("first", 1, 32).unwrap();
+
+        let mut rng = thread_rng();
+        let mut keys = Vec::new();
+        let mut key = [0x0u8;32];
+        for _ in 0 .. 200 {
+            rng.fill_bytes(&mut key);
+            db.put_keyed(&key, &key).unwrap();
+            keys.push(key);
+        }
+        db.batch().unwrap();
+
+        let mut by_bucket = HashMap::new();
+        for key in &keys {
+            let inspection = db.inspect_bucket(&key[..]).unwrap();
+            let (_, pref) = inspection.matching_slot.unwrap();
+            assert_eq!(pref, db.get_keyed(&key[..]).unwrap().unwrap().0);
+            assert!(inspection.all_slots.contains(&inspection.matching_slot.unwrap()));
+            by_bucket.entry(inspection.bucket_number).or_insert_with(Vec::new).push(inspection);
+        }
+        // fill target 32 forces keys to share buckets
+        assert!(by_bucket.values().any(|inspections| inspections.len() > 1));
+        for inspections in by_bucket.values() {
+            // every key of the bucket sees the full chain
+            let chain = &inspections[0].all_slots;
+            assert_eq!(chain.len(), inspections.len());
+            for inspection in inspections {
+                assert_eq!(&inspection.all_slots, chain);
+            }
+        }
+
+        rng.fill_bytes(&mut key);
+        let inspection = db.inspect_bucket(&key[..]).unwrap();
+        assert_eq!(inspection.matching_slot, None);
+        if let Some(inspections) = by_bucket.get(&inspection.bucket_number) {
+            assert_eq!(inspection.all_slots, inspections[0].all_slots);
+        }
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_batch_with_progress() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        let mut rng = thread_rng();
+        let mut key = [0x0u8;32];
+        for _ in 0 .. 10000 {
+            rng.fill_bytes(&mut key);
+            db.put_keyed(&key, &key).unwrap();
+        }
+
+        let phases = RefCell::new(Vec::new());
+        db.batch_with_progress(&|phase| phases.borrow_mut().push(phase)).unwrap();
+        let phases = phases.into_inner();
+        assert_eq!(phases.len(), 5);
+        assert_eq!(phases[0], BatchPhase::FlushingData);
+        assert_eq!(phases[1], BatchPhase::FlushingLinks);
+        match phases[2] {
+            BatchPhase::FlushingTable(pages) => assert!(pages > 1),
+            _ => panic!("expected FlushingTable")
+        }
+        assert_eq!(phases[3], BatchPhase::SyncingLog);
+        assert_eq!(phases[4], BatchPhase::Done);
+        assert_eq!(db.get_keyed(&key).unwrap().unwrap().1, key.to_vec());
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_long_key() {
+        let mut db = Transient::new_db("first", 1, 1).unwrap();
+
+        let key = (0 .. 500).map(|i| i as u8).collect::<Vec<_>>();
+        let pref = db.put_keyed(&key, b"long key").unwrap();
+        db.batch().unwrap();
+        assert_eq!(db.get_keyed(&key).unwrap().unwrap(), (pref, b"long key".to_vec()));
+        assert_eq!(db.get(pref).unwrap(), (key, b"long key".to_vec()));
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_iter_from() {
+        let mut db = Transient::new_hammersbald(1, 1).unwrap();
+
+        for i in 0u32 .. 1000 {
+            db.put_keyed(&i.to_be_bytes(), &i.to_be_bytes()).unwrap();
+        }
+        db.batch().unwrap();
+        let checkpoint = PRef::from(db.stats().unwrap().data_file_bytes);
+        for i in 1000u32 .. 1500 {
+            db.put_keyed(&i.to_be_bytes(), &i.to_be_bytes()).unwrap();
+        }
+        db.batch().unwrap();
+
+        let iter = db.iter_from(checkpoint);
+        assert_eq!(iter.ei.starting_pos(), checkpoint);
+        // a batch pads the data file with referred data that has no key
+        let keys = iter.filter(|(_, key, _)| !key.is_empty()).map(|(_, key, _)| BigEndian::read_u32(&key)).collect::<Vec<_>>();
+        assert_eq!(keys, (1000u32 .. 1500).collect::<Vec<_>>());
         db.shutdown();
     }
 }
@@ -19,82 +19,168 @@
 //!
 
 use page::Page;
-use pagedfile::PagedFile;
+use pagedfile::{PagedFile, FadviseAdvice};
+#[cfg(feature = "tokio_support")]
+use pagedfile::spawn_page_read;
 
 use error::Error;
 use pref::PRef;
+use stats::BackupStats;
 
+use std::collections::VecDeque;
 use std::sync::{Mutex, Arc, Condvar};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
+#[cfg(feature = "tokio_support")]
+use std::future::Future;
+
+// pages queued before writers block, if not set otherwise
+const DEFAULT_CAPACITY: usize = 128;
 
 pub struct AsyncFile {
-    inner: Arc<AsyncFileInner>
+    inner: Arc<AsyncFileInner>,
+    writer: Option<thread::JoinHandle<()>>
 }
 
 struct AsyncFileInner {
     file: Mutex<Box<dyn PagedFile + Send + Sync>>,
     work: Condvar,
     flushed: Condvar,
+    not_full: Condvar,
     run: AtomicBool,
-    queue: Mutex<Vec<Page>>
+    capacity: usize,
+    queue: Mutex<VecDeque<Page>>
 }
 
 impl AsyncFileInner {
-    pub fn new(file: Box<dyn PagedFile + Send + Sync>) -> Result<AsyncFileInner, Error> {
+    pub fn new(file: Box<dyn PagedFile + Send + Sync>, capacity: usize) -> Result<AsyncFileInner, Error> {
         Ok(AsyncFileInner { file: Mutex::new(file), flushed: Condvar::new(), work: Condvar::new(),
+            not_full: Condvar::new(),
             run: AtomicBool::new(true),
-            queue: Mutex::new(Vec::new())})
+            capacity: capacity.max(1),
+            queue: Mutex::new(VecDeque::new())})
+    }
+
+    // queue a page, blocks while the queue is full
+    fn append_page(&self, page: Page) -> Result<(), Error> {
+        let mut queue = self.queue.lock()?;
+        while queue.len() >= self.capacity {
+            self.work.notify_one();
+            queue = self.not_full.wait(queue)?;
+        }
+        queue.push_back(page);
+        self.work.notify_one();
+        Ok(())
+    }
+
+    fn read_in_queue(&self, pref: PRef) -> Result<Option<Page>, Error> {
+        let queue = self.queue.lock().expect("page queue lock poisoned");
+        if queue.len() > 0 {
+            let file = self.file.lock().expect("file lock poisoned");
+            let len = PRef::from(file.len()?);
+            if pref >= len {
+                let index = len.pages_until(pref);
+                if index < queue.len() {
+                    let page = queue[index].clone();
+                    return Ok(Some(page));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn read_page(&self, pref: PRef) -> Result<Option<Page>, Error> {
+        if let Some(page) = self.read_in_queue(pref)? {
+            return Ok(Some(page));
+        }
+        let file = self.file.lock().expect("file lock poisoned");
+        file.read_page(pref)
     }
 }
 
 impl AsyncFile {
     pub fn new(file: Box<dyn PagedFile + Send + Sync>) -> Result<AsyncFile, Error> {
-        let inner = Arc::new(AsyncFileInner::new(file)?);
+        Self::new_bounded(file, DEFAULT_CAPACITY)
+    }
+
+    /// an async file queueing at most capacity pages, appending blocks until the background writer catches up
+    pub fn new_bounded(file: Box<dyn PagedFile + Send + Sync>, capacity: usize) -> Result<AsyncFile, Error> {
+        let inner = Arc::new(AsyncFileInner::new(file, capacity)?);
         let inner2 = inner.clone();
-        thread::Builder::new().name("hammersbald".to_string()).spawn(move || { AsyncFile::background(inner2) }).expect("hammersbald can not start thread for async file IO");
-        Ok(AsyncFile { inner })
+        let writer = thread::Builder::new().name("hammersbald".to_string()).spawn(move || { AsyncFile::background(inner2) }).expect("hammersbald can not start thread for async file IO");
+        Ok(AsyncFile { inner, writer: Some(writer) })
     }
 
+    // writes until stopped and the queue is empty
     fn background(inner: Arc<AsyncFileInner>) {
         let mut queue = inner.queue.lock().expect("page queue lock poisoned");
-        while inner.run.load(Ordering::Acquire) {
-            while queue.is_empty() {
+        loop {
+            while queue.is_empty() && inner.run.load(Ordering::Acquire) {
                 queue = inner.work.wait(queue).expect("page queue lock poisoned");
             }
+            if queue.is_empty() {
+                return;
+            }
             let mut file = inner.file.lock().expect("file lock poisoned");
             for page in queue.iter() {
                 file.append_page(page.clone()).expect("can not write in background");
             }
             queue.clear();
             inner.flushed.notify_all();
+            inner.not_full.notify_all();
         }
     }
 
-    fn read_in_queue(&self, pref: PRef) -> Result<Option<Page>, Error> {
-        let queue = self.inner.queue.lock().expect("page queue lock poisoned");
-        if queue.len() > 0 {
-            let file = self.inner.file.lock().expect("file lock poisoned");
-            let len = PRef::from(file.len()?);
-            if pref >= len {
-                let index = len.pages_until(pref);
-                if index < queue.len() {
-                    let page = queue[index].clone();
-                    return Ok(Some(page));
-                }
+    /// wait until all queued pages are written and flush the file
+    pub fn flush_and_drain(&self) -> Result<(), Error> {
+        let mut queue = self.inner.queue.lock()?;
+        self.inner.work.notify_one();
+        while !queue.is_empty() {
+            queue = self.inner.flushed.wait(queue)?;
+        }
+        let mut file = self.inner.file.lock()?;
+        file.flush()
+    }
+
+    /// write all queued pages then stop the background writer
+    /// shutdown has no result, so a failing write is ignored, it is not repeated on the next open either
+    pub fn shutdown_and_drain(&mut self) {
+        self.flush_and_drain().ok();
+        self.stop();
+    }
+
+    // stop the background writer and wait until it wrote what is queued and released the file
+    fn stop(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            {
+                // the writer checks run holding the queue lock, so it can not miss the notification
+                let _queue = self.inner.queue.lock();
+                self.inner.run.store(false, Ordering::Release);
+                self.inner.work.notify_all();
             }
+            // a writer that panicked already reported
+            writer.join().ok();
         }
-        Ok(None)
+    }
+
+    /// read a page without blocking the async executor, must be called within a tokio runtime
+    #[cfg(feature = "tokio_support")]
+    #[allow(unused)]
+    pub fn read_page_async(&self, pref: PRef) -> impl Future<Output=Result<Option<Page>, Error>> {
+        let inner = self.inner.clone();
+        spawn_page_read(move || inner.read_page(pref))
+    }
+}
+
+impl Drop for AsyncFile {
+    fn drop(&mut self) {
+        self.stop();
     }
 }
 
 impl PagedFile for AsyncFile {
     fn read_page(&self, pref: PRef) -> Result<Option<Page>, Error> {
-        if let Some(page) = self.read_in_queue(pref)? {
-            return Ok(Some(page));
-        }
-        let file = self.inner.file.lock().expect("file lock poisoned");
-        file.read_page(pref)
+        self.inner.read_page(pref)
     }
 
     fn len(&self) -> Result<u64, Error> {
@@ -109,22 +195,44 @@ impl PagedFile for AsyncFile {
         self.inner.file.lock().unwrap().sync()
     }
 
+    fn advise(&self, advice: FadviseAdvice) -> Result<(), Error> {
+        self.inner.file.lock().unwrap().advise(advice)
+    }
+
+    fn copy_files(&self, dest_name: &str) -> Result<BackupStats, Error> {
+        self.flush_and_drain()?;
+        self.inner.file.lock().unwrap().copy_files(dest_name)
+    }
+
+    fn copy_to(&self, dest: &mut dyn PagedFile) -> Result<u64, Error> {
+        self.flush_and_drain()?;
+        self.inner.file.lock().unwrap().copy_to(dest)
+    }
+
+    fn name(&self) -> String {
+        self.inner.file.lock().unwrap().name()
+    }
+
     fn shutdown(&mut self) {
-        let mut queue = self.inner.queue.lock().unwrap();
-        self.inner.work.notify_one();
-        while !queue.is_empty() {
-            queue = self.inner.flushed.wait(queue).unwrap();
-        }
-        let mut file = self.inner.file.lock().unwrap();
-        file.flush().unwrap();
-        self.inner.run.store(false, Ordering::Release)
+        self.shutdown_and_drain()
+    }
+
+    fn replacement(&self) -> Result<Box<dyn PagedFile>, Error> {
+        self.inner.file.lock().unwrap().replacement()
+    }
+
+    fn replace_with(&mut self, replacement: Box<dyn PagedFile>) -> Result<(), Error> {
+        self.flush_and_drain()?;
+        self.inner.file.lock().unwrap().replace_with(replacement)
+    }
+
+    fn complete_replacement(&mut self, commit: bool) -> Result<(), Error> {
+        self.flush_and_drain()?;
+        self.inner.file.lock().unwrap().complete_replacement(commit)
     }
 
     fn append_page(&mut self, page: Page) -> Result<(), Error> {
-        let mut queue = self.inner.queue.lock().unwrap();
-        queue.push(page.clone());
-        self.inner.work.notify_one();
-        Ok(())
+        self.inner.append_page(page)
     }
 
     fn update_page(&mut self, _: Page) -> Result<u64, Error> {
@@ -132,12 +240,116 @@ impl PagedFile for AsyncFile {
     }
 
     fn flush(&mut self) -> Result<(), Error> {
-        let mut queue = self.inner.queue.lock().unwrap();
-        self.inner.work.notify_one();
-        while !queue.is_empty() {
-            queue = self.inner.flushed.wait(queue).unwrap();
+        self.flush_and_drain()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use transient::Transient;
+    use page::PAGE_SIZE;
+
+    use super::*;
+
+    #[test]
+    fn test_flush_and_drain() {
+        let mut file = AsyncFile::new(Box::new(Transient::new(true))).unwrap();
+        for i in 0 .. 20 {
+            let mut page = Page::new();
+            page.write_u64(0, i);
+            file.append_page(page).unwrap();
         }
-        let mut file = self.inner.file.lock().unwrap();
-        file.flush()
+        file.flush_and_drain().unwrap();
+        assert!(file.inner.queue.lock().unwrap().is_empty());
+        assert_eq!(file.len().unwrap(), 20 * PAGE_SIZE as u64);
+        for i in 0 .. 20 {
+            let page = file.inner.file.lock().unwrap().read_page(PRef::from(i * PAGE_SIZE as u64)).unwrap().unwrap();
+            assert_eq!(page.read_u64(0), i);
+        }
+        file.shutdown_and_drain();
+    }
+
+    #[test]
+    fn test_writer_stops() {
+        let mut file = AsyncFile::new(Box::new(Transient::new(true))).unwrap();
+        let inner = Arc::downgrade(&file.inner);
+        file.append_page(Page::new()).unwrap();
+        file.shutdown_and_drain();
+        assert_eq!(file.len().unwrap(), PAGE_SIZE as u64);
+        assert!(file.writer.is_none());
+        drop(file);
+        // the writer held the file
+        assert!(inner.upgrade().is_none());
+
+        // also if not shut down
+        let mut file = AsyncFile::new(Box::new(Transient::new(true))).unwrap();
+        let inner = Arc::downgrade(&file.inner);
+        file.append_page(Page::new()).unwrap();
+        drop(file);
+        assert!(inner.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_shutdown_poisoned() {
+        let mut file = AsyncFile::new(Box::new(Transient::new(true))).unwrap();
+        let inner = file.inner.clone();
+        thread::spawn(move || {
+            let _queue = inner.queue.lock().unwrap();
+            panic!("poison the queue");
+        }).join().unwrap_err();
+        assert!(file.flush_and_drain().is_err());
+        file.shutdown_and_drain();
+    }
+
+    #[test]
+    fn test_bounded_queue() {
+        let mut file = AsyncFile::new_bounded(Box::new(Transient::new(true)), 4).unwrap();
+        let writers = (0 .. 2u64).map(|w| {
+            let inner = file.inner.clone();
+            thread::spawn(move || {
+                for i in 0 .. 50u64 {
+                    let mut page = Page::new();
+                    page.write_u64(0, w);
+                    page.write_u64(8, i);
+                    inner.append_page(page).unwrap();
+                    assert!(inner.queue.lock().unwrap().len() <= 4);
+                }
+            })
+        }).collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        file.shutdown_and_drain();
+
+        assert_eq!(file.len().unwrap(), 100 * PAGE_SIZE as u64);
+        let mut next = [0u64; 2];
+        for n in 0 .. 100 {
+            let page = file.read_page(PRef::from(n * PAGE_SIZE as u64)).unwrap().unwrap();
+            let w = page.read_u64(0) as usize;
+            assert_eq!(page.read_u64(8), next[w]);
+            next[w] += 1;
+        }
+        assert_eq!(next, [50, 50]);
+    }
+
+    #[cfg(feature = "tokio_support")]
+    #[test]
+    fn test_read_page_async() {
+        let mut file = AsyncFile::new(Box::new(Transient::new(true))).unwrap();
+        for i in 0 .. 10 {
+            let mut page = Page::new();
+            page.write_u64(0, i);
+            file.append_page(page).unwrap();
+        }
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let _guard = runtime.enter();
+        // pages might still be queued or already written
+        let reads = (0 .. 10).map(|i| file.read_page_async(PRef::from(i * PAGE_SIZE as u64))).collect::<Vec<_>>();
+        for (i, read) in reads.into_iter().enumerate() {
+            let page = runtime.block_on(read).unwrap().unwrap();
+            assert_eq!(page.read_u64(0), i as u64);
+        }
+        file.shutdown_and_drain();
     }
 }
@@ -0,0 +1,221 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Asynchronous file on tokio
+//! an append only file written on the blocking thread pool of a tokio runtime
+//!
+
+use page::Page;
+use pagedfile::{PagedFile, FadviseAdvice};
+use error::Error;
+use pref::PRef;
+use stats::BackupStats;
+
+use tokio::runtime::Handle;
+
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub struct TokioAsyncFile {
+    inner: Arc<TokioAsyncFileInner>
+}
+
+struct TokioAsyncFileInner {
+    file: Mutex<Box<dyn PagedFile>>,
+    queue: Mutex<VecDeque<Page>>,
+    // a writer task is scheduled
+    writing: AtomicBool,
+    handle: Handle
+}
+
+impl TokioAsyncFileInner {
+    // a plain mutex, as tasks of the runtime call the db and must not block on it
+    fn lock_queue(&self) -> Result<MutexGuard<'_, VecDeque<Page>>, Error> {
+        Ok(self.queue.lock()?)
+    }
+
+    // write queued pages in order, the queue stays locked until they are in the file
+    fn write_queued(&self) -> Result<(), Error> {
+        let mut queue = self.lock_queue()?;
+        let mut file = self.file.lock()?;
+        for page in queue.drain(..) {
+            file.append_page(page)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.write_queued()?;
+        self.file.lock()?.flush()
+    }
+
+    fn read_page(&self, pref: PRef) -> Result<Option<Page>, Error> {
+        let queue = self.lock_queue()?;
+        let file = self.file.lock()?;
+        if !queue.is_empty() {
+            let len = PRef::from(file.len()?);
+            if pref >= len {
+                let index = len.pages_until(pref);
+                if index < queue.len() {
+                    return Ok(Some(queue[index].clone()));
+                }
+            }
+        }
+        file.read_page(pref)
+    }
+
+    fn background(inner: Arc<TokioAsyncFileInner>) {
+        loop {
+            inner.write_queued().expect("can not write in background");
+            inner.writing.store(false, Ordering::Release);
+            // a page queued meanwhile did not schedule a writer
+            if inner.lock_queue().map(|queue| queue.is_empty()).unwrap_or(true) || inner.writing.swap(true, Ordering::AcqRel) {
+                return;
+            }
+        }
+    }
+}
+
+impl TokioAsyncFile {
+    /// an async file writing on the runtime it is created in
+    pub fn new(file: Box<dyn PagedFile>) -> Result<TokioAsyncFile, Error> {
+        let handle = Handle::try_current().map_err(|e| Error::IO(io::Error::new(ErrorKind::Other, e.to_string())))?;
+        Ok(TokioAsyncFile { inner: Arc::new(TokioAsyncFileInner {
+            file: Mutex::new(file), queue: Mutex::new(VecDeque::new()), writing: AtomicBool::new(false), handle }) })
+    }
+}
+
+impl PagedFile for TokioAsyncFile {
+    fn read_page(&self, pref: PRef) -> Result<Option<Page>, Error> {
+        self.inner.read_page(pref)
+    }
+
+    fn len(&self) -> Result<u64, Error> {
+        self.inner.file.lock()?.len()
+    }
+
+    fn truncate(&mut self, new_len: u64) -> Result<(), Error> {
+        self.inner.file.lock()?.truncate(new_len)
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        self.inner.file.lock()?.sync()
+    }
+
+    fn advise(&self, advice: FadviseAdvice) -> Result<(), Error> {
+        self.inner.file.lock()?.advise(advice)
+    }
+
+    fn copy_files(&self, dest_name: &str) -> Result<BackupStats, Error> {
+        self.inner.flush()?;
+        self.inner.file.lock()?.copy_files(dest_name)
+    }
+
+    fn copy_to(&self, dest: &mut dyn PagedFile) -> Result<u64, Error> {
+        self.inner.flush()?;
+        self.inner.file.lock()?.copy_to(dest)
+    }
+
+    fn name(&self) -> String {
+        self.inner.file.lock().unwrap().name()
+    }
+
+    // shutdown has no result, so a failing write is ignored
+    fn shutdown(&mut self) {
+        self.inner.flush().ok();
+        if let Ok(mut file) = self.inner.file.lock() {
+            file.shutdown()
+        }
+    }
+
+    fn replacement(&self) -> Result<Box<dyn PagedFile>, Error> {
+        self.inner.file.lock()?.replacement()
+    }
+
+    fn replace_with(&mut self, replacement: Box<dyn PagedFile>) -> Result<(), Error> {
+        self.inner.flush()?;
+        self.inner.file.lock()?.replace_with(replacement)
+    }
+
+    fn complete_replacement(&mut self, commit: bool) -> Result<(), Error> {
+        self.inner.flush()?;
+        self.inner.file.lock()?.complete_replacement(commit)
+    }
+
+    fn append_page(&mut self, page: Page) -> Result<(), Error> {
+        self.inner.lock_queue()?.push_back(page);
+        if !self.inner.writing.swap(true, Ordering::AcqRel) {
+            let inner = self.inner.clone();
+            self.inner.handle.spawn_blocking(move || TokioAsyncFileInner::background(inner));
+        }
+        Ok(())
+    }
+
+    fn update_page(&mut self, _: Page) -> Result<u64, Error> {
+        unimplemented!()
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use transient::Transient;
+    use page::PAGE_SIZE;
+
+    use super::*;
+
+    use std::future;
+    use std::task::Poll;
+
+    #[test]
+    fn test_concurrent_append() {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let _guard = runtime.enter();
+        let file = Arc::new(Mutex::new(TokioAsyncFile::new(Box::new(Transient::new(true))).unwrap()));
+        let writers = (0 .. 4u64).map(|w| {
+            let file = file.clone();
+            runtime.spawn(future::poll_fn(move |_| {
+                for i in 0 .. 250u64 {
+                    let mut page = Page::new();
+                    page.write_u64(0, w);
+                    page.write_u64(8, i);
+                    file.lock().unwrap().append_page(page).unwrap();
+                }
+                Poll::Ready(())
+            }))
+        }).collect::<Vec<_>>();
+        for writer in writers {
+            runtime.block_on(writer).unwrap();
+        }
+        file.lock().unwrap().flush().unwrap();
+
+        let file = file.lock().unwrap();
+        assert_eq!(file.len().unwrap(), 1000 * PAGE_SIZE as u64);
+        let mut next = [0u64; 4];
+        for n in 0 .. 1000 {
+            let page = file.read_page(PRef::from(n * PAGE_SIZE as u64)).unwrap().unwrap();
+            let w = page.read_u64(0) as usize;
+            assert_eq!(page.read_u64(8), next[w]);
+            next[w] += 1;
+        }
+        assert_eq!(next, [250; 4]);
+    }
+}
@@ -0,0 +1,85 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Repair a Hammersbald db that can not be opened after a crash
+//!
+//! usage: repair --db name [--dry-run | --rebuild-index]
+//!
+//! exits with 0 if the db was repaired, 1 if it could not be
+//! --rebuild-index discards the hash table and indexes all keyed data anew,
+//! for a table that is lost or corrupted beyond repair
+//!
+
+extern crate hammersbald;
+
+use std::env;
+use std::process;
+
+fn usage() -> ! {
+    eprintln!("usage: repair --db name [--dry-run | --rebuild-index]");
+    process::exit(2)
+}
+
+fn main() {
+    let mut name = None;
+    let mut dry_run = false;
+    let mut rebuild = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--db" => name = Some(args.next().unwrap_or_else(|| usage())),
+            "--dry-run" => dry_run = true,
+            "--rebuild-index" => rebuild = true,
+            _ => usage()
+        }
+    }
+    let name = name.unwrap_or_else(|| usage());
+    if dry_run && rebuild {
+        usage();
+    }
+
+    if rebuild {
+        match hammersbald::rebuild_index(name.as_str()) {
+            Ok(indexed) => println!("records indexed: {}", indexed),
+            Err(e) => {
+                eprintln!("can not rebuild the index of {}: {}", name, e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    match hammersbald::repair(name.as_str(), dry_run) {
+        Ok(report) => {
+            println!("incomplete pages cut: {} bytes", report.partial_page_bytes);
+            println!("written after last batch: {} bytes", report.unlogged_bytes);
+            println!("table pages in log: {}", report.logged_table_pages);
+            if dry_run {
+                println!("dry run, nothing was changed");
+            }
+            else {
+                println!("unreadable keys: {}", report.unreadable_keys);
+                if report.unreadable_keys > 0 {
+                    process::exit(1);
+                }
+            }
+        },
+        Err(e) => {
+            eprintln!("can not repair {}: {}", name, e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Print statistics of the hash table of a Hammersbald db
+//!
+//! usage: stats --db name [--stats histogram]
+//!
+//! the db is opened for reading only
+//! --stats histogram also prints the number of buckets by the number of keys in them
+//!
+
+extern crate hammersbald;
+
+use std::env;
+use std::process;
+
+fn usage() -> ! {
+    eprintln!("usage: stats --db name [--stats histogram]");
+    process::exit(2)
+}
+
+fn main() {
+    let mut name = None;
+    let mut histogram = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--db" => name = Some(args.next().unwrap_or_else(|| usage())),
+            "--stats" => match args.next().as_ref().map(|s| s.as_str()) {
+                Some("histogram") => histogram = true,
+                _ => usage()
+            },
+            _ => usage()
+        }
+    }
+    let name = name.unwrap_or_else(|| usage());
+
+    let db = match hammersbald::open_read_only(name.as_str(), 100) {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("can not open {}: {}", name, e);
+            process::exit(1);
+        }
+    };
+    let stats = db.stats().unwrap_or_else(|e| {
+        eprintln!("can not read the hash table of {}: {}", name, e);
+        process::exit(1);
+    });
+    println!("buckets: {}", stats.bucket_count);
+    println!("keys: {}", stats.entry_count);
+    println!("load factor: {:.2}", stats.load_factor);
+    println!("collision rate: {:.4}", stats.collision_rate);
+    println!("data file: {} bytes", stats.data_file_bytes);
+    println!("link file: {} bytes", stats.link_file_bytes);
+
+    if histogram {
+        let fill = db.bucket_fill_histogram().unwrap_or_else(|e| {
+            eprintln!("can not read the hash table of {}: {}", name, e);
+            process::exit(1);
+        });
+        println!("max bucket depth: {}", fill.len().saturating_sub(1));
+        for (keys, buckets) in fill.iter().enumerate().filter(|(_, buckets)| **buckets > 0) {
+            println!("{:>4} keys: {} buckets", keys, buckets);
+        }
+    }
+}
@@ -17,17 +17,36 @@
 //! # Hammersbald bitcoin support
 //!
 
+use std::collections::HashSet;
 use std::io;
 use std::marker::PhantomData;
+use std::sync::{Arc, mpsc};
 
-use bitcoin_hashes::Hash;
-use bitcoin::{Block, BlockHash, BlockHeader, Transaction, Txid, Wtxid};
+use bitcoin_hashes::{Hash, hash160, sha256};
+use bitcoin::{Address, Block, BlockHash, BlockHeader, OutPoint, Script, Transaction, TxOut, Txid, Wtxid};
 use bitcoin::consensus::encode::{Decodable, Encodable, serialize, deserialize};
 
 use Error;
 use HammersbaldAPI;
 use HammersbaldIterator;
+use HammersbaldDataReader;
+use HammersbaldDataWriter;
+use IndexedIterator;
+use ApproximateResult;
+use BucketInspection;
+use BatchPhase;
+use DagIterator;
+use AccessLog;
+use ReferredIterator;
+use PrefixIterator;
 use PRef;
+use Stats;
+use MemTableStats;
+use RecoveryStats;
+use DBStats;
+use BackupStats;
+use LinkCompactionStats;
+use CompactionReport;
 
 /// A trait implemented for Bitcoin object that should be retrievable
 /// by a hash identifier.
@@ -64,7 +83,7 @@ pub trait BitcoinObject<Key>: Sized where Key: Hash, <Key as Hash>::Engine: io::
         let object = Self::decode(&mut decoder)?;
 
         if decoder.position() as usize != bytes.len() {
-            return Err(Error::Corrupted("corrupt data".to_owned()));
+            return Err(Error::corrupted("corrupt data"));
         }
 
         Ok(object)
@@ -98,6 +117,63 @@ impl BitcoinObject<BlockHash> for Block {
     }
 }
 
+/// key prefix of the unspent transaction outputs
+const UTXO_PREFIX: &[u8] = b"utxo:";
+
+fn utxo_key(outpoint: &OutPoint) -> Vec<u8> {
+    let mut key = UTXO_PREFIX.to_vec();
+    key.extend_from_slice(&serialize(outpoint)[..]);
+    key
+}
+
+/// key prefix of the output script index
+const SCRIPT_PREFIX: &[u8] = b"script:";
+
+// scripts are hashed as they might be longer than the maximal key length
+fn script_key(script: &Script) -> Vec<u8> {
+    let mut key = SCRIPT_PREFIX.to_vec();
+    key.extend_from_slice(&sha256::Hash::hash(script.as_bytes())[..]);
+    key
+}
+
+/// key prefix of the address index
+const ADDRESS_PREFIX: &[u8] = b"addr:";
+
+// key of the address paid by an output script, if it is standard
+// pay to public key outputs are indexed as pay to the hash of the key
+fn address_key(script: &Script) -> Option<Vec<u8>> {
+    let bytes = script.as_bytes();
+    let mut key = ADDRESS_PREFIX.to_vec();
+    if script.is_p2pkh() {
+        key.push(b'p');
+        key.extend_from_slice(&bytes[3 .. 23]);
+    } else if script.is_p2pk() {
+        key.push(b'p');
+        key.extend_from_slice(&hash160::Hash::hash(&bytes[1 .. bytes.len() - 1])[..]);
+    } else if script.is_p2sh() {
+        key.push(b's');
+        key.extend_from_slice(&bytes[2 .. 22]);
+    } else if script.is_witness_program() {
+        key.push(b'w');
+        key.push(bytes[0]);
+        key.extend_from_slice(&bytes[2 ..]);
+    } else {
+        return None;
+    }
+    Some(key)
+}
+
+/// Result of BitcoinAdaptor::verify_chain_consistency
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChainConsistencyResult {
+    /// number of headers found from the tip backwards
+    pub chain_length: u32,
+    /// all headers are found back to one with zero prev_blockhash
+    pub is_consistent: bool,
+    /// hash of the first header (from the tip) whose predecessor is not found
+    pub first_break: Option<BlockHash>
+}
+
 /// Bitcoin adaptor
 pub struct BitcoinAdaptor {
     hammersbald: Box<dyn HammersbaldAPI>
@@ -158,11 +234,197 @@ impl BitcoinAdaptor {
         Ok(None)
     }
 
+    /// Forget bitcoin objects stored with put_object_by_hash
+    pub fn forget_objects_by_hash<H: Hash>(&mut self, ids: &[H]) -> Result<(), Error> {
+        let keys = ids.iter().map(|id| &id[..]).collect::<Vec<_>>();
+        self.hammersbald.forget_batch(&keys)
+    }
+
+    /// forget a bitcoin object stored with its hash, returns if it was stored
+    pub fn delete_object_by_hash<H: Hash>(&mut self, id: H) -> Result<bool, Error> {
+        self.delete_object_by_key(&id[..])
+    }
+
+    /// forget data stored with key, returns if it was stored
+    pub fn delete_object_by_key(&mut self, key: &[u8]) -> Result<bool, Error> {
+        if !self.hammersbald.contains_key(key)? {
+            return Ok(false);
+        }
+        self.hammersbald.forget(key)?;
+        Ok(true)
+    }
+
     /// quick check if the db contains a key. This might return false positive.
     pub fn may_have_hash<H: Hash>(&self, key: H) -> Result<bool, Error> {
         Ok(self.hammersbald.may_have_key(&key[..])?)
     }
 
+    /// Store an unspent transaction output
+    pub fn put_unspent_output(&mut self, outpoint: &OutPoint, output: &TxOut) -> Result<PRef, Error> {
+        self.hammersbald.put_keyed(&utxo_key(outpoint)[..], &serialize(output)[..])
+    }
+
+    /// Retrieve an unspent transaction output
+    pub fn get_unspent_output(&self, outpoint: &OutPoint) -> Result<Option<TxOut>, Error> {
+        if let Some((_, data)) = self.hammersbald.get_keyed(&utxo_key(outpoint)[..])? {
+            return Ok(Some(deserialize(&data[..])?));
+        }
+        Ok(None)
+    }
+
+    /// Forget a spent transaction output
+    pub fn forget_unspent_output(&mut self, outpoint: &OutPoint) -> Result<(), Error> {
+        self.hammersbald.forget(&utxo_key(outpoint)[..])
+    }
+
+    /// Store all outputs of the transactions of a block as unspent
+    pub fn put_block_outputs(&mut self, block: &Block) -> Result<(), Error> {
+        for tx in &block.txdata {
+            let txid = tx.txid();
+            for (vout, output) in tx.output.iter().enumerate() {
+                self.put_unspent_output(&OutPoint::new(txid, vout as u32), output)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// index the transaction by the scripts of its outputs
+    pub fn index_output_scripts(&mut self, tx: &Transaction, txid: &Txid) -> Result<(), Error> {
+        for output in &tx.output {
+            self.add_txid(&script_key(&output.script_pubkey), txid)?;
+        }
+        Ok(())
+    }
+
+    /// ids of indexed transactions with an output to the script
+    pub fn get_txids_for_script(&self, script: &Script) -> Result<Vec<Txid>, Error> {
+        self.get_txids(&script_key(script))
+    }
+
+    /// index the transaction by the addresses its outputs pay to
+    /// pay to public key hash, script hash and witness program outputs are indexed
+    pub fn index_transaction_addresses(&mut self, tx: &Transaction, txid: &Txid) -> Result<(), Error> {
+        for output in &tx.output {
+            if let Some(key) = address_key(&output.script_pubkey) {
+                self.add_txid(&key, txid)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// ids of indexed transactions with an output to the address
+    pub fn get_transactions_for_address(&self, address: &Address) -> Result<Vec<Txid>, Error> {
+        match address_key(&address.script_pubkey()) {
+            Some(key) => self.get_txids(&key),
+            None => Ok(Vec::new())
+        }
+    }
+
+    // add txid to the list of transaction ids stored at key
+    // the key refers to the last entry of the list, each entry refers to the one added before
+    fn add_txid(&mut self, key: &[u8], txid: &Txid) -> Result<(), Error> {
+        let mut refs = Vec::new();
+        if let Some((_, head)) = self.hammersbald.get_keyed(key)? {
+            let head = HammersbaldDataReader::new(&head).read_ref()?;
+            // outputs of a transaction to the same key are listed once
+            if self.get_txid_entry(head)?.0 == *txid {
+                return Ok(());
+            }
+            refs.push(head);
+        }
+        let entry = self.hammersbald.put_with_refs(&txid[..], &refs)?;
+        let mut head = HammersbaldDataWriter::new();
+        head.write_ref(entry);
+        self.hammersbald.put_keyed(key, head.as_slice())?;
+        Ok(())
+    }
+
+    // txid of a list entry and the entry added before it
+    fn get_txid_entry(&self, pref: PRef) -> Result<(Txid, Option<PRef>), Error> {
+        let (txid, refs) = self.hammersbald.get_with_refs(pref)?;
+        if refs.len() > 1 || txid.len() != 32 {
+            return Err(Error::corrupted("invalid entry of a txid list"));
+        }
+        let prev = refs.first().cloned();
+        match prev {
+            Some(prev) if prev >= pref => return Err(Error::corrupted("txid list entry does not refer to an earlier one")),
+            _ => {}
+        }
+        let txid = Txid::from_slice(&txid).map_err(|_| Error::corrupted("invalid txid in index"))?;
+        Ok((txid, prev))
+    }
+
+    // txids of the list stored at key in the order they were added, each once
+    fn get_txids(&self, key: &[u8]) -> Result<Vec<Txid>, Error> {
+        let mut result = Vec::new();
+        if let Some((_, head)) = self.hammersbald.get_keyed(key)? {
+            let mut next = Some(HammersbaldDataReader::new(&head).read_ref()?);
+            while let Some(pref) = next {
+                let (txid, prev) = self.get_txid_entry(pref)?;
+                result.push(txid);
+                next = prev;
+            }
+        }
+        result.reverse();
+        let mut seen = HashSet::new();
+        result.retain(|txid| seen.insert(*txid));
+        Ok(result)
+    }
+
+    /// store consecutive block headers with their hash as key, as put_object_by_hash would, with one put_keyed_batch
+    /// the first header must follow a stored header or be a genesis header, nothing is stored if the chain is disconnected
+    /// returns persistent references to the headers in their order
+    pub fn put_header_chain(&mut self, headers: &[BlockHeader]) -> Result<Vec<PRef>, Error> {
+        if let Some(first) = headers.first() {
+            if first.prev_blockhash[..] != [0u8; 32] && !self.hammersbald.contains_key(&first.prev_blockhash[..])? {
+                return Err(Error::InvalidArgument("disconnected header chain".to_string()));
+            }
+        }
+        let hashes = headers.iter().map(|header| header.block_hash()).collect::<Vec<_>>();
+        if headers.iter().skip(1).zip(hashes.iter()).any(|(header, prev)| header.prev_blockhash != *prev) {
+            return Err(Error::InvalidArgument("disconnected header chain".to_string()));
+        }
+        let encoded = headers.iter().map(serialize).collect::<Vec<_>>();
+        let items = hashes.iter().zip(encoded.iter()).map(|(hash, data)| (&hash[..], &data[..])).collect::<Vec<_>>();
+        self.hammersbald.put_keyed_batch(&items)
+    }
+
+    /// walk headers stored with put_object_by_hash from the tip back to genesis
+    /// this is a diagnostic, headers are not validated
+    pub fn verify_chain_consistency(&self, tip: &BlockHash) -> Result<ChainConsistencyResult, Error> {
+        let mut chain_length = 0;
+        let mut current = *tip;
+        let mut successor = None;
+        while let Some((_, header)) = self.get_object_by_hash::<BlockHash, BlockHeader>(current)? {
+            chain_length += 1;
+            if header.prev_blockhash[..] == [0u8; 32] {
+                return Ok(ChainConsistencyResult { chain_length, is_consistent: true, first_break: None });
+            }
+            successor = Some(current);
+            current = header.prev_blockhash;
+        }
+        Ok(ChainConsistencyResult { chain_length, is_consistent: false, first_break: Some(successor.unwrap_or(*tip)) })
+    }
+
+    /// iterate over the unspent transaction outputs
+    pub fn iter_unspent_outputs(&self) -> UtxoIterator {
+        UtxoIterator {
+            inner: self.hammersbald.iter(),
+            hammersbald: self.hammersbald.as_ref(),
+        }
+    }
+
+    /// number of unspent transaction outputs
+    pub fn count_utxos(&self) -> Result<usize, Error> {
+        let mut count = 0;
+        for (pref, key, _) in self.hammersbald.iter() {
+            if key.starts_with(UTXO_PREFIX) && self.hammersbald.get_pref_for_key(&key[..])? == Some(pref) {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
     /// iterate over all data, useful only if data is homogenous
     pub fn iter_decodable<T> (&self) -> HammersbaldDecodableIterator<T>
         where T: Decodable + ?Sized
@@ -172,6 +434,16 @@ impl BitcoinAdaptor {
             data: PhantomData,
         }
     }
+
+    /// iterate over data resuming at a token saved with HammersbaldDecodableIterator::save_token
+    pub fn iter_decodable_from_pref<T> (&self, start: PRef) -> HammersbaldDecodableIterator<T>
+        where T: Decodable + ?Sized
+    {
+        HammersbaldDecodableIterator{
+            inner: self.iter_from_token(start),
+            data: PhantomData,
+        }
+    }
 }
 
 /// An iterator over a stream of decodable data.
@@ -180,6 +452,13 @@ pub struct HammersbaldDecodableIterator<'a, T> {
     data: PhantomData<T>
 }
 
+impl<'a, T> HammersbaldDecodableIterator<'a, T> {
+    /// token to resume iteration after the last returned data with BitcoinAdaptor::iter_decodable_from_pref
+    pub fn save_token(&self) -> PRef {
+        self.inner.save_token()
+    }
+}
+
 impl<'a, T: Decodable> Iterator for HammersbaldDecodableIterator<'a, T> {
     type Item = (PRef, T);
 
@@ -193,11 +472,46 @@ impl<'a, T: Decodable> Iterator for HammersbaldDecodableIterator<'a, T> {
     }
 }
 
+/// An iterator over the unspent transaction outputs.
+///
+/// Outputs that were spent or overwritten are skipped.
+pub struct UtxoIterator<'a> {
+    inner: HammersbaldIterator<'a>,
+    hammersbald: &'a dyn HammersbaldAPI
+}
+
+impl<'a> Iterator for UtxoIterator<'a> {
+    type Item = (OutPoint, TxOut);
+
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        while let Some((pref, key, data)) = self.inner.next() {
+            if key.starts_with(UTXO_PREFIX) {
+                if let Ok(Some(current)) = self.hammersbald.get_pref_for_key(&key[..]) {
+                    if current == pref {
+                        if let (Ok(outpoint), Ok(output)) = (deserialize(&key[UTXO_PREFIX.len()..]), deserialize(&data[..])) {
+                            return Some((outpoint, output));
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
 impl HammersbaldAPI for BitcoinAdaptor {
     fn batch(&mut self) -> Result<(), Error> {
         self.hammersbald.batch()
     }
 
+    fn batch_with_progress(&mut self, progress: &dyn Fn(BatchPhase)) -> Result<(), Error> {
+        self.hammersbald.batch_with_progress(progress)
+    }
+
+    fn checkpoint(&mut self) -> Result<(), Error> {
+        self.hammersbald.checkpoint()
+    }
+
     fn shutdown(&mut self) {
         self.hammersbald.shutdown()
     }
@@ -206,29 +520,215 @@ impl HammersbaldAPI for BitcoinAdaptor {
         self.hammersbald.put_keyed(key, data)
     }
 
+    fn put_keyed_batch(&mut self, items: &[(&[u8], &[u8])]) -> Result<Vec<PRef>, Error> {
+        self.hammersbald.put_keyed_batch(items)
+    }
+
+    fn put_keyed_unique(&mut self, key: &[u8], data: &[u8]) -> Result<PRef, Error> {
+        self.hammersbald.put_keyed_unique(key, data)
+    }
+
+    fn put_keyed_if_absent(&mut self, key: &[u8], data: &[u8]) -> Result<Option<PRef>, Error> {
+        self.hammersbald.put_keyed_if_absent(key, data)
+    }
+
     fn get_keyed(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>)>, Error> {
         self.hammersbald.get_keyed(key)
     }
 
+    fn get_keyed_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<PRef>, Error> {
+        self.hammersbald.get_keyed_into(key, buf)
+    }
+
+    fn put_keyed_with_links(&mut self, key: &[u8], data: &[u8], links: &[PRef]) -> Result<PRef, Error> {
+        self.hammersbald.put_keyed_with_links(key, data, links)
+    }
+
+    fn get_keyed_with_links(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>, Vec<PRef>)>, Error> {
+        self.hammersbald.get_keyed_with_links(key)
+    }
+
+    fn put_keyed_versioned(&mut self, key: &[u8], data: &[u8]) -> Result<(PRef, u32), Error> {
+        self.hammersbald.put_keyed_versioned(key, data)
+    }
+
+    fn get_keyed_version(&self, key: &[u8], version: u32) -> Result<Option<(PRef, Vec<u8>)>, Error> {
+        self.hammersbald.get_keyed_version(key, version)
+    }
+
+    fn put_pref_list(&mut self, prefs: &[PRef]) -> Result<PRef, Error> {
+        self.hammersbald.put_pref_list(prefs)
+    }
+
+    fn get_pref_list(&self, pref: PRef) -> Result<Vec<PRef>, Error> {
+        self.hammersbald.get_pref_list(pref)
+    }
+
+    fn get_pref_for_key(&self, key: &[u8]) -> Result<Option<PRef>, Error> {
+        self.hammersbald.get_pref_for_key(key)
+    }
+
     fn put(&mut self, data: &[u8]) -> Result<PRef, Error> {
         self.hammersbald.put(data)
     }
 
+    fn put_with_refs(&mut self, data: &[u8], refs: &[PRef]) -> Result<PRef, Error> {
+        self.hammersbald.put_with_refs(data, refs)
+    }
+
+    fn put_referred_dag(&mut self, nodes: &[(&[u8], Vec<usize>)]) -> Result<Vec<PRef>, Error> {
+        self.hammersbald.put_referred_dag(nodes)
+    }
+
     fn get(&self, pref: PRef) -> Result<(Vec<u8>, Vec<u8>), Error> {
         self.hammersbald.get(pref)
     }
 
+    fn get_with_refs(&self, pref: PRef) -> Result<(Vec<u8>, Vec<PRef>), Error> {
+        self.hammersbald.get_with_refs(pref)
+    }
+
     fn may_have_key(&self, key: &[u8]) -> Result<bool, Error> {
         self.hammersbald.may_have_key(key)
     }
 
+    fn may_have_key_bulk(&self, keys: &[&[u8]]) -> Result<Vec<bool>, Error> {
+        self.hammersbald.may_have_key_bulk(keys)
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool, Error> {
+        self.hammersbald.contains_key(key)
+    }
+
+    fn get_keyed_approximate(&self, key: &[u8]) -> Result<ApproximateResult, Error> {
+        self.hammersbald.get_keyed_approximate(key)
+    }
+
+    fn inspect_bucket(&self, key: &[u8]) -> Result<BucketInspection, Error> {
+        self.hammersbald.inspect_bucket(key)
+    }
+
+    fn watch(&self, key: &[u8]) -> Result<mpsc::Receiver<Option<Vec<u8>>>, Error> {
+        self.hammersbald.watch(key)
+    }
+
+    fn set_access_log(&mut self, log: Arc<dyn AccessLog>) {
+        self.hammersbald.set_access_log(log)
+    }
+
     fn forget(&mut self, key: &[u8]) -> Result<(), crate::error::Error> {
         self.hammersbald.forget(key)
     }
 
+    fn forget_batch(&mut self, keys: &[&[u8]]) -> Result<(), Error> {
+        self.hammersbald.forget_batch(keys)
+    }
+
     fn iter(&self) -> HammersbaldIterator {
         self.hammersbald.iter()
     }
+
+    fn dag(&self, root: PRef) -> DagIterator {
+        self.hammersbald.dag(root)
+    }
+
+    fn iter_since_pref(&self, pref: PRef) -> HammersbaldIterator {
+        self.hammersbald.iter_since_pref(pref)
+    }
+
+    fn iter_from(&self, start: PRef) -> HammersbaldIterator {
+        self.hammersbald.iter_from(start)
+    }
+
+    fn iter_range(&self, from: PRef, to: PRef) -> HammersbaldIterator {
+        self.hammersbald.iter_range(from, to)
+    }
+
+    fn current_data_pos(&self) -> PRef {
+        self.hammersbald.current_data_pos()
+    }
+
+    fn prefix_scan<'a>(&'a self, prefix: &'a [u8]) -> PrefixIterator<'a> {
+        self.hammersbald.prefix_scan(prefix)
+    }
+
+    #[cfg(feature = "parallel_reads")]
+    fn prefix_scan_parallel(&self, prefix: &[u8]) -> Result<Vec<(PRef, Vec<u8>, Vec<u8>)>, Error> {
+        self.hammersbald.prefix_scan_parallel(prefix)
+    }
+
+    fn iter_indexed(&self) -> IndexedIterator {
+        self.hammersbald.iter_indexed()
+    }
+
+    fn iter_referred(&self) -> ReferredIterator {
+        self.hammersbald.iter_referred()
+    }
+
+    fn set_sip_keys(&mut self, sip0: u64, sip1: u64) -> Result<(), Error> {
+        self.hammersbald.set_sip_keys(sip0, sip1)
+    }
+
+    fn get_sip_keys(&self) -> (u64, u64) {
+        self.hammersbald.get_sip_keys()
+    }
+
+    fn count_live_keys(&self) -> Result<usize, Error> {
+        self.hammersbald.count_live_keys()
+    }
+
+    fn get_stats(&self) -> Result<DBStats, Error> {
+        self.hammersbald.get_stats()
+    }
+
+    fn last_recovery_stats(&self) -> Option<RecoveryStats> {
+        self.hammersbald.last_recovery_stats()
+    }
+
+    fn stats(&self) -> Result<MemTableStats, Error> {
+        self.hammersbald.stats()
+    }
+
+    fn bucket_fill_histogram(&self) -> Result<Vec<usize>, Error> {
+        self.hammersbald.bucket_fill_histogram()
+    }
+
+    fn stats_snapshot(&self) -> Result<Stats, Error> {
+        self.hammersbald.stats_snapshot()
+    }
+
+    fn backup(&mut self, dest_name: &str) -> Result<BackupStats, Error> {
+        self.hammersbald.backup(dest_name)
+    }
+
+    fn rehash_to_target(&mut self, target_bucket_count: usize) -> Result<(), Error> {
+        self.hammersbald.rehash_to_target(target_bucket_count)
+    }
+
+    fn compact_link_file(&mut self) -> Result<LinkCompactionStats, Error> {
+        self.hammersbald.compact_link_file()
+    }
+
+    fn compact_data_file(&mut self) -> Result<u64, Error> {
+        self.hammersbald.compact_data_file()
+    }
+
+    fn compact(&mut self) -> Result<CompactionReport, Error> {
+        self.hammersbald.compact()
+    }
+
+    fn clone_to_transient(&self) -> Result<Box<dyn HammersbaldAPI>, Error> {
+        self.hammersbald.clone_to_transient()
+    }
+
+    fn try_clone(&mut self) -> Result<Box<dyn HammersbaldAPI>, Error> {
+        self.hammersbald.try_clone()
+    }
+
+    // bitcoin's Transaction is imported in this module
+    fn transaction(&mut self) -> ::Transaction {
+        self.hammersbald.transaction()
+    }
 }
 
 #[cfg(test)]
@@ -289,4 +789,215 @@ mod test {
             panic!("can not find genesis block");
         }
     }
+
+    #[test]
+    pub fn utxo_test() {
+        let mut bdb = BitcoinAdaptor::new(transient(1).unwrap());
+
+        let genesis = genesis_block(Network::Bitcoin);
+        bdb.put_block_outputs(&genesis).unwrap();
+        bdb.batch().unwrap();
+
+        let coinbase = &genesis.txdata[0];
+        let utxos = bdb.iter_unspent_outputs().collect::<Vec<_>>();
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(utxos[0].0, OutPoint::new(coinbase.txid(), 0));
+        assert_eq!(utxos[0].1, coinbase.output[0]);
+        assert_eq!(bdb.count_utxos().unwrap(), 1);
+
+        bdb.forget_unspent_output(&OutPoint::new(coinbase.txid(), 0)).unwrap();
+        assert_eq!(bdb.count_utxos().unwrap(), 0);
+        assert!(bdb.get_unspent_output(&OutPoint::new(coinbase.txid(), 0)).unwrap().is_none());
+    }
+
+    #[test]
+    pub fn chain_consistency_test() {
+        let mut bdb = BitcoinAdaptor::new(transient(1).unwrap());
+
+        let mut header = genesis_block(Network::Bitcoin).header;
+        let mut hashes = Vec::new();
+        for _ in 0 .. 5 {
+            bdb.put_object_by_hash::<BlockHash, BlockHeader>(&header).unwrap();
+            hashes.push(header.block_hash());
+            header.prev_blockhash = header.block_hash();
+            header.nonce += 1;
+        }
+        // header pointing to a block that is not stored
+        header.prev_blockhash = BlockHash::hash(b"missing");
+        bdb.put_object_by_hash::<BlockHash, BlockHeader>(&header).unwrap();
+        let broken = header.block_hash();
+        header.prev_blockhash = broken;
+        bdb.put_object_by_hash::<BlockHash, BlockHeader>(&header).unwrap();
+        bdb.batch().unwrap();
+
+        assert_eq!(bdb.verify_chain_consistency(&hashes[4]).unwrap(),
+                   ChainConsistencyResult { chain_length: 5, is_consistent: true, first_break: None });
+        assert_eq!(bdb.verify_chain_consistency(&header.block_hash()).unwrap(),
+                   ChainConsistencyResult { chain_length: 2, is_consistent: false, first_break: Some(broken) });
+    }
+
+    #[test]
+    pub fn header_chain_test() {
+        let mut bdb = BitcoinAdaptor::new(transient(1).unwrap());
+
+        let mut header = genesis_block(Network::Bitcoin).header;
+        let mut headers = Vec::new();
+        for _ in 0 .. 10 {
+            headers.push(header);
+            header.prev_blockhash = header.block_hash();
+            header.nonce += 1;
+        }
+        let prefs = bdb.put_header_chain(&headers[.. 5]).unwrap();
+        // continues the stored headers
+        let more = bdb.put_header_chain(&headers[5 ..]).unwrap();
+        bdb.batch().unwrap();
+        for (pref, header) in prefs.iter().chain(more.iter()).zip(headers.iter()) {
+            assert_eq!(bdb.get_object_by_hash::<_, BlockHeader>(header.block_hash()).unwrap(), Some((*pref, *header)));
+        }
+
+        // a gap in the chain
+        let mut gap = vec!(header);
+        header.prev_blockhash = BlockHash::hash(b"missing");
+        gap.push(header);
+        assert!(bdb.put_header_chain(&gap).is_err());
+        assert!(bdb.get_object_by_hash::<_, BlockHeader>(gap[0].block_hash()).unwrap().is_none());
+        // not following a stored header
+        assert!(bdb.put_header_chain(&gap[1 ..]).is_err());
+    }
+
+    #[test]
+    pub fn script_index_test() {
+        let mut bdb = BitcoinAdaptor::new(transient(1).unwrap());
+
+        let script = Script::new_p2pkh(&bitcoin::PubkeyHash::hash(b"payee"));
+        let mut txids = Vec::new();
+        for lock_time in 0 .. 3 {
+            let tx = Transaction {
+                version: 1,
+                lock_time,
+                input: vec!(),
+                output: vec!(TxOut { value: 1000, script_pubkey: script.clone() })
+            };
+            let txid = tx.txid();
+            bdb.index_output_scripts(&tx, &txid).unwrap();
+            txids.push(txid);
+        }
+        bdb.batch().unwrap();
+
+        assert_eq!(bdb.get_txids_for_script(&script).unwrap(), txids);
+        let other = Script::new_p2pkh(&bitcoin::PubkeyHash::hash(b"other"));
+        assert!(bdb.get_txids_for_script(&other).unwrap().is_empty());
+    }
+
+    #[test]
+    pub fn long_script_index_test() {
+        let mut bdb = BitcoinAdaptor::new(transient(1).unwrap());
+
+        let script = Script::new_p2pkh(&bitcoin::PubkeyHash::hash(b"busy payee"));
+        let mut txids = Vec::new();
+        for lock_time in 0 .. 1000 {
+            let tx = Transaction {
+                version: 1,
+                lock_time,
+                input: vec!(),
+                output: vec!(TxOut { value: 1000, script_pubkey: script.clone() }, TxOut { value: 1, script_pubkey: script.clone() })
+            };
+            let txid = tx.txid();
+            bdb.index_output_scripts(&tx, &txid).unwrap();
+            txids.push(txid);
+        }
+        // indexed again
+        bdb.index_output_scripts(&Transaction { version: 1, lock_time: 0, input: vec!(),
+            output: vec!(TxOut { value: 1000, script_pubkey: script.clone() }, TxOut { value: 1, script_pubkey: script.clone() }) }, &txids[0]).unwrap();
+        bdb.batch().unwrap();
+
+        assert_eq!(bdb.get_txids_for_script(&script).unwrap(), txids);
+        // an entry and a head for each transaction, not the whole list
+        assert!(bdb.get_stats().unwrap().data_file_bytes < 1000 * 256);
+    }
+
+    #[test]
+    pub fn address_index_test() {
+        let mut bdb = BitcoinAdaptor::new(transient(1).unwrap());
+
+        let genesis = genesis_block(Network::Bitcoin);
+        let coinbase = &genesis.txdata[0];
+        let txid = coinbase.txid();
+        bdb.index_transaction_addresses(coinbase, &txid).unwrap();
+        bdb.batch().unwrap();
+
+        // the genesis coinbase pays to a public key, known by the address of its hash
+        let address = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa".parse::<Address>().unwrap();
+        assert_eq!(bdb.get_transactions_for_address(&address).unwrap(), vec!(txid));
+        let other = "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy".parse::<Address>().unwrap();
+        assert!(bdb.get_transactions_for_address(&other).unwrap().is_empty());
+
+        // many payments to the address, some with more than one output to it
+        let mut txids = vec!(txid);
+        let before = bdb.get_stats().unwrap().data_file_bytes;
+        for lock_time in 0 .. 1000 {
+            let output = TxOut { value: 1000, script_pubkey: address.script_pubkey() };
+            let tx = Transaction { version: 1, lock_time, input: vec!(), output: vec!(output.clone(), output) };
+            let txid = tx.txid();
+            bdb.index_transaction_addresses(&tx, &txid).unwrap();
+            txids.push(txid);
+        }
+        bdb.batch().unwrap();
+        assert_eq!(bdb.get_transactions_for_address(&address).unwrap(), txids);
+        assert!(bdb.get_stats().unwrap().data_file_bytes - before < 1000 * 256);
+    }
+
+    #[test]
+    pub fn iter_decodable_from_pref_test() {
+        let mut bdb = BitcoinAdaptor::new(transient(1).unwrap());
+
+        for lock_time in 0 .. 200 {
+            let tx = Transaction { version: 1, lock_time, input: vec!(), output: vec!() };
+            bdb.put_object(&tx).unwrap();
+        }
+        bdb.batch().unwrap();
+
+        let mut iter = bdb.iter_decodable::<Transaction>();
+        let first = iter.by_ref().take(100).map(|(_, tx)| tx.lock_time).collect::<Vec<_>>();
+        assert_eq!(first, (0 .. 100).collect::<Vec<_>>());
+        let token = iter.save_token();
+
+        let rest = bdb.iter_decodable_from_pref::<Transaction>(token).map(|(_, tx)| tx.lock_time).collect::<Vec<_>>();
+        assert_eq!(rest, (100 .. 200).collect::<Vec<_>>());
+    }
+
+    #[test]
+    pub fn delete_object_test() {
+        let mut bdb = BitcoinAdaptor::new(transient(1).unwrap());
+
+        let header = genesis_block(Network::Bitcoin).header;
+        bdb.put_object_by_hash::<BlockHash, BlockHeader>(&header).unwrap();
+        bdb.put_keyed(b"key", b"data").unwrap();
+        bdb.batch().unwrap();
+
+        assert!(bdb.delete_object_by_hash(header.block_hash()).unwrap());
+        assert!(bdb.get_object_by_hash::<BlockHash, BlockHeader>(header.block_hash()).unwrap().is_none());
+        assert!(!bdb.may_have_hash(header.block_hash()).unwrap());
+        assert!(!bdb.delete_object_by_hash(header.block_hash()).unwrap());
+
+        assert!(bdb.delete_object_by_key(b"key").unwrap());
+        assert!(!bdb.contains_key(b"key").unwrap());
+        assert!(!bdb.delete_object_by_key(b"key").unwrap());
+    }
+
+    #[test]
+    pub fn compact_with_objects_test() {
+        let mut bdb = BitcoinAdaptor::new(transient(1).unwrap());
+
+        let genesis = genesis_block(Network::Bitcoin);
+        bdb.put_object_by_hash(&genesis.header).unwrap();
+        // objects without key are only accessible by pref, compaction must not remove them
+        let pref = bdb.put_object(&genesis.txdata[0]).unwrap();
+        match bdb.compact_data_file() {
+            Err(Error::InvalidArgument(_)) => {},
+            _ => panic!("compaction should refuse while objects without key are stored")
+        }
+        assert_eq!(bdb.get_object::<Transaction>(pref).unwrap().1, genesis.txdata[0]);
+        assert!(bdb.get_object_by_hash::<_, BlockHeader>(genesis.block_hash()).unwrap().is_some());
+    }
 }
@@ -18,25 +18,25 @@
 //!
 
 use page::{Page, PAGE_SIZE};
-use pagedfile::PagedFile;
+use pagedfile::{PagedFile, FadviseAdvice};
 use pref::PRef;
 use error::Error;
+use stats::{BackupStats, CacheStats};
 
-use lru_cache::LruCache;
-
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use std::cmp::max;
+use std::cmp::{min, max};
 
 pub struct CachedFile {
     file: Box<dyn PagedFile>,
-    cache: Mutex<Cache>
+    cache: Mutex<ClockCache>
 }
 
 impl CachedFile {
     /// create a read cached file with a page cache of given size
     pub fn new(file: Box<dyn PagedFile>, pages: usize) -> Result<CachedFile, Error> {
         let len = file.len()?;
-        Ok(CachedFile{file, cache: Mutex::new(Cache::new(len, pages))})
+        Ok(CachedFile{file, cache: Mutex::new(ClockCache::new(len, pages))})
     }
 }
 
@@ -53,6 +53,19 @@ impl PagedFile for CachedFile {
         Ok(None)
     }
 
+    fn read_shared_page(&self, pref: PRef) -> Result<Option<Arc<Page>>, Error> {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(page) = cache.get_shared(pref) {
+            return Ok(Some(page));
+        }
+        if let Some(page) = self.file.read_page(pref)? {
+            let page = Arc::new(page);
+            cache.cache(pref, page.clone());
+            return Ok(Some(page));
+        }
+        Ok(None)
+    }
+
     fn len(&self) -> Result<u64, Error> {
         self.file.len()
     }
@@ -66,10 +79,47 @@ impl PagedFile for CachedFile {
         self.file.sync()
     }
 
+    fn advise(&self, advice: FadviseAdvice) -> Result<(), Error> {
+        self.file.advise(advice)
+    }
+
+    fn copy_files(&self, dest_name: &str) -> Result<BackupStats, Error> {
+        self.file.copy_files(dest_name)
+    }
+
+    fn copy_to(&self, dest: &mut dyn PagedFile) -> Result<u64, Error> {
+        self.file.copy_to(dest)
+    }
+
+    fn name(&self) -> String {
+        self.file.name()
+    }
+
     fn shutdown(&mut self) {
         self.file.shutdown()
     }
 
+    fn replacement(&self) -> Result<Box<dyn PagedFile>, Error> {
+        self.file.replacement()
+    }
+
+    fn replace_with(&mut self, replacement: Box<dyn PagedFile>) -> Result<(), Error> {
+        self.file.replace_with(replacement)?;
+        // pages cached are those of the replaced file
+        let mut cache = self.cache.lock().unwrap();
+        cache.reset_len(0);
+        cache.reset_len(self.file.len()?);
+        Ok(())
+    }
+
+    fn complete_replacement(&mut self, commit: bool) -> Result<(), Error> {
+        self.file.complete_replacement(commit)?;
+        let mut cache = self.cache.lock().unwrap();
+        cache.reset_len(0);
+        cache.reset_len(self.file.len()?);
+        Ok(())
+    }
+
     fn append_page(&mut self, page: Page) -> Result<(), Error> {
         let mut cache = self.cache.lock().unwrap();
         cache.append(page.clone());
@@ -86,25 +136,95 @@ impl PagedFile for CachedFile {
         self.cache.lock().unwrap().clear();
         self.file.flush()
     }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        let cache = self.cache.lock().unwrap();
+        Some(CacheStats { hits: cache.hits, misses: cache.misses, evictions: cache.evictions,
+            cached_pages: cache.index.len(), capacity: cache.capacity })
+    }
 }
 
 
-pub struct Cache {
-    reads: LruCache<PRef, Arc<Page>>,
-    len: u64
+// a page is evicted after the clock hand passed it this many times without it being read
+const USAGE_MAX: u8 = 3;
+
+struct ClockSlot {
+    pref: PRef,
+    page: Option<Arc<Page>>,
+    // recently used, decremented as the hand passes
+    usage: u8
 }
 
-impl Cache {
-    pub fn new(len: u64, size: usize) -> Cache {
-        Cache { reads: LruCache::new(size), len }
+/// a CLOCK cache, pages read repeatedly survive sequential scans of several times its capacity
+pub struct ClockCache {
+    slots: Vec<ClockSlot>,
+    index: HashMap<PRef, usize>,
+    hand: usize,
+    capacity: usize,
+    len: u64,
+    // counters of cache_stats, updated under the lock of the cache
+    hits: u64,
+    misses: u64,
+    evictions: u64
+}
+
+impl ClockCache {
+    pub fn new(len: u64, size: usize) -> ClockCache {
+        ClockCache { slots: Vec::with_capacity(size), index: HashMap::new(), hand: 0, capacity: max(size, 1), len,
+            hits: 0, misses: 0, evictions: 0 }
     }
 
     pub fn cache(&mut self, pref: PRef, page: Arc<Page>) {
-        self.reads.insert(pref, page);
+        if let Some(n) = self.index.get(&pref) {
+            let slot = &mut self.slots[*n];
+            slot.page = Some(page);
+            slot.usage = min(slot.usage + 1, USAGE_MAX);
+            return;
+        }
+        let n = if self.slots.len() < self.capacity {
+            self.slots.push(ClockSlot { pref, page: None, usage: 0 });
+            self.slots.len() - 1
+        } else {
+            self.victim()
+        };
+        self.slots[n] = ClockSlot { pref, page: Some(page), usage: 0 };
+        self.index.insert(pref, n);
+    }
+
+    // find a free slot, evicting the first unused page under the hand
+    fn victim(&mut self) -> usize {
+        loop {
+            let n = self.hand;
+            self.hand = (self.hand + 1) % self.slots.len();
+            let slot = &mut self.slots[n];
+            if slot.page.is_none() {
+                return n;
+            }
+            if slot.usage > 0 {
+                slot.usage -= 1;
+                continue;
+            }
+            let evicted = slot.pref;
+            slot.page = None;
+            self.index.remove(&evicted);
+            self.evictions += 1;
+            return n;
+        }
+    }
+
+    fn remove_where<P: Fn(PRef) -> bool>(&mut self, predicate: P) {
+        for slot in self.slots.iter_mut() {
+            if slot.page.is_some() && predicate(slot.pref) {
+                slot.page = None;
+                self.index.remove(&slot.pref);
+            }
+        }
     }
 
     pub fn clear(&mut self) {
-        self.reads.clear();
+        self.slots.clear();
+        self.index.clear();
+        self.hand = 0;
     }
 
     pub fn append(&mut self, page: Page) ->u64 {
@@ -124,27 +244,91 @@ impl Cache {
     }
 
     pub fn get(&mut self, pref: PRef) -> Option<Page> {
-        use std::ops::Deref;
-        if let Some(content) = self.reads.get_mut(&pref) {
-            return Some(content.clone().deref().clone())
+        self.get_shared(pref).map(|page| page.as_ref().clone())
+    }
+
+    pub fn get_shared(&mut self, pref: PRef) -> Option<Arc<Page>> {
+        if let Some(n) = self.index.get(&pref) {
+            let slot = &mut self.slots[*n];
+            slot.usage = min(slot.usage + 1, USAGE_MAX);
+            self.hits += 1;
+            return slot.page.clone();
         }
+        self.misses += 1;
         None
     }
 
     pub fn reset_len(&mut self, len: u64) {
         self.len = len;
-        let to_delete: Vec<_> = self.reads.iter().filter_map(
-            |(o, _)| {
-                let l = o.as_u64();
-                if l >= len {
-                    Some(l)
-                }
-                else {
-                    None
-                }
-            }).collect();
-        for o in to_delete {
-            self.reads.remove(&PRef::from(o));
+        self.remove_where(|pref| pref.as_u64() >= len);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use transient::Transient;
+
+    use super::*;
+    use rand::{thread_rng, RngCore};
+
+    #[test]
+    fn test_evictions() {
+        let mut file = Transient::new(true);
+        for _ in 0 .. 100 {
+            file.append_page(Page::new()).unwrap();
+        }
+        let cached = CachedFile::new(Box::new(file), 3).unwrap();
+        for i in 0 .. 100 {
+            cached.read_page(PRef::from(i * PAGE_SIZE as u64)).unwrap().unwrap();
+        }
+        assert_eq!(cached.cache_stats().unwrap().evictions, 100 - 3);
+    }
+
+    #[test]
+    fn test_scan_keeps_hot_page() {
+        let mut file = Transient::new(true);
+        for _ in 0 .. 101 {
+            file.append_page(Page::new()).unwrap();
+        }
+        let cached = CachedFile::new(Box::new(file), 30).unwrap();
+        let hot = PRef::from(0);
+        // a miss, then hits up to the maximum usage
+        for _ in 0 .. USAGE_MAX + 1 {
+            cached.read_page(hot).unwrap().unwrap();
+        }
+        // a scan of more than twice the capacity
+        for i in 1 .. 101 {
+            cached.read_page(PRef::from(i * PAGE_SIZE as u64)).unwrap().unwrap();
+        }
+        assert_eq!(cached.cache_stats().unwrap().evictions, 100 - 29);
+        assert!(cached.cache.lock().unwrap().get(hot).is_some());
+    }
+
+    #[test]
+    fn test_statistics() {
+        let mut file = Transient::new(true);
+        for _ in 0 .. 1000 {
+            file.append_page(Page::new()).unwrap();
+        }
+        let cached = CachedFile::new(Box::new(file), 100).unwrap();
+        let mut rng = thread_rng();
+        // a working set of 50 pages
+        for _ in 0 .. 10000 {
+            let n = rng.next_u32() as u64 % 50;
+            cached.read_page(PRef::from(n * PAGE_SIZE as u64)).unwrap().unwrap();
+        }
+        let stats = cached.cache_stats().unwrap();
+        assert_eq!(stats.hits + stats.misses, 10000);
+        assert!(stats.hit_rate() > 0.5);
+        assert_eq!(stats.evictions, 0);
+        assert_eq!((stats.cached_pages, stats.capacity), (50, 100));
+
+        for n in 0 .. 1000 {
+            cached.read_page(PRef::from(n * PAGE_SIZE as u64)).unwrap().unwrap();
         }
+        let stats = cached.cache_stats().unwrap();
+        assert!(stats.evictions >= 900);
+        assert_eq!(stats.cached_pages, 100);
+        assert_eq!(CacheStats::default().hit_rate(), 0.0);
     }
 }
@@ -19,16 +19,31 @@
 //!
 
 use page::PAGE_SIZE;
-use pagedfile::{PagedFile, PagedFileAppender};
-use format::{Envelope, Payload, Data, IndexedData, Link};
+use pagedfile::{PagedFile, PagedFileAppender, PagedFileIterator};
+#[cfg(feature = "fadvise")]
+use pagedfile::FadviseAdvice;
+use format::{Envelope, Payload, Data, DataWithRefs, IndexedData, Link};
+#[cfg(feature = "compression")]
+use format::COMPRESSION_LZ4;
+#[cfg(any(feature = "compression", test))]
+use format::CompressedData;
 use error::Error;
 use pref::PRef;
+use stats::{BackupStats, CacheStats, TypeCounts, TypeBytes};
 
 use byteorder::{ByteOrder, BigEndian};
 
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+
 /// file storing indexed and referred data
 pub struct DataFile {
-    appender: PagedFileAppender
+    appender: PagedFileAppender,
+    // envelopes start at multiples of this
+    alignment: u64,
+    // indexed data without referred data is compressed if that makes it at least this ratio smaller
+    #[cfg(feature = "compression")]
+    min_compression_gain: Option<f32>
 }
 
 impl DataFile {
@@ -36,15 +51,38 @@ impl DataFile {
     pub fn new(file: Box<dyn PagedFile>) -> Result<DataFile, Error> {
         let len = file.len()?;
         if len % PAGE_SIZE as u64 != 0 {
-            return Err(Error::Corrupted("data file does not end at page boundary".to_string()));
+            return Err(Error::corrupted("data file does not end at page boundary"));
         }
-        if len >= PAGE_SIZE as u64 {
-            return Ok(DataFile{appender: PagedFileAppender::new(file, PRef::from(len))});
+        // reads by pref are random, iterators advise sequential reads until they end
+        #[cfg(feature = "fadvise")]
+        file.advise(FadviseAdvice::Random).ok();
+        let appender = if len >= PAGE_SIZE as u64 {
+            PagedFileAppender::new(file, PRef::from(len))
         }
         else {
-            let appender = PagedFileAppender::new(file, PRef::from(0));
-            return Ok(DataFile{appender})
+            PagedFileAppender::new(file, PRef::from(0))
+        };
+        Ok(DataFile{appender, alignment: 1, #[cfg(feature = "compression")] min_compression_gain: None})
+    }
+
+    /// compress indexed data without referred data appended with append_data
+    /// if that makes it at least min_gain_ratio smaller, None stores it as is
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self, min_gain_ratio: Option<f32>) -> DataFile {
+        self.min_compression_gain = min_gain_ratio;
+        self
+    }
+
+    /// create new file where all envelopes start at a multiple of sector_size, as needed for O_DIRECT
+    /// sector_size must be a power of two of at least 512 that divides the page size
+    #[allow(unused)]
+    pub fn new_sector_aligned(file: Box<dyn PagedFile>, sector_size: usize) -> Result<DataFile, Error> {
+        if sector_size < 512 || !sector_size.is_power_of_two() || PAGE_SIZE % sector_size != 0 {
+            return Err(Error::InvalidArgument(format!("sector size {} is not a power of two of at least 512 that divides page size", sector_size)));
         }
+        let mut data_file = Self::new(file)?;
+        data_file.alignment = sector_size as u64;
+        Ok(data_file)
     }
 
     /// return an iterator of all payloads
@@ -52,51 +90,196 @@ impl DataFile {
         EnvelopeIterator::new(&self.appender)
     }
 
+    /// return an iterator of referred payloads whose pref is in live_prefs
+    #[allow(unused)]
+    pub fn iter_reachable<'a>(&'a self, live_prefs: &'a HashSet<PRef>) -> ReachableIterator<'a> {
+        ReachableIterator{ envelopes: self.envelopes(), live_prefs }
+    }
+
+    /// return a breadth first iterator of the DAG stored with put_referred_dag at root
+    pub fn dag<'a>(&'a self, root: PRef) -> DagIterator<'a> {
+        DagIterator::new(self, root)
+    }
+
+    /// return an iterator of payloads starting at start, to scan only what was appended since
+    pub fn iter_from<'f>(&'f self, start: PRef) -> EnvelopeIterator<'f> {
+        EnvelopeIterator::new_from(&self.appender, start)
+    }
+
+    /// return an iterator of payloads starting at from and ending before to
+    pub fn iter_range<'f>(&'f self, from: PRef, to: PRef) -> EnvelopeIterator<'f> {
+        EnvelopeIterator::new_range(&self.appender, from, to)
+    }
+
+    /// positions of envelopes splitting the file into at most n parts of similar length, the first is 0
+    /// only the lengths of envelopes are read
+    #[cfg(feature = "parallel_reads")]
+    pub fn segment_starts(&self, n: usize) -> Result<Vec<PRef>, Error> {
+        let end = self.appender.position().as_u64();
+        let step = (end / n.max(1) as u64).max(1);
+        let mut starts = vec!(PRef::from(0));
+        let mut pos = 0;
+        let mut next = step;
+        while pos < end {
+            let mut len = [0u8;3];
+            self.appender.read(PRef::from(pos), &mut len, 3)?;
+            let length = BigEndian::read_u24(&len) as u64;
+            if length == 0 {
+                break;
+            }
+            pos += 3 + length;
+            if pos >= next && pos < end {
+                starts.push(PRef::from(pos));
+                next = pos + step;
+            }
+        }
+        Ok(starts)
+    }
+
+    /// position the next payload is appended at
+    pub fn position(&self) -> PRef {
+        self.appender.position()
+    }
+
+    /// iterate the pages of the file, the last page is only complete after flush
+    pub fn page_iter(&self) -> PagedFileIterator {
+        PagedFileIterator::new(&self.appender, PRef::from(0))
+    }
+
+    /// number of entries by payload type, alignment padding is not counted
+    pub fn count_by_type(&self) -> TypeCounts {
+        let mut counts = TypeCounts::default();
+        for (_, envelope) in self.envelopes() {
+            match envelope.payload().first() {
+                Some(0) => counts.indexed += 1,
+                Some(1) if !is_padding(envelope.payload()) => counts.referred += 1,
+                Some(4) => counts.referred += 1,
+                Some(2) => counts.links += 1,
+                _ => {}
+            }
+        }
+        counts
+    }
+
+    /// bytes of entries by payload type, alignment padding is not counted
+    pub fn bytes_by_type(&self) -> TypeBytes {
+        let mut bytes = TypeBytes::default();
+        for (_, envelope) in self.envelopes() {
+            let len = envelope.payload().len() as u64 + 3;
+            match envelope.payload().first() {
+                Some(0) => bytes.indexed += len,
+                Some(1) if !is_padding(envelope.payload()) => bytes.referred += len,
+                Some(4) => bytes.referred += len,
+                Some(2) => bytes.links += len,
+                _ => {}
+            }
+        }
+        bytes
+    }
+
+    /// copy the underlying files to files named dest_name
+    pub fn copy_files(&self, dest_name: &str) -> Result<BackupStats, Error> {
+        self.appender.copy_files(dest_name)
+    }
+
+    /// name of the underlying file for error reports
+    pub fn name(&self) -> String {
+        self.appender.name()
+    }
+
     /// shutdown
     pub fn shutdown(&mut self) {
         self.appender.shutdown()
     }
 
     /// get a stored content at pref
-    pub fn get_envelope(&self, mut pref: PRef) -> Result<Envelope, Error> {
+    pub fn get_envelope(&self, pref: PRef) -> Result<Envelope, Error> {
+        Ok(Envelope::deseralize(self.read_envelope_raw(pref)?.into_owned()))
+    }
+
+    /// get the payload of a stored content at pref
+    /// borrowed if the content is not yet written to the file and not compressed
+    pub fn read_envelope_raw<'a>(&'a self, pref: PRef) -> Result<Cow<'a, [u8]>, Error> {
+        let payload = self.stored_payload(pref)?;
+        if let Payload::Compressed(compressed) = Payload::deserialize(&payload)? {
+            return Ok(Cow::Owned(compressed.decompress()?));
+        }
+        Ok(payload)
+    }
+
+    // the payload at pref as stored, compressed or not
+    fn stored_payload<'a>(&'a self, mut pref: PRef) -> Result<Cow<'a, [u8]>, Error> {
         let mut len = [0u8;3];
         pref = self.appender.read(pref, &mut len, 3)?;
         let blen = BigEndian::read_u24(&len) as usize;
-        if blen >= PAGE_SIZE {
-            let mut buf = vec!(0u8; blen);
-            self.appender.read(pref, &mut buf, blen)?;
-            Ok(Envelope::deseralize(buf))
+        self.appender.read_slice(pref, blen)
+    }
+
+    /// get the key of indexed data at pref without reading the data
+    /// returns None if pref is not indexed data
+    pub fn get_key(&self, pref: PRef) -> Result<Option<Vec<u8>>, Error> {
+        // envelope length, payload type and key length
+        let mut head = [0u8;6];
+        let pos = self.appender.read(pref, &mut head, 6)?;
+        if head[3] == 3 {
+            if let Payload::Indexed(indexed) = Payload::deserialize(&self.read_envelope_raw(pref)?)? {
+                return Ok(Some(indexed.key.to_vec()));
+            }
+            return Ok(None);
         }
-        else {
-            let mut buf = [0u8;PAGE_SIZE];
-            self.appender.read(pref, &mut buf, blen)?;
-            Ok(Envelope::deseralize(buf[0..blen].to_vec()))
+        if head[3] != 0 {
+            return Ok(None);
+        }
+        let key_len = BigEndian::read_u16(&head[4..6]) as usize;
+        if BigEndian::read_u24(&head[0..3]) < 3 + key_len as u32 {
+            return Err(Error::corrupted_at("key is longer than its envelope", &self.appender.name(), pref));
         }
+        let mut key = vec!(0u8; key_len);
+        self.appender.read(pos, &mut key, key_len)?;
+        Ok(Some(key))
     }
 
     /// append link
     pub fn append_link(&mut self, link: Link) -> Result<PRef, Error> {
         let mut payload = vec!();
         Payload::Link(link).serialize(&mut payload);
-        let envelope = Envelope::new(payload.as_slice());
-        let mut store = vec!();
-        envelope.serialize(&mut store);
-        let me = self.appender.position();
-        self.appender.append(store.as_slice())?;
-        Ok(me)
+        self.pad_to(self.alignment)?;
+        self.append_payload(payload.as_slice())
     }
 
-    /// append indexed data
-    pub fn append_data(&mut self, key: &[u8], data: &[u8]) -> Result<PRef, Error> {
-        let indexed = IndexedData::new(key, Data::new(data));
+    /// append indexed data with persistent references to other data
+    pub fn append_data(&mut self, key: &[u8], data: &[u8], referred: &[PRef]) -> Result<PRef, Error> {
+        #[cfg(feature = "compression")]
+        {
+            if let (Some(min_gain_ratio), true) = (self.min_compression_gain, referred.is_empty()) {
+                return self.append_data_compressed(key, data, min_gain_ratio);
+            }
+        }
+        if referred.len() > u16::max_value() as usize {
+            return Err(Error::InvalidArgument(format!("too many referred data {}", referred.len())));
+        }
+        let indexed = IndexedData::new(key, Data::new(data), referred.to_vec());
         let mut payload = vec!();
         Payload::Indexed(indexed).serialize(&mut payload);
-        let envelope = Envelope::new(payload.as_slice());
-        let mut store = vec!();
-        envelope.serialize(&mut store);
-        let me = self.appender.position();
-        self.appender.append(store.as_slice())?;
-        Ok(me)
+        self.pad_to(self.alignment)?;
+        self.append_payload(payload.as_slice())
+    }
+
+    /// append indexed data compressed if that makes it at least min_gain_ratio smaller
+    /// readers see the uncompressed data
+    #[cfg(feature = "compression")]
+    pub fn append_data_compressed(&mut self, key: &[u8], data: &[u8], min_gain_ratio: f32) -> Result<PRef, Error> {
+        let indexed = IndexedData::new(key, Data::new(data), vec!());
+        let mut payload = vec!();
+        Payload::Indexed(indexed).serialize(&mut payload);
+        let compressed = CompressedData::compress(payload.as_slice());
+        if (compressed.len() as f32) <= payload.len() as f32 * (1.0 - min_gain_ratio) {
+            let uncompressed_len = payload.len();
+            payload.clear();
+            Payload::Compressed(CompressedData::new(COMPRESSION_LZ4, uncompressed_len, compressed.as_slice())).serialize(&mut payload);
+        }
+        self.pad_to(self.alignment)?;
+        self.append_payload(payload.as_slice())
     }
 
     /// append referred data
@@ -104,7 +287,140 @@ impl DataFile {
         let data = Data::new(data);
         let mut payload = vec!();
         Payload::Referred(data).serialize(&mut payload);
-        let envelope = Envelope::new(payload.as_slice());
+        self.pad_to(self.alignment)?;
+        self.append_payload(payload.as_slice())
+    }
+
+    /// append referred data with persistent references to other data, a node DagIterator follows
+    pub fn append_referred_with_refs(&mut self, data: &[u8], refs: &[PRef]) -> Result<PRef, Error> {
+        if refs.len() > u16::max_value() as usize {
+            return Err(Error::InvalidArgument(format!("too many refs {}", refs.len())));
+        }
+        let node = DataWithRefs::new(Data::new(data), refs.to_vec());
+        let mut payload = vec!();
+        Payload::ReferredWithRefs(node).serialize(&mut payload);
+        self.pad_to(self.alignment)?;
+        self.append_payload(payload.as_slice())
+    }
+
+    /// append referred data starting at a multiple of align, which is one of 1, 2, 4, 8 or 16
+    #[allow(unused)]
+    pub fn append_referred_aligned(&mut self, data: &[u8], align: usize) -> Result<PRef, Error> {
+        match align {
+            1 | 2 | 4 | 8 | 16 => {},
+            _ => return Err(Error::InvalidArgument(format!("invalid alignment {}", align)))
+        }
+        let data = Data::new(data);
+        let mut payload = vec!();
+        Payload::Referred(data).serialize(&mut payload);
+        self.pad_to(self.alignment.max(align as u64))?;
+        self.append_payload(payload.as_slice())
+    }
+
+    /// write the envelopes at live prefs and the referred data they list, in their order, to a replacement of this file
+    /// referred data is also kept if a node kept refers to it, the refs of nodes are moved along
+    /// refuses if there is referred data no indexed data or node lists, as a pref to it might be held elsewhere
+    /// returns the replacement to swap in with replace_with and the new pref of each envelope kept
+    pub fn compact(&mut self, live: &HashSet<PRef>) -> Result<(DataFile, HashMap<PRef, PRef>), Error> {
+        self.flush()?;
+        let mut listed = HashSet::new();
+        let mut listed_by_any = HashSet::new();
+        let mut referred = Vec::new();
+        let mut nodes = HashMap::new();
+        for (pref, envelope) in self.envelopes() {
+            match Payload::deserialize(envelope.payload())? {
+                Payload::Indexed(indexed) => {
+                    if live.contains(&pref) {
+                        listed.extend(indexed.referred.iter().cloned());
+                    }
+                    listed_by_any.extend(indexed.referred);
+                },
+                Payload::Referred(_) if !is_padding(envelope.payload()) => referred.push(pref),
+                Payload::ReferredWithRefs(node) => {
+                    listed_by_any.extend(node.refs.iter().cloned());
+                    nodes.insert(pref, node.refs);
+                    referred.push(pref);
+                },
+                _ => {}
+            }
+        }
+        close_over_refs(&mut listed, &nodes);
+        if let Some(pref) = referred.iter().find(|pref| !listed_by_any.contains(pref)) {
+            return Err(Error::InvalidArgument(format!("referred data at {} is not listed by indexed data or a node, compaction would remove it", pref)));
+        }
+        let mut replacement = self.replacement()?;
+        match self.copy_kept(&mut replacement, live, &listed) {
+            Ok(remapping) => Ok((replacement, remapping)),
+            Err(e) => {
+                drop(replacement);
+                self.complete_replacement(false)?;
+                Err(e)
+            }
+        }
+    }
+
+    // append kept envelopes to the replacement, lists of referred data point to their copies
+    fn copy_kept(&self, replacement: &mut DataFile, live: &HashSet<PRef>, listed: &HashSet<PRef>) -> Result<HashMap<PRef, PRef>, Error> {
+        let mut remapping = HashMap::with_capacity(live.len() + listed.len());
+        for (pref, envelope) in self.envelopes() {
+            if !live.contains(&pref) && !listed.contains(&pref) {
+                continue;
+            }
+            let mut payload = vec!();
+            match Payload::deserialize(envelope.payload())? {
+                Payload::Indexed(ref indexed) if !indexed.referred.is_empty() => {
+                    let mut referred = Vec::with_capacity(indexed.referred.len());
+                    for r in &indexed.referred {
+                        referred.push(*remapping.get(r).ok_or_else(|| Error::corrupted_at("referred data is not stored before data listing it", &self.name(), pref))?);
+                    }
+                    Payload::Indexed(IndexedData::new(indexed.key, Data::new(indexed.data.data), referred)).serialize(&mut payload);
+                },
+                Payload::ReferredWithRefs(ref node) if !node.refs.is_empty() => {
+                    let mut refs = Vec::with_capacity(node.refs.len());
+                    for r in &node.refs {
+                        refs.push(*remapping.get(r).ok_or_else(|| Error::corrupted_at("referred data is not stored before data referring to it", &self.name(), pref))?);
+                    }
+                    Payload::ReferredWithRefs(DataWithRefs::new(Data::new(node.data.data), refs)).serialize(&mut payload);
+                },
+                _ => payload = self.stored_payload(pref)?.into_owned()
+            }
+            replacement.pad_to(self.alignment)?;
+            let moved = replacement.append_payload(payload.as_slice())?;
+            remapping.insert(pref, moved);
+        }
+        // iteration skips what it can not decompress
+        if let Some(pref) = live.iter().find(|pref| !remapping.contains_key(pref)) {
+            return Err(Error::corrupted_at("can not read data to keep", &self.name(), *pref));
+        }
+        replacement.flush()?;
+        Ok(remapping)
+    }
+
+    /// a new empty data file with the alignment of this one, to be swapped in with replace_with
+    pub fn replacement(&self) -> Result<DataFile, Error> {
+        Ok(DataFile{appender: PagedFileAppender::new(self.appender.replacement()?, PRef::from(0)), alignment: self.alignment,
+            #[cfg(feature = "compression")] min_compression_gain: self.min_compression_gain})
+    }
+
+    /// take the content of a replacement created with replacement, after flushing and syncing it
+    pub fn replace_with(&mut self, mut replacement: DataFile) -> Result<(), Error> {
+        replacement.flush()?;
+        replacement.sync()?;
+        self.appender.replace_with(Box::new(replacement.appender))
+    }
+
+    /// finish replacing with a replacement left by a crash if commit, remove the replacement otherwise
+    pub fn complete_replacement(&mut self, commit: bool) -> Result<(), Error> {
+        self.appender.complete_replacement(commit)
+    }
+
+    /// truncate file
+    pub fn truncate(&mut self, pref: u64) -> Result<(), Error> {
+        self.appender.truncate(pref)
+    }
+
+    fn append_payload(&mut self, payload: &[u8]) -> Result<PRef, Error> {
+        let envelope = Envelope::new(payload);
         let mut store = vec!();
         envelope.serialize(&mut store);
         let me = self.appender.position();
@@ -112,23 +428,25 @@ impl DataFile {
         Ok(me)
     }
 
-    /// truncate file
-    pub fn truncate(&mut self, pref: u64) -> Result<(), Error> {
-        self.appender.truncate(pref)
+    // pad with referred data until position is a multiple of alignment
+    fn pad_to(&mut self, alignment: u64) -> Result<(), Error> {
+        let pos = self.appender.position().as_u64() % alignment;
+        if pos > 0 {
+            // smallest padding is 8 bytes: envelope length, type, zero data length and a zero byte after the data
+            let mut gap = (alignment - pos) as usize;
+            while gap < 8 {
+                gap += alignment as usize;
+            }
+            let mut payload = vec!(0u8; gap - 3);
+            payload[0] = 1;
+            self.append_payload(payload.as_slice())?;
+        }
+        Ok(())
     }
 
     /// flush buffers
     pub fn flush(&mut self) -> Result<(), Error> {
-        let pos = self.appender.position();
-        if pos.in_page_pos() > 0 {
-            if PAGE_SIZE - pos.in_page_pos() >= 7 {
-                let padding = vec!(0u8; PAGE_SIZE - pos.in_page_pos() - 7);
-                self.append_referred(padding.as_slice())?;
-            } else {
-                let padding = vec!(0u8; 2 * PAGE_SIZE - pos.in_page_pos() - 7);
-                self.append_referred(padding.as_slice())?;
-            }
-        }
+        self.pad_to(PAGE_SIZE as u64)?;
         self.appender.flush()
     }
 
@@ -141,18 +459,67 @@ impl DataFile {
     pub fn len(&self) -> Result<u64, Error> {
         self.appender.len()
     }
+
+    /// counters of the page cache, none if the file is not cached
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.appender.cache_stats()
+    }
+}
+
+/// true if payload is padding: referred data with bytes after its data, which data appended never has
+pub fn is_padding(payload: &[u8]) -> bool {
+    payload.len() > 4 && payload[0] == 1 && BigEndian::read_u24(&payload[1 .. 4]) as usize + 4 < payload.len()
+}
+
+/// add to listed the references of the nodes listed, and of the nodes those refer to
+pub fn close_over_refs(listed: &mut HashSet<PRef>, nodes: &HashMap<PRef, Vec<PRef>>) {
+    let mut todo = listed.iter().cloned().collect::<Vec<_>>();
+    while let Some(pref) = todo.pop() {
+        if let Some(refs) = nodes.get(&pref) {
+            for r in refs {
+                if listed.insert(*r) {
+                    todo.push(*r);
+                }
+            }
+        }
+    }
 }
 
 /// Iterate data file content
 pub struct EnvelopeIterator<'f> {
     file: &'f PagedFileAppender,
-    pos: PRef
+    start: PRef,
+    pos: PRef,
+    fence: PRef
 }
 
 impl<'f> EnvelopeIterator<'f> {
     /// create a new iterator
     pub fn new(file: &'f PagedFileAppender) -> EnvelopeIterator<'f> {
-        EnvelopeIterator {file, pos: PRef::from(0)}
+        Self::new_from(file, PRef::from(0))
+    }
+
+    /// create a new iterator starting at pos
+    pub fn new_from(file: &'f PagedFileAppender, pos: PRef) -> EnvelopeIterator<'f> {
+        Self::new_range(file, pos, PRef::invalid())
+    }
+
+    /// create a new iterator of envelopes starting at pos and before fence
+    pub fn new_range(file: &'f PagedFileAppender, pos: PRef, fence: PRef) -> EnvelopeIterator<'f> {
+        // only a hint, iteration works without it
+        #[cfg(feature = "fadvise")]
+        file.advise(FadviseAdvice::Sequential).ok();
+        EnvelopeIterator {file, start: pos, pos, fence}
+    }
+
+    /// position the iteration started at
+    pub fn starting_pos(&self) -> PRef {
+        self.start
+    }
+
+    /// position of the next envelope
+    pub fn pos(&self) -> PRef {
+        self.pos
     }
 }
 
@@ -160,19 +527,248 @@ impl<'f> Iterator for EnvelopeIterator<'f> {
     type Item = (PRef, Envelope);
 
     fn next(&mut self) -> Option<<Self as Iterator>::Item> {
-        if self.pos.is_valid() {
+        while self.pos.is_valid() && self.pos < self.fence {
             let start = self.pos;
             let mut len = [0u8;3];
-            if let Ok(pos) = self.file.read(start, &mut len, 3) {
-                let length = BigEndian::read_u24(&len) as usize;
-                if length > 0 {
-                    let mut buf = vec!(0u8; length);
-                    self.pos = self.file.read(pos, &mut buf, length).unwrap();
-                    let envelope = Envelope::deseralize(buf);
-                    return Some((start, envelope))
+            let pos = match self.file.read(start, &mut len, 3) {
+                Ok(pos) => pos,
+                Err(_) => break
+            };
+            let length = BigEndian::read_u24(&len) as usize;
+            if length == 0 {
+                break;
+            }
+            let mut buf = vec!(0u8; length);
+            self.pos = self.file.read(pos, &mut buf, length).unwrap();
+            // a payload that can not be decompressed is skipped, as written without the compression feature
+            // or corrupted, the envelopes after it are still iterated
+            if let Ok(envelope) = Envelope::deseralize(buf).decompressed() {
+                return Some((start, envelope))
+            }
+        }
+        // back to the advice of reads by pref
+        #[cfg(feature = "fadvise")]
+        self.file.advise(FadviseAdvice::Random).ok();
+        None
+    }
+}
+
+/// Iterate referred data reachable from live data
+pub struct ReachableIterator<'f> {
+    envelopes: EnvelopeIterator<'f>,
+    live_prefs: &'f HashSet<PRef>
+}
+
+impl<'f> Iterator for ReachableIterator<'f> {
+    type Item = (PRef, Envelope);
+
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        while let Some((pref, envelope)) = self.envelopes.next() {
+            if self.live_prefs.contains(&pref) {
+                match Payload::deserialize(envelope.payload()) {
+                    Ok(Payload::Referred(_)) | Ok(Payload::ReferredWithRefs(_)) => return Some((pref, envelope)),
+                    _ => {}
                 }
             }
         }
         None
     }
 }
+
+/// Iterate referred data of a DAG breadth first, yielding depth, pref and envelope of nodes
+/// a node is stored with append_referred_with_refs, referred data without refs is a leaf
+pub struct DagIterator<'f> {
+    file: &'f DataFile,
+    queue: VecDeque<(usize, PRef)>,
+    seen: HashSet<PRef>
+}
+
+impl<'f> DagIterator<'f> {
+    /// create a new iterator starting at root
+    pub fn new(file: &'f DataFile, root: PRef) -> DagIterator<'f> {
+        let mut queue = VecDeque::new();
+        queue.push_back((0, root));
+        let mut seen = HashSet::new();
+        seen.insert(root);
+        DagIterator { file, queue, seen }
+    }
+}
+
+impl<'f> Iterator for DagIterator<'f> {
+    type Item = (usize, PRef, Envelope);
+
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        while let Some((depth, pref)) = self.queue.pop_front() {
+            if let Ok(envelope) = self.file.get_envelope(pref) {
+                let children = match Payload::deserialize(envelope.payload()) {
+                    Ok(Payload::ReferredWithRefs(node)) => node.refs,
+                    Ok(Payload::Referred(_)) => Vec::new(),
+                    _ => continue
+                };
+                for child in children {
+                    // a node reachable on several paths is visited once, this also breaks cycles
+                    if self.seen.insert(child) {
+                        self.queue.push_back((depth + 1, child));
+                    }
+                }
+                return Some((depth, pref, envelope));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate rand;
+
+    use transient::Transient;
+
+    use super::*;
+    use self::rand::{thread_rng, Rng};
+
+    #[test]
+    fn test_append_referred_aligned() {
+        let mut data_file = DataFile::new(Box::new(Transient::new(true))).unwrap();
+        let first = data_file.append_referred(&[1, 2, 3]).unwrap();
+        let aligned = data_file.append_referred_aligned(&[4, 5, 6], 8).unwrap();
+        assert_eq!(aligned.as_u64() % 8, 0);
+        match data_file.append_referred_aligned(&[7], 3) {
+            Err(Error::InvalidArgument(_)) => {},
+            _ => panic!("alignment 3 should be rejected")
+        }
+        data_file.flush().unwrap();
+
+        let envelopes = data_file.envelopes().collect::<Vec<_>>();
+        assert_eq!(envelopes[0].0, first);
+        // padding is zeros and ends where the aligned data starts
+        let (pad, ref envelope) = envelopes[1];
+        assert_eq!(pad.as_u64() + 3 + envelope.payload().len() as u64, aligned.as_u64());
+        match Payload::deserialize(envelope.payload()).unwrap() {
+            Payload::Referred(padding) => assert!(padding.data.iter().all(|b| *b == 0)),
+            _ => panic!("padding should be referred data")
+        }
+        assert!(is_padding(envelope.payload()));
+        assert!(!is_padding(envelopes[0].1.payload()));
+        assert_eq!(envelopes[2].0, aligned);
+        match Payload::deserialize(data_file.get_envelope(aligned).unwrap().payload()).unwrap() {
+            Payload::Referred(data) => assert_eq!(data.data, &[4, 5, 6]),
+            _ => panic!("unexpected payload")
+        }
+    }
+
+    #[test]
+    fn test_append_referred_aligned_at_any_offset() {
+        for align in &[1usize, 2, 4, 8, 16] {
+            // a referred envelope is 7 bytes and its data, so starts cover every offset within the alignment
+            for start in 0 .. 24 {
+                let mut data_file = DataFile::new(Box::new(Transient::new(true))).unwrap();
+                if start > 0 {
+                    data_file.append_referred(vec!(1u8; start - 1).as_slice()).unwrap();
+                }
+                let aligned = data_file.append_referred_aligned(&[*align as u8], *align).unwrap();
+                assert_eq!(aligned.as_u64() % *align as u64, 0);
+                data_file.flush().unwrap();
+                assert!(data_file.envelopes().any(|(pref, _)| pref == aligned));
+                match Payload::deserialize(data_file.get_envelope(aligned).unwrap().payload()).unwrap() {
+                    Payload::Referred(data) => assert_eq!(data.data, &[*align as u8]),
+                    _ => panic!("unexpected payload")
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_append_data_compressed() {
+        let mut plain = DataFile::new(Box::new(Transient::new(true))).unwrap();
+        let mut compressed = DataFile::new(Box::new(Transient::new(true))).unwrap();
+
+        let mut records = Vec::new();
+        for i in 0 .. 1000u32 {
+            let key = [(i >> 8) as u8, i as u8];
+            let data = if i % 2 == 0 { vec!(0u8; 1000) } else { b"script".iter().cycle().take(900).cloned().collect::<Vec<_>>() };
+            plain.append_data(&key, data.as_slice(), &[]).unwrap();
+            let pref = compressed.append_data_compressed(&key, data.as_slice(), 0.1).unwrap();
+            records.push((pref, key, data));
+        }
+        // random data does not compress, it is stored as is
+        let mut random = vec!(0u8; 1000);
+        thread_rng().fill(random.as_mut_slice());
+        let pref = compressed.append_data_compressed(&[0xff, 0xff], random.as_slice(), 0.1).unwrap();
+        records.push((pref, [0xff, 0xff], random));
+        plain.flush().unwrap();
+        compressed.flush().unwrap();
+        assert!(compressed.len().unwrap() < plain.len().unwrap());
+
+        for (pref, key, data) in &records {
+            assert_eq!(compressed.get_key(*pref).unwrap().unwrap(), key.to_vec());
+            match Payload::deserialize(&compressed.read_envelope_raw(*pref).unwrap()).unwrap() {
+                Payload::Indexed(indexed) => assert_eq!(indexed.data.data, data.as_slice()),
+                _ => panic!("unexpected payload")
+            }
+        }
+        let iterated = compressed.envelopes()
+            .filter(|(_, envelope)| if let Ok(Payload::Indexed(_)) = Payload::deserialize(envelope.payload()) { true } else { false })
+            .count();
+        assert_eq!(iterated, records.len());
+    }
+
+    #[test]
+    fn test_iterate_past_undecompressable() {
+        let mut data_file = DataFile::new(Box::new(Transient::new(true))).unwrap();
+        let first = data_file.append_data(&[1], &[1], &[]).unwrap();
+        let mut payload = vec!();
+        Payload::Compressed(CompressedData::new(0xff, 10, &[1, 2, 3])).serialize(&mut payload);
+        let unknown = data_file.append_payload(payload.as_slice()).unwrap();
+        let last = data_file.append_data(&[2], &[2], &[]).unwrap();
+        data_file.flush().unwrap();
+
+        let iterated = data_file.envelopes().map(|(pref, _)| pref).collect::<Vec<_>>();
+        assert_eq!(&iterated[.. 2], &[first, last]);
+        assert!(!iterated.contains(&unknown));
+
+        let live = [first, unknown, last].iter().cloned().collect::<HashSet<_>>();
+        match data_file.compact(&live) {
+            Err(Error::Corrupted { .. }) => {},
+            _ => panic!("compaction should not drop what it can not read")
+        }
+        assert_eq!(data_file.envelopes().count(), iterated.len());
+    }
+
+    #[test]
+    fn test_sector_aligned() {
+        for sector_size in &[0, 1, 2, 4, 8, 256, 768, 2 * PAGE_SIZE] {
+            match DataFile::new_sector_aligned(Box::new(Transient::new(true)), *sector_size) {
+                Err(Error::InvalidArgument(_)) => {},
+                _ => panic!("sector size {} should be rejected", sector_size)
+            }
+        }
+        let mut data_file = DataFile::new_sector_aligned(Box::new(Transient::new(true)), 512).unwrap();
+
+        let mut rng = thread_rng();
+        let mut prefs = Vec::new();
+        for i in 0 .. 50 {
+            let data = vec!(i as u8; rng.gen_range(1, 2000));
+            let pref = if i % 2 == 0 {
+                data_file.append_referred(data.as_slice()).unwrap()
+            } else {
+                data_file.append_data(&[i as u8], data.as_slice(), &[]).unwrap()
+            };
+            assert_eq!(pref.as_u64() % 512, 0);
+            prefs.push((pref, data));
+        }
+        data_file.flush().unwrap();
+        assert_eq!(data_file.len().unwrap() % PAGE_SIZE as u64, 0);
+
+        let iterated = data_file.envelopes().map(|(pref, _)| pref).collect::<Vec<_>>();
+        for (pref, data) in prefs {
+            assert!(iterated.contains(&pref));
+            match Payload::deserialize(data_file.get_envelope(pref).unwrap().payload()).unwrap() {
+                Payload::Referred(referred) => assert_eq!(referred.data, data.as_slice()),
+                Payload::Indexed(indexed) => assert_eq!(indexed.data.data, data.as_slice()),
+                _ => panic!("unexpected payload")
+            }
+        }
+    }
+}
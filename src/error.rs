@@ -18,14 +18,23 @@
 //!
 //!
 
+use pref::PRef;
+
 use std::{fmt, io, sync};
 
 /// Errors returned by this library
 pub enum Error {
     /// pref is invalid (> 2^48)
     InvalidOffset,
-    /// corrupted data
-    Corrupted(String),
+    /// corrupted data, with the file and position it was found at if known
+    Corrupted {
+        /// what is wrong
+        message: String,
+        /// name of the file
+        file: Option<String>,
+        /// position within the file
+        pref: Option<PRef>
+    },
     /// key too long
     KeyTooLong,
     /// wrapped IO error
@@ -34,11 +43,37 @@ pub enum Error {
     Poisoned(String),
     /// Queue error
     Queue(String),
+    /// db is already opened, possibly by an other process
+    AlreadyOpen,
+    /// write to a db opened read only
+    ReadOnly,
+    /// not enough space on disk, with the available bytes
+    InsufficientSpace(u64),
+    /// key is already in the db
+    AlreadyExists,
+    /// operation did not finish in time
+    Timeout,
+    /// operation is not available within a transaction
+    InTransaction,
+    /// argument of a call is not valid, with what is wrong
+    InvalidArgument(String),
     /// Bitcoin encoding error.
     #[cfg(feature = "bitcoin_support")]
     BitcoinDecode(bitcoin::consensus::encode::Error),
 }
 
+impl Error {
+    /// corrupted data without further context
+    pub fn corrupted<S: Into<String>>(message: S) -> Error {
+        Error::Corrupted { message: message.into(), file: None, pref: None }
+    }
+
+    /// corrupted data found in a file at pref
+    pub fn corrupted_at<S: Into<String>>(message: S, file: &str, pref: PRef) -> Error {
+        Error::Corrupted { message: message.into(), file: Some(file.to_string()), pref: Some(pref) }
+    }
+}
+
 impl std::error::Error for Error {
     fn description(&self) -> &str {
         "description() is deprecated; use Display"
@@ -48,10 +83,17 @@ impl std::error::Error for Error {
         match *self {
             Error::InvalidOffset => None,
             Error::KeyTooLong => None,
-            Error::Corrupted(_) => None,
+            Error::Corrupted{..} => None,
             Error::IO(ref e) => Some(e),
             Error::Poisoned(_) => None,
             Error::Queue(_) => None,
+            Error::AlreadyOpen => None,
+            Error::ReadOnly => None,
+            Error::InsufficientSpace(_) => None,
+            Error::AlreadyExists => None,
+            Error::Timeout => None,
+            Error::InTransaction => None,
+            Error::InvalidArgument(_) => None,
             #[cfg(feature = "bitcoin_support")]
             Error::BitcoinDecode(ref e) => Some(e),
         }
@@ -63,10 +105,26 @@ impl fmt::Display for Error {
         match &self {
             Error::InvalidOffset => write!(f, "invalid pref"),
             Error::KeyTooLong => write!(f, "key too long"),
-            Error::Corrupted(ref s) => write!(f, "corrupted data: {}", s),
+            Error::Corrupted{ref message, ref file, ref pref} => {
+                write!(f, "corrupted data: {}", message)?;
+                if let Some(file) = file {
+                    write!(f, " in file {}", file)?;
+                }
+                if let Some(pref) = pref {
+                    write!(f, " at {}", pref)?;
+                }
+                Ok(())
+            },
             Error::IO(e) => e.fmt(f),
             Error::Poisoned(ref s) => write!(f, "lock poisoned: {}", s),
             Error::Queue(ref s) => write!(f, "queue error {}", s),
+            Error::AlreadyOpen => write!(f, "db is already open"),
+            Error::ReadOnly => write!(f, "db is read only"),
+            Error::InsufficientSpace(available) => write!(f, "insufficient space, {} bytes available", available),
+            Error::AlreadyExists => write!(f, "key already exists"),
+            Error::Timeout => write!(f, "timeout"),
+            Error::InTransaction => write!(f, "not available within a transaction"),
+            Error::InvalidArgument(ref s) => write!(f, "invalid argument: {}", s),
             #[cfg(feature = "bitcoin_support")]
             Error::BitcoinDecode(e) => write!(f, "bitcoin parsing error: {}", e),
         }
@@ -103,9 +161,54 @@ impl<T> From<sync::mpsc::SendError<T>> for Error {
     }
 }
 
+impl From<sync::mpsc::RecvTimeoutError> for Error {
+    fn from(err: sync::mpsc::RecvTimeoutError) -> Error {
+        match err {
+            sync::mpsc::RecvTimeoutError::Timeout => Error::Timeout,
+            sync::mpsc::RecvTimeoutError::Disconnected => Error::Queue(err.to_string())
+        }
+    }
+}
+
 #[cfg(feature = "bitcoin_support")]
 impl From<bitcoin::consensus::encode::Error> for Error {
     fn from(e: bitcoin::consensus::encode::Error) -> Error {
         Error::BitcoinDecode(e)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::error::Error as StdError;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_display() {
+        assert!(Error::AlreadyOpen.to_string().contains("already open"));
+        assert!(Error::ReadOnly.to_string().contains("read only"));
+        assert!(Error::InsufficientSpace(4096).to_string().contains("insufficient space"));
+        assert!(Error::InsufficientSpace(4096).to_string().contains("4096"));
+        assert!(Error::AlreadyExists.to_string().contains("already exists"));
+        assert!(Error::Timeout.to_string().contains("timeout"));
+        assert_eq!(Error::corrupted("bad").to_string(), "corrupted data: bad");
+        assert_eq!(Error::InvalidArgument("bad".to_string()).to_string(), "invalid argument: bad");
+        assert_eq!(Error::corrupted_at("bad", "db.0.tb", PRef::from(4096)).to_string(),
+                   format!("corrupted data: bad in file db.0.tb at {}", PRef::from(4096)));
+        for e in &[Error::AlreadyOpen, Error::ReadOnly, Error::InsufficientSpace(0), Error::AlreadyExists, Error::Timeout] {
+            assert!(e.source().is_none());
+        }
+    }
+
+    #[test]
+    fn test_from_recv_timeout() {
+        let (sender, receiver) = mpsc::channel::<()>();
+        let e: Error = receiver.recv_timeout(Duration::from_millis(1)).unwrap_err().into();
+        assert!(match e { Error::Timeout => true, _ => false });
+        drop(sender);
+        let e: Error = receiver.recv_timeout(Duration::from_millis(1)).unwrap_err().into();
+        assert!(match e { Error::Queue(_) => true, _ => false });
+    }
+}
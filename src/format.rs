@@ -49,6 +49,14 @@ impl Envelope {
     pub fn deseralize(buffer: Vec<u8>) -> Envelope {
         Envelope{buffer}
     }
+
+    /// the envelope with a compressed payload replaced by the payload it compressed
+    pub fn decompressed(self) -> Result<Envelope, Error> {
+        if let Payload::Compressed(compressed) = Payload::deserialize(self.payload())? {
+            return Ok(Envelope{buffer: compressed.decompress()?});
+        }
+        Ok(self)
+    }
 }
 
 /// payloads in the data file
@@ -58,7 +66,11 @@ pub enum Payload<'e> {
     /// data
     Referred(Data<'e>),
     /// hash table extension,
-    Link(Link<'e>)
+    Link(Link<'e>),
+    /// a compressed payload
+    Compressed(CompressedData<'e>),
+    /// data with persistent references to other data
+    ReferredWithRefs(DataWithRefs<'e>)
 }
 
 impl<'e> Payload<'e> {
@@ -76,6 +88,14 @@ impl<'e> Payload<'e> {
             Payload::Link(link) => {
                 result.write_u8(2).unwrap();
                 link.serialize(result);
+            },
+            Payload::Compressed(compressed) => {
+                result.write_u8(3).unwrap();
+                compressed.serialize(result);
+            },
+            Payload::ReferredWithRefs(node) => {
+                result.write_u8(4).unwrap();
+                node.serialize(result);
             }
         }
     }
@@ -83,11 +103,13 @@ impl<'e> Payload<'e> {
     /// deserialize from storage
     pub fn deserialize(slice: &'e [u8]) -> Result<Payload, Error> {
         match slice [0] {
-            0 => Ok(Payload::Indexed(IndexedData::deserialize(&slice[1..]))),
+            0 => Ok(Payload::Indexed(IndexedData::deserialize(&slice[1..])?)),
             1 => Ok(Payload::Referred(Data::deserialize(&slice[1..]))),
             2 => Ok(Payload::Link(Link::deserialize(&slice[1..]))),
+            3 => Ok(Payload::Compressed(CompressedData::deserialize(&slice[1..]))),
+            4 => Ok(Payload::ReferredWithRefs(DataWithRefs::deserialize(&slice[1..])?)),
             // Link and Table are not serialized with a type
-            _ => Err(Error::Corrupted("unknown payload type".to_string()))
+            _ => Err(Error::corrupted("unknown payload type"))
         }
     }
 }
@@ -119,33 +141,156 @@ impl<'e> Data<'e> {
     }
 }
 
+/// data accessible only if its position is known, with persistent references to other data
+pub struct DataWithRefs<'e> {
+    /// data
+    pub data: Data<'e>,
+    /// persistent references to other data
+    pub refs: Vec<PRef>
+}
+
+impl<'e> DataWithRefs<'e> {
+    /// new data with references
+    pub fn new(data: Data<'e>, refs: Vec<PRef>) -> DataWithRefs<'e> {
+        DataWithRefs { data, refs }
+    }
+
+    /// serialize for storage
+    pub fn serialize(&self, result: &mut dyn Write) {
+        self.data.serialize(result);
+        result.write_u16::<BigEndian>(self.refs.len() as u16).unwrap();
+        for pref in &self.refs {
+            result.write_u48::<BigEndian>(pref.as_u64()).unwrap();
+        }
+    }
+
+    /// deserialize from storage
+    pub fn deserialize(slice: &'e [u8]) -> Result<DataWithRefs<'e>, Error> {
+        let data = Data::deserialize(slice);
+        let pos = 3 + data.data.len();
+        if slice.len() < pos + 2 {
+            return Err(Error::corrupted("data with references is truncated"));
+        }
+        let n = BigEndian::read_u16(&slice[pos .. pos+2]) as usize;
+        if slice.len() < pos + 2 + n*6 {
+            return Err(Error::corrupted("data with references is truncated"));
+        }
+        let mut refs = Vec::with_capacity(n);
+        for i in 0 .. n {
+            let p = pos + 2 + i*6;
+            refs.push(read_pref(&slice[p .. p+6])?);
+        }
+        Ok(DataWithRefs{data, refs})
+    }
+}
+
+/// LZ4 compression of a payload
+#[allow(unused)]
+pub const COMPRESSION_LZ4: u8 = 0;
+
+/// a serialized payload compressed
+pub struct CompressedData<'e> {
+    /// compression algorithm, only COMPRESSION_LZ4 is known
+    pub compression_type: u8,
+    /// length of the serialized payload
+    pub uncompressed_len: usize,
+    /// compressed serialized payload
+    pub compressed: &'e [u8]
+}
+
+impl<'e> CompressedData<'e> {
+    /// new compressed data
+    #[allow(unused)]
+    pub fn new(compression_type: u8, uncompressed_len: usize, compressed: &'e [u8]) -> CompressedData<'e> {
+        CompressedData{compression_type, uncompressed_len, compressed}
+    }
+
+    /// compress a serialized payload with LZ4
+    #[cfg(feature = "compression")]
+    pub fn compress(payload: &[u8]) -> Vec<u8> {
+        lz4_flex::block::compress(payload)
+    }
+
+    /// the serialized payload
+    #[cfg(feature = "compression")]
+    pub fn decompress(&self) -> Result<Vec<u8>, Error> {
+        if self.compression_type != COMPRESSION_LZ4 {
+            return Err(Error::corrupted(format!("unknown compression type {}", self.compression_type)));
+        }
+        let payload = lz4_flex::block::decompress(self.compressed, self.uncompressed_len)
+            .map_err(|e| Error::corrupted(format!("can not decompress payload: {}", e)))?;
+        if payload.len() != self.uncompressed_len {
+            return Err(Error::corrupted(format!("decompressed payload length {} instead of {}", payload.len(), self.uncompressed_len)));
+        }
+        Ok(payload)
+    }
+
+    /// the serialized payload
+    #[cfg(not(feature = "compression"))]
+    pub fn decompress(&self) -> Result<Vec<u8>, Error> {
+        Err(Error::corrupted("compressed payload needs the compression feature"))
+    }
+
+    /// serialize for storage
+    pub fn serialize(&self, result: &mut dyn Write) {
+        result.write_u8(self.compression_type).unwrap();
+        result.write_u24::<BigEndian>(self.uncompressed_len as u32).unwrap();
+        result.write_all(self.compressed).unwrap();
+    }
+
+    /// deserialize from storage
+    pub fn deserialize(slice: &'e [u8]) -> CompressedData<'e> {
+        let compression_type = slice[0];
+        let uncompressed_len = BigEndian::read_u24(&slice[1 .. 4]) as usize;
+        CompressedData{compression_type, uncompressed_len, compressed: &slice[4 ..]}
+    }
+}
+
 /// data accessible with a key
 pub struct IndexedData<'e> {
     /// key
     pub key: &'e [u8],
     /// data
-    pub data: Data<'e>
+    pub data: Data<'e>,
+    /// persistent references to other data
+    pub referred: Vec<PRef>
 }
 
 impl<'e> IndexedData<'e> {
     /// new indexed data
-    pub fn new(key: &'e [u8], data: Data<'e>) -> IndexedData<'e> {
-        IndexedData {key, data}
+    pub fn new(key: &'e [u8], data: Data<'e>, referred: Vec<PRef>) -> IndexedData<'e> {
+        IndexedData {key, data, referred}
     }
 
     /// serialize for storage
+    /// referred prefs are only written if there are any, so data without them is stored as before
     pub fn serialize(&self, result: &mut dyn Write) {
-        result.write_u8(self.key.len() as u8).unwrap();
+        result.write_u16::<BigEndian>(self.key.len() as u16).unwrap();
         result.write(self.key).unwrap();
         self.data.serialize(result);
+        if !self.referred.is_empty() {
+            result.write_u16::<BigEndian>(self.referred.len() as u16).unwrap();
+            for pref in &self.referred {
+                result.write_u48::<BigEndian>(pref.as_u64()).unwrap();
+            }
+        }
     }
 
     /// deserialize from storage
-    pub fn deserialize(slice: &'e [u8]) -> IndexedData<'e> {
-        let key_len = slice[0] as usize;
-        let key = &slice[1 .. key_len+1];
-        let data = Data::deserialize(&slice[key_len+1 ..]);
-        IndexedData{key, data }
+    pub fn deserialize(slice: &'e [u8]) -> Result<IndexedData<'e>, Error> {
+        let key_len = BigEndian::read_u16(&slice[0 .. 2]) as usize;
+        let key = &slice[2 .. key_len+2];
+        let data = Data::deserialize(&slice[key_len+2 ..]);
+        let mut referred = Vec::new();
+        let pos = key_len + 2 + 3 + data.data.len();
+        if slice.len() >= pos + 2 {
+            let n = BigEndian::read_u16(&slice[pos .. pos+2]) as usize;
+            for i in 0 .. n {
+                let p = pos + 2 + i*6;
+                referred.push(read_pref(&slice[p .. p+6])?);
+            }
+        }
+        Ok(IndexedData{key, data, referred })
     }
 }
 
@@ -167,14 +312,14 @@ impl<'e> Link<'e> {
     }
 
     /// get slots
-    pub fn slots(&self) -> Vec<(u32, PRef)> {
+    pub fn slots(&self) -> Result<Vec<(u32, PRef)>, Error> {
         let mut slots = vec!();
         for i in 0 .. self.links.len()/10 {
             let hash = BigEndian::read_u32(&self.links[i*10..i*10+4]);
-            let pref = PRef::from(BigEndian::read_u48(&self.links[i*10+4..i*10+10]));
+            let pref = read_pref(&self.links[i*10+4..i*10+10])?;
             slots.push((hash, pref));
         }
-        slots
+        Ok(slots)
     }
 
     /// serialize for storage
@@ -187,3 +332,27 @@ impl<'e> Link<'e> {
         Link{links: slice}
     }
 }
+
+// a stored pref, that must be valid
+fn read_pref(slice: &[u8]) -> Result<PRef, Error> {
+    let n = BigEndian::read_u48(slice);
+    PRef::try_new(n).map_err(|_| Error::corrupted(format!("stored pref {} is out of range", n)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_invalid_link() {
+        let mut payload = vec!();
+        Payload::Link(Link::deserialize(&Link::from_slots(&[(1, PRef::from(42)), (2, PRef::invalid())]))).serialize(&mut payload);
+        match Payload::deserialize(payload.as_slice()).unwrap() {
+            Payload::Link(link) => match link.slots() {
+                Err(Error::Corrupted{..}) => {},
+                _ => panic!("a link to u48::MAX should be corrupted")
+            },
+            _ => panic!("unexpected payload")
+        }
+    }
+}
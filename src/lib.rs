@@ -29,12 +29,28 @@
 
 extern crate bitcoin_hashes;
 extern crate byteorder;
-extern crate lru_cache;
+extern crate crc32c;
+extern crate fs2;
 extern crate rand;
 
 #[cfg(feature = "bitcoin_support")]
 extern crate bitcoin;
 
+#[cfg(feature = "serde_support")]
+extern crate serde;
+
+#[cfg(all(feature = "fadvise", target_os = "linux"))]
+extern crate nix;
+
+#[cfg(feature = "parallel_reads")]
+extern crate rayon;
+
+#[cfg(feature = "tokio_support")]
+extern crate tokio;
+
+#[cfg(feature = "compression")]
+extern crate lz4_flex;
+
 
 mod page;
 mod pagedfile;
@@ -44,6 +60,8 @@ mod cachedfile;
 mod singlefile;
 mod rolledfile;
 mod asyncfile;
+#[cfg(feature = "tokio_support")]
+mod asyncfile_tokio;
 mod memtable;
 mod format;
 mod datafile;
@@ -52,21 +70,44 @@ mod transient;
 mod persistent;
 mod error;
 mod stats;
+mod accesslog;
+mod readonly;
+mod transaction;
 mod api;
 
 pub use pref::PRef;
 pub use error::Error;
+pub use stats::{Stats, StatsDiff, BackupStats, CompactionReport, LinkCompactionStats, MemTableStats, RecoveryStats, DBStats, TypeCounts, TypeBytes, CacheStats};
+pub use accesslog::{AccessLog, FileAccessLog};
+pub use memtable::BatchPhase;
 pub use api::{
     HammersbaldAPI,
     HammersbaldDataWriter,
     HammersbaldDataReader,
     HammersbaldIterator,
+    ApproximateResult,
+    BucketInspection,
+    FilteredHammersbaldIterator,
+    IndexedIterator,
+    ReferredIterator,
+    PrefixIterator,
     persistent,
-    transient
+    persistent_with_config,
+    open_read_only,
+    transient,
+    repair,
+    rebuild_index,
+    stats_diff
 };
+#[cfg(feature = "tokio_support")]
+pub use api::{AsyncHammersbald, persistent_async};
+pub use persistent::{RepairReport, Config};
+pub use pagedfile::SyncMode;
+pub use datafile::DagIterator;
+pub use transaction::{Transaction, TransactionOp};
 
 #[cfg(feature = "bitcoin_support")]
 mod bitcoin_adaptor;
 
 #[cfg(feature = "bitcoin_support")]
-pub use bitcoin_adaptor::{BitcoinAdaptor, BitcoinObject};
+pub use bitcoin_adaptor::{BitcoinAdaptor, BitcoinObject, ChainConsistencyResult, UtxoIterator};
@@ -18,30 +18,281 @@
 //! The writer of the log file.
 //!
 
-use page::Page;
+use page::{Page, PAGE_SIZE, PAGE_PAYLOAD_SIZE, PAGE_CHECKSUM_POS};
 use pagedfile::{PagedFile, PagedFileIterator};
+use tablefile::TableFile;
 use error::Error;
 use pref::PRef;
+use stats::{BackupStats, RecoveryStats};
 
-use std::collections::HashSet;
+use bitcoin_hashes::siphash24;
+use byteorder::{ByteOrder, BigEndian};
+
+use std::collections::{HashMap, HashSet};
+
+// number of page checksums that fit into a checksum page
+const CHECKSUMS_PER_PAGE: usize = (PAGE_PAYLOAD_SIZE - 2)/8;
+// marks a first page recording replacements of the files instead of the lengths at a batch start
+const REPLACEMENT: u8 = 1;
+// number of moved records that fit into a page, as old and new pref
+const MOVES_PER_PAGE: usize = PAGE_CHECKSUM_POS/12;
+// keys of the checksums of logged pages in the first page, after the file lengths
+const KEYS_POS: usize = 25;
 
 pub struct LogFile {
     file: Box<dyn PagedFile>,
     logged: HashSet<PRef>,
-    source_len: u64
+    source_len: u64,
+    sip0: u64,
+    sip1: u64,
+    checksums: Vec<u64>
 }
 
 impl LogFile {
     pub fn new(rw: Box<dyn PagedFile>) -> LogFile {
-        LogFile { file: rw, logged: HashSet::new(), source_len:0 }
+        LogFile { file: rw, logged: HashSet::new(), source_len:0, sip0: 0, sip1: 0, checksums: Vec::new() }
+    }
+
+    /// set keys of the checksum of logged pages
+    pub fn set_checksum_keys(&mut self, sip0: u64, sip1: u64) {
+        self.sip0 = sip0;
+        self.sip1 = sip1;
+    }
+
+    /// keys of the checksum of logged pages stored in the first page by init
+    /// None if the first page fails its checksum or records replacements
+    pub fn logged_checksum_keys(&self) -> Result<Option<(u64, u64)>, Error> {
+        match self.read_page(PRef::from(0))? {
+            Some(ref first) if first.slice(18, 1)[0] != REPLACEMENT && first.verify_checksum() =>
+                Ok(Some((first.read_u64(KEYS_POS), first.read_u64(KEYS_POS + 8)))),
+            _ => Ok(None)
+        }
+    }
+
+    /// set keys of the checksum of logged pages and start the log anew with them in its first page
+    /// only for a log without logged pages, as at the start of a batch
+    pub fn rekey(&mut self, sip0: u64, sip1: u64) -> Result<(), Error> {
+        if self.len()? > PAGE_SIZE as u64 {
+            return Err(Error::InvalidArgument("checksum keys of a log with logged pages can not change".to_string()));
+        }
+        self.set_checksum_keys(sip0, sip1);
+        if let Some(first) = self.read_page(PRef::from(0))? {
+            self.init(first.read_pref(0).as_u64(), first.read_pref(6).as_u64(), first.read_pref(12).as_u64())?;
+        }
+        Ok(())
+    }
+
+    fn checksum(&self, page: &Page) -> u64 {
+        siphash24::Hash::hash_to_u64_with_keys(self.sip0, self.sip1, page.slice(0, PAGE_SIZE))
+    }
+
+    // a checksum page covers the pages logged since the previous checksum page
+    // it is marked with an invalid pref as no table page has that
+    fn append_checksums(&mut self) -> Result<(), Error> {
+        if !self.checksums.is_empty() {
+            let mut page = Page::new();
+            let mut n = [0u8; 2];
+            BigEndian::write_u16(&mut n, self.checksums.len() as u16);
+            page.write(0, &n);
+            for (i, checksum) in self.checksums.iter().enumerate() {
+                page.write_u64(2 + i*8, *checksum);
+            }
+            page.write_pref(PAGE_PAYLOAD_SIZE, PRef::invalid());
+            self.checksums.clear();
+            self.file.append_page(page)?;
+        }
+        Ok(())
+    }
+
+    /// apply logged pages to the table after checking their checksums
+    /// pages not yet covered by a checksum page are ignored as the table was not modified after them
+    /// truncated_bytes of the result are the bytes of the log from the first page that failed its checksum
+    #[allow(unused)]
+    pub fn replay_into(&self, table: &mut dyn PagedFile) -> Result<RecoveryStats, Error> {
+        self.replay_from(table, PRef::from(PAGE_SIZE as u64))
+    }
+
+    /// apply logged pages at or after log position from, as replay_into does
+    /// pages before from are still checked but assumed to be applied already
+    /// a page failing its checksum was torn while writing the log, the table is only written after
+    /// the log is synced, so it and all pages after it are skipped
+    pub fn replay_from(&self, table: &mut dyn PagedFile, from: PRef) -> Result<RecoveryStats, Error> {
+        self.replay(table, &|pos, _| pos >= from)
+    }
+
+    /// apply all logged pages that pass their checksum to a table, which may be of a copy of the db
+    /// returns the number of pages applied
+    #[allow(unused)]
+    pub fn apply_to(&self, table: &mut TableFile) -> Result<usize, Error> {
+        self.apply_to_with_filter(table, |_| true)
+    }
+
+    /// apply_to only for logged pages whose position in the table satisfies pred
+    #[allow(unused)]
+    pub fn apply_to_with_filter(&self, table: &mut TableFile, pred: impl Fn(PRef) -> bool) -> Result<usize, Error> {
+        Ok(self.replay(table, &|_, pref| pred(pref))?.pages_replayed)
+    }
+
+    // apply verified pages for which apply returns true given their log position and table position
+    fn replay(&self, table: &mut dyn PagedFile, apply: &dyn Fn(PRef, PRef) -> bool) -> Result<RecoveryStats, Error> {
+        let mut verified = Vec::new();
+        let mut pending = Vec::new();
+        let mut torn = None;
+        for (pos, page) in self.iter_with_offsets().skip(1) {
+            if page.pref().is_valid() {
+                pending.push((pos, page));
+            }
+            else {
+                let mut n = [0u8; 2];
+                page.read(0, &mut n);
+                let n = BigEndian::read_u16(&n) as usize;
+                if n != pending.len() {
+                    torn = Some(pending.first().map(|(p, _)| *p).unwrap_or(pos));
+                    break;
+                }
+                if let Some(i) = pending.iter().enumerate().position(|(i, (_, logged))| page.read_u64(2 + i*8) != self.checksum(logged)) {
+                    torn = Some(pending[i].0);
+                    pending.truncate(i);
+                    verified.extend(pending.drain(..));
+                    break;
+                }
+                verified.extend(pending.drain(..));
+            }
+        }
+        let mut stats = RecoveryStats::default();
+        stats.pages_skipped = self.iter_with_offsets().skip(1).filter(|(_, page)| page.pref().is_valid()).count() - verified.len();
+        for (pos, page) in verified {
+            if apply(pos, page.pref()) {
+                table.update_page(page)?;
+                stats.pages_replayed += 1;
+            }
+        }
+        if let Some(torn) = torn {
+            stats.truncated_bytes = self.len()? - torn.as_u64();
+        }
+        Ok(stats)
+    }
+
+    /// check the log without replaying it, returns the number of pages
+    /// the first page must hold valid file lengths, logged pages must be within the logged table length
+    pub fn verify_integrity(&self) -> Result<usize, Error> {
+        let len = self.len()?;
+        if len % PAGE_SIZE as u64 != 0 {
+            return Err(Error::corrupted_at("log ends within a page", &self.name(), PRef::from(len).this_page()));
+        }
+        let mut pages = self.iter_with_offsets();
+        let table_len = match pages.next() {
+            Some((pos, first)) => {
+                for i in 0 .. 3 {
+                    if !first.read_pref(i * 6).is_valid() {
+                        return Err(Error::corrupted_at("invalid file length in first log page", &self.name(), pos));
+                    }
+                }
+                if first.slice(18, 1)[0] == REPLACEMENT {
+                    // pages after the first are moved records, checked by replaced_prefs
+                    return Ok(self.page_iter().count());
+                }
+                first.read_pref(6).as_u64()
+            },
+            None => return Ok(0)
+        };
+        let mut n = 1;
+        for (pos, page) in pages {
+            let pref = page.pref();
+            if pref.is_valid() {
+                if pref.as_u64() >= table_len {
+                    return Err(Error::corrupted_at(format!("logged page {} is beyond the table length {}", pref, table_len), &self.name(), pos));
+                }
+            }
+            else {
+                let mut count = [0u8; 2];
+                page.read(0, &mut count);
+                if BigEndian::read_u16(&count) as usize > CHECKSUMS_PER_PAGE {
+                    return Err(Error::corrupted_at("too many checksums in log page", &self.name(), pos));
+                }
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    /// a log with more than its first page was not reset by the end of a batch, so recovery is needed
+    /// as it is for a log recording replacements of the files
+    pub fn is_dirty(&self) -> bool {
+        // if the length can not be read recovery should decide
+        self.len().map(|len| len > PAGE_SIZE as u64).unwrap_or(true) || self.is_replacement().unwrap_or(true)
+    }
+
+    /// start a log recording the lengths of replacements of the data, table and link files and the new pref of each moved record
+    /// once synced recovery completes replacing the files, its pages are checksummed as a torn log does not replace them
+    pub fn init_replacement(&mut self, data_len: u64, table_len: u64, link_len: u64, remapping: &HashMap<PRef, PRef>) -> Result<(), Error> {
+        self.checksums.clear();
+        self.truncate(0)?;
+        let mut first = Page::new();
+        first.write_pref(0, PRef::from(data_len));
+        first.write_pref(6, PRef::from(table_len));
+        first.write_pref(12, PRef::from(link_len));
+        first.write(18, &[REPLACEMENT]);
+        first.write_pref(19, PRef::from(remapping.len() as u64));
+        first.write_checksum();
+        self.append_page(first)?;
+
+        let mut moves = remapping.iter().collect::<Vec<_>>();
+        moves.sort();
+        for chunk in moves.chunks(MOVES_PER_PAGE) {
+            let mut page = Page::new();
+            for (i, (from, to)) in chunk.iter().enumerate() {
+                page.write_pref(i*12, **from);
+                page.write_pref(i*12 + 6, **to);
+            }
+            page.write_checksum();
+            // not a table page
+            page.write_pref(PAGE_PAYLOAD_SIZE, PRef::invalid());
+            self.append_page(page)?;
+        }
+        self.flush()
+    }
+
+    /// true if the log was started with init_replacement, it might be torn
+    pub fn is_replacement(&self) -> Result<bool, Error> {
+        match self.read_page(PRef::from(0))? {
+            Some(first) => Ok(first.slice(18, 1)[0] == REPLACEMENT),
+            None => Ok(false)
+        }
+    }
+
+    /// the new pref of each moved record if the log records replacements of the files completely
+    pub fn replaced_prefs(&self) -> Result<Option<HashMap<PRef, PRef>>, Error> {
+        let mut pages = self.page_iter();
+        let n = match pages.next() {
+            Some(ref first) if first.slice(18, 1)[0] == REPLACEMENT && first.verify_checksum() => first.read_pref(19).as_u64() as usize,
+            _ => return Ok(None)
+        };
+        let mut remapping = HashMap::new();
+        while remapping.len() < n {
+            match pages.next() {
+                Some(ref page) if page.verify_checksum() => {
+                    for i in 0 .. (n - remapping.len()).min(MOVES_PER_PAGE) {
+                        remapping.insert(page.read_pref(i*12), page.read_pref(i*12 + 6));
+                    }
+                },
+                _ => return Ok(None)
+            }
+        }
+        Ok(Some(remapping))
     }
 
     pub fn init(&mut self, data_len: u64, table_len: u64, link_len: u64) -> Result<(), Error> {
+        self.checksums.clear();
         self.truncate(0)?;
         let mut first = Page::new();
         first.write_pref(0, PRef::from(data_len));
         first.write_pref(6, PRef::from(table_len));
         first.write_pref(12, PRef::from(link_len));
+        // recovery needs the keys before the table, whose first page might be torn
+        first.write_u64(KEYS_POS, self.sip0);
+        first.write_u64(KEYS_POS + 8, self.sip1);
+        first.write_checksum();
 
         self.append_page(first)?;
         self.flush()?;
@@ -52,10 +303,20 @@ impl LogFile {
         PagedFileIterator::new(self, PRef::from(0))
     }
 
+    /// iterate pages together with their position in the log file
+    pub fn iter_with_offsets<'a>(&'a self) -> impl Iterator<Item=(PRef, Page)> + 'a {
+        self.page_iter().enumerate().map(|(n, page)| (PRef::from((n * PAGE_SIZE) as u64), page))
+    }
+
     pub fn log_page(&mut self, pref: PRef, source: &dyn PagedFile) -> Result<(), Error>{
         if pref.as_u64() < self.source_len && self.logged.insert(pref) {
             if let Some(page) = source.read_page(pref)? {
+                let checksum = self.checksum(&page);
                 self.append_page(page)?;
+                self.checksums.push(checksum);
+                if self.checksums.len() == CHECKSUMS_PER_PAGE {
+                    self.append_checksums()?;
+                }
             }
         }
         Ok(())
@@ -84,6 +345,18 @@ impl PagedFile for LogFile {
         self.file.sync()
     }
 
+    fn copy_files(&self, dest_name: &str) -> Result<BackupStats, Error> {
+        self.file.copy_files(dest_name)
+    }
+
+    fn copy_to(&self, dest: &mut dyn PagedFile) -> Result<u64, Error> {
+        self.file.copy_to(dest)
+    }
+
+    fn name(&self) -> String {
+        self.file.name()
+    }
+
     fn shutdown(&mut self) {}
 
     fn append_page(&mut self, page: Page) -> Result<(), Error> {
@@ -95,6 +368,120 @@ impl PagedFile for LogFile {
     }
 
     fn flush(&mut self) -> Result<(), Error> {
+        self.append_checksums()?;
         Ok(self.file.flush()?)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use transient::Transient;
+    use rolledfile::RolledFile;
+    use memtable::MemTable;
+
+    use super::*;
+
+    use std::{env, fs};
+
+    #[test]
+    fn test_checksum() {
+        let mut table = TableFile::new(Box::new(Transient::new(false))).unwrap();
+        for i in 0 .. 3 {
+            table.update_page(MemTable::invalid_offsets_page(PRef::from(i * PAGE_SIZE as u64))).unwrap();
+        }
+        let mut log = LogFile::new(Box::new(Transient::new(true)));
+        log.set_checksum_keys(42, 99);
+        log.init(0, table.len().unwrap(), 0).unwrap();
+        log.reset(table.len().unwrap());
+        log.log_page(PRef::from(PAGE_SIZE as u64), &table).unwrap();
+        log.log_page(PRef::from(2 * PAGE_SIZE as u64), &table).unwrap();
+        log.flush().unwrap();
+        assert_eq!(log.replay_into(&mut table).unwrap(), RecoveryStats { pages_replayed: 2, pages_skipped: 0, truncated_bytes: 0, remapping: HashMap::new() });
+
+        let mut copy = Transient::new(true);
+        for (i, page) in log.page_iter().enumerate() {
+            if i == 2 {
+                let mut buf = page.into_buf();
+                buf[2000] ^= 1;
+                copy.append_page(Page::from_buf(buf)).unwrap();
+            } else {
+                copy.append_page(page).unwrap();
+            }
+        }
+        let mut corrupted = LogFile::new(Box::new(copy));
+        corrupted.set_checksum_keys(42, 99);
+        // the first logged page is still replayed, the corrupted second one is skipped
+        // and the log is cut from it, before its checksum page
+        assert_eq!(corrupted.replay_into(&mut table).unwrap(), RecoveryStats { pages_replayed: 1, pages_skipped: 1, truncated_bytes: 2 * PAGE_SIZE as u64, remapping: HashMap::new() });
+    }
+
+    #[test]
+    fn test_iter_with_offsets() {
+        let mut table = TableFile::new(Box::new(Transient::new(false))).unwrap();
+        for i in 0 .. 6 {
+            table.update_page(MemTable::invalid_offsets_page(PRef::from(i * PAGE_SIZE as u64))).unwrap();
+        }
+        let mut log = LogFile::new(Box::new(Transient::new(true)));
+        log.init(0, table.len().unwrap(), 0).unwrap();
+        log.reset(table.len().unwrap());
+        for i in 1 .. 6 {
+            log.log_page(PRef::from(i * PAGE_SIZE as u64), &table).unwrap();
+        }
+        log.flush().unwrap();
+
+        let offsets = log.iter_with_offsets().map(|(pos, _)| pos).collect::<Vec<_>>();
+        // first page, 5 logged pages and their checksum page
+        assert_eq!(offsets.len(), 7);
+        for (i, pos) in offsets.iter().enumerate() {
+            assert_eq!(pos.as_u64(), (i * PAGE_SIZE) as u64);
+        }
+
+        // modify logged table pages 1 and 2 then replay the log from the entry of page 2
+        for i in 1 .. 3 {
+            let mut page = table.read_page(PRef::from(i * PAGE_SIZE as u64)).unwrap().unwrap();
+            page.write(0, &[1]);
+            table.update_page(page).unwrap();
+        }
+        let (from, _) = log.iter_with_offsets().find(|(_, page)| page.pref().as_u64() == 2 * PAGE_SIZE as u64).unwrap();
+        log.replay_from(&mut table, from).unwrap();
+        let mut first = [0u8; 1];
+        table.read_page(PRef::from(PAGE_SIZE as u64)).unwrap().unwrap().read(0, &mut first);
+        assert_eq!(first, [1]);
+        table.read_page(PRef::from(2 * PAGE_SIZE as u64)).unwrap().unwrap().read(0, &mut first);
+        assert_eq!(first, [0xff]);
+    }
+
+    #[test]
+    fn test_verify_integrity() {
+        let mut table = TableFile::new(Box::new(Transient::new(false))).unwrap();
+        for i in 0 .. 4 {
+            table.update_page(MemTable::invalid_offsets_page(PRef::from(i * PAGE_SIZE as u64))).unwrap();
+        }
+        let name = env::temp_dir().join("hammersbald_test_verify_log").to_string_lossy().to_string();
+        let path = name.clone() + ".0.lg";
+        fs::remove_file(&path).ok();
+        {
+            let mut log = LogFile::new(Box::new(RolledFile::new(&name, "lg", true, 1 << 20).unwrap()));
+            log.init(0, table.len().unwrap(), 0).unwrap();
+            assert!(!log.is_dirty());
+            log.reset(table.len().unwrap());
+            for i in 1 .. 4 {
+                log.log_page(PRef::from(i * PAGE_SIZE as u64), &table).unwrap();
+            }
+            log.flush().unwrap();
+            assert!(log.is_dirty());
+            // first page, 3 logged pages and their checksum page
+            assert_eq!(log.verify_integrity().unwrap(), 5);
+        }
+
+        // cut the log within the last page, as a crash while writing it would
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(4 * PAGE_SIZE as u64 + 100).unwrap();
+        let log = LogFile::new(Box::new(RolledFile::new(&name, "lg", true, 1 << 20).unwrap()));
+        match log.verify_integrity() {
+            Err(Error::Corrupted{pref, ..}) => assert_eq!(pref, Some(PRef::from(4 * PAGE_SIZE as u64))),
+            _ => panic!("torn log page should be reported")
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
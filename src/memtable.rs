@@ -20,28 +20,38 @@
 //!
 use error::Error;
 use pref::PRef;
-use datafile::{DataFile, EnvelopeIterator};
-use tablefile::{TableFile, FIRST_PAGE_HEAD, BUCKETS_FIRST_PAGE, BUCKETS_PER_PAGE, BUCKET_SIZE};
+use datafile::{DataFile, EnvelopeIterator, DagIterator};
+use tablefile::{TableFile, FIRST_PAGE_HEAD, BUCKETS_FIRST_PAGE, BUCKETS_PER_PAGE, BUCKET_SIZE, FORMAT_VERSION};
 use logfile::LogFile;
 use page::PAGE_SIZE;
 use pagedfile::PagedFile;
 use format::{Link, Payload, Envelope};
 use page::Page;
+use transient::Transient;
+use stats::{BackupStats, CompactionReport, LinkCompactionStats, MemTableStats, RecoveryStats, TypeCounts, TypeBytes};
 
 use bitcoin_hashes::siphash24;
 use rand::{thread_rng, RngCore};
+use byteorder::{WriteBytesExt, ReadBytesExt, ByteOrder, BigEndian};
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::cmp::{min, max};
+use std::io::{Read, Write};
+use std::mem;
 use std::sync::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 const INIT_BUCKETS: usize = 512;
 const INIT_LOGMOD :usize = 8;
+const SNAPSHOT_MAGIC: &[u8; 4] = b"HBMT";
+const UNRESOLVED: u32 = 0xffffffff;
 
 pub struct MemTable {
     step: usize,
     forget: usize,
+    entry_count: usize,
     log_mod: u32,
     sip0: u64,
     sip1: u64,
@@ -51,19 +61,144 @@ pub struct MemTable {
     data_file: DataFile,
     table_file: TableFile,
     link_file: DataFile,
-    bucket_fill_target: usize
+    bucket_fill_target: usize,
+    generation: AtomicU64,
+    counters: Counters,
+    // of the data and link, table and log files, zeros if the files are not chunked
+    chunk_sizes: [u64; 3]
+}
+
+/// counters of changes since the memtable was created
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Counters {
+    /// keys stored
+    pub inserted: usize,
+    /// keys forgotten
+    pub forgotten: usize,
+    /// bytes of data no longer accessible with its key, as forgotten or overwritten
+    pub freed_bytes: u64,
+    /// batches ended
+    pub batches: usize
+}
+
+/// phases of ending a batch, reported to the callback of batch_with_progress in this order
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BatchPhase {
+    /// writing and syncing the data file
+    FlushingData,
+    /// writing the slots of modified buckets to the link file
+    FlushingLinks,
+    /// writing the given number of pages of the hash table
+    FlushingTable(u64),
+    /// starting a new log of the next batch
+    SyncingLog,
+    /// the batch ended
+    Done
 }
 
 impl MemTable {
-    pub fn new(log_file: LogFile, table_file: TableFile, data_file: DataFile, link_file: DataFile, bucket_fill_target: usize) -> MemTable {
+    pub fn new(mut log_file: LogFile, table_file: TableFile, data_file: DataFile, link_file: DataFile, bucket_fill_target: usize) -> MemTable {
         let mut rng = thread_rng();
+        let sip0 = rng.next_u64();
+        let sip1 = rng.next_u64();
+        log_file.set_checksum_keys(sip0, sip1);
 
-        MemTable {log_mod: INIT_LOGMOD as u32, step: 0, forget: 0,
-            sip0: rng.next_u64(),
-            sip1: rng.next_u64(),
+        MemTable {log_mod: INIT_LOGMOD as u32, step: 0, forget: 0, entry_count: 0,
+            sip0,
+            sip1,
             buckets: RwLock::new(vec!(Bucket::default(); INIT_BUCKETS)),
             dirty: Dirty::new(INIT_BUCKETS), log_file, table_file, data_file, link_file,
-            bucket_fill_target: max(min(bucket_fill_target, 128), 1)}
+            bucket_fill_target: max(min(bucket_fill_target, 128), 1),
+            generation: AtomicU64::new(0),
+            counters: Counters::default(),
+            chunk_sizes: [0; 3]}
+    }
+
+    /// set keys of the hash function and end the current batch
+    /// only possible while no data is accessible with keys
+    pub fn set_sip_keys(&mut self, sip0: u64, sip1: u64) -> Result<(), Error> {
+        if self.buckets.read().unwrap().iter().any(|b| b.stored.is_valid() || b.slots.as_ref().map_or(false, |s| !s.is_empty())) {
+            return Err(Error::InvalidArgument("hash keys can not be changed once data is stored with keys".to_string()));
+        }
+        // pages logged from here on are checksummed with the new keys the log must start with
+        self.batch()?;
+        self.sip0 = sip0;
+        self.sip1 = sip1;
+        self.log_file.rekey(sip0, sip1)?;
+        self.log_file.sync()?;
+        self.batch()
+    }
+
+    /// keys of the hash function
+    pub fn sip_keys(&self) -> (u64, u64) {
+        (self.sip0, self.sip1)
+    }
+
+    /// chunk sizes of the data and link, table and log files stored in the first table page, zeros if none are
+    pub fn chunk_sizes(&self) -> [u64; 3] {
+        self.chunk_sizes
+    }
+
+    /// set the chunk sizes stored in the first table page at the next batch
+    pub fn set_chunk_sizes(&mut self, chunk_sizes: [u64; 3]) {
+        self.chunk_sizes = chunk_sizes;
+    }
+
+    pub fn counters(&self) -> Counters {
+        self.counters
+    }
+
+    /// statistics of the hash table, this resolves all buckets
+    pub fn stats(&self) -> Result<MemTableStats, Error> {
+        let bucket_count = self.buckets.read().unwrap().len();
+        let mut entry_count = 0;
+        let mut colliding = 0;
+        for bucket_number in 0 .. bucket_count {
+            self.resolve_bucket(bucket_number)?;
+            if let Some(Some(slots)) = self.buckets.read().unwrap().get(bucket_number).map(|b| b.slots.as_ref()) {
+                entry_count += slots.len();
+                let mut hashes = HashMap::new();
+                for (hash, _) in slots {
+                    *hashes.entry(*hash).or_insert(0) += 1;
+                }
+                colliding += hashes.values().filter(|n| **n > 1).sum::<usize>();
+            }
+        }
+        Ok(MemTableStats {
+            bucket_count,
+            entry_count,
+            load_factor: if bucket_count > 0 { entry_count as f64 / bucket_count as f64 } else { 0.0 },
+            rehash_step: self.step,
+            log_mod: self.log_mod,
+            collision_rate: if entry_count > 0 { colliding as f64 / entry_count as f64 } else { 0.0 },
+            data_file_bytes: self.data_file.len()?,
+            link_file_bytes: self.link_file.len()?,
+            data_cache: self.data_file.cache_stats().unwrap_or_default(),
+            link_cache: self.link_file.cache_stats().unwrap_or_default(),
+            table_cache: self.table_file.cache_stats().unwrap_or_default()
+        })
+    }
+
+    /// number of buckets by the number of their slots, index i is the number of buckets with i slots
+    /// this resolves all buckets
+    pub fn bucket_fill_histogram(&self) -> Result<Vec<usize>, Error> {
+        let bucket_count = self.buckets.read().unwrap().len();
+        let mut histogram = Vec::new();
+        for bucket_number in 0 .. bucket_count {
+            self.resolve_bucket(bucket_number)?;
+            let fill = self.buckets.read().unwrap()[bucket_number].slots.as_ref().map_or(0, |slots| slots.len());
+            if histogram.len() <= fill {
+                histogram.resize(fill + 1, 0);
+            }
+            histogram[fill] += 1;
+        }
+        Ok(histogram)
+    }
+
+    /// the most slots of any bucket, this resolves all buckets
+    #[allow(unused)]
+    pub fn max_bucket_depth(&self) -> Result<usize, Error> {
+        Ok(self.bucket_fill_histogram()?.len().saturating_sub(1))
     }
 
     pub fn params(&self) -> (usize, u32, usize, u64, u64, u64, u64, u64) {
@@ -71,13 +206,48 @@ impl MemTable {
         self.sip0, self.sip1)
     }
 
+    /// length of the log file
+    pub fn log_file_len(&self) -> Result<u64, Error> {
+        self.log_file.len()
+    }
+
     /// end current batch and start a new batch
     pub fn batch(&mut self)  -> Result<(), Error> {
+        self.batch_with_progress(&|_| {})
+    }
+
+    /// end current batch and start a new batch, calling progress at the start of each phase
+    pub fn batch_with_progress(&mut self, progress: &dyn Fn(BatchPhase))  -> Result<(), Error> {
+        #[cfg(debug_assertions)]
+        let modified = self.dirty.iter_set_bits().collect::<Vec<_>>();
+        self.persist(progress)?;
+
+        #[cfg(debug_assertions)]
+        self.validate_buckets(modified.into_iter())?;
+
+        self.counters.batches += 1;
+        progress(BatchPhase::Done);
+        Ok(())
+    }
+
+    /// write what is stored so far to disk and make it the state recovered after a crash
+    /// unlike batch this does not count a batch or validate the modified buckets
+    pub fn checkpoint(&mut self) -> Result<(), Error> {
+        self.persist(&|_| {})
+    }
+
+    // write data, links and table to disk, then log their lengths as the new state to recover to
+    fn persist(&mut self, progress: &dyn Fn(BatchPhase)) -> Result<(), Error> {
         self.log_file.flush()?;
         self.log_file.sync()?;
 
-        self.flush()?;
-        self.dirty.clear();
+        // data is append only, recovery truncates it to the length logged at the end of the batch
+        progress(BatchPhase::FlushingData);
+        self.data_file.flush()?;
+        self.data_file.sync()?;
+        let data_len = self.data_file.len()?;
+
+        self.flush_with_progress(progress)?;
 
         self.table_file.sync()?;
         let table_len = self.table_file.len()?;
@@ -85,18 +255,90 @@ impl MemTable {
         self.link_file.sync()?;
         let link_len = self.link_file.len()?;
 
-        self.data_file.flush()?;
-        self.data_file.sync()?;
-        let data_len = self.data_file.len()?;
-
+        progress(BatchPhase::SyncingLog);
         self.log_file.reset(table_len);
         self.log_file.init(data_len, table_len, link_len)?;
         self.log_file.flush()?;
-        self.log_file.sync()?;
+        self.log_file.sync()
+    }
+
+    /// check that slots refer to data with a key of their hash and are in the bucket of their hash
+    /// this reads all data accessible with keys
+    #[allow(unused)]
+    pub fn validate_consistency(&self) -> Result<(), Error> {
+        let n = self.buckets.read().unwrap().len();
+        self.validate_buckets(0 .. n)
+    }
 
+    fn validate_buckets<I: Iterator<Item=usize>>(&self, buckets: I) -> Result<(), Error> {
+        // grow rolls over only after step passed 2^(log_mod + 1)
+        if self.step > 1 << (self.log_mod + 1) {
+            return Err(Error::corrupted(format!("step {} is above 2^{}", self.step, self.log_mod + 1)));
+        }
+        for bucket_number in buckets {
+            self.resolve_bucket(bucket_number)?;
+            let slots = match self.buckets.read().unwrap().get(bucket_number) {
+                Some(bucket) => bucket.slots.clone().unwrap_or_default(),
+                None => return Err(Error::corrupted(format!("bucket {} should exist", bucket_number)))
+            };
+            let mut seen = HashSet::new();
+            for (hash, pref) in slots {
+                if !seen.insert(pref) {
+                    return Err(Error::corrupted(format!("bucket {} refers to {} more than once", bucket_number, pref)));
+                }
+                if self.bucket_for_hash(hash) != bucket_number {
+                    return Err(Error::corrupted(format!("hash {} is not in its bucket {}", hash, bucket_number)));
+                }
+                if let Payload::Indexed(indexed) = Payload::deserialize(&self.data_file.read_envelope_raw(pref)?)? {
+                    if self.hash(indexed.key) != hash {
+                        return Err(Error::corrupted_at("key does not have the hash of its slot", &self.data_file.name(), pref));
+                    }
+                } else {
+                    return Err(Error::corrupted_at("pref should point to indexed data", &self.data_file.name(), pref));
+                }
+            }
+        }
         Ok(())
     }
 
+    /// number of entries in data and link files by payload type
+    pub fn count_by_type(&self) -> TypeCounts {
+        let mut counts = self.data_file.count_by_type();
+        counts += self.link_file.count_by_type();
+        counts
+    }
+
+    /// bytes of entries in data and link files by payload type
+    pub fn bytes_by_type(&self) -> TypeBytes {
+        let mut bytes = self.data_file.bytes_by_type();
+        bytes += self.link_file.bytes_by_type();
+        bytes
+    }
+
+    /// copy all files to files named dest_name, to be called after batch
+    pub fn backup(&self, dest_name: &str) -> Result<BackupStats, Error> {
+        let mut stats = self.log_file.copy_files(dest_name)?;
+        stats += self.table_file.copy_files(dest_name)?;
+        stats += self.data_file.copy_files(dest_name)?;
+        stats += self.link_file.copy_files(dest_name)?;
+        Ok(stats)
+    }
+
+    /// copy all files to transient files, to be called after batch
+    pub fn copy_to_transient(&self) -> Result<(LogFile, TableFile, DataFile, DataFile), Error> {
+        let log = LogFile::new(Box::new(Transient::copy_of(&self.log_file, true)?));
+        let table = TableFile::new(Box::new(Transient::copy_of(&self.table_file, false)?))?;
+        let mut data = Transient::new(true);
+        for page in self.data_file.page_iter() {
+            data.append_page(page)?;
+        }
+        let mut link = Transient::new(true);
+        for page in self.link_file.page_iter() {
+            link.append_page(page)?;
+        }
+        Ok((log, table, DataFile::new(Box::new(data))?, DataFile::new(Box::new(link))?))
+    }
+
     /// stop background writer
     pub fn shutdown(&mut self) {
         self.data_file.shutdown();
@@ -105,7 +347,43 @@ impl MemTable {
         self.log_file.shutdown();
     }
 
+    #[allow(unused)]
     pub fn recover(&mut self) -> Result<(), Error> {
+        self.recover_with_stats().map(|_| ())
+    }
+
+    pub fn recover_with_stats(&mut self) -> Result<RecoveryStats, Error> {
+        self.recover_from(PRef::from(PAGE_SIZE as u64))
+    }
+
+    // recover replaying only log entries at or after log position log_pos
+    // those before are assumed to be applied to the table already
+    pub fn recover_from(&mut self, log_pos: PRef) -> Result<RecoveryStats, Error> {
+        // files are replaced once the log records their replacements, a torn log leaves them as of the last batch
+        if self.log_file.is_replacement()? {
+            let remapping = self.log_file.replaced_prefs()?;
+            let replaced = remapping.is_some();
+            self.complete_replacements(replaced)?;
+            let (mut data_len, mut table_len, mut link_len) = (self.data_file.len()?, self.table_file.len()?, self.link_file.len()?);
+            if replaced {
+                if let Some(page) = self.log_file.read_page(PRef::from(0))? {
+                    data_len = page.read_pref(0).as_u64();
+                    table_len = page.read_pref(6).as_u64();
+                    link_len = page.read_pref(12).as_u64();
+                }
+                self.table_file.truncate(table_len)?;
+                self.data_file.truncate(data_len)?;
+                self.link_file.truncate(link_len)?;
+            }
+            self.log_file.init(data_len, table_len, link_len)?;
+            self.log_file.flush()?;
+            self.log_file.sync()?;
+            return Ok(RecoveryStats { remapping: remapping.unwrap_or_default(), ..RecoveryStats::default() });
+        }
+        // replacements written before a crash that were not recorded in the log
+        self.complete_replacements(false)?;
+
+        let mut stats = RecoveryStats::default();
         let mut data_len = 0;
         let mut table_len = 0;
         let mut link_len = 0;
@@ -120,8 +398,19 @@ impl MemTable {
         }
 
         if self.log_file.len()? > PAGE_SIZE as u64 {
-            for page in self.log_file.page_iter().skip(1) {
-                self.table_file.update_page(page)?;
+            // logged pages are checksummed with the keys in the first log page, the first table page
+            // might be torn and is only read after replay, except for logs written without the keys
+            match self.log_file.logged_checksum_keys()? {
+                Some((sip0, sip1)) => self.log_file.set_checksum_keys(sip0, sip1),
+                None => if let Some(first) = self.table_file.read_page(PRef::from(0))? {
+                    self.log_file.set_checksum_keys(first.read_u64(12), first.read_u64(20));
+                }
+            }
+            stats = self.log_file.replay_from(&mut self.table_file, log_pos)?;
+            // pages skipped for failing their checksum are reported in stats
+            if stats.truncated_bytes > 0 {
+                let torn = self.log_file.len()? - stats.truncated_bytes;
+                self.log_file.truncate(torn)?;
             }
             self.table_file.flush()?;
             self.table_file.sync()?;
@@ -131,11 +420,17 @@ impl MemTable {
             self.log_file.sync()?;
         }
 
-        Ok(())
+        Ok(stats)
     }
 
     pub fn load(&mut self) -> Result<(), Error>{
         if let Some(first) = self.table_file.read_page(PRef::from(0))? {
+            let mut version = [0u8; 2];
+            first.read(28, &mut version);
+            let version = BigEndian::read_u16(&version);
+            if version != FORMAT_VERSION {
+                return Err(Error::corrupted_at(format!("database format version {} instead of {}", version, FORMAT_VERSION), &self.table_file.name(), PRef::from(0)));
+            }
             let n_buckets = first.read_pref(0).as_u64() as u32;
             self.buckets = RwLock::new(vec![Bucket::default(); n_buckets as usize]);
             self.dirty = Dirty::new(n_buckets as usize);
@@ -143,6 +438,9 @@ impl MemTable {
             self.log_mod = (32 - n_buckets.leading_zeros()) as u32 - 2;
             self.sip0 = first.read_u64(12);
             self.sip1 = first.read_u64(20);
+            self.log_file.set_checksum_keys(self.sip0, self.sip1);
+            self.entry_count = first.read_pref(30).as_u64() as usize;
+            self.chunk_sizes = Self::read_chunk_sizes(&first);
         }
 
         let mut buckets = self.buckets.write().unwrap();
@@ -159,12 +457,331 @@ impl MemTable {
         Ok(())
     }
 
+    /// estimated bytes of memory used by buckets, their slots and the dirty bitmap
+    pub fn estimated_memory_usage(&self) -> usize {
+        let buckets = self.buckets.read().unwrap();
+        let slots = buckets.iter()
+            .map(|b| b.slots.as_ref().map(|s| s.len()).unwrap_or(0)).sum::<usize>();
+        buckets.len() * mem::size_of::<Bucket>() + slots * 10 + self.dirty.bits.len() * mem::size_of::<u64>()
+    }
+
+    /// end the current batch and replace the link file with one of only the links the table refers to
+    pub fn compact_link_file(&mut self) -> Result<LinkCompactionStats, Error> {
+        self.batch()?;
+        let bytes_before = self.link_file.len()?;
+        let entries_before = self.link_file.count_by_type().links;
+
+        let n = self.buckets.read().unwrap().len();
+        for bucket_number in 0 .. n {
+            self.resolve_bucket(bucket_number)?;
+        }
+        self.replace_files(None, &HashMap::new())?;
+
+        Ok(LinkCompactionStats {
+            bytes_before,
+            bytes_after: self.link_file.len()?,
+            entries_removed: entries_before - self.link_file.count_by_type().links
+        })
+    }
+
+    /// end the current batch and replace the data file with one of only data accessible with keys and referred data they list
+    /// refuses if there is referred data no indexed data lists
+    /// the new pref of each record kept is in the log until the files are swapped, and in the recovery stats of a swap completed at open
+    /// returns the bytes reclaimed
+    pub fn compact_data_file(&mut self) -> Result<u64, Error> {
+        let report = self.compact()?;
+        Ok(report.bytes_before - report.bytes_after)
+    }
+
+    /// end current batch and compact the data file as compact_data_file does
+    /// returns what the compaction did, including the new pref of each kept record
+    pub fn compact(&mut self) -> Result<CompactionReport, Error> {
+        self.batch()?;
+        let n = self.buckets.read().unwrap().len();
+        let mut live = HashSet::new();
+        for bucket_number in 0 .. n {
+            self.resolve_bucket(bucket_number)?;
+            if let Some(ref slots) = self.buckets.read().unwrap()[bucket_number].slots {
+                live.extend(slots.iter().map(|(_, pref)| *pref));
+            }
+        }
+        let bytes_before = self.data_file.len()?;
+        let (data, remapping) = self.data_file.compact(&live)?;
+        let bytes_after = data.len()?;
+        let records_moved = remapping.iter().filter(|(pref, moved)| pref != moved).count();
+        self.replace_files(Some(data), &remapping)?;
+        Ok(CompactionReport { bytes_before, bytes_after, records_moved, remapping })
+    }
+
+    // write links and table of the resolved buckets, with slots remapped if data is given, to replacements of their files,
+    // then swap these and the replacement of the data file in, once the log recorded their lengths and remapping
+    // an error before leaves the files as of the last batch and reloads the table, recovery completes a recorded swap
+    fn replace_files(&mut self, data: Option<DataFile>, remapping: &HashMap<PRef, PRef>) -> Result<(), Error> {
+        let written = if data.is_some() { self.remap_prefs(remapping) } else { Ok(()) }
+            .and_then(|_| self.write_replacements(data));
+        let (data, link, table) = match written {
+            Ok(replacements) => replacements,
+            Err(e) => {
+                self.complete_replacements(false)?;
+                self.load()?;
+                return Err(e);
+            }
+        };
+        let data_len = match data {
+            Some(ref data) => data.len()?,
+            None => self.data_file.len()?
+        };
+        let table_len = table.len()?;
+        let link_len = link.len()?;
+        self.log_file.init_replacement(data_len, table_len, link_len, remapping)?;
+        self.log_file.sync()?;
+
+        if let Some(data) = data {
+            self.data_file.replace_with(data)?;
+        }
+        self.link_file.replace_with(link)?;
+        self.table_file.replace_with(Box::new(table))?;
+
+        self.log_file.reset(table_len);
+        self.log_file.init(data_len, table_len, link_len)?;
+        self.log_file.flush()?;
+        self.log_file.sync()
+    }
+
+    // sync the data replacement and write links and table pages of all buckets to synced replacements of their files
+    fn write_replacements(&mut self, data: Option<DataFile>) -> Result<(Option<DataFile>, DataFile, TableFile), Error> {
+        let data = match data {
+            Some(mut data) => {
+                data.flush()?;
+                data.sync()?;
+                Some(data)
+            },
+            None => None
+        };
+        let link = self.link_file.replacement()?;
+        let table = TableFile::new(self.table_file.replacement()?)?;
+        let link_file = mem::replace(&mut self.link_file, link);
+        let table_file = mem::replace(&mut self.table_file, table);
+        let n = self.buckets.read().unwrap().len();
+        for bucket_number in 0 .. n {
+            self.dirty.set(bucket_number);
+        }
+        let flushed = self.flush_with_progress(&|_| {});
+        let link = mem::replace(&mut self.link_file, link_file);
+        let table = mem::replace(&mut self.table_file, table_file);
+        flushed?;
+        link.sync()?;
+        table.sync()?;
+        Ok((data, link, table))
+    }
+
+    // finish replacing the files with replacements left by a crash if commit, remove them otherwise
+    fn complete_replacements(&mut self, commit: bool) -> Result<(), Error> {
+        self.data_file.complete_replacement(commit)?;
+        self.link_file.complete_replacement(commit)?;
+        self.table_file.complete_replacement(commit)
+    }
+
+    /// point slots to new prefs, every slot of a resolved bucket must have an entry in map
+    pub fn remap_prefs(&mut self, map: &HashMap<PRef, PRef>) -> Result<(), Error> {
+        let mut buckets = self.buckets.write().unwrap();
+        for (bucket_number, bucket) in buckets.iter_mut().enumerate() {
+            if let Some(ref mut slots) = bucket.slots {
+                if !slots.is_empty() {
+                    for slot in slots.iter_mut() {
+                        slot.1 = *map.get(&slot.1).ok_or_else(|| Error::corrupted("no new pref for a slot"))?;
+                    }
+                    self.dirty.set(bucket_number);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// discard the hash table and store the key of every indexed record of the data file, then end the batch
+    /// for a table lost or corrupted beyond repair, forgotten keys are accessible again, not crash safe
+    /// returns the number of records indexed
+    pub fn rebuild_from_data_file(&mut self) -> Result<usize, Error> {
+        let indexed = self.data_file.envelopes().filter_map(|(pref, envelope)| {
+            match Payload::deserialize(envelope.payload()) {
+                Ok(Payload::Indexed(indexed)) => Some((indexed.key.to_vec(), pref)),
+                _ => None
+            }
+        }).collect::<Vec<_>>();
+
+        self.buckets = RwLock::new(vec![Bucket::default(); INIT_BUCKETS]);
+        self.dirty = Dirty::new(INIT_BUCKETS);
+        self.step = 0;
+        self.log_mod = INIT_LOGMOD as u32;
+        self.forget = 0;
+        self.entry_count = 0;
+        // links and table pages are written anew at flush
+        self.table_file.truncate(0)?;
+        self.link_file.truncate(0)?;
+
+        for (key, pref) in &indexed {
+            self.put(key, *pref)?;
+        }
+        self.batch()?;
+        Ok(indexed.len())
+    }
+
+    /// release slots of the least recently accessed buckets, keeping keep_n resolved
+    /// modified buckets are not released until the next batch
+    /// returns the number of buckets released, they are reloaded from the link file on next access
+    #[allow(unused)]
+    pub fn evict_cold_buckets(&mut self, keep_n: usize) -> usize {
+        let mut buckets = self.buckets.write().unwrap();
+        let mut resolved = buckets.iter().enumerate()
+            .filter(|(n, b)| b.slots.is_some() && !self.dirty.get(*n))
+            .map(|(n, b)| (b.accessed, n)).collect::<Vec<_>>();
+        if resolved.len() <= keep_n {
+            return 0;
+        }
+        resolved.sort_unstable();
+        let evict = resolved.len() - keep_n;
+        for (_, n) in &resolved[.. evict] {
+            buckets[*n].slots = None;
+        }
+        evict
+    }
+
+    /// call f with bucket number and slots of each resolved bucket in parallel
+    /// returns results in bucket order
+    #[cfg(feature = "parallel_reads")]
+    #[allow(unused)]
+    pub fn for_each_slot_parallel<F, R>(&self, f: F) -> Vec<R>
+        where F: Fn(usize, &[(u32, PRef)]) -> R + Send + Sync, R: Send {
+        use rayon::prelude::*;
+
+        let buckets = self.buckets.read().unwrap();
+        buckets.par_iter().enumerate()
+            .filter_map(|(n, b)| b.slots.as_ref().map(|slots| f(n, slots.as_slice())))
+            .collect()
+    }
+
+    pub fn bucket_fill_target(&self) -> usize {
+        self.bucket_fill_target
+    }
+
+    /// number of keys stored, maintained as keys are stored and forgotten
+    pub fn entry_count(&self) -> usize {
+        self.entry_count
+    }
+
+    /// true if nothing changed since the last batch
+    pub fn is_clean(&self) -> bool {
+        !self.dirty.is_dirty()
+    }
+
+    /// write the in-memory hash table, returns the number of bytes written
+    pub fn serialize(&self, writer: &mut dyn Write) -> Result<u64, Error> {
+        let buckets = self.buckets.read().unwrap();
+        writer.write_all(SNAPSHOT_MAGIC)?;
+        writer.write_u64::<BigEndian>(self.sip0)?;
+        writer.write_u64::<BigEndian>(self.sip1)?;
+        writer.write_u64::<BigEndian>(self.step as u64)?;
+        writer.write_u32::<BigEndian>(self.log_mod)?;
+        writer.write_u48::<BigEndian>(self.table_file.len()?)?;
+        writer.write_u48::<BigEndian>(self.data_file.len()?)?;
+        writer.write_u48::<BigEndian>(self.link_file.len()?)?;
+        writer.write_u32::<BigEndian>(buckets.len() as u32)?;
+        let mut written = 4 + 8 + 8 + 8 + 4 + 3*6 + 4;
+        for bucket in buckets.iter() {
+            writer.write_u48::<BigEndian>(bucket.stored.as_u64())?;
+            if let Some(ref slots) = bucket.slots {
+                writer.write_u32::<BigEndian>(slots.len() as u32)?;
+                writer.write_all(Link::from_slots(slots.as_slice()).as_slice())?;
+                written += 6 + 4 + 10 * slots.len() as u64;
+            }
+            else {
+                writer.write_u32::<BigEndian>(UNRESOLVED)?;
+                written += 6 + 4;
+            }
+        }
+        Ok(written)
+    }
+
+    /// read a memtable written with serialize, the files must be those the snapshot was taken of
+    #[allow(unused)]
+    pub fn deserialize(reader: &mut dyn Read, log_file: LogFile, table_file: TableFile, data_file: DataFile, link_file: DataFile, bucket_fill_target: usize) -> Result<MemTable, Error> {
+        let mut mem = MemTable::new(log_file, table_file, data_file, link_file, bucket_fill_target);
+        if !mem.restore(reader)? {
+            return Err(Error::corrupted("memtable snapshot does not match files"));
+        }
+        Ok(mem)
+    }
+
+    /// replace the in-memory hash table with a snapshot written with serialize
+    /// returns false and leaves the memtable unchanged if the snapshot was not taken of the current files
+    /// or the first table page is of an other format version or sip keys
+    pub fn restore(&mut self, reader: &mut dyn Read) -> Result<bool, Error> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != SNAPSHOT_MAGIC {
+            return Err(Error::corrupted("not a memtable snapshot"));
+        }
+        let sip0 = reader.read_u64::<BigEndian>()?;
+        let sip1 = reader.read_u64::<BigEndian>()?;
+        let step = reader.read_u64::<BigEndian>()? as usize;
+        let log_mod = reader.read_u32::<BigEndian>()?;
+        let table_len = reader.read_u48::<BigEndian>()?;
+        let data_len = reader.read_u48::<BigEndian>()?;
+        let link_len = reader.read_u48::<BigEndian>()?;
+        if table_len != self.table_file.len()? || data_len != self.data_file.len()? || link_len != self.link_file.len()? {
+            return Ok(false);
+        }
+        // the snapshot is taken after a batch, so the first page holds the number of keys
+        let mut entry_count = 0;
+        let mut chunk_sizes = [0; 3];
+        if let Some(first) = self.table_file.read_page(PRef::from(0))? {
+            let mut version = [0u8; 2];
+            first.read(28, &mut version);
+            if BigEndian::read_u16(&version) != FORMAT_VERSION || first.read_u64(12) != sip0 || first.read_u64(20) != sip1 {
+                return Ok(false);
+            }
+            entry_count = first.read_pref(30).as_u64() as usize;
+            chunk_sizes = Self::read_chunk_sizes(&first);
+        }
+        let n_buckets = reader.read_u32::<BigEndian>()? as usize;
+        let mut buckets = Vec::with_capacity(n_buckets);
+        for _ in 0 .. n_buckets {
+            let stored = PRef::from(reader.read_u48::<BigEndian>()?);
+            let n_slots = reader.read_u32::<BigEndian>()?;
+            let slots = if n_slots == UNRESOLVED {
+                None
+            } else {
+                let mut links = vec!(0u8; 10 * n_slots as usize);
+                reader.read_exact(&mut links)?;
+                Some(Link::deserialize(links.as_slice()).slots()?)
+            };
+            buckets.push(Bucket{stored, slots, accessed: 0});
+        }
+        self.sip0 = sip0;
+        self.sip1 = sip1;
+        self.log_file.set_checksum_keys(sip0, sip1);
+        self.step = step;
+        self.log_mod = log_mod;
+        self.forget = 0;
+        self.dirty = Dirty::new(n_buckets);
+        self.buckets = RwLock::new(buckets);
+        self.entry_count = entry_count;
+        self.chunk_sizes = chunk_sizes;
+        Ok(true)
+    }
+
+    /// chunk sizes stored in a first table page
+    pub fn read_chunk_sizes(first: &Page) -> [u64; 3] {
+        [first.read_pref(36).as_u64(), first.read_pref(42).as_u64(), first.read_pref(48).as_u64()]
+    }
+
     fn resolve_bucket(&self, bucket_number: usize) -> Result<(), Error> {
         if let Some(bucket) = self.buckets.write().unwrap().get_mut(bucket_number) {
+            bucket.accessed = self.generation.fetch_add(1, Ordering::Relaxed);
             if bucket.slots.is_none() {
                 if bucket.stored.is_valid() {
-                    if let Ok(Payload::Link(link)) = Payload::deserialize(self.link_file.get_envelope(bucket.stored)?.payload()) {
-                        bucket.slots = Some(link.slots());
+                    if let Ok(Payload::Link(link)) = Payload::deserialize(&self.link_file.read_envelope_raw(bucket.stored)?) {
+                        bucket.slots = Some(link.slots()?);
                     }
                 }
             }
@@ -172,23 +789,13 @@ impl MemTable {
         Ok(())
     }
 
-    pub fn flush(&mut self) -> Result<(), Error> {
-        {
-            // first page
-            let fp = PRef::from(0);
-            let mut page = self.table_file.read_page(fp)?.unwrap_or(Self::invalid_offsets_page(fp));
-            page.write_pref(0, PRef::from(self.buckets.read().unwrap().len() as u64));
-            page.write_pref(6, PRef::from(self.step as u64));
-            page.write_u64(12, self.sip0);
-            page.write_u64(20, self.sip1);
-            self.table_file.update_page(page)?;
-        }
+    fn flush_with_progress(&mut self, progress: &dyn Fn(BatchPhase)) -> Result<(), Error> {
+        progress(BatchPhase::FlushingLinks);
+        let mut stored = Vec::new();
         if self.dirty.is_dirty() {
-            let dirty_iterator = DirtyIterator::new(&self.dirty);
-            for (bucket_number, _) in dirty_iterator.enumerate().filter(|a| a.1) {
-                let bucket_pref= TableFile::table_offset(bucket_number);
-                if let Some(mut bucket) = self.buckets.write().unwrap().get_mut(bucket_number) {
-                    let mut page = self.table_file.read_page(bucket_pref.this_page())?.unwrap_or(Self::invalid_offsets_page(bucket_pref.this_page()));
+            let mut buckets = self.buckets.write().unwrap();
+            for bucket_number in self.dirty.iter_set_bits() {
+                if let Some(bucket) = buckets.get_mut(bucket_number) {
                     if let Some(ref slots) = bucket.slots {
                         let link = if slots.len() > 0 {
                             let slots = Link::from_slots(slots.as_slice());
@@ -197,14 +804,52 @@ impl MemTable {
                             PRef::invalid()
                         };
                         bucket.stored = link;
-                        page.write_pref(bucket_pref.in_page_pos(), link);
-                        self.table_file.update_page(page)?;
+                        stored.push((bucket_number, link));
                     }
                 }
             }
         }
         self.dirty.clear();
         self.link_file.flush()?;
+
+        // buckets are in ascending order, so are their pages
+        let mut pages = vec!(PRef::from(0));
+        pages.extend(stored.iter().map(|(bucket_number, _)| TableFile::table_offset(*bucket_number).this_page()));
+        pages.dedup();
+        progress(BatchPhase::FlushingTable(pages.len() as u64));
+        {
+            // first page
+            let fp = PRef::from(0);
+            let mut page = self.table_file.read_page(fp)?.unwrap_or(Self::invalid_offsets_page(fp));
+            page.write_pref(0, PRef::from(self.buckets.read().unwrap().len() as u64));
+            page.write_pref(6, PRef::from(self.step as u64));
+            page.write_u64(12, self.sip0);
+            page.write_u64(20, self.sip1);
+            let mut version = [0u8; 2];
+            BigEndian::write_u16(&mut version, FORMAT_VERSION);
+            page.write(28, &version);
+            page.write_pref(30, PRef::from(self.entry_count as u64));
+            for (i, chunk_size) in self.chunk_sizes.iter().enumerate() {
+                page.write_pref(36 + i * 6, PRef::from(*chunk_size));
+            }
+            self.table_file.update_page(page)?;
+        }
+        let mut current: Option<Page> = None;
+        for (bucket_number, link) in stored {
+            let bucket_pref = TableFile::table_offset(bucket_number);
+            if current.as_ref().map_or(true, |page| page.pref() != bucket_pref.this_page()) {
+                if let Some(page) = current.take() {
+                    self.table_file.update_page(page)?;
+                }
+                current = Some(self.table_file.read_page(bucket_pref.this_page())?.unwrap_or(Self::invalid_offsets_page(bucket_pref.this_page())));
+            }
+            if let Some(ref mut page) = current {
+                page.write_pref(bucket_pref.in_page_pos(), link);
+            }
+        }
+        if let Some(page) = current {
+            self.table_file.update_page(page)?;
+        }
         self.table_file.flush()?;
         Ok(())
     }
@@ -221,6 +866,7 @@ impl MemTable {
                 page.write_pref(o*BUCKET_SIZE, PRef::invalid());
             }
         }
+        page.write_checksum();
         page
     }
 
@@ -228,6 +874,13 @@ impl MemTable {
         BucketIterator{file: self, n:0}
     }
 
+    /// keys of data accessible with keys, their data is not read
+    #[allow(unused)]
+    pub fn iter_live_keys<'a>(&'a self) -> impl Iterator<Item=Vec<u8>> +'a {
+        self.slots().flat_map(|slots| slots.into_iter())
+            .filter_map(move |(_, pref)| self.data_file.get_key(pref).ok().and_then(|key| key))
+    }
+
     pub fn buckets<'a>(&'a self) -> impl Iterator<Item=PRef> +'a {
         self.table_file.iter()
     }
@@ -236,62 +889,174 @@ impl MemTable {
         self.data_file.envelopes()
     }
 
+    pub fn dag<'a>(&'a self, root: PRef) -> DagIterator<'a> {
+        self.data_file.dag(root)
+    }
+
+    pub fn data_envelopes_from<'a>(&'a self, pos: PRef) -> EnvelopeIterator<'a> {
+        self.data_file.iter_from(pos)
+    }
+
+    pub fn data_envelopes_range<'a>(&'a self, from: PRef, to: PRef) -> EnvelopeIterator<'a> {
+        self.data_file.iter_range(from, to)
+    }
+
+    /// positions splitting the data file into at most n segments, see DataFile::segment_starts
+    #[cfg(feature = "parallel_reads")]
+    pub fn data_segment_starts(&self, n: usize) -> Result<Vec<PRef>, Error> {
+        self.data_file.segment_starts(n)
+    }
+
+    /// position the next data is appended at
+    pub fn data_position(&self) -> PRef {
+        self.data_file.position()
+    }
+
     pub fn link_envelopes<'a>(&'a self) -> impl Iterator<Item=(PRef, Envelope)> +'a {
         self.link_file.envelopes()
     }
 
-    pub fn append_data(&mut self, key: &[u8], data: &[u8]) -> Result<PRef, Error> {
-        self.data_file.append_data(key, data)
+    pub fn append_data(&mut self, key: &[u8], data: &[u8], referred: &[PRef]) -> Result<PRef, Error> {
+        self.data_file.append_data(key, data, referred)
     }
 
     pub fn append_referred(&mut self, data: &[u8]) -> Result<PRef, Error> {
         self.data_file.append_referred(data)
     }
 
+    pub fn append_referred_with_refs(&mut self, data: &[u8], refs: &[PRef]) -> Result<PRef, Error> {
+        self.data_file.append_referred_with_refs(data, refs)
+    }
+
     pub fn get_envelope(&self, pref: PRef) -> Result<Envelope, Error> {
         self.data_file.get_envelope(pref)
     }
 
+    /// payload at pref, borrowed if not yet written and not compressed
+    pub fn read_envelope_raw<'a>(&'a self, pref: PRef) -> Result<Cow<'a, [u8]>, Error> {
+        self.data_file.read_envelope_raw(pref)
+    }
+
     pub fn put(&mut self, key: &[u8], data_offset: PRef) -> Result<(), Error>{
         let hash = self.hash(key);
         let bucket = self.bucket_for_hash(hash);
 
         self.remove_duplicate(key, hash, bucket)?;
 
-        self.store_to_bucket(bucket, hash, data_offset)?;
-
-        if self.forget == 0 {
-            if hash % self.bucket_fill_target as u32 == 0 && self.step < (1 << 31) {
-                if self.step < (1 << self.log_mod) {
-                    let step = self.step;
-                    self.rehash_bucket(step)?;
-                }
+        self.insert(hash, bucket, data_offset)
+    }
 
-                self.step += 1;
-                if self.step > (1 << (self.log_mod + 1)) {
-                    self.log_mod += 1;
-                    self.step = 0;
-                }
+    /// associate data_offset with a key that is not yet stored
+    /// this skips the search of earlier data of the key, debug builds check that there is none
+    pub fn put_no_dedup(&mut self, key: &[u8], data_offset: PRef) -> Result<(), Error> {
+        #[cfg(debug_assertions)]
+        {
+            // the key of data_offset is already appended
+            if let Some(pref) = self.get_pref(key)? {
+                debug_assert!(false, "key is already stored at {}", pref);
+            }
+        }
+        let hash = self.hash(key);
+        let bucket = self.bucket_for_hash(hash);
+        self.insert(hash, bucket, data_offset)
+    }
 
-                self.buckets.write().unwrap().push(Bucket::default());
-                self.dirty.append();
+    /// associate data_offsets with their keys, growing the hash table only after all are stored
+    pub fn put_all(&mut self, items: &[(&[u8], PRef)]) -> Result<(), Error> {
+        let mut grow = 0;
+        for (key, data_offset) in items {
+            let hash = self.hash(key);
+            let bucket = self.bucket_for_hash(hash);
+            self.remove_duplicate(key, hash, bucket)?;
+            self.store_to_bucket(bucket, hash, *data_offset)?;
+            self.entry_count += 1;
+            self.counters.inserted += 1;
+            if self.should_grow(hash) {
+                grow += 1;
             }
         }
+        for _ in 0 .. grow {
+            if self.step < (1 << 31) {
+                self.grow()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, hash: u32, bucket: usize, data_offset: PRef) -> Result<(), Error> {
+        self.store_to_bucket(bucket, hash, data_offset)?;
+        self.entry_count += 1;
+        self.counters.inserted += 1;
+
+        if self.should_grow(hash) {
+            self.grow()?;
+        }
+        Ok(())
+    }
+
+    // forgotten keys make room for new keys without growth
+    fn should_grow(&mut self, hash: u32) -> bool {
+        if self.forget == 0 {
+            hash % self.bucket_fill_target as u32 == 0 && self.step < (1 << 31)
+        }
         else {
             self.forget -= 1;
+            false
+        }
+    }
+
+    // one step of the linear hash
+    fn grow(&mut self) -> Result<(), Error> {
+        if self.step < (1 << self.log_mod) {
+            let step = self.step;
+            self.rehash_bucket(step)?;
         }
+
+        self.step += 1;
+        if self.step > (1 << (self.log_mod + 1)) {
+            self.log_mod += 1;
+            self.step = 0;
+        }
+
+        self.buckets.write().unwrap().push(Bucket::default());
+        self.dirty.append();
         Ok(())
     }
 
+    /// grow the hash table to at least target_bucket_count buckets and end the batch
+    /// the table does not shrink
+    pub fn rehash_to_target(&mut self, target_bucket_count: usize) -> Result<(), Error> {
+        while self.buckets.read().unwrap().len() < target_bucket_count && self.step < (1 << 31) {
+            self.grow()?;
+        }
+        self.batch()
+    }
+
     pub fn forget(&mut self, key: &[u8]) -> Result<(), Error> {
         let hash = self.hash(key);
         let bucket = self.bucket_for_hash(hash);
         if self.remove_duplicate(key, hash, bucket)? {
             self.forget += 1;
+            self.counters.forgotten += 1;
         }
         Ok(())
     }
 
+    /// forget keys, counting the removals once at the end, returns the number of keys removed
+    pub fn forget_all(&mut self, keys: &[&[u8]]) -> Result<usize, Error> {
+        let mut removed = 0;
+        for key in keys {
+            let hash = self.hash(key);
+            let bucket = self.bucket_for_hash(hash);
+            if self.remove_duplicate(key, hash, bucket)? {
+                removed += 1;
+            }
+        }
+        self.forget += removed;
+        self.counters.forgotten += removed;
+        Ok(removed)
+    }
+
     fn remove_duplicate(&mut self, key: &[u8], hash: u32, bucket_number: usize) -> Result<bool, Error> {
         let mut remove = None;
         self.resolve_bucket(bucket_number)?;
@@ -299,10 +1064,11 @@ impl MemTable {
             if let Some(ref mut slots) = bucket.slots {
                 for (n, (_, pref)) in slots.iter().enumerate()
                     .filter(|s| (s.1).0 == hash) {
-                    let envelope = self.data_file.get_envelope(*pref)?;
-                    if let Payload::Indexed(indexed) = Payload::deserialize(envelope.payload())? {
+                    let payload = self.data_file.read_envelope_raw(*pref)?;
+                    if let Payload::Indexed(indexed) = Payload::deserialize(&payload)? {
                         if indexed.key == key {
                             remove = Some(n);
+                            self.counters.freed_bytes += payload.len() as u64 + 3;
                             break;
                         }
                     }
@@ -313,6 +1079,7 @@ impl MemTable {
             }
         }
         if remove.is_some() {
+            self.entry_count -= 1;
             self.modify_bucket(bucket_number)?;
         }
         Ok(remove.is_some())
@@ -328,7 +1095,7 @@ impl MemTable {
                 bucket.slots = Some(vec!((hash, pref)));
             }
         } else {
-            return Err(Error::Corrupted(format!("memtable does not have the bucket {}", bucket).to_string()))
+            return Err(Error::corrupted(format!("memtable does not have the bucket {}", bucket).to_string()))
         }
         self.modify_bucket(bucket)?;
         Ok(())
@@ -336,7 +1103,8 @@ impl MemTable {
 
     fn rehash_bucket(&mut self, bucket: usize) -> Result<(), Error> {
         let mut rewrite = false;
-        let mut new_bucket_store = Bucket::default();
+        // slots of a bucket left empty must be stored too, else its earlier link remains in the table
+        let mut new_bucket_store = Bucket { slots: Some(Vec::new()), ..Bucket::default() };
         let mut moves = HashMap::new();
         self.resolve_bucket(bucket)?;
         if let Some(b) = self.buckets.read().unwrap().get(bucket as usize) {
@@ -346,19 +1114,14 @@ impl MemTable {
                     if new_bucket != bucket {
                         moves.entry(new_bucket).or_insert(Vec::new()).push((*hash, *pref));
                         rewrite = true;
-                    } else {
-                        if let Some(ref mut slots) = new_bucket_store.slots {
-                            slots.push((*hash, *pref));
-                        }
-                        else {
-                            new_bucket_store.slots = Some(vec!((*hash, *pref)));
-                        }
+                    } else if let Some(ref mut slots) = new_bucket_store.slots {
+                        slots.push((*hash, *pref));
                     }
                 }
             }
         }
         else {
-            return Err(Error::Corrupted(format!("does not have bucket {} for rehash", bucket)));
+            return Err(Error::corrupted(format!("does not have bucket {} for rehash", bucket)));
         }
         if rewrite {
             for (bucket, added) in moves {
@@ -379,7 +1142,9 @@ impl MemTable {
         } else {
             PRef::from(((bucket - BUCKETS_FIRST_PAGE)/BUCKETS_PER_PAGE + 1) as u64 * PAGE_SIZE as u64)
         };
-        self.log_file.log_page(bucket_page, &self.table_file)
+        self.log_file.log_page(bucket_page, &self.table_file)?;
+        // the first page holds the number of keys, recovery must restore it with the buckets
+        self.log_file.log_page(PRef::from(0), &self.table_file)
     }
 
     pub fn may_have_key(&self, key: &[u8]) -> Result<bool, Error> {
@@ -393,34 +1158,161 @@ impl MemTable {
                 }
             }
         } else {
-            return Err(Error::Corrupted(format!("bucket {} should exist", bucket_number)));
+            return Err(Error::corrupted(format!("bucket {} should exist", bucket_number)));
         }
         Ok(false)
     }
 
-    // get the data last associated with the key
-    pub fn get(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>)>, Error> {
+    /// may_have_key for many keys with a single read lock of the buckets
+    /// keys in buckets not resolved yet are reported as maybe present
+    pub fn may_have_key_bulk(&self, keys: &[&[u8]]) -> Result<Vec<bool>, Error> {
+        let hashes = keys.iter().map(|key| self.hash(key)).collect::<Vec<_>>();
+        let buckets = self.buckets.read().unwrap();
+        let mut result = Vec::with_capacity(keys.len());
+        for hash in hashes {
+            let bucket_number = self.bucket_for_hash(hash);
+            match buckets.get(bucket_number) {
+                Some(bucket) => result.push(match bucket.slots {
+                    Some(ref slots) => slots.iter().any(|(h, _)| *h == hash),
+                    None => bucket.stored.is_valid()
+                }),
+                None => return Err(Error::corrupted(format!("bucket {} should exist", bucket_number)))
+            }
+        }
+        Ok(result)
+    }
+
+    // true if the key is stored, data is not read
+    pub fn has_key(&self, key: &[u8]) -> Result<bool, Error> {
+        if !self.may_have_key(key)? {
+            return Ok(false);
+        }
         let hash = self.hash(key);
         let bucket_number = self.bucket_for_hash(hash);
-        self.resolve_bucket(bucket_number)?;
-        if let Some(ref bucket) = self.buckets.read().unwrap().get(bucket_number) {
-            if let Some(ref slots) = bucket.slots {
-                for (h, data) in slots {
-                    if *h == hash {
-                        let envelope = self.data_file.get_envelope(*data)?;
-                        if let Payload::Indexed(indexed) = Payload::deserialize(envelope.payload())? {
-                            if indexed.key == key {
-                                return Ok(Some((*data, indexed.data.data.to_vec())));
-                            }
+        let prefs = match self.buckets.read().unwrap().get(bucket_number) {
+            Some(bucket) => bucket.slots.as_ref().map(|slots|
+                slots.iter().filter(|(h, _)| *h == hash).map(|(_, pref)| *pref).collect::<Vec<_>>()).unwrap_or_default(),
+            None => return Err(Error::corrupted(format!("bucket {} should exist", bucket_number)))
+        };
+        for pref in prefs {
+            match self.data_file.get_key(pref)? {
+                Some(ref k) if k.as_slice() == key => return Ok(true),
+                Some(_) => {},
+                None => return Err(Error::corrupted_at("pref should point to indexed data", &self.data_file.name(), pref))
+            }
+        }
+        Ok(false)
+    }
+
+    // the pref of the first slot whose hash matches the key, without reading data
+    pub fn get_pref_approximate(&self, key: &[u8]) -> Result<Option<PRef>, Error> {
+        let hash = self.hash(key);
+        let bucket_number = self.bucket_for_hash(hash);
+        self.resolve_bucket(bucket_number)?;
+        if let Some(bucket) = self.buckets.read().unwrap().get(bucket_number) {
+            if let Some(ref slots) = bucket.slots {
+                return Ok(slots.iter().find(|(h, _)| *h == hash).map(|(_, pref)| *pref));
+            }
+        } else {
+            return Err(Error::corrupted(format!("bucket {} should exist", bucket_number)));
+        }
+        Ok(None)
+    }
+
+    // the bucket the key is stored in
+    pub fn bucket_for_key(&self, key: &[u8]) -> usize {
+        self.bucket_for_hash(self.hash(key))
+    }
+
+    // all slots of the bucket the key is stored in, colliding or not
+    pub fn get_bucket_pref_chain(&self, key: &[u8]) -> Result<Vec<(u32, PRef)>, Error> {
+        let bucket_number = self.bucket_for_key(key);
+        self.resolve_bucket(bucket_number)?;
+        if let Some(bucket) = self.buckets.read().unwrap().get(bucket_number) {
+            return Ok(bucket.slots.clone().unwrap_or_default());
+        }
+        Err(Error::corrupted(format!("bucket {} should exist", bucket_number)))
+    }
+
+    // get the data last associated with the key and its references to other data
+    pub fn get(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>, Vec<PRef>)>, Error> {
+        let hash = self.hash(key);
+        let bucket_number = self.bucket_for_hash(hash);
+        self.resolve_bucket(bucket_number)?;
+        if let Some(ref bucket) = self.buckets.read().unwrap().get(bucket_number) {
+            if let Some(ref slots) = bucket.slots {
+                for (h, data) in slots {
+                    if *h == hash {
+                        let payload = self.data_file.read_envelope_raw(*data)?;
+                        if let Payload::Indexed(indexed) = Payload::deserialize(&payload)? {
+                            if indexed.key == key {
+                                return Ok(Some((*data, indexed.data.data.to_vec(), indexed.referred)));
+                            }
                         } else {
-                            return Err(Error::Corrupted("pref should point to indexed data".to_string()));
+                            return Err(Error::corrupted_at("pref should point to indexed data", &self.data_file.name(), *data));
                         }
                     }
                 }
             }
         }
         else {
-            return Err(Error::Corrupted(format!("bucket {} should exist", bucket_number)));
+            return Err(Error::corrupted(format!("bucket {} should exist", bucket_number)));
+        }
+        Ok(None)
+    }
+
+    // copy the data last associated with the key into buf, buf is cleared first
+    pub fn get_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<PRef>, Error> {
+        buf.clear();
+        let hash = self.hash(key);
+        let bucket_number = self.bucket_for_hash(hash);
+        self.resolve_bucket(bucket_number)?;
+        if let Some(ref bucket) = self.buckets.read().unwrap().get(bucket_number) {
+            if let Some(ref slots) = bucket.slots {
+                for (h, data) in slots {
+                    if *h == hash {
+                        let payload = self.data_file.read_envelope_raw(*data)?;
+                        if let Payload::Indexed(indexed) = Payload::deserialize(&payload)? {
+                            if indexed.key == key {
+                                buf.extend_from_slice(indexed.data.data);
+                                return Ok(Some(*data));
+                            }
+                        } else {
+                            return Err(Error::corrupted_at("pref should point to indexed data", &self.data_file.name(), *data));
+                        }
+                    }
+                }
+            }
+        }
+        else {
+            return Err(Error::corrupted(format!("bucket {} should exist", bucket_number)));
+        }
+        Ok(None)
+    }
+
+    // get the pref of the data last associated with the key, without copying the data
+    pub fn get_pref(&self, key: &[u8]) -> Result<Option<PRef>, Error> {
+        let hash = self.hash(key);
+        let bucket_number = self.bucket_for_hash(hash);
+        self.resolve_bucket(bucket_number)?;
+        if let Some(ref bucket) = self.buckets.read().unwrap().get(bucket_number) {
+            if let Some(ref slots) = bucket.slots {
+                for (h, data) in slots {
+                    if *h == hash {
+                        let payload = self.data_file.read_envelope_raw(*data)?;
+                        if let Payload::Indexed(indexed) = Payload::deserialize(&payload)? {
+                            if indexed.key == key {
+                                return Ok(Some(*data));
+                            }
+                        } else {
+                            return Err(Error::corrupted_at("pref should point to indexed data", &self.data_file.name(), *data));
+                        }
+                    }
+                }
+            }
+        }
+        else {
+            return Err(Error::corrupted(format!("bucket {} should exist", bucket_number)));
         }
         Ok(None)
     }
@@ -475,6 +1367,14 @@ impl Dirty {
         self.bits.iter().any(|n| *n != 0)
     }
 
+    /// positions of set bits, clean words are skipped
+    pub fn iter_set_bits<'a>(&'a self) -> impl Iterator<Item=usize> + 'a {
+        let used = self.used;
+        self.bits.iter().enumerate()
+            .flat_map(|(n, word)| SetBits { word: *word, base: n << 6 })
+            .take_while(move |n| *n < used)
+    }
+
     pub fn append(&mut self) {
         self.used += 1;
         if self.used >= (self.bits.len() << 6) {
@@ -510,34 +1410,31 @@ impl<'a> Iterator for BucketIterator<'a> {
     }
 }
 
-struct DirtyIterator<'b> {
-    bits: &'b Dirty,
-    pos: usize
+// positions of set bits in a word
+struct SetBits {
+    word: u64,
+    base: usize
 }
 
-impl<'b> DirtyIterator<'b> {
-    pub fn new(bits: &'b Dirty) -> DirtyIterator<'b> {
-        DirtyIterator {bits, pos: 0}
-    }
-}
-
-impl<'b> Iterator for DirtyIterator<'b> {
-    type Item = bool;
+impl Iterator for SetBits {
+    type Item = usize;
 
     fn next(&mut self) -> Option<<Self as Iterator>::Item> {
-        if self.pos < self.bits.used {
-            let pos = self.pos;
-            self.pos += 1;
-            return Some(self.bits.get(pos));
+        if self.word == 0 {
+            return None;
         }
-        return None;
+        let n = self.word.trailing_zeros() as usize;
+        self.word &= self.word - 1;
+        Some(self.base + n)
     }
 }
 
 #[derive(Clone, Default)]
 pub struct Bucket {
     stored: PRef,
-    slots: Option<Vec<(u32, PRef)>>
+    slots: Option<Vec<(u32, PRef)>>,
+    // generation of last access
+    accessed: u64
 }
 
 
@@ -546,11 +1443,15 @@ mod test {
     extern crate rand;
 
     use transient::Transient;
+    use rolledfile::RolledFile;
 
     use super::*;
     use self::rand::thread_rng;
-    use std::collections::HashMap;
+    use std::collections::{HashMap, HashSet};
+    use std::{env, fs, process};
+    use std::path::Path;
     use self::rand::RngCore;
+    use byteorder::ByteOrder;
 
     #[test]
     fn test_dirty() {
@@ -568,6 +1469,16 @@ mod test {
         assert!(dirty.get(65));
     }
 
+    #[test]
+    fn test_iter_set_bits() {
+        let mut dirty = Dirty::new(200);
+        assert_eq!(dirty.iter_set_bits().count(), 0);
+        for n in &[127, 0, 64, 63] {
+            dirty.set(*n);
+        }
+        assert_eq!(dirty.iter_set_bits().collect::<Vec<_>>(), vec!(0, 63, 64, 127));
+    }
+
         #[test]
     fn test() {
         let mut db = Transient::new_db("first", 1, 1).unwrap();
@@ -596,5 +1507,919 @@ mod test {
 
         db.shutdown();
     }
-}
 
+    #[test]
+    fn test_estimated_memory_usage() {
+        let mut mem = new_memtable();
+
+        let mut rng = thread_rng();
+        let mut key = [0x0u8;32];
+        let mut keys = Vec::new();
+
+        for _ in 0 .. 10000 {
+            rng.fill_bytes(&mut key);
+            let o = mem.append_data(&key, &key, &[]).unwrap();
+            mem.put(&key, o).unwrap();
+            keys.push(key);
+        }
+        mem.batch().unwrap();
+
+        let usage = mem.estimated_memory_usage();
+        assert!(usage > 100 * 1024 && usage < 10 * 1024 * 1024);
+        for k in &keys[0 .. 1000] {
+            mem.forget(k).unwrap();
+        }
+        assert!(mem.estimated_memory_usage() < usage);
+        mem.shutdown();
+    }
+
+    #[test]
+    fn test_iter_reachable() {
+        let mut mem = new_memtable();
+
+        let mut referred = Vec::new();
+        for i in 0u8 .. 3 {
+            let mut data = vec!();
+            for j in 0u8 .. 2 {
+                let pref = mem.append_referred(&[i, j]).unwrap();
+                referred.push(pref);
+                data.extend_from_slice(&pref.as_u64().to_be_bytes()[2..]);
+            }
+            let key = [i];
+            let o = mem.append_data(&key, &data, &[]).unwrap();
+            mem.put(&key, o).unwrap();
+        }
+        mem.forget(&[1u8]).unwrap();
+        mem.batch().unwrap();
+
+        let mut live_prefs = HashSet::new();
+        for slots in mem.slots() {
+            for (_, pref) in slots {
+                if let Payload::Indexed(indexed) = Payload::deserialize(mem.get_envelope(pref).unwrap().payload()).unwrap() {
+                    for r in indexed.data.data.chunks(6) {
+                        live_prefs.insert(PRef::from(BigEndian::read_u48(r)));
+                    }
+                }
+            }
+        }
+        let reachable = mem.data_file.iter_reachable(&live_prefs).map(|(pref, _)| pref).collect::<Vec<_>>();
+        assert_eq!(reachable, vec!(referred[0], referred[1], referred[4], referred[5]));
+        mem.shutdown();
+    }
+
+    #[test]
+    fn test_evict_cold_buckets() {
+        let mut mem = new_memtable();
+
+        let mut rng = thread_rng();
+        let mut key = [0x0u8;32];
+        let mut keys = Vec::new();
+        for _ in 0 .. 5000 {
+            rng.fill_bytes(&mut key);
+            let o = mem.append_data(&key, &key, &[]).unwrap();
+            mem.put(&key, o).unwrap();
+            keys.push(key);
+        }
+        mem.batch().unwrap();
+        assert!(mem.buckets.read().unwrap().len() >= 1000);
+
+        let mut accessed = HashSet::new();
+        for k in &keys {
+            if accessed.len() == 100 {
+                break;
+            }
+            assert!(mem.get(&k[..]).unwrap().is_some());
+            accessed.insert(mem.bucket_for_hash(mem.hash(&k[..])));
+        }
+        let resolved = mem.buckets.read().unwrap().iter().filter(|b| b.slots.is_some()).count();
+        assert_eq!(mem.evict_cold_buckets(100), resolved - 100);
+        for (n, bucket) in mem.buckets.read().unwrap().iter().enumerate() {
+            assert_eq!(bucket.slots.is_some(), accessed.contains(&n));
+        }
+
+        for k in &keys {
+            assert_eq!(mem.get(&k[..]).unwrap().unwrap().1, k.to_vec());
+        }
+        mem.shutdown();
+    }
+
+    #[cfg(feature = "parallel_reads")]
+    #[test]
+    fn test_for_each_slot_parallel() {
+        let mut mem = new_memtable();
+
+        let mut rng = thread_rng();
+        let mut key = [0x0u8;32];
+        for _ in 0 .. 5000 {
+            rng.fill_bytes(&mut key);
+            let o = mem.append_data(&key, &key, &[]).unwrap();
+            mem.put(&key, o).unwrap();
+        }
+        mem.batch().unwrap();
+
+        let mut parallel = HashMap::new();
+        for len in mem.for_each_slot_parallel(|_, slots| slots.len()) {
+            *parallel.entry(len).or_insert(0) += 1;
+        }
+        let mut sequential = HashMap::new();
+        for bucket in mem.buckets.read().unwrap().iter() {
+            if let Some(ref slots) = bucket.slots {
+                *sequential.entry(slots.len()).or_insert(0) += 1;
+            }
+        }
+        assert_eq!(parallel, sequential);
+        mem.shutdown();
+    }
+
+    #[test]
+    fn test_one_link_per_bucket_and_batch() {
+        let mut mem = new_memtable();
+
+        let mut rng = thread_rng();
+        let mut key = [0x0u8;32];
+        let mut bucket = None;
+        let mut n = 0;
+        while n < 10 {
+            rng.fill_bytes(&mut key);
+            let b = mem.bucket_for_hash(mem.hash(&key[..]));
+            if *bucket.get_or_insert(b) == b {
+                let o = mem.append_data(&key, &key, &[]).unwrap();
+                mem.put(&key, o).unwrap();
+                n += 1;
+            }
+        }
+        mem.batch().unwrap();
+
+        // slots of a bucket are written to the link file once at the end of a batch
+        // rehashing while inserting might have modified other buckets too
+        let links = mem.link_envelopes()
+            .filter(|(_, e)| if let Ok(Payload::Link(_)) = Payload::deserialize(e.payload()) { true } else { false })
+            .count();
+        let stored = mem.buckets.read().unwrap().iter().filter(|b| b.stored.is_valid()).count();
+        assert_eq!(links, stored);
+        mem.shutdown();
+    }
+
+    fn new_memtable() -> MemTable {
+        let log = LogFile::new(Box::new(Transient::new(true)));
+        let table = TableFile::new(Box::new(Transient::new(false))).unwrap();
+        let data = DataFile::new(Box::new(Transient::new(true))).unwrap();
+        let link = DataFile::new(Box::new(Transient::new(true))).unwrap();
+        let mut mem = MemTable::new(log, table, data, link, 1);
+        mem.recover().unwrap();
+        mem.load().unwrap();
+        mem.batch().unwrap();
+        mem
+    }
+
+    #[test]
+    fn test_compact_link_file() {
+        let mut mem = new_memtable();
+
+        let mut rng = thread_rng();
+        let mut keys = Vec::new();
+        for _ in 0 .. 50 {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            keys.push(key);
+        }
+        // modify the buckets of the keys twice
+        for round in 0u8 .. 2 {
+            for key in &keys {
+                let pref = mem.append_data(key, &[round], &[]).unwrap();
+                mem.put(key, pref).unwrap();
+            }
+            mem.batch().unwrap();
+        }
+        let links = mem.link_file.count_by_type().links;
+        let stored = mem.buckets.read().unwrap().iter().filter(|b| b.stored.is_valid()).count();
+
+        let stats = mem.compact_link_file().unwrap();
+        assert_eq!(stats.bytes_after * 2, stats.bytes_before);
+        assert_eq!(stats.entries_removed, links - stored);
+        assert_eq!(mem.link_file.count_by_type().links, stored);
+
+        // reload buckets from the compacted links
+        mem.evict_cold_buckets(0);
+        for key in &keys {
+            assert_eq!(mem.get(key).unwrap().unwrap().1, vec!(1));
+        }
+        mem.validate_consistency().unwrap();
+        mem.shutdown();
+    }
+
+    // a memtable of files in dir with chunks of a few pages
+    fn persistent_files(dir: &Path) -> MemTable {
+        let name = dir.join("db").to_string_lossy().to_string();
+        let chunk = 2 * PAGE_SIZE as u64;
+        let log = LogFile::new(Box::new(RolledFile::new(&name, "lg", true, chunk).unwrap()));
+        let table = TableFile::new(Box::new(RolledFile::new(&name, "tb", false, chunk).unwrap())).unwrap();
+        let data = DataFile::new(Box::new(RolledFile::new(&name, "bc", true, chunk).unwrap())).unwrap();
+        let link = DataFile::new(Box::new(RolledFile::new(&name, "bl", true, chunk).unwrap())).unwrap();
+        MemTable::new(log, table, data, link, 1)
+    }
+
+    fn persistent_memtable(dir: &Path) -> MemTable {
+        let mut mem = persistent_files(dir);
+        mem.recover().unwrap();
+        mem.load().unwrap();
+        mem.batch().unwrap();
+        mem
+    }
+
+    fn replacement_files(dir: &Path) -> usize {
+        fs::read_dir(dir).unwrap().filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().ends_with(".new")).count()
+    }
+
+    #[test]
+    fn test_compact_link_file_crash() {
+        let dir = env::temp_dir().join(format!("hammersbald_test_compact_link_file_crash_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut mem = persistent_memtable(&dir);
+
+        let mut rng = thread_rng();
+        let mut keys = Vec::new();
+        for _ in 0 .. 200 {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            keys.push(key);
+        }
+        for round in 0u8 .. 2 {
+            for key in &keys {
+                let pref = mem.append_data(key, &[round], &[]).unwrap();
+                mem.put(key, pref).unwrap();
+            }
+            mem.batch().unwrap();
+        }
+        let links_before = mem.link_file.len().unwrap();
+
+        // crash after writing the replacements, before the log recorded them
+        let n = mem.buckets.read().unwrap().len();
+        for bucket_number in 0 .. n {
+            mem.resolve_bucket(bucket_number).unwrap();
+        }
+        let (_, link, table) = mem.write_replacements(None).unwrap();
+        let links_after = link.len().unwrap();
+        assert!(links_after * 2 <= links_before);
+        drop((link, table));
+        mem.shutdown();
+        drop(mem);
+        assert!(replacement_files(&dir) > 0);
+
+        let mut mem = persistent_memtable(&dir);
+        assert_eq!(replacement_files(&dir), 0);
+        assert_eq!(mem.link_file.len().unwrap(), links_before);
+        for key in &keys {
+            assert_eq!(mem.get(key).unwrap().unwrap().1, vec!(1));
+        }
+
+        // crash after the log recorded the replacements and the link file was swapped in
+        for bucket_number in 0 .. n {
+            mem.resolve_bucket(bucket_number).unwrap();
+        }
+        let (_, link, table) = mem.write_replacements(None).unwrap();
+        let (data_len, table_len, link_len) = (mem.data_file.len().unwrap(), table.len().unwrap(), link.len().unwrap());
+        drop((link, table));
+        mem.log_file.init_replacement(data_len, table_len, link_len, &HashMap::new()).unwrap();
+        mem.log_file.sync().unwrap();
+        mem.link_file.complete_replacement(true).unwrap();
+        mem.shutdown();
+        drop(mem);
+
+        let mut mem = persistent_memtable(&dir);
+        assert_eq!(replacement_files(&dir), 0);
+        assert_eq!(mem.link_file.len().unwrap(), links_after);
+        for key in &keys {
+            assert_eq!(mem.get(key).unwrap().unwrap().1, vec!(1));
+        }
+        mem.validate_consistency().unwrap();
+
+        // compacting again finds nothing to remove
+        let stats = mem.compact_link_file().unwrap();
+        assert_eq!((stats.bytes_before, stats.bytes_after, stats.entries_removed), (links_after, links_after, 0));
+        mem.shutdown();
+        drop(mem);
+        let mut mem = persistent_memtable(&dir);
+        for key in &keys {
+            assert_eq!(mem.get(key).unwrap().unwrap().1, vec!(1));
+        }
+        mem.shutdown();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_iter_live_keys() {
+        let mut mem = new_memtable();
+
+        let mut rng = thread_rng();
+        let mut keys = HashSet::new();
+        for _ in 0 .. 100 {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            let pref = mem.append_data(&key, &[1u8; 4000], &[]).unwrap();
+            mem.put(&key, pref).unwrap();
+            keys.insert(key.to_vec());
+        }
+        mem.append_referred(&[2u8; 4000]).unwrap();
+        // forgotten and overwritten keys are not live
+        let forgotten = keys.iter().next().unwrap().clone();
+        mem.forget(&forgotten).unwrap();
+        keys.remove(&forgotten);
+        let key = keys.iter().next().unwrap().clone();
+        let pref = mem.append_data(&key, &[3u8; 4000], &[]).unwrap();
+        mem.put(&key, pref).unwrap();
+        mem.batch().unwrap();
+
+        let live = mem.iter_live_keys().collect::<Vec<_>>();
+        assert_eq!(live.len(), 99);
+        assert_eq!(live.into_iter().collect::<HashSet<_>>(), keys);
+        mem.shutdown();
+    }
+
+    #[test]
+    fn test_rehash_to_target() {
+        let log = LogFile::new(Box::new(Transient::new(true)));
+        let table = TableFile::new(Box::new(Transient::new(false))).unwrap();
+        let data = DataFile::new(Box::new(Transient::new(true))).unwrap();
+        let link = DataFile::new(Box::new(Transient::new(true))).unwrap();
+        let mut mem = MemTable::new(log, table, data, link, 128);
+        mem.recover().unwrap();
+        mem.load().unwrap();
+        mem.batch().unwrap();
+
+        let mut rng = thread_rng();
+        let mut check = HashMap::new();
+        for _ in 0 .. 1000 {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            let pref = mem.append_data(&key, &key, &[]).unwrap();
+            mem.put(&key, pref).unwrap();
+            check.insert(key, pref);
+        }
+        let before = mem.buckets.read().unwrap().len();
+        mem.rehash_to_target(4 * before).unwrap();
+        assert!(mem.buckets.read().unwrap().len() >= 4 * before);
+        for (key, pref) in &check {
+            assert_eq!(mem.get_pref(key).unwrap(), Some(*pref));
+        }
+        mem.validate_consistency().unwrap();
+
+        // does not shrink
+        let len = mem.buckets.read().unwrap().len();
+        mem.rehash_to_target(128).unwrap();
+        assert_eq!(mem.buckets.read().unwrap().len(), len);
+        mem.shutdown();
+    }
+
+    #[test]
+    fn test_validate_consistency() {
+        let mut mem = new_memtable();
+
+        let mut rng = thread_rng();
+        let mut key = [0x0u8;32];
+        for _ in 0 .. 1000 {
+            rng.fill_bytes(&mut key);
+            let pref = mem.append_data(&key, &[1], &[]).unwrap();
+            mem.put(&key, pref).unwrap();
+        }
+        mem.batch().unwrap();
+        mem.validate_consistency().unwrap();
+
+        {
+            let mut buckets = mem.buckets.write().unwrap();
+            let slots = buckets.iter_mut().filter_map(|b| b.slots.as_mut()).find(|s| !s.is_empty()).unwrap();
+            slots[0].0 ^= 1 << 31;
+        }
+        assert!(mem.validate_consistency().is_err());
+        mem.shutdown();
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut mem = new_memtable();
+
+        let mut rng = thread_rng();
+        let mut key = [0x0u8;32];
+        let mut data = [0x0u8;40];
+        let mut check = HashMap::new();
+
+        for _ in 0 .. 1000 {
+            rng.fill_bytes(&mut key);
+            rng.fill_bytes(&mut data);
+            let o = mem.append_data(&key, &data, &[]).unwrap();
+            mem.put(&key, o).unwrap();
+            check.insert(key, (o, data.to_vec()));
+        }
+        mem.batch().unwrap();
+        assert!(mem.is_clean());
+
+        let mut snapshot = vec!();
+        let written = mem.serialize(&mut snapshot).unwrap();
+        assert_eq!(written, snapshot.len() as u64);
+
+        let params = mem.params();
+        mem.buckets = RwLock::new(vec!(Bucket::default(); INIT_BUCKETS));
+        assert!(mem.restore(&mut snapshot.as_slice()).unwrap());
+        assert_eq!(mem.params(), params);
+        for (k, (o, data)) in &check {
+            assert_eq!(mem.get(&k[..]).unwrap().unwrap(), (*o, data.clone(), vec!()));
+        }
+
+        // a snapshot of different files is refused
+        rng.fill_bytes(&mut key);
+        let o = mem.append_data(&key, &data, &[]).unwrap();
+        mem.put(&key, o).unwrap();
+        mem.batch().unwrap();
+        assert!(!mem.restore(&mut snapshot.as_slice()).unwrap());
+        assert!(mem.get(&key[..]).unwrap().is_some());
+
+        mem.shutdown();
+    }
+
+    #[test]
+    fn test_referred() {
+        let mut mem = new_memtable();
+
+        let referred = (0 .. 3).map(|i| mem.append_referred(&[i]).unwrap()).collect::<Vec<_>>();
+        let pref = mem.append_data(b"with", b"data", &referred).unwrap();
+        mem.put(b"with", pref).unwrap();
+        let without = mem.append_data(b"without", b"data", &[]).unwrap();
+        mem.put(b"without", without).unwrap();
+        mem.batch().unwrap();
+
+        assert_eq!(mem.get(b"with").unwrap().unwrap(), (pref, b"data".to_vec(), referred));
+        assert_eq!(mem.get(b"without").unwrap().unwrap(), (without, b"data".to_vec(), vec!()));
+        mem.shutdown();
+    }
+
+    #[test]
+    fn test_format_version() {
+        let mut mem = new_memtable();
+        let mut first = mem.table_file.read_page(PRef::from(0)).unwrap().unwrap();
+        first.write(28, &[0, 1]);
+        mem.table_file.update_page(first).unwrap();
+        match mem.load() {
+            Err(Error::Corrupted{..}) => {},
+            _ => panic!("a database of an earlier format should be rejected")
+        }
+        mem.shutdown();
+    }
+
+    #[test]
+    fn test_torn_log_page() {
+        let mut mem = new_memtable();
+
+        let mut rng = thread_rng();
+        let mut keys = Vec::new();
+        for _ in 0 .. 1000 {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            let pref = mem.append_data(&key, &key, &[]).unwrap();
+            mem.put(&key, pref).unwrap();
+            keys.push(key);
+        }
+        mem.batch().unwrap();
+
+        // crash after the log of the next batch was written but the last half of a page was lost
+        let mut lost = Vec::new();
+        for _ in 0 .. 1000 {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            let pref = mem.append_data(&key, &key, &[]).unwrap();
+            mem.put(&key, pref).unwrap();
+            lost.push(key);
+        }
+        mem.log_file.flush().unwrap();
+        let pages = mem.log_file.page_iter().collect::<Vec<_>>();
+        assert!(pages.len() > 3);
+        mem.log_file.truncate(0).unwrap();
+        for (i, page) in pages.into_iter().enumerate() {
+            if i == 2 {
+                let mut buf = page.into_buf();
+                for b in buf[PAGE_SIZE/2 ..].iter_mut() {
+                    *b = 0;
+                }
+                mem.log_file.append_page(Page::from_buf(buf)).unwrap();
+            } else {
+                mem.log_file.append_page(page).unwrap();
+            }
+        }
+        mem.log_file.flush().unwrap();
+
+        mem.recover().unwrap();
+        mem.load().unwrap();
+        assert_eq!(mem.log_file.len().unwrap(), PAGE_SIZE as u64);
+        for key in &keys {
+            assert_eq!(mem.get(key).unwrap().unwrap().1, key.to_vec());
+        }
+        for key in &lost {
+            assert!(mem.get(key).unwrap().is_none());
+        }
+        mem.validate_consistency().unwrap();
+        mem.shutdown();
+    }
+
+    #[test]
+    fn test_batch_at_step_boundary() {
+        let mut mem = new_memtable();
+
+        let mut rng = thread_rng();
+        let mut keys = Vec::new();
+        // each put grows the table by one step with a bucket fill target of 1
+        while mem.step < 1 << (mem.log_mod + 1) {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            let pref = mem.append_data(&key, &key, &[]).unwrap();
+            mem.put(&key, pref).unwrap();
+            keys.push(key);
+        }
+        assert_eq!(mem.step, 512);
+        mem.batch().unwrap();
+        mem.validate_consistency().unwrap();
+
+        let mut key = [0x0u8;32];
+        rng.fill_bytes(&mut key);
+        let pref = mem.append_data(&key, &key, &[]).unwrap();
+        mem.put(&key, pref).unwrap();
+        keys.push(key);
+        assert_eq!((mem.step, mem.log_mod), (0, INIT_LOGMOD as u32 + 1));
+        mem.batch().unwrap();
+        mem.validate_consistency().unwrap();
+        for key in &keys {
+            assert_eq!(mem.get(key).unwrap().unwrap().1, key.to_vec());
+        }
+        mem.shutdown();
+    }
+
+    // write data and modified table pages to disk as batch does, but crash before the log is reset
+    fn write_without_batch(mem: &mut MemTable) {
+        mem.log_file.flush().unwrap();
+        mem.log_file.sync().unwrap();
+        mem.data_file.flush().unwrap();
+        mem.data_file.sync().unwrap();
+        mem.flush_with_progress(&|_| {}).unwrap();
+        mem.link_file.sync().unwrap();
+        mem.table_file.sync().unwrap();
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let mut mem = new_memtable();
+
+        let mut rng = thread_rng();
+        let mut keys = Vec::new();
+        for _ in 0 .. 100 {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            let pref = mem.append_data(&key, &key, &[]).unwrap();
+            mem.put(&key, pref).unwrap();
+            keys.push(key);
+        }
+        mem.batch().unwrap();
+        let batches = mem.counters.batches;
+
+        // crash after checkpoints of the next batch
+        for _ in 0 .. 10 {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            let pref = mem.append_data(&key, &key, &[]).unwrap();
+            mem.put(&key, pref).unwrap();
+            mem.checkpoint().unwrap();
+            assert!(mem.is_clean());
+            keys.push(key);
+        }
+        assert_eq!(mem.counters.batches, batches);
+        // stored after the last checkpoint
+        let mut lost = [0x0u8;32];
+        rng.fill_bytes(&mut lost);
+        let pref = mem.append_data(&lost, &lost, &[]).unwrap();
+        mem.put(&lost, pref).unwrap();
+        write_without_batch(&mut mem);
+
+        mem.recover().unwrap();
+        mem.load().unwrap();
+        for key in &keys {
+            assert_eq!(mem.get(key).unwrap().unwrap().1, key.to_vec());
+        }
+        assert!(mem.get(&lost).unwrap().is_none());
+        mem.validate_consistency().unwrap();
+        mem.shutdown();
+    }
+
+    #[test]
+    fn test_recover_torn_first_table_page() {
+        let dir = env::temp_dir().join(format!("hammersbald_test_recover_torn_first_table_page_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut mem = persistent_memtable(&dir);
+
+        let mut rng = thread_rng();
+        let mut keys = Vec::new();
+        for _ in 0 .. 100 {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            let pref = mem.append_data(&key, &key, &[]).unwrap();
+            mem.put(&key, pref).unwrap();
+            keys.push(key);
+        }
+        mem.batch().unwrap();
+
+        // crash while the batch overwrote the first table page
+        let mut lost = Vec::new();
+        for _ in 0 .. 10 {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            let pref = mem.append_data(&key, &key, &[]).unwrap();
+            mem.put(&key, pref).unwrap();
+            lost.push(key);
+        }
+        write_without_batch(&mut mem);
+        mem.shutdown();
+        drop(mem);
+        let table = dir.join("db.0.tb");
+        let mut content = fs::read(&table).unwrap();
+        for b in &mut content[.. 512] {
+            *b = 0;
+        }
+        fs::write(&table, content).unwrap();
+
+        let mut mem = persistent_files(&dir);
+        let stats = mem.recover_with_stats().unwrap();
+        assert!(stats.pages_replayed > 0);
+        assert_eq!(stats.pages_skipped, 0);
+        mem.load().unwrap();
+        for key in &keys {
+            assert_eq!(mem.get(key).unwrap().unwrap().1, key.to_vec());
+        }
+        for key in &lost {
+            assert!(mem.get(key).unwrap().is_none());
+        }
+        mem.validate_consistency().unwrap();
+        mem.shutdown();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_apply_log_to_copy() {
+        let mut mem = new_memtable();
+
+        let mut rng = thread_rng();
+        let mut keys = Vec::new();
+        for _ in 0 .. 1000 {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            let pref = mem.append_data(&key, &key, &[]).unwrap();
+            mem.put(&key, pref).unwrap();
+            keys.push(key);
+        }
+        mem.batch().unwrap();
+        let mut batched = Transient::new(false);
+        mem.table_file.copy_to(&mut batched).unwrap();
+
+        for _ in 0 .. 1000 {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            let pref = mem.append_data(&key, &key, &[]).unwrap();
+            mem.put(&key, pref).unwrap();
+        }
+        write_without_batch(&mut mem);
+
+        // copies of the table written before the crash and of the log of the batch
+        let mut copy = Transient::new(false);
+        mem.table_file.copy_to(&mut copy).unwrap();
+        let mut table = TableFile::new(Box::new(copy)).unwrap();
+        let mut log_copy = Transient::new(true);
+        mem.log_file.copy_to(&mut log_copy).unwrap();
+        let mut log = LogFile::new(Box::new(log_copy));
+        log.set_checksum_keys(mem.sip0, mem.sip1);
+
+        assert_eq!(log.apply_to_with_filter(&mut table, |pref| pref == PRef::from(0)).unwrap(), 1);
+        assert!(log.apply_to(&mut table).unwrap() > 1);
+        // the copy is reverted to the table of the batch
+        for n in 0 .. batched.len().unwrap() / PAGE_SIZE as u64 {
+            let pref = PRef::from(n * PAGE_SIZE as u64);
+            assert_eq!(table.read_page(pref).unwrap().unwrap().slice(0, PAGE_SIZE), batched.read_page(pref).unwrap().unwrap().slice(0, PAGE_SIZE));
+        }
+        mem.shutdown();
+    }
+
+    #[test]
+    fn test_bucket_fill_histogram() {
+        let mut mem = new_memtable();
+
+        let mut rng = thread_rng();
+        for _ in 0 .. 10000 {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            let pref = mem.append_data(&key, &key, &[]).unwrap();
+            mem.put(&key, pref).unwrap();
+        }
+        mem.batch().unwrap();
+
+        let histogram = mem.bucket_fill_histogram().unwrap();
+        assert_eq!(histogram.iter().sum::<usize>(), mem.buckets.read().unwrap().len());
+        assert_eq!(histogram.iter().enumerate().map(|(fill, n)| fill * n).sum::<usize>(), mem.entry_count());
+        assert!(mem.max_bucket_depth().unwrap() < 20);
+        assert_eq!(mem.max_bucket_depth().unwrap(), histogram.len() - 1);
+        mem.shutdown();
+    }
+
+    #[test]
+    fn test_may_have_key_bulk() {
+        let mut mem = new_memtable();
+
+        let mut rng = thread_rng();
+        let mut keys = Vec::new();
+        for _ in 0 .. 100 {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            let pref = mem.append_data(&key, &key, &[]).unwrap();
+            mem.put(&key, pref).unwrap();
+            keys.push(key);
+        }
+        mem.batch().unwrap();
+        let refs = keys.iter().map(|k| &k[..]).collect::<Vec<_>>();
+        assert!(mem.may_have_key_bulk(&refs).unwrap().iter().all(|b| *b));
+
+        // unresolved buckets are maybe present until resolved
+        mem.evict_cold_buckets(0);
+        let bucket = mem.bucket_for_key(&keys[0]);
+        assert!(mem.buckets.read().unwrap()[bucket].slots.is_none());
+        assert!(mem.may_have_key_bulk(&[&keys[0][..]]).unwrap()[0]);
+        assert!(mem.may_have_key(&keys[0]).unwrap());
+        assert!(mem.buckets.read().unwrap()[bucket].slots.is_some());
+        assert!(mem.may_have_key_bulk(&[&keys[0][..]]).unwrap()[0]);
+    }
+
+    #[test]
+    fn test_recovery_stats() {
+        let mut mem = new_memtable();
+
+        let mut rng = thread_rng();
+        for _ in 0 .. 1000 {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            let pref = mem.append_data(&key, &key, &[]).unwrap();
+            mem.put(&key, pref).unwrap();
+        }
+        mem.batch().unwrap();
+        for _ in 0 .. 1000 {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            let pref = mem.append_data(&key, &key, &[]).unwrap();
+            mem.put(&key, pref).unwrap();
+        }
+        mem.log_file.flush().unwrap();
+
+        // a zero page instead of the last logged page, before the checksum page
+        let pages = mem.log_file.page_iter().collect::<Vec<_>>();
+        let logged = pages.iter().skip(1).filter(|page| page.pref().is_valid()).count();
+        let zeroed = pages.len() - 2;
+        assert!(pages[zeroed].pref().is_valid() && !pages[zeroed + 1].pref().is_valid());
+        mem.log_file.truncate(0).unwrap();
+        for (i, page) in pages.into_iter().enumerate() {
+            mem.log_file.append_page(if i == zeroed { Page::new() } else { page }).unwrap();
+        }
+        mem.log_file.flush().unwrap();
+
+        let stats = mem.recover_with_stats().unwrap();
+        assert_eq!(stats.pages_skipped, 1);
+        assert_eq!(stats.pages_replayed, logged - 1);
+        assert_eq!(stats.truncated_bytes, 2 * PAGE_SIZE as u64);
+        assert!(stats.to_string().contains("skipped 1 pages"));
+
+        // nothing to do for a clean log
+        assert_eq!(mem.recover_with_stats().unwrap(), RecoveryStats::default());
+    }
+
+    #[test]
+    fn test_compact_data_file() {
+        let mut mem = new_memtable();
+
+        let mut rng = thread_rng();
+        let mut keys = Vec::new();
+        for _ in 0 .. 5000 {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            let pref = mem.append_data(&key, &key, &[]).unwrap();
+            mem.put(&key, pref).unwrap();
+            keys.push(key);
+        }
+        mem.batch().unwrap();
+        for key in &keys[1000 ..] {
+            mem.forget(key).unwrap();
+        }
+        mem.batch().unwrap();
+        let len = mem.data_file.len().unwrap();
+
+        let reclaimed = mem.compact_data_file().unwrap();
+        assert!(reclaimed > 0);
+        assert_eq!(mem.data_file.len().unwrap(), len - reclaimed);
+        assert_eq!(mem.data_file.count_by_type().indexed, 1000);
+
+        mem.evict_cold_buckets(0);
+        for key in &keys[.. 1000] {
+            assert_eq!(mem.get(key).unwrap().unwrap().1, key.to_vec());
+        }
+        for key in &keys[1000 ..] {
+            assert!(mem.get(key).unwrap().is_none());
+        }
+        mem.validate_consistency().unwrap();
+        mem.shutdown();
+    }
+
+    #[test]
+    fn test_compact_data_file_referred() {
+        let mut mem = new_memtable();
+        let forgotten = mem.append_referred(&[1]).unwrap();
+        let pref = mem.append_data(&[1], &[1], &[forgotten]).unwrap();
+        mem.put(&[1], pref).unwrap();
+        let kept = mem.append_referred(&[2]).unwrap();
+        let pref = mem.append_data(&[2], &[2], &[kept]).unwrap();
+        mem.put(&[2], pref).unwrap();
+        mem.forget(&[1]).unwrap();
+
+        mem.compact_data_file().unwrap();
+        assert_eq!(mem.data_file.count_by_type().indexed, 1);
+        let (_, data, referred) = mem.get(&[2]).unwrap().unwrap();
+        assert_eq!(data, vec!(2));
+        // the list points to the copy of the referred data
+        assert_ne!(referred, vec!(kept));
+        match Payload::deserialize(mem.data_file.get_envelope(referred[0]).unwrap().payload()).unwrap() {
+            Payload::Referred(data) => assert_eq!(data.data, &[2]),
+            _ => panic!("referred data expected")
+        }
+        assert_eq!(mem.data_file.count_by_type().referred, 1);
+
+        // referred data no key lists might be held by pref elsewhere
+        let unlisted = mem.append_referred(&[3]).unwrap();
+        let len = mem.data_file.len().unwrap();
+        match mem.compact_data_file() {
+            Err(Error::InvalidArgument(_)) => {},
+            _ => panic!("compaction should refuse to remove unlisted referred data")
+        }
+        assert!(mem.data_file.len().unwrap() >= len);
+        assert_eq!(mem.data_file.get_envelope(unlisted).unwrap().payload()[4 ..], [3]);
+        assert_eq!(mem.get(&[2]).unwrap().unwrap().1, vec!(2));
+        mem.validate_consistency().unwrap();
+        mem.shutdown();
+    }
+
+    #[test]
+    fn test_compact_data_file_crash() {
+        let dir = env::temp_dir().join(format!("hammersbald_test_compact_data_file_crash_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut mem = persistent_memtable(&dir);
+
+        let mut rng = thread_rng();
+        let mut keys = Vec::new();
+        for _ in 0 .. 200 {
+            let mut key = [0x0u8;32];
+            rng.fill_bytes(&mut key);
+            keys.push(key);
+        }
+        for round in 0u8 .. 2 {
+            for key in &keys {
+                let pref = mem.append_data(key, &[round], &[]).unwrap();
+                mem.put(key, pref).unwrap();
+            }
+            mem.batch().unwrap();
+        }
+        let data_before = mem.data_file.len().unwrap();
+
+        // crash after the log recorded the replacements and the data file was swapped in
+        let n = mem.buckets.read().unwrap().len();
+        let mut live = HashSet::new();
+        for bucket_number in 0 .. n {
+            mem.resolve_bucket(bucket_number).unwrap();
+            if let Some(ref slots) = mem.buckets.read().unwrap()[bucket_number].slots {
+                live.extend(slots.iter().map(|(_, pref)| *pref));
+            }
+        }
+        let (data, remapping) = mem.data_file.compact(&live).unwrap();
+        assert_eq!(remapping.len(), keys.len());
+        mem.remap_prefs(&remapping).unwrap();
+        let (data, link, table) = mem.write_replacements(Some(data)).unwrap();
+        let data_after = data.unwrap().len().unwrap();
+        assert!(data_after * 2 <= data_before);
+        let (table_len, link_len) = (table.len().unwrap(), link.len().unwrap());
+        drop((link, table));
+        mem.log_file.init_replacement(data_after, table_len, link_len, &remapping).unwrap();
+        mem.log_file.sync().unwrap();
+        mem.data_file.complete_replacement(true).unwrap();
+        mem.shutdown();
+        drop(mem);
+
+        let mut mem = persistent_files(&dir);
+        let stats = mem.recover_with_stats().unwrap();
+        assert_eq!(stats.remapping, remapping);
+        mem.load().unwrap();
+        assert_eq!(replacement_files(&dir), 0);
+        assert_eq!(mem.data_file.len().unwrap(), data_after);
+        for key in &keys {
+            assert_eq!(mem.get(key).unwrap().unwrap().1, vec!(1));
+        }
+        mem.validate_consistency().unwrap();
+        mem.shutdown();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -22,9 +22,12 @@
 
 use pref::PRef;
 use byteorder::{ByteOrder, BigEndian};
+use crc32c::crc32c;
 
 pub const PAGE_SIZE: usize = 4096;
 pub const PAGE_PAYLOAD_SIZE: usize = 4090;
+// table pages keep a checksum of the bytes before it in the last two bytes of their payload
+pub const PAGE_CHECKSUM_POS: usize = 4088;
 
 /// A page of the persistent files
 #[derive(Clone)]
@@ -66,6 +69,11 @@ impl Page {
         buf.copy_from_slice(&self.content[pos .. pos+len])
     }
 
+    /// borrow a slice at position
+    pub fn slice(&self, pos: usize, len: usize) -> &[u8] {
+        &self.content[pos .. pos+len]
+    }
+
     /// write a pref into the page
     pub fn write_pref(&mut self, pos: usize, pref: PRef) {
         let mut buf = [0u8; 6];
@@ -94,4 +102,37 @@ impl Page {
     pub fn into_buf(self) -> [u8; PAGE_SIZE] {
         self.content
     }
+
+    /// CRC32C of the content before the checksum position, truncated to 16 bits
+    pub fn compute_checksum(&self) -> u16 {
+        crc32c(&self.content[0 .. PAGE_CHECKSUM_POS]) as u16
+    }
+
+    /// store the checksum, only for pages that do not use the bytes at the checksum position
+    pub fn write_checksum(&mut self) {
+        let checksum = self.compute_checksum();
+        BigEndian::write_u16(&mut self.content[PAGE_CHECKSUM_POS .. PAGE_PAYLOAD_SIZE], checksum);
+    }
+
+    /// does the stored checksum match the content
+    pub fn verify_checksum(&self) -> bool {
+        BigEndian::read_u16(&self.content[PAGE_CHECKSUM_POS .. PAGE_PAYLOAD_SIZE]) == self.compute_checksum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_checksum() {
+        let mut page = Page::new_table_page(PRef::from(PAGE_SIZE as u64));
+        page.write(100, &[1, 2, 3, 4, 5]);
+        page.write_checksum();
+        assert!(page.verify_checksum());
+
+        let mut buf = page.into_buf();
+        buf[2000] ^= 1;
+        assert!(!Page::from_buf(buf).verify_checksum());
+    }
 }
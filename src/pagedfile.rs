@@ -20,9 +20,19 @@
 use page::{Page, PAGE_SIZE};
 use error::Error;
 use pref::PRef;
+use stats::{BackupStats, CacheStats};
 
+use std::borrow::Cow;
 use std::cmp::min;
 use std::io::{self, ErrorKind};
+use std::ops::Deref;
+use std::sync::Arc;
+#[cfg(feature = "tokio_support")]
+use std::future::Future;
+#[cfg(feature = "tokio_support")]
+use std::pin::Pin;
+#[cfg(feature = "tokio_support")]
+use std::task::{Context, Poll};
 
 /// a paged file
 pub trait PagedFile : Send + Sync {
@@ -42,6 +52,110 @@ pub trait PagedFile : Send + Sync {
     fn update_page(&mut self, page: Page) -> Result<u64, Error>;
     /// flush buffered writes
     fn flush(&mut self) -> Result<(), Error>;
+    /// read a page at pref that might be shared with a cache
+    fn read_shared_page(&self, pref: PRef) -> Result<Option<Arc<Page>>, Error> {
+        Ok(self.read_page(pref)?.map(Arc::new))
+    }
+    /// give the OS a hint on the expected access pattern
+    #[allow(unused)]
+    fn advise(&self, _advice: FadviseAdvice) -> Result<(), Error> {
+        Ok(())
+    }
+    /// copy the underlying files to files named dest_name, nothing to copy if not backed by files
+    fn copy_files(&self, _dest_name: &str) -> Result<BackupStats, Error> {
+        Ok(BackupStats::default())
+    }
+    /// name of the underlying file for error reports
+    fn name(&self) -> String {
+        String::from("transient")
+    }
+    /// append all pages to dest, returns the bytes copied
+    fn copy_to(&self, dest: &mut dyn PagedFile) -> Result<u64, Error> {
+        let mut pref = PRef::from(0);
+        while let Some(page) = self.read_page(pref)? {
+            dest.append_page(page)?;
+            pref += PAGE_SIZE as u64;
+        }
+        Ok(pref.as_u64())
+    }
+    /// a new empty file to write what replaces this file, to be swapped in with replace_with
+    /// the files of a replacement are ignored when this file is opened
+    fn replacement(&self) -> Result<Box<dyn PagedFile>, Error> {
+        Err(Error::InvalidArgument(format!("{} can not be replaced", self.name())))
+    }
+    /// take the content of a replacement created with replacement, that was written and synced
+    /// a crash while replacing is completed by complete_replacement
+    fn replace_with(&mut self, _replacement: Box<dyn PagedFile>) -> Result<(), Error> {
+        Err(Error::InvalidArgument(format!("{} can not be replaced", self.name())))
+    }
+    /// finish replacing with a replacement left by a crash if commit, remove the replacement otherwise
+    fn complete_replacement(&mut self, _commit: bool) -> Result<(), Error> {
+        Ok(())
+    }
+    /// counters of the page cache, none if the file is not cached
+    fn cache_stats(&self) -> Option<CacheStats> {
+        None
+    }
+}
+
+/// how sync makes writes durable
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncMode {
+    /// write data and metadata, such as modification time
+    Full,
+    /// write data and the metadata needed to read it
+    Data,
+    /// leave it to the OS, a crash of the system might lose or corrupt the db
+    None
+}
+
+/// access pattern hints for the OS
+#[derive(Clone, Copy, Debug)]
+#[allow(unused)]
+pub enum FadviseAdvice {
+    /// data will be read sequentially
+    Sequential,
+    /// data will be read in random order
+    Random,
+    /// data at (pos, len) will be needed soon
+    WillNeed(u64, u64),
+    /// data at (pos, len) will not be needed soon
+    DontNeed(u64, u64)
+}
+
+/// read a page on the blocking thread pool of tokio, must be called within a tokio runtime
+#[cfg(feature = "tokio_support")]
+pub fn spawn_page_read<F>(read: F) -> impl Future<Output=Result<Option<Page>, Error>>
+    where F: FnOnce() -> Result<Option<Page>, Error> + Send + 'static
+{
+    BlockingFuture { handle: tokio::task::spawn_blocking(read) }
+}
+
+/// the result of file IO on the blocking thread pool of tokio
+#[cfg(feature = "tokio_support")]
+pub struct BlockingFuture<T> {
+    handle: tokio::task::JoinHandle<Result<T, Error>>
+}
+
+#[cfg(feature = "tokio_support")]
+impl<T> BlockingFuture<T> {
+    /// wait for a task spawned with spawn_blocking
+    pub fn new(handle: tokio::task::JoinHandle<Result<T, Error>>) -> BlockingFuture<T> {
+        BlockingFuture { handle }
+    }
+}
+
+#[cfg(feature = "tokio_support")]
+impl<T> Future for BlockingFuture<T> {
+    type Output = Result<T, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match Pin::new(&mut self.handle).poll(cx) {
+            Poll::Ready(Ok(result)) => Poll::Ready(result),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(Error::IO(io::Error::new(ErrorKind::Other, e.to_string())))),
+            Poll::Pending => Poll::Pending
+        }
+    }
 }
 
 pub trait PagedFileRead {
@@ -108,6 +222,67 @@ impl PagedFileAppender {
         }
         Ok(pos)
     }
+
+    /// read a slice, borrowed if it is within the page not yet written
+    pub fn read_slice(&self, mut pos: PRef, len: usize) -> Result<Cow<[u8]>, Error> {
+        if let Some(ref page) = self.page {
+            if pos.this_page() == self.pos.this_page() && pos.in_page_pos() + len <= self.pos.in_page_pos() {
+                return Ok(Cow::Borrowed(page.slice(pos.in_page_pos(), len)));
+            }
+        }
+        let mut buf = Vec::with_capacity(len);
+        while buf.len() < len {
+            let have = min(PAGE_SIZE - pos.in_page_pos(), len - buf.len());
+            let current = if pos.this_page() == self.pos.this_page() { self.page.as_ref() } else { None };
+            if let Some(page) = current {
+                buf.extend_from_slice(page.slice(pos.in_page_pos(), have));
+            }
+            else if let Some(page) = self.file.read_shared_page(pos.this_page())? {
+                buf.extend_from_slice(page.slice(pos.in_page_pos(), have));
+            }
+            else {
+                return Err(Error::IO(io::Error::from(ErrorKind::UnexpectedEof)));
+            }
+            pos += have as u64;
+        }
+        Ok(Cow::Owned(buf))
+    }
+
+    /// read len bytes starting at start into a new vector
+    #[allow(unused)]
+    pub fn read_to_vec(&self, start: PRef, len: usize) -> Result<Vec<u8>, Error> {
+        Ok(self.read_slice(start, len)?.into_owned())
+    }
+
+    /// the rest of the page starting at start, without copying if the page is written and cached
+    #[allow(unused)]
+    pub fn read_into_page_slice(&self, start: PRef) -> Result<PageSlice, Error> {
+        let pos = start.in_page_pos();
+        if let Some(ref page) = self.page {
+            if start.this_page() == self.pos.this_page() && pos <= self.pos.in_page_pos() {
+                return Ok(PageSlice { page: Arc::new(page.clone()), pos, len: self.pos.in_page_pos() - pos });
+            }
+        }
+        if let Some(page) = self.file.read_shared_page(start.this_page())? {
+            return Ok(PageSlice { page, pos, len: PAGE_SIZE - pos });
+        }
+        Err(Error::IO(io::Error::from(ErrorKind::UnexpectedEof)))
+    }
+}
+
+/// a slice of a page that might be shared with a cache
+pub struct PageSlice {
+    page: Arc<Page>,
+    pos: usize,
+    len: usize
+}
+
+impl Deref for PageSlice {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.page.slice(self.pos, self.len)
+    }
 }
 
 impl PagedFile for PagedFileAppender {
@@ -145,6 +320,46 @@ impl PagedFile for PagedFileAppender {
         unimplemented!()
     }
 
+    fn advise(&self, advice: FadviseAdvice) -> Result<(), Error> {
+        self.file.advise(advice)
+    }
+
+    fn copy_files(&self, dest_name: &str) -> Result<BackupStats, Error> {
+        self.file.copy_files(dest_name)
+    }
+
+    fn copy_to(&self, dest: &mut dyn PagedFile) -> Result<u64, Error> {
+        self.file.copy_to(dest)
+    }
+
+    fn name(&self) -> String {
+        self.file.name()
+    }
+
+    fn replacement(&self) -> Result<Box<dyn PagedFile>, Error> {
+        self.file.replacement()
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        self.file.cache_stats()
+    }
+
+    fn replace_with(&mut self, replacement: Box<dyn PagedFile>) -> Result<(), Error> {
+        self.page = None;
+        self.file.replace_with(replacement)?;
+        self.pos = PRef::from(self.file.len()?);
+        Ok(())
+    }
+
+    fn complete_replacement(&mut self, commit: bool) -> Result<(), Error> {
+        self.file.complete_replacement(commit)?;
+        if commit {
+            self.page = None;
+            self.pos = PRef::from(self.file.len()?);
+        }
+        Ok(())
+    }
+
     fn flush(&mut self) -> Result<(), Error> {
         if let Some(ref mut page) = self.page {
             if self.pos.in_page_pos() > 0 {
@@ -187,3 +402,35 @@ impl<'file> Iterator for PagedFileIterator<'file> {
         None
     }
 }
+
+#[cfg(test)]
+mod test {
+    use transient::Transient;
+    use cachedfile::CachedFile;
+
+    use super::*;
+
+    #[test]
+    fn test_read_into_page_slice() {
+        let file = CachedFile::new(Box::new(Transient::new(true)), 10).unwrap();
+        let mut appender = PagedFileAppender::new(Box::new(file), PRef::from(0));
+        appender.append(&[0u8; 100]).unwrap();
+        let start = appender.position();
+        let blob = (0 .. 1000).map(|i| i as u8).collect::<Vec<_>>();
+        appender.append(&blob).unwrap();
+
+        // from the page not yet written
+        assert_eq!(&*appender.read_into_page_slice(start).unwrap(), blob.as_slice());
+
+        appender.flush().unwrap();
+        let first = appender.read_into_page_slice(start).unwrap();
+        let second = appender.read_into_page_slice(start).unwrap();
+        assert_eq!(&first[.. 1000], blob.as_slice());
+        assert_eq!(first.len(), PAGE_SIZE - 100);
+        // both borrow the cached page
+        assert!(Arc::ptr_eq(&first.page, &second.page));
+
+        assert_eq!(appender.read_to_vec(start, 1000).unwrap(), blob);
+        assert!(appender.read_into_page_slice(PRef::from(PAGE_SIZE as u64)).is_err());
+    }
+}
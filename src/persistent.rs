@@ -19,44 +19,343 @@
 //! Implements persistent store
 
 use api::{Hammersbald, HammersbaldAPI};
+#[cfg(feature = "tokio_support")]
+use api::AsyncHammersbald;
 use asyncfile::AsyncFile;
+#[cfg(feature = "tokio_support")]
+use asyncfile_tokio::TokioAsyncFile;
 use cachedfile::CachedFile;
 use datafile::DataFile;
 use error::Error;
 use logfile::LogFile;
+use memtable::MemTable;
+use page::PAGE_SIZE;
+use pagedfile::{PagedFile, PagedFileIterator, SyncMode};
+use pref::PRef;
+use readonly::ReadOnlyHammersbald;
 use rolledfile::RolledFile;
 use tablefile::TableFile;
 
+use fs2::{self, FileExt};
+
+use std::fs::{self, File, OpenOptions};
+
 const TABLE_CHUNK_SIZE: u64 = 1024 * 1024 * 1024;
 const DATA_CHUNK_SIZE: u64 = 1024 * 1024 * 1024;
 const LOG_CHUNK_SIZE: u64 = 1024 * 1024 * 1024;
+// pages queued for the background writer before appending blocks
+const WRITE_QUEUE_PAGES: usize = 256;
+
+/// Parameters of a persistent db
+/// chunk sizes must be multiples of the page size, a db must be opened with the chunk sizes it was created with
+/// as they are stored in the db, Config::from_db returns them
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// name of the db, its files are named name.chunk.extension
+    pub name: String,
+    /// read cache size in pages, for each of the data, link and table files
+    pub cached_data_pages: usize,
+    /// average re-use of a hash table bucket
+    pub bucket_fill_target: usize,
+    /// size of the chunks of the data and link files
+    pub data_chunk_bytes: u64,
+    /// size of the chunks of the table file
+    pub table_chunk_bytes: u64,
+    /// size of the chunks of the log file
+    pub log_chunk_bytes: u64,
+    /// how the end of a batch makes writes durable
+    pub sync_mode: SyncMode,
+    /// data stored with a key is compressed if that makes it at least this ratio smaller, None stores it as is
+    #[cfg(feature = "compression")]
+    pub min_compression_gain: Option<f32>
+}
+
+impl Config {
+    /// parameters of a db of name with the chunk sizes stored in its table, default values otherwise
+    /// the defaults are also taken if the first table page can not be read, opening then checks them
+    pub fn from_db(name: &str) -> Config {
+        let mut config = Config::new(name);
+        if let Some([data, table, log]) = Self::stored_chunk_sizes(name) {
+            config.data_chunk_bytes = data;
+            config.table_chunk_bytes = table;
+            config.log_chunk_bytes = log;
+        }
+        config
+    }
+
+    // chunk sizes in the first table page if it can be read and has them
+    fn stored_chunk_sizes(name: &str) -> Option<[u64; 3]> {
+        // the first page is in the first chunk whatever its size
+        let table = RolledFile::new_read_only(name, "tb", TABLE_CHUNK_SIZE).ok()?;
+        match table.read_page(PRef::from(0)) {
+            Ok(Some(ref first)) if first.verify_checksum() => {
+                let chunk_sizes = MemTable::read_chunk_sizes(first);
+                if chunk_sizes.iter().all(|size| *size > 0) {
+                    return Some(chunk_sizes);
+                }
+                None
+            },
+            _ => None
+        }
+    }
+
+    fn chunk_sizes(&self) -> [u64; 3] {
+        [self.data_chunk_bytes, self.table_chunk_bytes, self.log_chunk_bytes]
+    }
+
+    /// parameters of a db of name with default values
+    pub fn new(name: &str) -> Config {
+        Config {
+            name: name.to_string(),
+            cached_data_pages: 100,
+            bucket_fill_target: 2,
+            data_chunk_bytes: DATA_CHUNK_SIZE,
+            table_chunk_bytes: TABLE_CHUNK_SIZE,
+            log_chunk_bytes: LOG_CHUNK_SIZE,
+            sync_mode: SyncMode::Data,
+            #[cfg(feature = "compression")]
+            min_compression_gain: None
+        }
+    }
+
+    /// set the read cache size in pages
+    pub fn with_cached_pages(mut self, cached_data_pages: usize) -> Config {
+        self.cached_data_pages = cached_data_pages;
+        self
+    }
+
+    /// set the average re-use of a hash table bucket
+    pub fn with_bucket_fill_target(mut self, bucket_fill_target: usize) -> Config {
+        self.bucket_fill_target = bucket_fill_target;
+        self
+    }
+
+    /// set the size of the chunks of the data and link files
+    pub fn with_data_chunk_bytes(mut self, data_chunk_bytes: u64) -> Config {
+        self.data_chunk_bytes = data_chunk_bytes;
+        self
+    }
+
+    /// set the size of the chunks of the table file
+    pub fn with_table_chunk_bytes(mut self, table_chunk_bytes: u64) -> Config {
+        self.table_chunk_bytes = table_chunk_bytes;
+        self
+    }
+
+    /// set the size of the chunks of the log file
+    pub fn with_log_chunk_bytes(mut self, log_chunk_bytes: u64) -> Config {
+        self.log_chunk_bytes = log_chunk_bytes;
+        self
+    }
+
+    /// set how the end of a batch makes writes durable
+    pub fn with_sync_mode(mut self, sync_mode: SyncMode) -> Config {
+        self.sync_mode = sync_mode;
+        self
+    }
+
+    /// compress data stored with a key if that makes it at least min_gain_ratio smaller
+    /// reads see the uncompressed data, a db with compressed data needs the compression feature to be read
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self, min_gain_ratio: f32) -> Config {
+        self.min_compression_gain = Some(min_gain_ratio);
+        self
+    }
+}
+
+/// Findings and actions of a repair
+#[derive(Clone, Debug, Default)]
+pub struct RepairReport {
+    /// bytes of incomplete pages at the end of files, cut off
+    pub partial_page_bytes: u64,
+    /// bytes written after the last batch, cut off
+    pub unlogged_bytes: u64,
+    /// table pages restored from the log
+    pub logged_table_pages: usize,
+    /// keys whose data could not be read after repair, should be zero
+    pub unreadable_keys: usize
+}
 
 /// Implements persistent storage
 pub struct Persistent {}
 
 impl Persistent {
+    /// create or open a db
+    pub fn open(config: Config) -> Result<Box<dyn HammersbaldAPI>, Error> {
+        let lock = Self::lock(&config.name)?;
+        Ok(Box::new(Self::open_locked(&config, lock)?))
+    }
+
+    /// create or open a db whose appends are written on the blocking thread pool of tokio
+    /// must be called within a tokio runtime, the db may then be used from its tasks
+    /// appends do not wait for IO, reads and batches block the calling thread, flush_async of the db does not
+    #[cfg(feature = "tokio_support")]
+    pub fn new_async_db(config: Config) -> Result<AsyncHammersbald, Error> {
+        let lock = Self::lock(&config.name)?;
+        AsyncHammersbald::new(Box::new(Self::open_with_writer(&config, lock, &|file| Ok(Box::new(TokioAsyncFile::new(file)?)))?))
+    }
+
     /// create a new db
+    #[deprecated(note = "use Persistent::open")]
+    #[allow(unused)]
     pub fn new_db(name: &str, cached_data_pages: usize, bucket_fill_target: usize) -> Result<Box<dyn HammersbaldAPI>, Error> {
+        Self::open(Config::new(name).with_cached_pages(cached_data_pages).with_bucket_fill_target(bucket_fill_target))
+    }
+
+    /// open a db for reading only, while it might be written by an other process
+    /// nothing is recovered, reads see what the writer wrote, consistent only between its batches
+    /// all writes return Error::ReadOnly
+    pub fn open_read_only(name: &str, cached_data_pages: usize) -> Result<Box<dyn HammersbaldAPI>, Error> {
+        let config = Config::from_db(name);
         let data = DataFile::new(
             Box::new(CachedFile::new(
-                Box::new(AsyncFile::new(
-                    Box::new(RolledFile::new(
-                        name, "bc", true, DATA_CHUNK_SIZE)?))?), cached_data_pages)?))?;
+                Box::new(RolledFile::new_read_only(name, "bc", config.data_chunk_bytes)?), cached_data_pages)?))?;
 
         let link = DataFile::new(
             Box::new(CachedFile::new(
-                Box::new(AsyncFile::new(
-                    Box::new(RolledFile::new(
-                        name, "bl", true, DATA_CHUNK_SIZE)?))?), cached_data_pages)?))?;
+                Box::new(RolledFile::new_read_only(name, "bl", config.data_chunk_bytes)?), cached_data_pages)?))?;
+
+        let log = LogFile::new(Box::new(RolledFile::new_read_only(name, "lg", config.log_chunk_bytes)?));
+
+        let table = TableFile::new(
+            Box::new(CachedFile::new(
+                Box::new(RolledFile::new_read_only(name, "tb", config.table_chunk_bytes)?), cached_data_pages)?))?;
+
+        let db = Hammersbald::new_read_only(log, table, data, link)?;
+        Self::check_chunk_sizes(db.chunk_sizes(), &config)?;
+        Ok(Box::new(ReadOnlyHammersbald::new(db)))
+    }
+
+    /// repair a db that can not be opened after a crash
+    /// cuts files to complete pages, then opens the db, which reverts to the last batch, and checks that all keys are readable.
+    /// with dry_run only reports what would be cut.
+    pub fn open_repair(name: &str, dry_run: bool) -> Result<RepairReport, Error> {
+        let lock = Self::lock(name)?;
+        let config = Config::from_db(name).with_bucket_fill_target(1);
+        let mut report = RepairReport::default();
+        let mut log = RolledFile::new(name, "lg", true, config.log_chunk_bytes)?;
+        Self::cut_partial_page(&mut log, dry_run, &mut report)?;
+        let mut logged = [0u64; 3];
+        if let Some(first) = log.read_page(PRef::from(0))? {
+            for (i, len) in logged.iter_mut().enumerate() {
+                *len = first.read_pref(i * 6).as_u64();
+            }
+        }
+        report.logged_table_pages = PagedFileIterator::new(&log, PRef::from(0)).skip(1).filter(|p| p.pref().is_valid()).count();
+
+        let files = [("bc", true, config.data_chunk_bytes, logged[0]), ("tb", false, config.table_chunk_bytes, logged[1]), ("bl", true, config.data_chunk_bytes, logged[2])];
+        for (extension, append, chunk_size, logged_len) in files.iter() {
+            let mut file = RolledFile::new(name, extension, *append, *chunk_size)?;
+            let len = Self::cut_partial_page(&mut file, dry_run, &mut report)?;
+            if len > *logged_len {
+                report.unlogged_bytes += len - *logged_len;
+            }
+        }
+        if !dry_run {
+            // a snapshot of the unrepaired files is useless
+            let _ = fs::remove_file(name.to_string() + ".mt");
+            let mut db = Self::open_locked(&config, lock)?;
+            report.unreadable_keys = db.verify_integrity()?;
+            db.shutdown();
+        }
+        Ok(report)
+    }
+
+    /// index all keyed data anew, for a table lost or corrupted beyond repair
+    /// the table and log are discarded, keys forgotten are accessible again
+    /// returns the number of records indexed
+    pub fn open_rebuild_index(name: &str) -> Result<usize, Error> {
+        let lock = Self::lock(name)?;
+        // the table storing the chunk sizes is discarded, if it is lost the first of several data chunks has the size
+        let mut config = Config::from_db(name);
+        let chunks = RolledFile::new_read_only(name, "bc", DATA_CHUNK_SIZE)?.chunk_files()?;
+        if let [(0, first), _, ..] = chunks.as_slice() {
+            config.data_chunk_bytes = *first;
+        }
+        let mut report = RepairReport::default();
+        let mut data = RolledFile::new(name, "bc", true, config.data_chunk_bytes)?;
+        Self::cut_partial_page(&mut data, false, &mut report)?;
+        RolledFile::new(name, "tb", false, config.table_chunk_bytes)?.truncate(0)?;
+        RolledFile::new(name, "lg", true, config.log_chunk_bytes)?.truncate(0)?;
+        let _ = fs::remove_file(name.to_string() + ".mt");
+
+        let mut db = Self::open_locked(&config, lock)?;
+        let indexed = db.rebuild_index()?;
+        db.shutdown();
+        Ok(indexed)
+    }
+
+    // returns the length of the file without the incomplete page
+    fn cut_partial_page(file: &mut RolledFile, dry_run: bool, report: &mut RepairReport) -> Result<u64, Error> {
+        let len = file.len()?;
+        let partial = len % PAGE_SIZE as u64;
+        report.partial_page_bytes += partial;
+        if partial > 0 && !dry_run {
+            file.truncate(len - partial)?;
+        }
+        Ok(len - partial)
+    }
+
+    // exclusive lock of name.lock, released as the file is closed
+    fn lock(name: &str) -> Result<File, Error> {
+        let file = OpenOptions::new().read(true).write(true).create(true).open(name.to_string() + ".lock")?;
+        if let Err(e) = file.try_lock_exclusive() {
+            if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() {
+                return Err(Error::AlreadyOpen);
+            }
+            return Err(Error::IO(e));
+        }
+        Ok(file)
+    }
+
+    fn open_locked(config: &Config, lock: File) -> Result<Hammersbald, Error> {
+        Self::open_with_writer(config, lock, &|file| Ok(Box::new(AsyncFile::new_bounded(file, WRITE_QUEUE_PAGES)?)))
+    }
+
+    // open with append only files written by writer
+    fn open_with_writer(config: &Config, lock: File, writer: &dyn Fn(Box<dyn PagedFile>) -> Result<Box<dyn PagedFile>, Error>) -> Result<Hammersbald, Error> {
+        let name = config.name.as_str();
+        // recovery would write the files with the wrong chunk sizes
+        if let Some(stored) = Config::stored_chunk_sizes(name) {
+            Self::check_chunk_sizes(stored, config)?;
+        }
+        let rolled = |extension: &str, append_only: bool, chunk_size: u64| -> Result<RolledFile, Error> {
+            let mut file = RolledFile::new(name, extension, append_only, chunk_size)?;
+            file.set_sync_mode(config.sync_mode);
+            Ok(file)
+        };
+
+        let data = DataFile::new(
+            Box::new(CachedFile::new(
+                writer(Box::new(rolled("bc", true, config.data_chunk_bytes)?))?, config.cached_data_pages)?))?;
+        #[cfg(feature = "compression")]
+        let data = data.with_compression(config.min_compression_gain);
+
+        let link = DataFile::new(
+            Box::new(CachedFile::new(
+                writer(Box::new(rolled("bl", true, config.data_chunk_bytes)?))?, config.cached_data_pages)?))?;
 
         let log = LogFile::new(
-            Box::new(AsyncFile::new(
-                Box::new(RolledFile::new(name, "lg", true, LOG_CHUNK_SIZE)?))?));
+            writer(Box::new(rolled("lg", true, config.log_chunk_bytes)?))?);
 
         let table = TableFile::new(
             Box::new(CachedFile::new(
-            Box::new(RolledFile::new(name, "tb", false, TABLE_CHUNK_SIZE)?), cached_data_pages)?))?;
+            Box::new(rolled("tb", false, config.table_chunk_bytes)?), config.cached_data_pages)?))?;
+
+        let mut db = Hammersbald::new_with_snapshot(log, table, data, link, config.bucket_fill_target, (name.to_string() + ".mt").as_str())?;
+        db.set_lock_file(lock);
+        if db.chunk_sizes() == [0; 3] {
+            db.set_chunk_sizes(config.chunk_sizes())?;
+        }
+        Self::check_chunk_sizes(db.chunk_sizes(), config)?;
+        Ok(db)
+    }
 
-        Ok(Box::new(Hammersbald::new(log, table, data, link, bucket_fill_target)?))
+    // files read with other chunk sizes than those they were written with are garbled
+    fn check_chunk_sizes(stored: [u64; 3], config: &Config) -> Result<(), Error> {
+        if stored != [0; 3] && stored != config.chunk_sizes() {
+            return Err(Error::InvalidArgument(format!("db {} has chunk sizes {:?} of data, table and log, not those configured, open with Config::from_db", config.name, stored)));
+        }
+        Ok(())
     }
 }
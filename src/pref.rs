@@ -18,6 +18,12 @@
 //! allows reference of a data space of 2^48
 
 use page::PAGE_SIZE;
+use error::Error;
+
+#[cfg(feature = "serde_support")]
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+#[cfg(feature = "serde_support")]
+use serde::de::Error as DeError;
 
 use std::cmp::Ordering;
 use std::fmt;
@@ -53,6 +59,20 @@ impl From<u64> for PRef {
     }
 }
 
+#[cfg(feature = "serde_support")]
+impl Serialize for PRef {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.0)
+    }
+}
+
+#[cfg(feature = "serde_support")]
+impl<'de> Deserialize<'de> for PRef {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        PRef::try_new(u64::deserialize(deserializer)?).map_err(|e| D::Error::custom(e.to_string()))
+    }
+}
+
 impl fmt::Display for PRef {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         write!(f, "{}", self.0)
@@ -100,6 +120,15 @@ impl ops::SubAssign<u64> for PRef {
 }
 
 impl PRef {
+    /// construct a valid pref, Error::InvalidOffset if n is out of its range
+    /// use this for numbers read from untrusted input, From<u64> accepts any
+    pub fn try_new (n: u64) -> Result<PRef, Error> {
+        if n >= INVALID {
+            return Err(Error::InvalidOffset);
+        }
+        Ok(PRef(n))
+    }
+
     /// construct an invalid pref
     pub fn invalid () -> PRef {
         PRef(INVALID)
@@ -149,4 +178,56 @@ impl PRef {
     pub fn add_pages(&self, n: usize) -> PRef {
         PRef(self.0 + n as u64 *PAGE_SIZE as u64)
     }
+
+    /// construct from page number and position within the page
+    pub fn from_page_offset(page: u64, offset: usize) -> Result<PRef, Error> {
+        if offset >= PAGE_SIZE || page >= INVALID / PAGE_SIZE as u64 {
+            return Err(Error::InvalidOffset);
+        }
+        Ok(PRef(page * PAGE_SIZE as u64 + offset as u64))
+    }
+
+    /// page number and position within the page
+    pub fn to_page_offset(&self) -> (u64, usize) {
+        (self.page_number(), self.in_page_pos())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_page_offset() {
+        let pref = PRef::from_page_offset(5, 300).unwrap();
+        assert_eq!(pref.page_number(), 5);
+        assert_eq!(pref.in_page_pos(), 300);
+        assert_eq!(pref.to_page_offset(), (5, 300));
+        assert!(PRef::from_page_offset(5, PAGE_SIZE).is_err());
+        assert!(PRef::from_page_offset(INVALID / PAGE_SIZE as u64, 0).is_err());
+    }
+
+    #[test]
+    fn test_try_new() {
+        assert_eq!(PRef::try_new(INVALID - 1).unwrap().as_u64(), INVALID - 1);
+        match PRef::try_new(INVALID) {
+            Err(Error::InvalidOffset) => {},
+            _ => panic!("u48::MAX is not a valid pref")
+        }
+        assert!(PRef::try_new(u64::max_value()).is_err());
+    }
+
+    #[cfg(feature = "serde_support")]
+    #[test]
+    fn test_deserialize() {
+        use serde::de::IntoDeserializer;
+        use serde::de::value::{Error as ValueError, U64Deserializer};
+
+        let de: U64Deserializer<ValueError> = 4096u64.into_deserializer();
+        assert_eq!(PRef::deserialize(de).unwrap(), PRef::from(4096));
+        let de: U64Deserializer<ValueError> = INVALID.into_deserializer();
+        assert!(PRef::deserialize(de).is_err());
+        let de: U64Deserializer<ValueError> = u64::max_value().into_deserializer();
+        assert!(PRef::deserialize(de).is_err());
+    }
 }
@@ -0,0 +1,275 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Read only db
+//!
+//! A view of a db that refuses all writes
+//!
+
+use api::{Hammersbald, HammersbaldAPI, HammersbaldIterator, IndexedIterator, ReferredIterator, PrefixIterator, ApproximateResult, BucketInspection};
+use accesslog::AccessLog;
+use datafile::DagIterator;
+use error::Error;
+use memtable::BatchPhase;
+use pref::PRef;
+use transaction::Transaction;
+use stats::{Stats, MemTableStats, BackupStats, CompactionReport, LinkCompactionStats, RecoveryStats, DBStats};
+
+use std::sync::{Arc, mpsc};
+
+/// a db that returns Error::ReadOnly for all writes
+pub struct ReadOnlyHammersbald {
+    hammersbald: Hammersbald
+}
+
+impl ReadOnlyHammersbald {
+    /// wrap a db opened without recovery, none of its files are written
+    pub fn new(hammersbald: Hammersbald) -> ReadOnlyHammersbald {
+        ReadOnlyHammersbald { hammersbald }
+    }
+}
+
+impl HammersbaldAPI for ReadOnlyHammersbald {
+    fn batch(&mut self) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn batch_with_progress(&mut self, _progress: &dyn Fn(BatchPhase)) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn checkpoint(&mut self) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn shutdown(&mut self) {}
+
+    fn put_keyed(&mut self, _key: &[u8], _data: &[u8]) -> Result<PRef, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn put_keyed_batch(&mut self, _items: &[(&[u8], &[u8])]) -> Result<Vec<PRef>, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn put_keyed_unique(&mut self, _key: &[u8], _data: &[u8]) -> Result<PRef, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn put_keyed_if_absent(&mut self, _key: &[u8], _data: &[u8]) -> Result<Option<PRef>, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn get_keyed(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>)>, Error> {
+        self.hammersbald.get_keyed(key)
+    }
+
+    fn get_keyed_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<PRef>, Error> {
+        self.hammersbald.get_keyed_into(key, buf)
+    }
+
+    fn put_keyed_with_links(&mut self, _key: &[u8], _data: &[u8], _links: &[PRef]) -> Result<PRef, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn get_keyed_with_links(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>, Vec<PRef>)>, Error> {
+        self.hammersbald.get_keyed_with_links(key)
+    }
+
+    fn put_keyed_versioned(&mut self, _key: &[u8], _data: &[u8]) -> Result<(PRef, u32), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn get_keyed_version(&self, key: &[u8], version: u32) -> Result<Option<(PRef, Vec<u8>)>, Error> {
+        self.hammersbald.get_keyed_version(key, version)
+    }
+
+    fn get_pref_for_key(&self, key: &[u8]) -> Result<Option<PRef>, Error> {
+        self.hammersbald.get_pref_for_key(key)
+    }
+
+    fn put(&mut self, _data: &[u8]) -> Result<PRef, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn put_with_refs(&mut self, _data: &[u8], _refs: &[PRef]) -> Result<PRef, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn put_referred_dag(&mut self, _nodes: &[(&[u8], Vec<usize>)]) -> Result<Vec<PRef>, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn get(&self, pref: PRef) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        self.hammersbald.get(pref)
+    }
+
+    fn get_with_refs(&self, pref: PRef) -> Result<(Vec<u8>, Vec<PRef>), Error> {
+        self.hammersbald.get_with_refs(pref)
+    }
+
+    fn put_pref_list(&mut self, _prefs: &[PRef]) -> Result<PRef, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn get_pref_list(&self, pref: PRef) -> Result<Vec<PRef>, Error> {
+        self.hammersbald.get_pref_list(pref)
+    }
+
+    fn may_have_key(&self, key: &[u8]) -> Result<bool, Error> {
+        self.hammersbald.may_have_key(key)
+    }
+
+    fn may_have_key_bulk(&self, keys: &[&[u8]]) -> Result<Vec<bool>, Error> {
+        self.hammersbald.may_have_key_bulk(keys)
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool, Error> {
+        self.hammersbald.contains_key(key)
+    }
+
+    fn get_keyed_approximate(&self, key: &[u8]) -> Result<ApproximateResult, Error> {
+        self.hammersbald.get_keyed_approximate(key)
+    }
+
+    fn inspect_bucket(&self, key: &[u8]) -> Result<BucketInspection, Error> {
+        self.hammersbald.inspect_bucket(key)
+    }
+
+    fn set_access_log(&mut self, log: Arc<dyn AccessLog>) {
+        self.hammersbald.set_access_log(log)
+    }
+
+    fn watch(&self, key: &[u8]) -> Result<mpsc::Receiver<Option<Vec<u8>>>, Error> {
+        self.hammersbald.watch(key)
+    }
+
+    fn forget(&mut self, _key: &[u8]) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn forget_batch(&mut self, _keys: &[&[u8]]) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn iter(&self) -> HammersbaldIterator {
+        self.hammersbald.iter()
+    }
+
+    fn iter_indexed(&self) -> IndexedIterator {
+        self.hammersbald.iter_indexed()
+    }
+
+    fn iter_referred(&self) -> ReferredIterator {
+        self.hammersbald.iter_referred()
+    }
+
+    fn dag(&self, root: PRef) -> DagIterator {
+        self.hammersbald.dag(root)
+    }
+
+    fn iter_since_pref(&self, pref: PRef) -> HammersbaldIterator {
+        self.hammersbald.iter_since_pref(pref)
+    }
+
+    fn iter_from(&self, start: PRef) -> HammersbaldIterator {
+        self.hammersbald.iter_from(start)
+    }
+
+    fn iter_range(&self, from: PRef, to: PRef) -> HammersbaldIterator {
+        self.hammersbald.iter_range(from, to)
+    }
+
+    fn current_data_pos(&self) -> PRef {
+        self.hammersbald.current_data_pos()
+    }
+
+    fn prefix_scan<'a>(&'a self, prefix: &'a [u8]) -> PrefixIterator<'a> {
+        self.hammersbald.prefix_scan(prefix)
+    }
+
+    #[cfg(feature = "parallel_reads")]
+    fn prefix_scan_parallel(&self, prefix: &[u8]) -> Result<Vec<(PRef, Vec<u8>, Vec<u8>)>, Error> {
+        self.hammersbald.prefix_scan_parallel(prefix)
+    }
+
+    fn set_sip_keys(&mut self, _sip0: u64, _sip1: u64) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn get_sip_keys(&self) -> (u64, u64) {
+        self.hammersbald.get_sip_keys()
+    }
+
+    fn stats_snapshot(&self) -> Result<Stats, Error> {
+        self.hammersbald.stats_snapshot()
+    }
+
+    fn count_live_keys(&self) -> Result<usize, Error> {
+        self.hammersbald.count_live_keys()
+    }
+
+    fn get_stats(&self) -> Result<DBStats, Error> {
+        self.hammersbald.get_stats()
+    }
+
+    fn last_recovery_stats(&self) -> Option<RecoveryStats> {
+        self.hammersbald.last_recovery_stats()
+    }
+
+    fn stats(&self) -> Result<MemTableStats, Error> {
+        self.hammersbald.stats()
+    }
+
+    fn bucket_fill_histogram(&self) -> Result<Vec<usize>, Error> {
+        self.hammersbald.bucket_fill_histogram()
+    }
+
+    fn backup(&mut self, _dest_name: &str) -> Result<BackupStats, Error> {
+        // a backup ends the current batch
+        Err(Error::ReadOnly)
+    }
+
+    fn rehash_to_target(&mut self, _target_bucket_count: usize) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn compact_link_file(&mut self) -> Result<LinkCompactionStats, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn compact_data_file(&mut self) -> Result<u64, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn compact(&mut self) -> Result<CompactionReport, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn clone_to_transient(&self) -> Result<Box<dyn HammersbaldAPI>, Error> {
+        self.hammersbald.clone_to_transient()
+    }
+
+    fn try_clone(&mut self) -> Result<Box<dyn HammersbaldAPI>, Error> {
+        // nothing to batch, the copy recovers an unfinished batch of the files
+        Ok(Box::new(self.hammersbald.copy_to_transient()?))
+    }
+
+    fn transaction(&mut self) -> Transaction {
+        // commit fails as the writes are refused
+        Transaction::new(self)
+    }
+}
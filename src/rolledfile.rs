@@ -21,30 +21,51 @@
 use error::Error;
 use pref::PRef;
 use page::{Page, PAGE_SIZE};
-use pagedfile::PagedFile;
+use pagedfile::{PagedFile, FadviseAdvice, SyncMode};
 use singlefile::SingleFile;
+use stats::BackupStats;
 
 use std::collections::HashMap;
 use std::fs::{self, File, OpenOptions};
 use std::path::Path;
 use std::cmp::max;
 
+// appended to the extension of the chunks of a replacement, so opening a file ignores them
+const REPLACEMENT_EXTENSION: &str = ".new";
+
 pub struct RolledFile {
     name: String,
     extension: String,
     files: HashMap<u16,SingleFile>,
     len: u64,
     append_only: bool,
-    chunk_size: u64
+    read_only: bool,
+    chunk_size: u64,
+    sync_mode: SyncMode
 }
 
 impl RolledFile {
     pub fn new (name: &str, extension: &str, append_only: bool, chunk_size: u64) -> Result<RolledFile, Error> {
-        let mut rolled = RolledFile { name: name.to_string(), extension: extension.to_string(), files: HashMap::new(), len: 0, append_only, chunk_size};
+        let mut rolled = RolledFile { name: name.to_string(), extension: extension.to_string(), files: HashMap::new(), len: 0, append_only, read_only: false, chunk_size, sync_mode: SyncMode::Data};
+        rolled.open()?;
+        Ok(rolled)
+    }
+
+    /// open existing chunks for reading only, writes return Error::ReadOnly
+    pub fn new_read_only (name: &str, extension: &str, chunk_size: u64) -> Result<RolledFile, Error> {
+        let mut rolled = RolledFile { name: name.to_string(), extension: extension.to_string(), files: HashMap::new(), len: 0, append_only: false, read_only: true, chunk_size, sync_mode: SyncMode::Data};
         rolled.open()?;
         Ok(rolled)
     }
 
+    /// set how sync writes the chunks to disk
+    pub fn set_sync_mode(&mut self, sync_mode: SyncMode) {
+        self.sync_mode = sync_mode;
+        for file in self.files.values_mut() {
+            file.set_sync_mode(sync_mode);
+        }
+    }
+
     fn open (&mut self) -> Result<(), Error> {
         // interesting file names are:
         // name.index.extension
@@ -71,9 +92,8 @@ impl RolledFile {
                                             if let Some(index) = ni.extension() {
                                                 if let Ok(number) = index.to_string_lossy().parse::<u16>() {
                                                     let filename = path.clone().to_string_lossy().to_string();
-                                                    let file = Self::open_file(self.append_only, filename)?;
-                                                    self.files.insert(number,
-                                                                      SingleFile::new_chunk(file, number as u64 * self.chunk_size, self.chunk_size)?);
+                                                    let chunk = self.open_chunk(filename, number as u64 * self.chunk_size)?;
+                                                    self.files.insert(number, chunk);
                                                     if let Some (file) = self.files.get(&number) {
                                                         if file.len().unwrap() > 0 {
                                                             highest_chunk = max(highest_chunk, number);
@@ -94,15 +114,108 @@ impl RolledFile {
             }
         }
         else {
-            return Err(Error::Corrupted("invalid db name".to_string()));
+            return Err(Error::InvalidArgument("invalid db name".to_string()));
         }
         Ok(())
     }
 
-    fn open_file (append: bool, path: String) -> Result<File, Error> {
+    /// the last n pages in forward order, fewer if the file is shorter
+    #[allow(unused)]
+    pub fn tail_pages(&self, n: usize) -> Result<Vec<(PRef, Page)>, Error> {
+        let mut pos = self.len.saturating_sub((n * PAGE_SIZE) as u64);
+        let mut pages = Vec::new();
+        while pos < self.len {
+            let pref = PRef::from(pos);
+            match self.read_page(pref)? {
+                Some(page) => pages.push((pref, page)),
+                None => return Err(Error::corrupted(format!("missing page {} in tail", pref)))
+            }
+            pos += PAGE_SIZE as u64;
+        }
+        Ok(pages)
+    }
+
+    /// index and length of each chunk in ascending index order
+    #[allow(unused)]
+    pub fn chunk_files(&self) -> Result<Vec<(u16, u64)>, Error> {
+        let mut chunks = Vec::with_capacity(self.files.len());
+        for (chunk, file) in &self.files {
+            chunks.push((*chunk, file.len()?));
+        }
+        chunks.sort();
+        Ok(chunks)
+    }
+
+    /// delete chunks that end before pref, the chunk containing pref is kept
+    /// returns the bytes freed, data in deleted chunks is no longer readable
+    #[allow(unused)]
+    pub fn prune_chunks_before(&mut self, pref: PRef) -> Result<u64, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if pref.as_u64() > self.len {
+            return Err(Error::InvalidOffset);
+        }
+        let prune = self.files.keys().filter(|c| (**c as u64 + 1) * self.chunk_size <= pref.as_u64()).cloned().collect::<Vec<_>>();
+        let mut freed = 0;
+        for chunk in prune {
+            if let Some(file) = self.files.remove(&chunk) {
+                freed += file.len()?;
+            }
+            fs::remove_file(self.chunk_path(&self.name, chunk))?;
+        }
+        Ok(freed)
+    }
+
+    fn chunk_path (&self, name: &str, chunk: u16) -> String {
+        name.to_string() + "." + chunk.to_string().as_str() + "." + self.extension.as_str()
+    }
+
+    // directory of the files of name
+    fn dir_of(name: &str) -> &Path {
+        match Path::new(name).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new(".")
+        }
+    }
+
+    // path of a chunk of the replacement of this file
+    fn replacement_path (&self, chunk: u16) -> String {
+        self.chunk_path(&self.name, chunk) + REPLACEMENT_EXTENSION
+    }
+
+    // indexes of the chunk files of name with the extension
+    fn chunks_of(name: &str, extension: &str) -> Result<Vec<u16>, Error> {
+        let mut chunks = Vec::new();
+        if let Some(basename) = Path::new(name).file_name() {
+            let prefix = basename.to_string_lossy().to_string() + ".";
+            let suffix = ".".to_string() + extension;
+            for entry in fs::read_dir(Self::dir_of(name))? {
+                let file_name = entry?.file_name().to_string_lossy().to_string();
+                if file_name.starts_with(&prefix) && file_name.ends_with(&suffix) && file_name.len() > prefix.len() + suffix.len() {
+                    if let Ok(chunk) = file_name[prefix.len() .. file_name.len() - suffix.len()].parse::<u16>() {
+                        chunks.push(chunk);
+                    }
+                }
+            }
+        }
+        Ok(chunks)
+    }
+
+    fn open_chunk (&self, path: String, base: u64) -> Result<SingleFile, Error> {
+        let file = self.open_file(path.clone())?;
+        let mut chunk = SingleFile::new_chunk(file, &path, base, self.chunk_size)?;
+        chunk.set_sync_mode(self.sync_mode);
+        Ok(chunk)
+    }
+
+    fn open_file (&self, path: String) -> Result<File, Error> {
         let mut open_mode = OpenOptions::new();
 
-        if append {
+        if self.read_only {
+            open_mode.read(true);
+        }
+        else if self.append_only {
             open_mode.read(true).append(true).create(true);
         }
         else{
@@ -128,8 +241,11 @@ impl PagedFile for RolledFile {
     }
 
     fn truncate(&mut self, new_len: u64) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
         if new_len % PAGE_SIZE as u64 != 0 {
-            return Err(Error::Corrupted(format!("truncate not to page boundary {}", new_len)));
+            return Err(Error::corrupted(format!("truncate not to page boundary {}", new_len)));
         }
         let chunk = (new_len / self.chunk_size) as u16;
         for (c, file) in &mut self.files {
@@ -151,15 +267,113 @@ impl PagedFile for RolledFile {
         Ok(())
     }
 
+    fn copy_files(&self, dest_name: &str) -> Result<BackupStats, Error> {
+        let mut stats = BackupStats::default();
+        // chunks of an earlier copy would extend this one
+        for chunk in Self::chunks_of(dest_name, &self.extension)? {
+            if !self.files.contains_key(&chunk) {
+                fs::remove_file(self.chunk_path(dest_name, chunk))?;
+            }
+        }
+        for chunk in self.files.keys() {
+            let dest = self.chunk_path(dest_name, *chunk);
+            stats.bytes_copied += fs::copy(self.chunk_path(&self.name, *chunk), &dest)?;
+            File::open(&dest)?.sync_all()?;
+            stats.files_copied += 1;
+        }
+        // names of new and removed chunks are durable with their directory
+        File::open(Self::dir_of(dest_name))?.sync_all()?;
+        Ok(stats)
+    }
+
+    fn name(&self) -> String {
+        self.name.clone() + "." + self.extension.as_str()
+    }
+
+    fn replacement(&self) -> Result<Box<dyn PagedFile>, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        // a replacement left by a crash would extend this one
+        for chunk in Self::chunks_of(&self.name, &(self.extension.clone() + REPLACEMENT_EXTENSION))? {
+            fs::remove_file(self.replacement_path(chunk))?;
+        }
+        let mut replacement = RolledFile::new(&self.name, &(self.extension.clone() + REPLACEMENT_EXTENSION), self.append_only, self.chunk_size)?;
+        replacement.set_sync_mode(self.sync_mode);
+        Ok(Box::new(replacement))
+    }
+
+    fn replace_with(&mut self, replacement: Box<dyn PagedFile>) -> Result<(), Error> {
+        let len = replacement.len()?;
+        replacement.sync()?;
+        // the chunks of the replacement are renamed to those of this file
+        drop(replacement);
+        self.complete_replacement(true)?;
+        self.truncate(len)
+    }
+
+    fn complete_replacement(&mut self, commit: bool) -> Result<(), Error> {
+        if self.read_only {
+            return Ok(());
+        }
+        let mut chunks = Self::chunks_of(&self.name, &(self.extension.clone() + REPLACEMENT_EXTENSION))?;
+        if chunks.is_empty() {
+            return Ok(());
+        }
+        if commit {
+            chunks.sort();
+            let last = chunks[chunks.len() - 1];
+            self.files.clear();
+            // chunks after the last of the replacement would extend it, a completion after a crash finds them removed
+            for chunk in Self::chunks_of(&self.name, &self.extension)? {
+                if chunk > last {
+                    fs::remove_file(self.chunk_path(&self.name, chunk))?;
+                }
+            }
+            for chunk in chunks {
+                fs::rename(self.replacement_path(chunk), self.chunk_path(&self.name, chunk))?;
+            }
+            self.len = 0;
+            self.open()?;
+        }
+        else {
+            for chunk in chunks {
+                fs::remove_file(self.replacement_path(chunk))?;
+            }
+        }
+        // renames and removals are durable with the directory
+        File::open(Self::dir_of(&self.name))?.sync_all()?;
+        Ok(())
+    }
+
+    fn copy_to(&self, dest: &mut dyn PagedFile) -> Result<u64, Error> {
+        let mut chunks = self.files.keys().cloned().collect::<Vec<_>>();
+        chunks.sort();
+        let mut copied = 0;
+        for chunk in chunks {
+            copied += self.files[&chunk].copy_to(dest)?;
+        }
+        Ok(copied)
+    }
+
+    fn advise(&self, advice: FadviseAdvice) -> Result<(), Error> {
+        for file in self.files.values() {
+            file.advise(advice)?;
+        }
+        Ok(())
+    }
+
     fn shutdown (&mut self) {}
 
     fn append_page (&mut self, page: Page) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
         let chunk = (self.len / self.chunk_size) as u16;
 
         if self.len % self.chunk_size == 0 && !self.files.contains_key(&chunk) {
-            let file = Self::open_file(self.append_only, (((self.name.clone() + ".")
-                + chunk.to_string().as_str()) + ".") + self.extension.as_str())?;
-            self.files.insert(chunk, SingleFile::new_chunk(file, self.len, self.chunk_size)?);
+            let file = self.open_chunk(self.chunk_path(&self.name, chunk), self.len)?;
+            self.files.insert(chunk, file);
         }
 
         if let Some (file) = self.files.get_mut(&chunk) {
@@ -167,26 +381,28 @@ impl PagedFile for RolledFile {
             self.len += PAGE_SIZE as u64;
         }
         else {
-            return Err(Error::Corrupted(format!("missing chunk in append {}", chunk)));
+            return Err(Error::corrupted(format!("missing chunk in append {}", chunk)));
         }
         Ok(())
     }
 
     fn update_page(&mut self, page: Page) -> Result<u64, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
         let n_offset = page.pref().as_u64();
         let chunk = (n_offset / self.chunk_size) as u16;
 
         if !self.files.contains_key(&chunk) {
-            let file = Self::open_file(self.append_only, (((self.name.clone() + ".")
-                + chunk.to_string().as_str()) + ".") + self.extension.as_str())?;
-            self.files.insert(chunk, SingleFile::new_chunk(file, (n_offset/self.chunk_size) * self.chunk_size, self.chunk_size)?);
+            let file = self.open_chunk(self.chunk_path(&self.name, chunk), (n_offset/self.chunk_size) * self.chunk_size)?;
+            self.files.insert(chunk, file);
         }
 
         if let Some(file) = self.files.get_mut(&chunk) {
             self.len = max(self.len, file.update_page(page)?  + chunk as u64 * self.chunk_size);
             Ok(self.len)
         } else {
-            return Err(Error::Corrupted(format!("missing chunk in write {}", chunk)));
+            return Err(Error::corrupted(format!("missing chunk in write {}", chunk)));
         }
     }
 
@@ -196,4 +412,177 @@ impl PagedFile for RolledFile {
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use transient::Transient;
+
+    use std::env;
+
+    #[test]
+    fn test_tail_pages() {
+        let name = env::temp_dir().join("hammersbald_test_tail_pages").to_string_lossy().to_string();
+        // 8 pages per chunk, so the tail spans chunks
+        let mut file = RolledFile::new(&name, "bc", true, 8 * PAGE_SIZE as u64).unwrap();
+        for i in 0 .. 20 {
+            let mut page = Page::new();
+            page.write_u64(0, i);
+            file.append_page(page).unwrap();
+        }
+        file.flush().unwrap();
+
+        let tail = file.tail_pages(5).unwrap();
+        assert_eq!(tail.len(), 5);
+        for (n, (pref, page)) in tail.iter().enumerate() {
+            assert_eq!(*pref, PRef::from((15 + n as u64) * PAGE_SIZE as u64));
+            assert_eq!(page.read_u64(0), 15 + n as u64);
+        }
+        assert_eq!(file.tail_pages(100).unwrap().len(), 20);
+        assert!(file.tail_pages(0).unwrap().is_empty());
+
+        for chunk in 0 .. 3 {
+            fs::remove_file(file.chunk_path(&name, chunk)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_prune_chunks_before() {
+        let name = env::temp_dir().join("hammersbald_test_prune_chunks").to_string_lossy().to_string();
+        // 2 pages per chunk, 4 chunks
+        let mut file = RolledFile::new(&name, "bc", true, 2 * PAGE_SIZE as u64).unwrap();
+        for i in 0 .. 8 {
+            let mut page = Page::new();
+            page.write_u64(0, i);
+            file.append_page(page).unwrap();
+        }
+        file.flush().unwrap();
+        assert_eq!(file.chunk_files().unwrap(), (0 .. 4).map(|c| (c, 2 * PAGE_SIZE as u64)).collect::<Vec<_>>());
+
+        // the chunk of the pref is kept
+        assert_eq!(file.prune_chunks_before(PRef::from(5 * PAGE_SIZE as u64)).unwrap(), 4 * PAGE_SIZE as u64);
+        assert!(!Path::new(&file.chunk_path(&name, 0)).exists());
+        assert!(!Path::new(&file.chunk_path(&name, 1)).exists());
+        assert_eq!(file.chunk_files().unwrap(), vec!((2, 2 * PAGE_SIZE as u64), (3, 2 * PAGE_SIZE as u64)));
+        assert!(file.read_page(PRef::from(0)).unwrap().is_none());
+        for i in 4 .. 8 {
+            assert_eq!(file.read_page(PRef::from(i * PAGE_SIZE as u64)).unwrap().unwrap().read_u64(0), i);
+        }
+        assert!(file.prune_chunks_before(PRef::from(9 * PAGE_SIZE as u64)).is_err());
+
+        for chunk in 2 .. 4 {
+            fs::remove_file(file.chunk_path(&name, chunk)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_copy_files() {
+        let name = env::temp_dir().join("hammersbald_test_copy_files").to_string_lossy().to_string();
+        let dest = env::temp_dir().join("hammersbald_test_copy_files_dest").to_string_lossy().to_string();
+        let mut file = RolledFile::new(&name, "bc", true, 2 * PAGE_SIZE as u64).unwrap();
+        for i in 0 .. 3 {
+            let mut page = Page::new();
+            page.write_u64(0, i);
+            file.append_page(page).unwrap();
+        }
+        file.flush().unwrap();
+
+        // a longer earlier copy
+        fs::write(file.chunk_path(&dest, 2), vec!(0u8; PAGE_SIZE)).unwrap();
+        fs::write(file.chunk_path(&dest, 3), vec!(0u8; PAGE_SIZE)).unwrap();
+        let stats = file.copy_files(&dest).unwrap();
+        assert_eq!(stats.files_copied, 2);
+        assert_eq!(stats.bytes_copied, 3 * PAGE_SIZE as u64);
+        assert!(!Path::new(&file.chunk_path(&dest, 2)).exists());
+        assert!(!Path::new(&file.chunk_path(&dest, 3)).exists());
+
+        let copy = RolledFile::new(&dest, "bc", true, 2 * PAGE_SIZE as u64).unwrap();
+        assert_eq!(copy.len().unwrap(), 3 * PAGE_SIZE as u64);
+        for i in 0 .. 3 {
+            assert_eq!(copy.read_page(PRef::from(i * PAGE_SIZE as u64)).unwrap().unwrap().read_u64(0), i);
+        }
+
+        for chunk in 0 .. 2 {
+            fs::remove_file(file.chunk_path(&name, chunk)).unwrap();
+            fs::remove_file(file.chunk_path(&dest, chunk)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_replace_with() {
+        let name = env::temp_dir().join("hammersbald_test_replace_with").to_string_lossy().to_string();
+        let mut file = RolledFile::new(&name, "bc", true, 2 * PAGE_SIZE as u64).unwrap();
+        for i in 0 .. 5 {
+            let mut page = Page::new();
+            page.write_u64(0, i);
+            file.append_page(page).unwrap();
+        }
+        file.flush().unwrap();
+
+        // a replacement not swapped in is removed
+        let mut replacement = file.replacement().unwrap();
+        replacement.append_page(Page::new()).unwrap();
+        replacement.flush().unwrap();
+        drop(replacement);
+        assert!(Path::new(&file.replacement_path(0)).exists());
+        file.complete_replacement(false).unwrap();
+        assert!(!Path::new(&file.replacement_path(0)).exists());
+        assert_eq!(file.len().unwrap(), 5 * PAGE_SIZE as u64);
+
+        let mut replacement = file.replacement().unwrap();
+        for i in 10 .. 13 {
+            let mut page = Page::new();
+            page.write_u64(0, i);
+            replacement.append_page(page).unwrap();
+        }
+        replacement.flush().unwrap();
+        file.replace_with(replacement).unwrap();
+        assert_eq!(file.len().unwrap(), 3 * PAGE_SIZE as u64);
+        assert_eq!(file.chunk_files().unwrap(), vec!((0, 2 * PAGE_SIZE as u64), (1, PAGE_SIZE as u64)));
+        assert!(!Path::new(&file.chunk_path(&name, 2)).exists());
+        assert!(RolledFile::chunks_of(&name, "bc.new").unwrap().is_empty());
+
+        let reopened = RolledFile::new(&name, "bc", true, 2 * PAGE_SIZE as u64).unwrap();
+        assert_eq!(reopened.len().unwrap(), 3 * PAGE_SIZE as u64);
+        for i in 0 .. 3 {
+            assert_eq!(reopened.read_page(PRef::from(i * PAGE_SIZE as u64)).unwrap().unwrap().read_u64(0), 10 + i);
+        }
+
+        for chunk in 0 .. 2 {
+            fs::remove_file(file.chunk_path(&name, chunk)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_copy_to() {
+        let name = env::temp_dir().join("hammersbald_test_copy_to").to_string_lossy().to_string();
+        // not append only, so appends after the copy must find the end of the file again
+        let mut file = RolledFile::new(&name, "tb", false, 8 * PAGE_SIZE as u64).unwrap();
+        for i in 0 .. 20 {
+            let mut page = Page::new();
+            page.write_u64(0, i);
+            file.append_page(page).unwrap();
+        }
+        file.flush().unwrap();
+
+        let mut copy = Transient::new(false);
+        assert_eq!(file.copy_to(&mut copy).unwrap(), 20 * PAGE_SIZE as u64);
+        assert_eq!(copy.len().unwrap(), 20 * PAGE_SIZE as u64);
+        for i in 0 .. 20 {
+            assert_eq!(copy.read_page(PRef::from(i * PAGE_SIZE as u64)).unwrap().unwrap().read_u64(0), i);
+        }
+
+        let mut page = Page::new();
+        page.write_u64(0, 20);
+        file.append_page(page).unwrap();
+        file.flush().unwrap();
+        for i in 0 .. 21 {
+            assert_eq!(file.read_page(PRef::from(i * PAGE_SIZE as u64)).unwrap().unwrap().read_u64(0), i);
+        }
+
+        for chunk in 0 .. 3 {
+            fs::remove_file(file.chunk_path(&name, chunk)).unwrap();
+        }
+    }
+}
@@ -19,44 +19,63 @@
 //!
 
 use error::Error;
-use pagedfile::PagedFile;
+use pagedfile::{PagedFile, SyncMode};
+#[cfg(feature = "tokio_support")]
+use pagedfile::spawn_page_read;
+#[cfg(all(feature = "fadvise", target_os = "linux"))]
+use pagedfile::FadviseAdvice;
 use page::{PAGE_SIZE, Page};
 use pref::PRef;
 
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "tokio_support")]
+use std::future::Future;
 use std::fs::File;
-use std::io::{Read,Write,Seek,SeekFrom};
-use std::cmp::max;
+use std::io::{self,Read,Write,Seek,SeekFrom};
+use std::cmp::{max, min};
 
 pub struct SingleFile {
-    file: Mutex<File>,
+    file: Arc<Mutex<File>>,
+    name: String,
     base: u64,
     len: u64,
-    chunk_size: u64
+    chunk_size: u64,
+    sync_mode: SyncMode
 }
 
 impl SingleFile {
     #[allow(unused)]
-    pub fn new (mut file: File) -> Result<SingleFile, Error> {
+    pub fn new (mut file: File, name: &str) -> Result<SingleFile, Error> {
         let len = file.seek(SeekFrom::End(0))?;
-        Ok(SingleFile{file: Mutex::new(file), base: 0, len, chunk_size: 1 << 47})
+        Ok(SingleFile{file: Arc::new(Mutex::new(file)), name: name.to_string(), base: 0, len, chunk_size: 1 << 47, sync_mode: SyncMode::Data})
     }
 
-    pub fn new_chunk (mut file: File, base: u64, chunk_size: u64) -> Result<SingleFile, Error> {
+    pub fn new_chunk (mut file: File, name: &str, base: u64, chunk_size: u64) -> Result<SingleFile, Error> {
         let len = file.seek(SeekFrom::End(0))?;
-        Ok(SingleFile{file: Mutex::new(file), base, len, chunk_size})
+        Ok(SingleFile{file: Arc::new(Mutex::new(file)), name: name.to_string(), base, len, chunk_size, sync_mode: SyncMode::Data})
     }
-}
 
-impl PagedFile for SingleFile {
-    fn read_page(&self, pref: PRef) -> Result<Option<Page>, Error> {
+    /// set how sync writes to disk
+    pub fn set_sync_mode(&mut self, sync_mode: SyncMode) {
+        self.sync_mode = sync_mode;
+    }
+
+    /// read a page without blocking the async executor, must be called within a tokio runtime
+    #[cfg(feature = "tokio_support")]
+    #[allow(unused)]
+    pub fn read_page_async(&self, pref: PRef) -> impl Future<Output=Result<Option<Page>, Error>> {
+        let (file, name, base, len, chunk_size) = (self.file.clone(), self.name.clone(), self.base, self.len, self.chunk_size);
+        spawn_page_read(move || Self::read_page_of(&file, &name, base, len, chunk_size, pref))
+    }
+
+    fn read_page_of(file: &Mutex<File>, name: &str, base: u64, len: u64, chunk_size: u64, pref: PRef) -> Result<Option<Page>, Error> {
         let o = pref.as_u64();
-        if o < self.base || o >= self.base + self.chunk_size {
-            return Err(Error::Corrupted("read from wrong file".to_string()));
+        if o < base || o >= base + chunk_size {
+            return Err(Error::corrupted_at("read from wrong file", name, pref));
         }
-        let pos = o - self.base;
-        if pos < self.len {
-            let mut file = self.file.lock().unwrap();
+        let pos = o - base;
+        if pos < len {
+            let mut file = file.lock().unwrap();
             file.seek(SeekFrom::Start(pos))?;
             let mut buffer = [0u8; PAGE_SIZE];
             file.read_exact(&mut buffer[..])?;
@@ -64,6 +83,12 @@ impl PagedFile for SingleFile {
         }
         Ok(None)
     }
+}
+
+impl PagedFile for SingleFile {
+    fn read_page(&self, pref: PRef) -> Result<Option<Page>, Error> {
+        Self::read_page_of(&self.file, &self.name, self.base, self.len, self.chunk_size, pref)
+    }
 
     fn len(&self) -> Result<u64, Error> {
         Ok(self.len)
@@ -75,7 +100,39 @@ impl PagedFile for SingleFile {
     }
 
     fn sync(&self) -> Result<(), Error> {
-        Ok(self.file.lock().unwrap().sync_data()?)
+        match self.sync_mode {
+            SyncMode::Full => Ok(self.file.lock().unwrap().sync_all()?),
+            SyncMode::Data => Ok(self.file.lock().unwrap().sync_data()?),
+            SyncMode::None => Ok(())
+        }
+    }
+
+    #[cfg(all(feature = "fadvise", target_os = "linux"))]
+    fn advise(&self, advice: FadviseAdvice) -> Result<(), Error> {
+        use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+        use std::os::unix::io::AsRawFd;
+
+        let (offset, len, advice) = match advice {
+            FadviseAdvice::Sequential => (0, 0, PosixFadviseAdvice::POSIX_FADV_SEQUENTIAL),
+            FadviseAdvice::Random => (0, 0, PosixFadviseAdvice::POSIX_FADV_RANDOM),
+            FadviseAdvice::WillNeed(pos, len) | FadviseAdvice::DontNeed(pos, len) => {
+                // clip to the range of this chunk
+                let start = max(pos, self.base);
+                let end = min(pos.saturating_add(len), self.base + self.chunk_size);
+                if start >= end {
+                    return Ok(());
+                }
+                let advice = if let FadviseAdvice::WillNeed(_, _) = advice {
+                    PosixFadviseAdvice::POSIX_FADV_WILLNEED
+                } else {
+                    PosixFadviseAdvice::POSIX_FADV_DONTNEED
+                };
+                (start - self.base, end - start, advice)
+            }
+        };
+        let file = self.file.lock().unwrap();
+        posix_fadvise(file.as_raw_fd(), offset as i64, len as i64, advice)
+            .map_err(|e| Error::IO(io::Error::from_raw_os_error(e as i32)))
     }
 
     fn shutdown (&mut self) {}
@@ -90,7 +147,7 @@ impl PagedFile for SingleFile {
     fn update_page(&mut self, page: Page) -> Result<u64, Error> {
         let o = page.pref().as_u64();
         if o < self.base || o >= self.base + self.chunk_size {
-            return Err(Error::Corrupted("write to wrong file".to_string()));
+            return Err(Error::corrupted_at("write to wrong file", &self.name, page.pref()));
         }
         let pos = o - self.base;
 
@@ -104,4 +161,89 @@ impl PagedFile for SingleFile {
     fn flush(&mut self) -> Result<(), Error> {
         Ok(self.file.lock().unwrap().flush()?)
     }
-}
\ No newline at end of file
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn copy_to(&self, dest: &mut dyn PagedFile) -> Result<u64, Error> {
+        let mut file = self.file.lock().unwrap();
+        // appends write at the current position of files not opened for append
+        let pos = file.seek(SeekFrom::Current(0))?;
+        file.seek(SeekFrom::Start(0))?;
+        let copied = io::copy(&mut (&mut *file).take(self.len), &mut PageWriter { dest, buffer: Vec::with_capacity(PAGE_SIZE) });
+        file.seek(SeekFrom::Start(pos))?;
+        Ok(copied?)
+    }
+}
+
+// appends what is written to a paged file, a page at a time
+struct PageWriter<'a> {
+    dest: &'a mut dyn PagedFile,
+    buffer: Vec<u8>
+}
+
+impl<'a> Write for PageWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = min(PAGE_SIZE - self.buffer.len(), buf.len());
+        self.buffer.extend_from_slice(&buf[.. n]);
+        if self.buffer.len() == PAGE_SIZE {
+            let mut page = [0u8; PAGE_SIZE];
+            page.copy_from_slice(self.buffer.as_slice());
+            self.buffer.clear();
+            self.dest.append_page(Page::from_buf(page)).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pagedfile::FadviseAdvice;
+
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_advise() {
+        let path = env::temp_dir().join("hammersbald_test_advise.bc");
+        let mut file = SingleFile::new(fs::OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap(), &path.to_string_lossy()).unwrap();
+        file.append_page(Page::new()).unwrap();
+        file.flush().unwrap();
+        file.advise(FadviseAdvice::Sequential).unwrap();
+        file.advise(FadviseAdvice::Random).unwrap();
+        file.advise(FadviseAdvice::WillNeed(0, PAGE_SIZE as u64)).unwrap();
+        file.advise(FadviseAdvice::DontNeed(0, PAGE_SIZE as u64)).unwrap();
+        // ranges outside of the file are ignored
+        file.advise(FadviseAdvice::WillNeed(1 << 48, PAGE_SIZE as u64)).unwrap();
+        assert!(file.read_page(PRef::from(0)).unwrap().is_some());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "tokio_support")]
+    #[test]
+    fn test_read_page_async() {
+        let path = env::temp_dir().join("hammersbald_test_read_page_async.bc");
+        let mut file = SingleFile::new(fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap(), &path.to_string_lossy()).unwrap();
+        for i in 0 .. 10 {
+            let mut page = Page::new();
+            page.write_u64(0, i);
+            file.append_page(page).unwrap();
+        }
+        file.flush().unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let _guard = runtime.enter();
+        let reads = (0 .. 10).map(|i| file.read_page_async(PRef::from(i * PAGE_SIZE as u64))).collect::<Vec<_>>();
+        for (i, read) in reads.into_iter().enumerate() {
+            let page = runtime.block_on(read).unwrap().unwrap();
+            assert_eq!(page.read_u64(0), i as u64);
+        }
+        assert!(runtime.block_on(file.read_page_async(PRef::from(10 * PAGE_SIZE as u64))).unwrap().is_none());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -19,10 +19,233 @@
 //!
 use api::Hammersbald;
 use format::Payload;
+use pref::PRef;
 
 use bitcoin_hashes::siphash24;
 
 use std::collections::{HashMap, HashSet};
+use std::{fmt, ops};
+
+/// Statistics of a db
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    /// estimated bytes of memory used by the in-memory hash table
+    pub memtable_bytes: usize,
+    /// number of stored entries by type
+    pub type_counts: TypeCounts,
+    /// bytes of stored entries by type
+    pub type_bytes: TypeBytes,
+    /// number of buckets of the hash table
+    pub buckets: usize,
+    /// keys stored since the db was opened
+    pub inserted: usize,
+    /// keys forgotten since the db was opened
+    pub forgotten: usize,
+    /// bytes of data no longer accessible with its key since the db was opened, as forgotten or overwritten
+    pub freed_bytes: u64,
+    /// batches ended since the db was opened
+    pub batches: usize
+}
+
+/// Changes between two statistics of a db, see stats_diff
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StatsDiff {
+    /// keys stored
+    pub inserted: usize,
+    /// keys forgotten
+    pub forgotten: usize,
+    /// bytes of data appended
+    pub data_bytes_added: u64,
+    /// bytes of data no longer accessible with its key
+    pub data_bytes_freed: u64,
+    /// buckets added to the hash table
+    pub buckets_added: usize,
+    /// batches ended
+    pub batches_elapsed: usize
+}
+
+/// Number of stored entries by type
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TypeCounts {
+    /// data accessible with a key
+    pub indexed: usize,
+    /// data accessible with a persistent reference
+    pub referred: usize,
+    /// hash table extensions
+    pub links: usize
+}
+
+impl ops::AddAssign for TypeCounts {
+    fn add_assign(&mut self, other: TypeCounts) {
+        self.indexed += other.indexed;
+        self.referred += other.referred;
+        self.links += other.links;
+    }
+}
+
+/// Bytes of stored entries by type, including their envelope
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TypeBytes {
+    /// data accessible with a key
+    pub indexed: u64,
+    /// data accessible with a persistent reference
+    pub referred: u64,
+    /// hash table extensions
+    pub links: u64
+}
+
+impl ops::AddAssign for TypeBytes {
+    fn add_assign(&mut self, other: TypeBytes) {
+        self.indexed += other.indexed;
+        self.referred += other.referred;
+        self.links += other.links;
+    }
+}
+
+/// counters of a page cache since the db was opened
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// reads found in the cache
+    pub hits: u64,
+    /// reads not found in the cache
+    pub misses: u64,
+    /// pages evicted to make room for others
+    pub evictions: u64,
+    /// pages now cached
+    pub cached_pages: usize,
+    /// pages the cache can hold
+    pub capacity: usize
+}
+
+impl CacheStats {
+    /// share of reads found in the cache, zero if there were no reads
+    pub fn hit_rate(&self) -> f64 {
+        if self.hits + self.misses == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / (self.hits + self.misses) as f64
+    }
+}
+
+/// Statistics of the hash table
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MemTableStats {
+    /// number of buckets
+    pub bucket_count: usize,
+    /// number of keys
+    pub entry_count: usize,
+    /// keys per bucket
+    pub load_factor: f64,
+    /// next bucket to split
+    pub rehash_step: usize,
+    /// log2 of buckets before the current round of splits
+    pub log_mod: u32,
+    /// fraction of keys sharing their hash with an other key of their bucket
+    pub collision_rate: f64,
+    /// length of the data file
+    pub data_file_bytes: u64,
+    /// length of the link file
+    pub link_file_bytes: u64,
+    /// page cache of the data file, tuned with cached_data_pages
+    pub data_cache: CacheStats,
+    /// page cache of the link file
+    pub link_cache: CacheStats,
+    /// page cache of the table file
+    pub table_cache: CacheStats
+}
+
+/// Statistics of a link file compaction
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LinkCompactionStats {
+    /// length of the link file before compaction
+    pub bytes_before: u64,
+    /// length of the link file after compaction
+    pub bytes_after: u64,
+    /// number of links no longer referred by the table
+    pub entries_removed: usize
+}
+
+/// Result of a data file compaction
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// length of the data file before compaction
+    pub bytes_before: u64,
+    /// length of the data file after compaction
+    pub bytes_after: u64,
+    /// number of records now at a different pref
+    pub records_moved: usize,
+    /// new pref of each record kept, by its old pref
+    pub remapping: HashMap<PRef, PRef>
+}
+
+/// Statistics of a backup
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BackupStats {
+    /// number of files copied
+    pub files_copied: usize,
+    /// number of bytes copied
+    pub bytes_copied: u64
+}
+
+impl ops::AddAssign for BackupStats {
+    fn add_assign(&mut self, other: BackupStats) {
+        self.files_copied += other.files_copied;
+        self.bytes_copied += other.bytes_copied;
+    }
+}
+
+/// Statistics of the files and the hash table of a db
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DBStats {
+    /// number of buckets
+    pub bucket_count: usize,
+    /// number of keys
+    pub entry_count: usize,
+    /// keys per bucket
+    pub load_factor: f64,
+    /// length of the data file
+    pub data_file_bytes: u64,
+    /// length of the link file
+    pub link_file_bytes: u64,
+    /// length of the table file
+    pub table_file_bytes: u64,
+    /// length of the log file
+    pub log_file_bytes: u64,
+    /// data stored with a key no longer accessible with it, as forgotten or overwritten
+    pub garbage_indexed: usize,
+    /// referred data not linked from data accessible with a key, alignment padding is not counted
+    /// data only the application holds a persistent reference to is also counted, as stored with put alone
+    pub garbage_referred: usize
+}
+
+impl fmt::Display for DBStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "buckets: {}, keys: {}, load factor: {:.2}, files: data {} link {} table {} log {} bytes, garbage: indexed {} referred {}",
+               self.bucket_count, self.entry_count, self.load_factor,
+               self.data_file_bytes, self.link_file_bytes, self.table_file_bytes, self.log_file_bytes,
+               self.garbage_indexed, self.garbage_referred)
+    }
+}
+
+/// Statistics of a recovery from the log
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecoveryStats {
+    /// logged table pages written back to the table
+    pub pages_replayed: usize,
+    /// logged table pages not written back as they or a page before them failed their checksum
+    pub pages_skipped: usize,
+    /// bytes cut from the log after a page failing its checksum
+    pub truncated_bytes: u64,
+    /// new pref of each record moved by a compaction the recovery completed, empty if there was none
+    pub remapping: HashMap<PRef, PRef>
+}
+
+impl fmt::Display for RecoveryStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "replayed {} pages, skipped {} pages, truncated {} bytes of the log, moved {} records",
+               self.pages_replayed, self.pages_skipped, self.truncated_bytes, self.remapping.len())
+    }
+}
 
 /// print some statistics on a db
 #[allow(unused)]
@@ -85,6 +308,9 @@ fn stats(db: &Hammersbald) {
             Payload::Referred(data) => {
                 referred += 1;
             },
+            Payload::ReferredWithRefs(_) => {
+                referred += 1;
+            },
             _ => panic!("Unexpected payload type in data at {}", pos)
         }
     }
@@ -20,16 +20,21 @@
 
 use std::cmp::max;
 
-use page::{Page, PAGE_SIZE, PAGE_PAYLOAD_SIZE};
+use page::{Page, PAGE_SIZE, PAGE_CHECKSUM_POS};
 use pagedfile::PagedFile;
 use memtable::MemTable;
 use error::Error;
 use pref::PRef;
+use stats::{BackupStats, CacheStats};
 
-pub const FIRST_PAGE_HEAD:usize = 28;
+pub const FIRST_PAGE_HEAD:usize = 54;
+// version of the file formats, stored in the first page after the sip keys
+// version 2 stores key lengths as u16, version 3 checksums table pages, version 4 stores the number of keys
+// version 5 stores the chunk sizes of the files
+pub const FORMAT_VERSION: u16 = 5;
 pub const BUCKET_SIZE: usize = 6;
-pub const BUCKETS_PER_PAGE:usize = PAGE_PAYLOAD_SIZE/BUCKET_SIZE;
-pub const BUCKETS_FIRST_PAGE:usize = (PAGE_PAYLOAD_SIZE - FIRST_PAGE_HEAD)/BUCKET_SIZE;
+pub const BUCKETS_PER_PAGE:usize = PAGE_CHECKSUM_POS/BUCKET_SIZE;
+pub const BUCKETS_FIRST_PAGE:usize = (PAGE_CHECKSUM_POS - FIRST_PAGE_HEAD)/BUCKET_SIZE;
 
 /// The key file
 pub struct TableFile {
@@ -57,6 +62,17 @@ impl TableFile {
     pub fn iter<'a>(&'a self) -> impl Iterator<Item=PRef> +'a {
         BucketIterator{file: self, n:0}
     }
+
+    /// write empty pages up to the page of target_bucket, so later updates do not have to
+    #[allow(unused)]
+    pub fn initialize_pages_up_to(&mut self, target_bucket: usize) -> Result<(), Error> {
+        let last = Self::table_offset(target_bucket).this_page();
+        while self.initialized_until <= last {
+            self.file.update_page(MemTable::invalid_offsets_page(self.initialized_until))?;
+            self.initialized_until = self.initialized_until.add_pages(1);
+        }
+        Ok(())
+    }
 }
 
 impl PagedFile for TableFile {
@@ -77,13 +93,48 @@ impl PagedFile for TableFile {
         self.file.sync()
     }
 
+    fn copy_files(&self, dest_name: &str) -> Result<BackupStats, Error> {
+        self.file.copy_files(dest_name)
+    }
+
+    fn copy_to(&self, dest: &mut dyn PagedFile) -> Result<u64, Error> {
+        self.file.copy_to(dest)
+    }
+
+    fn name(&self) -> String {
+        self.file.name()
+    }
+
     fn shutdown (&mut self) {}
 
+    fn cache_stats(&self) -> Option<CacheStats> {
+        self.file.cache_stats()
+    }
+
+    fn replacement(&self) -> Result<Box<dyn PagedFile>, Error> {
+        self.file.replacement()
+    }
+
+    fn replace_with(&mut self, replacement: Box<dyn PagedFile>) -> Result<(), Error> {
+        self.file.replace_with(replacement)?;
+        self.initialized_until = PRef::from(self.file.len()?);
+        Ok(())
+    }
+
+    fn complete_replacement(&mut self, commit: bool) -> Result<(), Error> {
+        self.file.complete_replacement(commit)?;
+        self.initialized_until = PRef::from(self.file.len()?);
+        Ok(())
+    }
+
     fn read_page(&self, pref: PRef) -> Result<Option<Page>, Error> {
         let result = self.file.read_page(pref)?;
         if let Some(ref page) = result {
             if page.pref() != pref {
-                return Err(Error::Corrupted(format!("table page {} does not have the pref of its position", pref)));
+                return Err(Error::corrupted_at(format!("table page has the pref {} instead of its position", page.pref()), &self.file.name(), pref));
+            }
+            if !page.verify_checksum() {
+                return Err(Error::corrupted_at("checksum mismatch of table page", &self.file.name(), pref));
             }
         }
         Ok(result)
@@ -93,13 +144,14 @@ impl PagedFile for TableFile {
         unimplemented!()
     }
 
-    fn update_page(&mut self, page: Page) -> Result<u64, Error> {
+    fn update_page(&mut self, mut page: Page) -> Result<u64, Error> {
         if page.pref().as_u64() >= self.len()? {
             while page.pref() > self.initialized_until {
                 self.file.update_page(MemTable::invalid_offsets_page(self.initialized_until))?;
                 self.initialized_until = self.initialized_until.add_pages(1);
             }
         }
+        page.write_checksum();
         self.initialized_until = max(self.initialized_until,page.pref().add_pages(1));
         self.file.update_page(page)
     }
@@ -123,3 +175,58 @@ impl<'a> Iterator for BucketIterator<'a> {
     }
 }
 
+#[cfg(test)]
+mod test {
+    use transient::Transient;
+    use rolledfile::RolledFile;
+
+    use super::*;
+
+    use std::{env, fs};
+
+    #[test]
+    fn test_initialize_pages_up_to() {
+        let mut table = TableFile::new(Box::new(Transient::new(false))).unwrap();
+        table.initialize_pages_up_to(1000).unwrap();
+        let last = TableFile::table_offset(1000).this_page();
+        assert_eq!(table.len().unwrap(), last.add_pages(1).as_u64());
+        assert_eq!(table.initialized_until, last.add_pages(1));
+        for bucket in 0 .. 1000 {
+            assert!(!table.read_page(TableFile::table_offset(bucket).this_page()).unwrap().unwrap()
+                .read_pref(TableFile::table_offset(bucket).in_page_pos()).is_valid());
+        }
+
+        let initialized_until = table.initialized_until;
+        table.update_page(MemTable::invalid_offsets_page(last)).unwrap();
+        assert_eq!(table.initialized_until, initialized_until);
+        assert_eq!(table.len().unwrap(), last.add_pages(1).as_u64());
+
+        // does not shrink
+        table.initialize_pages_up_to(10).unwrap();
+        assert_eq!(table.initialized_until, initialized_until);
+    }
+
+    #[test]
+    fn test_corrupted_page_names_file() {
+        let name = env::temp_dir().join("hammersbald_test_corrupted_table").to_string_lossy().to_string();
+        let path = name.clone() + ".0.tb";
+        fs::remove_file(&path).ok();
+        let mut rolled = RolledFile::new(&name, "tb", false, 8 * PAGE_SIZE as u64).unwrap();
+        // a page at its position but without checksum
+        rolled.update_page(Page::new_table_page(PRef::from(0))).unwrap();
+        let table = TableFile::new(Box::new(rolled)).unwrap();
+        match table.read_page(PRef::from(0)) {
+            Err(e @ Error::Corrupted{..}) => {
+                let message = e.to_string();
+                assert!(message.contains("checksum mismatch"));
+                assert!(message.contains(&(name.clone() + ".tb")));
+                if let Error::Corrupted{file, pref, ..} = e {
+                    assert_eq!(file, Some(name.clone() + ".tb"));
+                    assert_eq!(pref, Some(PRef::from(0)));
+                }
+            },
+            _ => panic!("checksum mismatch should be reported")
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
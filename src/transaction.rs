@@ -0,0 +1,502 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Transaction
+//!
+//! Writes buffered in memory that are applied and batched at once on commit
+//!
+
+use api::{HammersbaldAPI, HammersbaldIterator, IndexedIterator, ReferredIterator, PrefixIterator, ApproximateResult, BucketInspection};
+use accesslog::AccessLog;
+use datafile::DagIterator;
+use error::Error;
+use memtable::BatchPhase;
+use pref::PRef;
+use stats::{Stats, MemTableStats, BackupStats, CompactionReport, LinkCompactionStats, RecoveryStats, DBStats};
+
+use std::collections::HashSet;
+use std::sync::{Arc, mpsc};
+
+/// a write buffered by a transaction
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TransactionOp {
+    /// put_keyed of key and data
+    Put(Vec<u8>, Vec<u8>),
+    /// forget of key
+    Forget(Vec<u8>)
+}
+
+/// writes buffered until commit, nothing is written if rolled back or dropped
+///
+/// reads see committed data and the writes of the transaction. Data put within the
+/// transaction has no pref before commit, it is reported with an invalid pref.
+/// Iterators, references and statistics only see committed data.
+/// Writes other than put_keyed and forget return Error::InTransaction.
+pub struct Transaction<'a> {
+    db: &'a mut dyn HammersbaldAPI,
+    ops: Vec<TransactionOp>
+}
+
+impl<'a> Transaction<'a> {
+    /// start a transaction on a db
+    pub fn new(db: &'a mut dyn HammersbaldAPI) -> Transaction<'a> {
+        Transaction { db, ops: Vec::new() }
+    }
+
+    /// the buffered writes in the order they were made
+    pub fn ops(&self) -> &[TransactionOp] {
+        self.ops.as_slice()
+    }
+
+    /// apply the buffered writes to the db and end its batch
+    /// if a write fails, the writes applied before are undone and the batch is not ended
+    pub fn commit(self) -> Result<(), Error> {
+        let Transaction { db, ops } = self;
+        let mut undo = Vec::with_capacity(ops.len());
+        for op in &ops {
+            let key = match op {
+                TransactionOp::Put(key, _) | TransactionOp::Forget(key) => key.as_slice()
+            };
+            let applied = db.get_keyed(key)
+                .map(|before| undo.push((key, before.map(|(_, data)| data))))
+                .and_then(|_| match op {
+                    TransactionOp::Put(key, data) => db.put_keyed(key, data).map(|_| ()),
+                    TransactionOp::Forget(key) => db.forget(key)
+                });
+            if let Err(e) = applied {
+                for (key, before) in undo.into_iter().rev() {
+                    match before {
+                        Some(data) => { db.put_keyed(key, &data)?; },
+                        None => db.forget(key)?
+                    }
+                }
+                return Err(e);
+            }
+        }
+        db.batch()
+    }
+
+    /// drop the buffered writes
+    pub fn rollback(self) {}
+
+    // the last buffered write of key, Some(None) if it was forgotten
+    fn pending(&self, key: &[u8]) -> Option<Option<&[u8]>> {
+        for op in self.ops.iter().rev() {
+            match op {
+                TransactionOp::Put(k, data) if k.as_slice() == key => return Some(Some(data.as_slice())),
+                TransactionOp::Forget(k) if k.as_slice() == key => return Some(None),
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+impl<'a> HammersbaldAPI for Transaction<'a> {
+    fn batch(&mut self) -> Result<(), Error> {
+        Err(Error::InTransaction)
+    }
+
+    fn batch_with_progress(&mut self, _progress: &dyn Fn(BatchPhase)) -> Result<(), Error> {
+        Err(Error::InTransaction)
+    }
+
+    fn checkpoint(&mut self) -> Result<(), Error> {
+        Err(Error::InTransaction)
+    }
+
+    fn shutdown(&mut self) {}
+
+    fn put_keyed(&mut self, key: &[u8], data: &[u8]) -> Result<PRef, Error> {
+        if key.len() > u16::max_value() as usize || data.len() >= 1 << 23 {
+            return Err(Error::KeyTooLong);
+        }
+        self.ops.push(TransactionOp::Put(key.to_vec(), data.to_vec()));
+        Ok(PRef::invalid())
+    }
+
+    fn put_keyed_batch(&mut self, items: &[(&[u8], &[u8])]) -> Result<Vec<PRef>, Error> {
+        items.iter().map(|(key, data)| self.put_keyed(key, data)).collect()
+    }
+
+    fn put_keyed_unique(&mut self, key: &[u8], data: &[u8]) -> Result<PRef, Error> {
+        if self.contains_key(key)? {
+            return Err(Error::AlreadyExists);
+        }
+        self.put_keyed(key, data)
+    }
+
+    fn put_keyed_if_absent(&mut self, key: &[u8], data: &[u8]) -> Result<Option<PRef>, Error> {
+        if self.contains_key(key)? {
+            return Ok(None);
+        }
+        self.put_keyed(key, data).map(Some)
+    }
+
+    fn get_keyed(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>)>, Error> {
+        match self.pending(key) {
+            Some(data) => Ok(data.map(|data| (PRef::invalid(), data.to_vec()))),
+            None => self.db.get_keyed(key)
+        }
+    }
+
+    fn get_keyed_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<PRef>, Error> {
+        match self.pending(key) {
+            Some(data) => {
+                buf.clear();
+                Ok(data.map(|data| {
+                    buf.extend_from_slice(data);
+                    PRef::invalid()
+                }))
+            },
+            None => self.db.get_keyed_into(key, buf)
+        }
+    }
+
+    fn put_keyed_with_links(&mut self, _key: &[u8], _data: &[u8], _links: &[PRef]) -> Result<PRef, Error> {
+        Err(Error::InTransaction)
+    }
+
+    fn get_keyed_with_links(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>, Vec<PRef>)>, Error> {
+        match self.pending(key) {
+            Some(data) => Ok(data.map(|data| (PRef::invalid(), data.to_vec(), Vec::new()))),
+            None => self.db.get_keyed_with_links(key)
+        }
+    }
+
+    fn put_keyed_versioned(&mut self, _key: &[u8], _data: &[u8]) -> Result<(PRef, u32), Error> {
+        Err(Error::InTransaction)
+    }
+
+    fn get_keyed_version(&self, key: &[u8], version: u32) -> Result<Option<(PRef, Vec<u8>)>, Error> {
+        self.db.get_keyed_version(key, version)
+    }
+
+    fn get_pref_for_key(&self, key: &[u8]) -> Result<Option<PRef>, Error> {
+        match self.pending(key) {
+            Some(data) => Ok(data.map(|_| PRef::invalid())),
+            None => self.db.get_pref_for_key(key)
+        }
+    }
+
+    fn put(&mut self, _data: &[u8]) -> Result<PRef, Error> {
+        Err(Error::InTransaction)
+    }
+
+    fn put_with_refs(&mut self, _data: &[u8], _refs: &[PRef]) -> Result<PRef, Error> {
+        Err(Error::InTransaction)
+    }
+
+    fn put_referred_dag(&mut self, _nodes: &[(&[u8], Vec<usize>)]) -> Result<Vec<PRef>, Error> {
+        Err(Error::InTransaction)
+    }
+
+    fn get(&self, pref: PRef) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        self.db.get(pref)
+    }
+
+    fn get_with_refs(&self, pref: PRef) -> Result<(Vec<u8>, Vec<PRef>), Error> {
+        self.db.get_with_refs(pref)
+    }
+
+    fn put_pref_list(&mut self, _prefs: &[PRef]) -> Result<PRef, Error> {
+        Err(Error::InTransaction)
+    }
+
+    fn get_pref_list(&self, pref: PRef) -> Result<Vec<PRef>, Error> {
+        self.db.get_pref_list(pref)
+    }
+
+    fn may_have_key(&self, key: &[u8]) -> Result<bool, Error> {
+        match self.pending(key) {
+            Some(data) => Ok(data.is_some()),
+            None => self.db.may_have_key(key)
+        }
+    }
+
+    fn may_have_key_bulk(&self, keys: &[&[u8]]) -> Result<Vec<bool>, Error> {
+        let mut result = self.db.may_have_key_bulk(keys)?;
+        for (key, maybe) in keys.iter().zip(result.iter_mut()) {
+            if let Some(data) = self.pending(key) {
+                *maybe = data.is_some();
+            }
+        }
+        Ok(result)
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool, Error> {
+        match self.pending(key) {
+            Some(data) => Ok(data.is_some()),
+            None => self.db.contains_key(key)
+        }
+    }
+
+    fn get_keyed_approximate(&self, key: &[u8]) -> Result<ApproximateResult, Error> {
+        match self.pending(key) {
+            Some(Some(_)) => Ok(ApproximateResult::ProbablyPresent(PRef::invalid())),
+            Some(None) => Ok(ApproximateResult::DefinitelyAbsent),
+            None => self.db.get_keyed_approximate(key)
+        }
+    }
+
+    fn inspect_bucket(&self, key: &[u8]) -> Result<BucketInspection, Error> {
+        self.db.inspect_bucket(key)
+    }
+
+    fn set_access_log(&mut self, log: Arc<dyn AccessLog>) {
+        self.db.set_access_log(log)
+    }
+
+    fn watch(&self, key: &[u8]) -> Result<mpsc::Receiver<Option<Vec<u8>>>, Error> {
+        self.db.watch(key)
+    }
+
+    fn forget(&mut self, key: &[u8]) -> Result<(), Error> {
+        self.ops.push(TransactionOp::Forget(key.to_vec()));
+        Ok(())
+    }
+
+    fn forget_batch(&mut self, keys: &[&[u8]]) -> Result<(), Error> {
+        for key in keys {
+            self.forget(key)?;
+        }
+        Ok(())
+    }
+
+    fn iter(&self) -> HammersbaldIterator {
+        self.db.iter()
+    }
+
+    fn iter_indexed(&self) -> IndexedIterator {
+        self.db.iter_indexed()
+    }
+
+    fn iter_referred(&self) -> ReferredIterator {
+        self.db.iter_referred()
+    }
+
+    fn dag(&self, root: PRef) -> DagIterator {
+        self.db.dag(root)
+    }
+
+    fn iter_since_pref(&self, pref: PRef) -> HammersbaldIterator {
+        self.db.iter_since_pref(pref)
+    }
+
+    fn iter_from(&self, start: PRef) -> HammersbaldIterator {
+        self.db.iter_from(start)
+    }
+
+    fn iter_range(&self, from: PRef, to: PRef) -> HammersbaldIterator {
+        self.db.iter_range(from, to)
+    }
+
+    fn current_data_pos(&self) -> PRef {
+        self.db.current_data_pos()
+    }
+
+    fn prefix_scan<'b>(&'b self, prefix: &'b [u8]) -> PrefixIterator<'b> {
+        self.db.prefix_scan(prefix)
+    }
+
+    #[cfg(feature = "parallel_reads")]
+    fn prefix_scan_parallel(&self, prefix: &[u8]) -> Result<Vec<(PRef, Vec<u8>, Vec<u8>)>, Error> {
+        self.db.prefix_scan_parallel(prefix)
+    }
+
+    fn set_sip_keys(&mut self, _sip0: u64, _sip1: u64) -> Result<(), Error> {
+        Err(Error::InTransaction)
+    }
+
+    fn get_sip_keys(&self) -> (u64, u64) {
+        self.db.get_sip_keys()
+    }
+
+    fn stats_snapshot(&self) -> Result<Stats, Error> {
+        self.db.stats_snapshot()
+    }
+
+    fn count_live_keys(&self) -> Result<usize, Error> {
+        let mut count = self.db.count_live_keys()?;
+        let mut seen = HashSet::new();
+        for op in &self.ops {
+            let key = match op {
+                TransactionOp::Put(key, _) | TransactionOp::Forget(key) => key.as_slice()
+            };
+            if seen.insert(key) {
+                match (self.db.contains_key(key)?, self.contains_key(key)?) {
+                    (false, true) => count += 1,
+                    (true, false) => count -= 1,
+                    _ => {}
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    fn get_stats(&self) -> Result<DBStats, Error> {
+        self.db.get_stats()
+    }
+
+    fn last_recovery_stats(&self) -> Option<RecoveryStats> {
+        self.db.last_recovery_stats()
+    }
+
+    fn stats(&self) -> Result<MemTableStats, Error> {
+        self.db.stats()
+    }
+
+    fn bucket_fill_histogram(&self) -> Result<Vec<usize>, Error> {
+        self.db.bucket_fill_histogram()
+    }
+
+    fn backup(&mut self, _dest_name: &str) -> Result<BackupStats, Error> {
+        Err(Error::InTransaction)
+    }
+
+    fn rehash_to_target(&mut self, _target_bucket_count: usize) -> Result<(), Error> {
+        Err(Error::InTransaction)
+    }
+
+    fn compact_link_file(&mut self) -> Result<LinkCompactionStats, Error> {
+        Err(Error::InTransaction)
+    }
+
+    fn compact_data_file(&mut self) -> Result<u64, Error> {
+        Err(Error::InTransaction)
+    }
+
+    fn compact(&mut self) -> Result<CompactionReport, Error> {
+        Err(Error::InTransaction)
+    }
+
+    fn clone_to_transient(&self) -> Result<Box<dyn HammersbaldAPI>, Error> {
+        self.db.clone_to_transient()
+    }
+
+    fn try_clone(&mut self) -> Result<Box<dyn HammersbaldAPI>, Error> {
+        // would end the batch of the db
+        Err(Error::InTransaction)
+    }
+
+    fn transaction(&mut self) -> Transaction {
+        Transaction::new(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use api::{persistent, open_read_only, transient};
+
+    use std::{env, fs, process};
+
+    #[test]
+    fn test_commit() {
+        let dir = env::temp_dir().join(format!("hammersbald_test_transaction_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let name = dir.join("db").to_string_lossy().to_string();
+
+        let mut db = persistent(&name, 100, 1).unwrap();
+        db.put_keyed(b"committed", b"before").unwrap();
+        db.batch().unwrap();
+        {
+            let mut tx = db.transaction();
+            for i in 0u32 .. 100 {
+                tx.put_keyed(&i.to_be_bytes(), &i.to_le_bytes()).unwrap();
+            }
+            tx.forget(b"committed").unwrap();
+            // read your writes
+            for i in 0u32 .. 100 {
+                assert_eq!(tx.get_keyed(&i.to_be_bytes()).unwrap().unwrap().1, i.to_le_bytes().to_vec());
+            }
+            assert!(tx.get_keyed(b"committed").unwrap().is_none());
+
+            // a concurrent reader sees the committed state only
+            let mut reader = open_read_only(&name, 100).unwrap();
+            for i in 0u32 .. 100 {
+                assert!(reader.get_keyed(&i.to_be_bytes()).unwrap().is_none());
+            }
+            assert_eq!(reader.get_keyed(b"committed").unwrap().unwrap().1, b"before".to_vec());
+            reader.shutdown();
+
+            tx.commit().unwrap();
+        }
+        let mut reader = open_read_only(&name, 100).unwrap();
+        for i in 0u32 .. 100 {
+            assert_eq!(reader.get_keyed(&i.to_be_bytes()).unwrap().unwrap().1, i.to_le_bytes().to_vec());
+        }
+        assert!(reader.get_keyed(b"committed").unwrap().is_none());
+        reader.shutdown();
+
+        db.shutdown();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rollback() {
+        let mut db = transient(1).unwrap();
+        db.put_keyed(b"key", b"before").unwrap();
+        {
+            let mut tx = db.transaction();
+            tx.put_keyed(b"key", b"after").unwrap();
+            tx.put_keyed(b"other", b"data").unwrap();
+            assert!(match tx.put_keyed_unique(b"other", b"data") { Err(Error::AlreadyExists) => true, _ => false });
+            // stored within the transaction, so without a pref yet
+            assert_eq!(tx.put_keyed_if_absent(b"absent", b"data").unwrap(), Some(PRef::invalid()));
+            assert_eq!(tx.put_keyed_if_absent(b"other", b"other data").unwrap(), None);
+            assert_eq!(tx.get_keyed(b"other").unwrap().unwrap().1, b"data".to_vec());
+            assert!(match tx.put(b"data") { Err(Error::InTransaction) => true, _ => false });
+            assert_eq!(tx.get_keyed(b"key").unwrap().unwrap().1, b"after".to_vec());
+            tx.rollback();
+        }
+        assert_eq!(db.get_keyed(b"key").unwrap().unwrap().1, b"before".to_vec());
+        assert!(db.get_keyed(b"other").unwrap().is_none());
+        {
+            // dropped without commit
+            let mut tx = db.transaction();
+            tx.forget(b"key").unwrap();
+        }
+        assert!(db.contains_key(b"key").unwrap());
+        db.shutdown();
+    }
+
+    // the db refuses too long keys only in debug builds
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_commit_failing() {
+        let mut db = transient(1).unwrap();
+        db.put_keyed(b"key", b"before").unwrap();
+        db.put_keyed(b"forgotten", b"before").unwrap();
+        db.batch().unwrap();
+        {
+            let mut tx = db.transaction();
+            tx.put_keyed(b"key", b"after").unwrap();
+            tx.put_keyed(b"new", b"data").unwrap();
+            tx.forget(b"forgotten").unwrap();
+            // a write the db refuses after others were applied
+            tx.ops.push(TransactionOp::Put(vec!(0u8; 1 << 16), b"data".to_vec()));
+            match tx.commit() {
+                Err(Error::KeyTooLong) => {},
+                _ => panic!("commit should fail")
+            }
+        }
+        assert_eq!(db.get_keyed(b"key").unwrap().unwrap().1, b"before".to_vec());
+        assert_eq!(db.get_keyed(b"forgotten").unwrap().unwrap().1, b"before".to_vec());
+        assert!(db.get_keyed(b"new").unwrap().is_none());
+        db.shutdown();
+    }
+}
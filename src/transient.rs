@@ -25,7 +25,7 @@ use tablefile::TableFile;
 use datafile::DataFile;
 use pref::PRef;
 use page::{Page,PAGE_SIZE};
-use pagedfile::PagedFile;
+use pagedfile::{PagedFile, PagedFileIterator};
 use asyncfile::AsyncFile;
 use cachedfile::CachedFile;
 
@@ -50,11 +50,25 @@ struct Inner {
 
 impl Transient {
     /// create a new file
-    fn new (append: bool) -> Transient {
+    pub fn new (append: bool) -> Transient {
         Transient {inner: Mutex::new(Inner{data: Vec::new(), pos: 0, append})}
     }
 
     pub fn new_db (_name: &str, cached_data_pages: usize, bucket_fill_target: usize) -> Result<Box<dyn HammersbaldAPI>, Error> {
+        Ok(Box::new(Self::new_hammersbald(cached_data_pages, bucket_fill_target)?))
+    }
+
+    /// a transient file with the pages of file
+    pub fn copy_of (file: &dyn PagedFile, append: bool) -> Result<Transient, Error> {
+        let mut copy = Transient::new(append);
+        for page in PagedFileIterator::new(file, PRef::from(0)) {
+            copy.append_page(page)?;
+        }
+        Ok(copy)
+    }
+
+    /// create a transient db
+    pub fn new_hammersbald (cached_data_pages: usize, bucket_fill_target: usize) -> Result<Hammersbald, Error> {
         let log = LogFile::new(
             Box::new(AsyncFile::new(
             Box::new(Transient::new(true)))?));
@@ -69,7 +83,14 @@ impl Transient {
             Box::new(CachedFile::new(
                 Box::new(AsyncFile::new(Box::new(Transient::new(true)))?),
                 cached_data_pages)?))?;
-        Ok(Box::new(Hammersbald::new(log, table, data, link, bucket_fill_target)?))
+        Hammersbald::new(log, table, data, link, bucket_fill_target)
+    }
+}
+
+impl Clone for Transient {
+    fn clone(&self) -> Transient {
+        let inner = self.inner.lock().unwrap();
+        Transient {inner: Mutex::new(Inner{data: inner.data.clone(), pos: inner.pos, append: inner.append})}
     }
 }
 
@@ -93,7 +114,7 @@ impl PagedFile for Transient {
 
     fn truncate(&mut self, len: u64) -> Result<(), Error> {
         if len % PAGE_SIZE as u64 != 0 {
-            return Err(Error::Corrupted(format!("truncate not to page boundary {}", len)));
+            return Err(Error::corrupted(format!("truncate not to page boundary {}", len)));
         }
         let mut inner = self.inner.lock().unwrap();
         inner.data.truncate(len as usize);
@@ -119,6 +140,19 @@ impl PagedFile for Transient {
     }
 
     fn flush(&mut self) -> Result<(), Error> {Ok(())}
+
+    fn replacement(&self) -> Result<Box<dyn PagedFile>, Error> {
+        Ok(Box::new(Transient::new(self.inner.lock().unwrap().append)))
+    }
+
+    fn replace_with(&mut self, replacement: Box<dyn PagedFile>) -> Result<(), Error> {
+        let mut copy = Transient::new(true);
+        replacement.copy_to(&mut copy)?;
+        let mut inner = self.inner.lock().unwrap();
+        inner.data = copy.inner.into_inner().unwrap().data;
+        inner.pos = 0;
+        Ok(())
+    }
 }
 
 impl Read for Inner {
@@ -0,0 +1,56 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+extern crate hammersbald;
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::process::Command;
+
+#[test]
+fn repair_partial_page() {
+    let dir = env::temp_dir().join(format!("hammersbald_test_repair_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let name = dir.join("db").to_string_lossy().to_string();
+
+    let mut db = hammersbald::persistent(&name, 100, 1).unwrap();
+    for i in 0u32 .. 100 {
+        db.put_keyed(&i.to_be_bytes(), &i.to_be_bytes()).unwrap();
+    }
+    db.batch().unwrap();
+    db.shutdown();
+    drop(db);
+
+    // a crash in the middle of writing a page
+    let mut data = fs::OpenOptions::new().append(true).open(name.clone() + ".0.bc").unwrap();
+    data.write_all(&[0xffu8; 100]).unwrap();
+    drop(data);
+    assert!(hammersbald::persistent(&name, 100, 1).is_err());
+
+    let status = Command::new(env!("CARGO_BIN_EXE_repair")).args(&["--db", &name, "--dry-run"]).status().unwrap();
+    assert!(status.success());
+    assert!(hammersbald::persistent(&name, 100, 1).is_err());
+
+    let status = Command::new(env!("CARGO_BIN_EXE_repair")).args(&["--db", &name]).status().unwrap();
+    assert!(status.success());
+
+    let mut db = hammersbald::persistent(&name, 100, 1).unwrap();
+    for i in 0u32 .. 100 {
+        assert_eq!(db.get_keyed(&i.to_be_bytes()).unwrap().unwrap().1, i.to_be_bytes().to_vec());
+    }
+    db.shutdown();
+    fs::remove_dir_all(&dir).unwrap();
+}